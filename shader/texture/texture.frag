@@ -4,15 +4,53 @@
 
 layout (binding = 1) uniform sampler2D samplerColor;
 
-layout (binding = 0) uniform UBO{
-    vec3 color;
-} ubo;
+// The colour description of the video, ITU-T H.264 E.2.1, the samples are non-linear R'G'B'
+layout (binding = 0) uniform ColourParams {
+    // Linear RGB in the colour primaries of the video to BT.709 primaries
+    mat3 primaries;
+    // Table E-4
+    uint transferCharacteristics;
+    // The output is encoded here when the swapchain format does not do it
+    uint encodeSrgb;
+} colour;
 
 
 layout (location = 0) in vec2 o_uv;
 layout (location = 0) out vec4 uFragColor;
 
+// Linear light relative to the SDR reference white
+vec3 toLinear(vec3 v) {
+    switch (colour.transferCharacteristics) {
+    case 4u:
+        return pow(v, vec3(2.2));
+    case 5u:
+        return pow(v, vec3(2.8));
+    case 8u:
+        return v;
+    case 13u:
+        return mix(v / 12.92, pow((v + 0.055) / 1.055, vec3(2.4)), greaterThan(v, vec3(0.04045)));
+    case 16u: {
+        // SMPTE ST 2084, 10000 cd/m2 peak over a 203 cd/m2 reference white
+        vec3 p = pow(v, vec3(1.0 / 78.84375));
+        vec3 l = pow(max(p - 0.8359375, 0.0) / (18.8515625 - 18.6875 * p), vec3(1.0 / 0.1593017578125));
+        return l * (10000.0 / 203.0);
+    }
+    case 18u: {
+        // ARIB STD-B67 with the system gamma of a 1000 cd/m2 display, per component
+        vec3 e = mix(v * v / 3.0, (exp((v - 0.55991073) / 0.17883277) + 0.28466892) / 12.0, greaterThan(v, vec3(0.5)));
+        return pow(e, vec3(1.2)) * (1000.0 / 203.0);
+    }
+    default:
+        // BT.1886, the display of BT.601, BT.709 and BT.2020 video
+        return pow(v, vec3(2.4));
+    }
+}
+
 void main() {
-    vec4 color = texture(samplerColor, o_uv);
-    uFragColor = color;
+    vec3 color = texture(samplerColor, o_uv).rgb;
+    color = clamp(colour.primaries * toLinear(color), 0.0, 1.0);
+    if (colour.encodeSrgb != 0u) {
+        color = mix(color * 12.92, 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055, greaterThan(color, vec3(0.0031308)));
+    }
+    uFragColor = vec4(color, 1.0);
 }
@@ -0,0 +1,256 @@
+use anyhow::Result;
+
+use crate::nal::{NalUnit, NalUnitType};
+use crate::source::{AccessUnit, VideoSource};
+
+/// Locates the next NAL unit at or after `offset` and returns its byte range without the start code.
+///
+/// Both 3-byte and 4-byte start codes are accepted, the extra zero byte of the latter ends up as
+/// trailing zero of the previous NAL unit and is stripped together with any trailing_zero_8bits.
+fn next_nal_unit_range(data: &[u8], offset: usize) -> Option<(usize, usize)> {
+    let start = find_start_code(data, offset)? + 3;
+    let end = find_start_code(data, start).unwrap_or(data.len());
+
+    let mut trimmed_end = end;
+    while trimmed_end > start && data[trimmed_end - 1] == 0 {
+        trimmed_end -= 1;
+    }
+
+    Some((start, trimmed_end))
+}
+
+fn find_start_code(data: &[u8], offset: usize) -> Option<usize> {
+    data.get(offset..)?
+        .windows(3)
+        .position(|window| window == [0, 0, 1])
+        .map(|position| offset + position)
+}
+
+/// Splits an Annex B byte stream on start codes.
+pub struct AnnexBNalUnits<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+pub fn annexb_nal_units(data: &[u8]) -> AnnexBNalUnits<'_> {
+    AnnexBNalUnits { data, offset: 0 }
+}
+
+impl<'a> Iterator for AnnexBNalUnits<'a> {
+    type Item = Result<NalUnit<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (start, end) = next_nal_unit_range(self.data, self.offset)?;
+            self.offset = end;
+
+            // Skip stray start codes with nothing in between
+            if start < end {
+                return Some(NalUnit::parse(&self.data[start..end]));
+            }
+        }
+    }
+}
+
+/// Whether `nal_unit` begins a new access unit once the current one already holds a primary coded picture,
+/// ITU-T H.264 7.4.1.2.3.
+fn starts_access_unit(nal_unit: &NalUnit) -> bool {
+    match nal_unit.nal_unit_type {
+        NalUnitType::AccessUnitDelimiter
+        | NalUnitType::Sps
+        | NalUnitType::Pps
+        | NalUnitType::Sei
+        | NalUnitType::PrefixNalUnit
+        | NalUnitType::SubsetSps
+        | NalUnitType::DepthParameterSet
+        | NalUnitType::Reserved(17..=18) => true,
+        // first_mb_in_slice is the leading ue(v) of the slice header, it is 0 exactly when its first bit is set
+        NalUnitType::NonIdrSlice | NalUnitType::IdrSlice | NalUnitType::SliceDataPartitionA => {
+            matches!(nal_unit.payload().first(), Some(&byte) if byte & 0b10000000 != 0)
+        }
+        _ => false,
+    }
+}
+
+/// Raw H.264 elementary stream, as written to .h264/.264 files.
+///
+/// There is no timing information in the stream, access units are numbered in decode order and
+/// presentation order has to be recovered from the picture order count.
+pub struct AnnexBSource {
+    data: Vec<u8>,
+    offset: usize,
+    access_unit_count: i64,
+}
+
+impl AnnexBSource {
+    pub fn new(data: Vec<u8>) -> Self {
+        AnnexBSource {
+            data,
+            offset: 0,
+            access_unit_count: 0,
+        }
+    }
+
+    fn read_access_unit(&mut self) -> Result<Option<AccessUnit>> {
+        let mut nal_units = Vec::new();
+        let mut has_picture = false;
+        let mut is_keyframe = false;
+
+        while let Some((start, end)) = next_nal_unit_range(&self.data, self.offset) {
+            if start == end {
+                self.offset = end;
+                continue;
+            }
+
+            let nal_unit = match NalUnit::parse(&self.data[start..end]) {
+                Ok(nal_unit) => nal_unit,
+                Err(err) => {
+                    // Resume after the broken NAL unit on the next call
+                    self.offset = end;
+                    return Err(err);
+                }
+            };
+
+            if has_picture && starts_access_unit(&nal_unit) {
+                break;
+            }
+
+            self.offset = end;
+
+            has_picture |= nal_unit.nal_unit_type.is_slice()
+                || nal_unit.nal_unit_type == NalUnitType::SliceDataPartitionA;
+            is_keyframe |= nal_unit.nal_unit_type == NalUnitType::IdrSlice;
+
+            nal_units.push(nal_unit.data.to_vec());
+
+            if nal_unit.nal_unit_type == NalUnitType::EndOfStream {
+                break;
+            }
+        }
+
+        if nal_units.is_empty() {
+            return Ok(None);
+        }
+
+        let index = self.access_unit_count;
+        self.access_unit_count += 1;
+
+        Ok(Some(AccessUnit {
+            nal_units,
+            dts: index,
+            pts: index,
+            is_keyframe,
+        }))
+    }
+}
+
+impl VideoSource for AnnexBSource {
+    fn next_access_unit(&mut self) -> Option<Result<AccessUnit>> {
+        self.read_access_unit().transpose()
+    }
+
+    fn coded_extent(&self) -> Option<(u32, u32)> {
+        None
+    }
+
+    fn timescale(&self) -> u64 {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: &[u8] = &[0x67, 0x4d, 0x40, 0x1e];
+    const PPS: &[u8] = &[0x68, 0xee, 0x3c, 0x80];
+    const AUD: &[u8] = &[0x09, 0xf0];
+    const SEI: &[u8] = &[0x06, 0x05, 0x80];
+    const END_OF_STREAM: &[u8] = &[0x0b];
+    // first_mb_in_slice of 0 and of something else
+    const IDR: &[u8] = &[0x65, 0x88, 0x84];
+    const IDR_NEXT: &[u8] = &[0x65, 0x20, 0x10];
+    const P: &[u8] = &[0x41, 0x9a, 0x21];
+    const P_NEXT: &[u8] = &[0x41, 0x20, 0x10];
+
+    fn stream(nal_units: &[&[u8]]) -> Vec<u8> {
+        nal_units
+            .iter()
+            .enumerate()
+            .flat_map(|(i, nal_unit)| {
+                // Alternate between 4 and 3 byte start codes
+                let start_code: &[u8] = if i % 2 == 0 {
+                    &[0, 0, 0, 1]
+                } else {
+                    &[0, 0, 1]
+                };
+                [start_code, nal_unit].concat()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn start_codes() {
+        let data = [
+            0, 0, 0, 1, 0x67, 0xaa, // 4 byte start code
+            0, 0, 1, 0x68, 0xbb, 0, 0, // trailing_zero_8bits
+            0, 0, 1, 0, 0, 1, // nothing between two start codes
+            0, 0, 1, 0x65, 0x00, 0x88, 0x00,
+        ];
+
+        let nal_units = annexb_nal_units(&data)
+            .map(|nal_unit| nal_unit.map(|nal_unit| nal_unit.data))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            nal_units,
+            [&[0x67, 0xaa][..], &[0x68, 0xbb], &[0x65, 0x00, 0x88]]
+        );
+
+        assert_eq!(annexb_nal_units(&[0xaa, 0, 0]).count(), 0);
+        assert!(annexb_nal_units(&[0, 0, 1, 0xe7]).next().unwrap().is_err());
+    }
+
+    #[test]
+    fn access_units() {
+        let expected: [(&[&[u8]], bool); 6] = [
+            // Slices of one picture stay together
+            (&[AUD, SPS, PPS, IDR, IDR_NEXT], true),
+            (&[P, P_NEXT], false),
+            // A parameter set, an AUD or an SEI comes before the next picture
+            (&[PPS, P], false),
+            (&[AUD, P], false),
+            // What follows the end of the stream is a new access unit
+            (&[SEI, P, END_OF_STREAM], false),
+            (&[P], false),
+        ];
+        let nal_units = expected
+            .iter()
+            .flat_map(|(nal_units, _)| nal_units.iter().copied())
+            .collect::<Vec<_>>();
+
+        let mut source = AnnexBSource::new(stream(&nal_units));
+        for (index, (nal_units, is_keyframe)) in expected.into_iter().enumerate() {
+            let access_unit = source.next_access_unit().unwrap().unwrap();
+            assert_eq!(access_unit.nal_units, nal_units, "access unit {}", index);
+            assert_eq!(
+                access_unit.is_keyframe, is_keyframe,
+                "access unit {}",
+                index
+            );
+            assert_eq!(access_unit.dts, index as i64);
+            assert_eq!(access_unit.pts, index as i64);
+        }
+        assert!(source.next_access_unit().is_none());
+    }
+
+    #[test]
+    fn broken_nal_unit() {
+        let mut source = AnnexBSource::new(stream(&[&[0xe1, 0x9a], P]));
+
+        assert!(source.next_access_unit().unwrap().is_err());
+        // Reading resumes after it
+        assert_eq!(source.next_access_unit().unwrap().unwrap().nal_units, [P]);
+        assert!(source.next_access_unit().is_none());
+    }
+}
@@ -0,0 +1,318 @@
+use anyhow::{anyhow, Result};
+
+/// Trailing fields of the avcC box, only present for the High profiles.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AVCHighProfileExtension {
+    pub chroma_format: u8,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+    pub sps_ext: Vec<Vec<u8>>,
+}
+
+/// AVCDecoderConfigurationRecord, ISO/IEC 14496-15 5.3.3.1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AVCVideoConfiguration {
+    pub version: u8,
+    pub profile: u8,
+    pub compatibility: u8,
+    pub level: u8,
+    // indicates the length in bytes of the length field in an AVC video access unit used indicate the length of each NAL unit.
+    pub length_size_minus_one: u8,
+    pub sps: Vec<Vec<u8>>,
+    pub pps: Vec<Vec<u8>>,
+    pub high_profile_extension: Option<AVCHighProfileExtension>,
+}
+
+/// Profiles for which the record may carry the chroma format, bit depth and SPS extension fields.
+const HIGH_PROFILES: [u8; 4] = [100, 110, 122, 144];
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn remaining(&self) -> usize {
+        self.data.len() - self.offset
+    }
+
+    fn bytes(&mut self, len: usize, what: &str) -> Result<&'a [u8]> {
+        if self.remaining() < len {
+            return Err(anyhow!(
+                "avcC truncated reading {} at offset {}: need {} bytes, {} left",
+                what,
+                self.offset,
+                len,
+                self.remaining()
+            ));
+        }
+
+        let bytes = &self.data[self.offset..self.offset + len];
+        self.offset += len;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self, what: &str) -> Result<u8> {
+        Ok(self.bytes(1, what)?[0])
+    }
+
+    fn u16(&mut self, what: &str) -> Result<u16> {
+        let bytes = self.bytes(2, what)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn parameter_sets(&mut self, count: usize, what: &str) -> Result<Vec<Vec<u8>>> {
+        (0..count)
+            .map(|_| {
+                let size = self.u16(what)? as usize;
+                if size == 0 {
+                    return Err(anyhow!("avcC contains an empty {}", what));
+                }
+                Ok(self.bytes(size, what)?.to_vec())
+            })
+            .collect()
+    }
+}
+
+pub fn parse_avc_config(data: &[u8]) -> Result<AVCVideoConfiguration> {
+    let mut reader = ByteReader { data, offset: 0 };
+
+    let version = reader.u8("configurationVersion")?;
+    if version != 1 {
+        return Err(anyhow!("Unsupported avcC configurationVersion {}", version));
+    }
+
+    let profile = reader.u8("AVCProfileIndication")?;
+    let compatibility = reader.u8("profile_compatibility")?;
+    let level = reader.u8("AVCLevelIndication")?;
+
+    let length_size_minus_one = reader.u8("lengthSizeMinusOne")? & 0b00000011;
+    if length_size_minus_one == 2 {
+        return Err(anyhow!(
+            "avcC NAL unit length size of 3 bytes is not allowed"
+        ));
+    }
+
+    let number_of_sps_nalus = reader.u8("numOfSequenceParameterSets")? & 0b00011111;
+    let sps = reader.parameter_sets(number_of_sps_nalus as usize, "sequence parameter set")?;
+
+    let number_of_pps_nalus = reader.u8("numOfPictureParameterSets")?;
+    let pps = reader.parameter_sets(number_of_pps_nalus as usize, "picture parameter set")?;
+
+    // Plenty of muxers leave the extension out even for High profile streams
+    let high_profile_extension = if HIGH_PROFILES.contains(&profile) && reader.remaining() > 0 {
+        let chroma_format = reader.u8("chroma_format")? & 0b00000011;
+        let bit_depth_luma_minus8 = reader.u8("bit_depth_luma_minus8")? & 0b00000111;
+        let bit_depth_chroma_minus8 = reader.u8("bit_depth_chroma_minus8")? & 0b00000111;
+        let number_of_sps_ext_nalus = reader.u8("numOfSequenceParameterSetExt")?;
+        let sps_ext = reader.parameter_sets(
+            number_of_sps_ext_nalus as usize,
+            "sequence parameter set extension",
+        )?;
+
+        Some(AVCHighProfileExtension {
+            chroma_format,
+            bit_depth_luma_minus8,
+            bit_depth_chroma_minus8,
+            sps_ext,
+        })
+    } else {
+        None
+    };
+
+    Ok(AVCVideoConfiguration {
+        version,
+        profile,
+        compatibility,
+        level,
+        length_size_minus_one,
+        sps,
+        pps,
+        high_profile_extension,
+    })
+}
+
+fn write_parameter_sets(out: &mut Vec<u8>, parameter_sets: &[Vec<u8>], what: &str) -> Result<()> {
+    for parameter_set in parameter_sets {
+        let size = u16::try_from(parameter_set.len()).map_err(|_| {
+            anyhow!(
+                "{} of {} bytes does not fit avcC",
+                what,
+                parameter_set.len()
+            )
+        })?;
+        out.extend_from_slice(&size.to_be_bytes());
+        out.extend_from_slice(parameter_set);
+    }
+
+    Ok(())
+}
+
+impl AVCVideoConfiguration {
+    /// Size in bytes of the NAL unit length prefix in every sample of the track.
+    pub fn nal_length_size(&self) -> usize {
+        self.length_size_minus_one as usize + 1
+    }
+
+    /// Serializes the record back into the payload of an avcC box, reserved bits are written as ones.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.sps.len() > 0b00011111 {
+            return Err(anyhow!(
+                "avcC can hold at most 31 sequence parameter sets, got {}",
+                self.sps.len()
+            ));
+        }
+        if self.pps.len() > u8::MAX as usize {
+            return Err(anyhow!(
+                "avcC can hold at most 255 picture parameter sets, got {}",
+                self.pps.len()
+            ));
+        }
+
+        let mut out = vec![
+            self.version,
+            self.profile,
+            self.compatibility,
+            self.level,
+            0b11111100 | (self.length_size_minus_one & 0b00000011),
+            0b11100000 | self.sps.len() as u8,
+        ];
+        write_parameter_sets(&mut out, &self.sps, "Sequence parameter set")?;
+
+        out.push(self.pps.len() as u8);
+        write_parameter_sets(&mut out, &self.pps, "Picture parameter set")?;
+
+        if let Some(extension) = &self.high_profile_extension {
+            if extension.sps_ext.len() > u8::MAX as usize {
+                return Err(anyhow!(
+                    "avcC can hold at most 255 sequence parameter set extensions, got {}",
+                    extension.sps_ext.len()
+                ));
+            }
+
+            out.push(0b11111100 | (extension.chroma_format & 0b00000011));
+            out.push(0b11111000 | (extension.bit_depth_luma_minus8 & 0b00000111));
+            out.push(0b11111000 | (extension.bit_depth_chroma_minus8 & 0b00000111));
+            out.push(extension.sps_ext.len() as u8);
+            write_parameter_sets(
+                &mut out,
+                &extension.sps_ext,
+                "Sequence parameter set extension",
+            )?;
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn main_profile_config() -> AVCVideoConfiguration {
+        AVCVideoConfiguration {
+            version: 1,
+            profile: 77,
+            compatibility: 0x40,
+            level: 30,
+            length_size_minus_one: 3,
+            sps: vec![vec![0x67, 0x4d, 0x40, 0x1e, 0xe8]],
+            pps: vec![vec![0x68, 0xee, 0x3c, 0x80], vec![0x68, 0xce]],
+            high_profile_extension: None,
+        }
+    }
+
+    fn high_profile_config() -> AVCVideoConfiguration {
+        AVCVideoConfiguration {
+            profile: 100,
+            high_profile_extension: Some(AVCHighProfileExtension {
+                chroma_format: 1,
+                bit_depth_luma_minus8: 2,
+                bit_depth_chroma_minus8: 2,
+                sps_ext: vec![vec![0x6d, 0x01, 0x02]],
+            }),
+            ..main_profile_config()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for config in [main_profile_config(), high_profile_config()] {
+            let bytes = config.to_bytes().unwrap();
+            assert_eq!(parse_avc_config(&bytes).unwrap(), config);
+            assert_eq!(parse_avc_config(&bytes).unwrap().to_bytes().unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn reserved_bits_are_ignored() {
+        let mut bytes = main_profile_config().to_bytes().unwrap();
+        assert_eq!(bytes[4], 0xff);
+        assert_eq!(bytes[5], 0xe1);
+
+        bytes[4] = 0b00000011;
+        bytes[5] = 0b00000001;
+        assert_eq!(parse_avc_config(&bytes).unwrap(), main_profile_config());
+    }
+
+    #[test]
+    fn high_profile_without_extension() {
+        let config = AVCVideoConfiguration {
+            high_profile_extension: None,
+            ..high_profile_config()
+        };
+
+        let bytes = config.to_bytes().unwrap();
+        assert_eq!(parse_avc_config(&bytes).unwrap(), config);
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = main_profile_config().to_bytes().unwrap();
+        for len in 0..bytes.len() {
+            assert!(parse_avc_config(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+
+        // Cut anywhere but right before the extension
+        let bytes = high_profile_config().to_bytes().unwrap();
+        let without_extension = main_profile_config().to_bytes().unwrap().len();
+        for len in (0..bytes.len()).filter(|&len| len != without_extension) {
+            assert!(parse_avc_config(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let bytes = main_profile_config().to_bytes().unwrap();
+
+        let mut version = bytes.clone();
+        version[0] = 2;
+        assert!(parse_avc_config(&version).is_err());
+
+        let mut length_size = bytes.clone();
+        length_size[4] = 0b11111110;
+        assert!(parse_avc_config(&length_size).is_err());
+
+        // The first SPS claims no bytes
+        let mut empty_sps = bytes.clone();
+        empty_sps[6] = 0;
+        empty_sps[7] = 0;
+        assert!(parse_avc_config(&empty_sps).is_err());
+    }
+
+    #[test]
+    fn too_many_parameter_sets() {
+        let config = AVCVideoConfiguration {
+            sps: vec![vec![0x67]; 32],
+            ..main_profile_config()
+        };
+        assert!(config.to_bytes().is_err());
+
+        let config = AVCVideoConfiguration {
+            pps: vec![vec![0x68]; 256],
+            ..main_profile_config()
+        };
+        assert!(config.to_bytes().is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+use std::ptr;
+
+use anyhow::{anyhow, Result};
+use ash::{vk, Device};
+
+use crate::find_memorytype_index;
+use crate::source::{AccessUnit, START_CODE};
+use crate::sync::Timeline;
+
+/// Where an access unit was written in a [`BitstreamRing`], the source range of its decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BitstreamRegion {
+    pub offset: u64,
+    /// Size rounded up to `min_bitstream_buffer_size_alignment`, zero padded.
+    pub size: u64,
+}
+
+impl BitstreamRegion {
+    fn end(&self) -> u64 {
+        self.offset + self.size
+    }
+
+    fn overlaps(&self, other: &BitstreamRegion) -> bool {
+        self.offset < other.end() && other.offset < self.end()
+    }
+}
+
+/// A persistently mapped bitstream buffer access units are written to one after another,
+/// wrapping around to the start once the end is reached.
+///
+/// A region is handed back to the ring with the decode timeline value of the decode reading it,
+/// and overwritten once the timeline reached that value.
+pub struct BitstreamRing {
+    pub buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    mapped: *mut u8,
+    size: u64,
+    offset_alignment: u64,
+    size_alignment: u64,
+    // where the next region starts looking for space
+    head: u64,
+    // regions read by decodes in flight, oldest first
+    in_flight: VecDeque<(BitstreamRegion, u64)>,
+}
+
+impl BitstreamRing {
+    /// Creates a ring of at least `size` bytes for the video profiles of `profile_list_info`,
+    /// the alignments are `VideoCapabilitiesKHR::min_bitstream_buffer_offset_alignment` and
+    /// `min_bitstream_buffer_size_alignment`.
+    pub fn new(
+        device: &Device,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        profile_list_info: &mut vk::VideoProfileListInfoKHR,
+        offset_alignment: u64,
+        size_alignment: u64,
+        size: u64,
+    ) -> Result<Self> {
+        let offset_alignment = offset_alignment.max(1);
+        let size_alignment = size_alignment.max(1);
+        let size = align_up(size, size_alignment);
+
+        unsafe {
+            let buffer_info = vk::BufferCreateInfo {
+                p_next: profile_list_info as *mut _ as _,
+                size,
+                usage: vk::BufferUsageFlags::VIDEO_DECODE_SRC_KHR,
+                sharing_mode: vk::SharingMode::EXCLUSIVE,
+                ..Default::default()
+            };
+
+            let buffer = device.create_buffer(&buffer_info, None)?;
+
+            let memory_req = device.get_buffer_memory_requirements(buffer);
+            let memory_index = find_memorytype_index(
+                &memory_req,
+                device_memory_properties,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )
+            .ok_or_else(|| {
+                anyhow!("Unable to find suitable memorytype for the bitstream buffer")
+            })?;
+
+            let allocate_info = vk::MemoryAllocateInfo {
+                allocation_size: memory_req.size,
+                memory_type_index: memory_index,
+                ..Default::default()
+            };
+            let memory = device.allocate_memory(&allocate_info, None)?;
+            device.bind_buffer_memory(buffer, memory, 0)?;
+
+            let mapped =
+                device.map_memory(memory, 0, size, vk::MemoryMapFlags::empty())? as *mut u8;
+
+            Ok(BitstreamRing {
+                buffer,
+                memory,
+                mapped,
+                size,
+                offset_alignment,
+                size_alignment,
+                head: 0,
+                in_flight: VecDeque::new(),
+            })
+        }
+    }
+
+    /// Writes `access_unit` as an Annex B byte stream, start codes in front of every NAL unit as
+    /// in [`AccessUnit::to_annexb`] the slice offsets are relative to.
+    ///
+    /// Regions of finished decodes are reclaimed first, the ring only blocks on `decode_timeline`
+    /// when the access unit does not fit anywhere else.
+    pub fn write(
+        &mut self,
+        device: &Device,
+        decode_timeline: &Timeline,
+        access_unit: &AccessUnit,
+    ) -> Result<BitstreamRegion> {
+        let data_size = access_unit
+            .nal_units
+            .iter()
+            .map(|nal_unit| (START_CODE.len() + nal_unit.len()) as u64)
+            .sum::<u64>();
+        let region_size = align_up(data_size, self.size_alignment);
+
+        if region_size > self.size {
+            return Err(anyhow!(
+                "Access unit of {} bytes does not fit the {} byte bitstream buffer",
+                data_size,
+                self.size
+            ));
+        }
+
+        let completed_value = decode_timeline.completed_value(device)?;
+        while let Some(&(_, decode_value)) = self.in_flight.front() {
+            if decode_value > completed_value {
+                break;
+            }
+            self.in_flight.pop_front();
+        }
+
+        let mut offset = align_up(self.head, self.offset_alignment);
+        if offset + region_size > self.size {
+            offset = 0;
+        }
+        let region = BitstreamRegion {
+            offset,
+            size: region_size,
+        };
+
+        // Regions are handed back in the order they were written, waiting for the oldest one
+        // overlapping frees everything in front of it as well
+        while self
+            .in_flight
+            .iter()
+            .any(|(in_flight, _)| in_flight.overlaps(&region))
+        {
+            if let Some((_, decode_value)) = self.in_flight.pop_front() {
+                decode_timeline.wait(device, decode_value)?;
+            }
+        }
+
+        unsafe {
+            let mut dst = self.mapped.add(region.offset as usize);
+            for nal_unit in &access_unit.nal_units {
+                ptr::copy_nonoverlapping(START_CODE.as_ptr(), dst, START_CODE.len());
+                dst = dst.add(START_CODE.len());
+                ptr::copy_nonoverlapping(nal_unit.as_ptr(), dst, nal_unit.len());
+                dst = dst.add(nal_unit.len());
+            }
+            ptr::write_bytes(dst, 0, (region_size - data_size) as usize);
+        }
+
+        self.head = region.end();
+
+        Ok(region)
+    }
+
+    /// Hands `region` back once the decode timeline reached `decode_value`, the value of the
+    /// decode reading it.
+    pub fn release(&mut self, region: BitstreamRegion, decode_value: u64) {
+        self.in_flight.push_back((region, decode_value));
+    }
+
+    pub fn destroy(&self, device: &Device) {
+        unsafe {
+            device.unmap_memory(self.memory);
+            device.destroy_buffer(self.buffer, None);
+            device.free_memory(self.memory, None);
+        }
+    }
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
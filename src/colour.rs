@@ -0,0 +1,387 @@
+use ash::vk;
+
+use crate::sps::SequenceParameterSet;
+
+/// The colour description of a coded video sequence, ITU-T H.264 E.2.1, with every value the VUI
+/// leaves out or unspecified inferred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColourParams {
+    /// Table E-3
+    pub colour_primaries: u8,
+    /// Table E-4
+    pub transfer_characteristics: u8,
+    /// Table E-5
+    pub matrix_coefficients: u8,
+    pub video_full_range_flag: bool,
+}
+
+impl ColourParams {
+    /// Inferred values follow the video_format of Table E-2, or the display height without one:
+    /// BT.601 525 or 625 line for standard definition and BT.709 above.
+    pub fn from_sps(sps: &SequenceParameterSet) -> Self {
+        let video_signal_type = sps.vui.as_ref().and_then(|vui| vui.video_signal_type);
+        let colour_description =
+            video_signal_type.and_then(|video_signal_type| video_signal_type.colour_description);
+
+        let height = sps.display_rect().extent.height;
+        let video_format =
+            video_signal_type.map(|video_signal_type| video_signal_type.video_format);
+        // colour_primaries, transfer_characteristics and matrix_coefficients of the system
+        let (primaries, transfer, matrix) = match video_format {
+            // PAL, SECAM and MAC
+            Some(1) | Some(3) | Some(4) => (5, 6, 5),
+            // NTSC
+            Some(2) => (6, 6, 6),
+            _ if height <= 480 => (6, 6, 6),
+            _ if height <= 576 => (5, 6, 5),
+            _ => (1, 1, 1),
+        };
+
+        let colour_primaries = colour_description
+            .map(|colour_description| colour_description.colour_primaries)
+            .filter(|&colour_primaries| primaries_chromaticities(colour_primaries).is_some())
+            .unwrap_or(primaries);
+        // 2 is unspecified, 0, 3 and everything past 18 reserved
+        let transfer_characteristics = colour_description
+            .map(|colour_description| colour_description.transfer_characteristics)
+            .filter(|&transfer_characteristics| matches!(transfer_characteristics, 1 | 4..=18))
+            .unwrap_or(transfer);
+        // The ones a YCbCr model exists for
+        let matrix_coefficients = colour_description
+            .map(|colour_description| colour_description.matrix_coefficients)
+            .filter(|&matrix_coefficients| matches!(matrix_coefficients, 1 | 4..=7 | 9 | 10))
+            .unwrap_or(matrix);
+
+        ColourParams {
+            colour_primaries,
+            transfer_characteristics,
+            matrix_coefficients,
+            video_full_range_flag: video_signal_type
+                .is_some_and(|video_signal_type| video_signal_type.video_full_range_flag),
+        }
+    }
+}
+
+/// The colour parameters uniform of `shader/texture/texture.frag`, laid out as std140.
+///
+/// The fragment shader takes the R'G'B' samples to linear light with the transfer function of
+/// the video, to BT.709 primaries, and encodes them for display unless the swapchain format is
+/// sRGB and does so itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct ColourUniform {
+    /// Columns of the matrix taking linear RGB in the colour primaries of the video to BT.709
+    /// primaries.
+    pub primaries: [[f32; 4]; 3],
+    pub transfer_characteristics: u32,
+    pub encode_srgb: u32,
+    pub _pad: [u32; 2],
+}
+
+impl ColourUniform {
+    pub fn new(colour_params: &ColourParams, surface_format: vk::Format) -> Self {
+        let bt709 = primaries_chromaticities(1).unwrap();
+        let source = primaries_chromaticities(colour_params.colour_primaries).unwrap_or(bt709);
+
+        // No chromatic adaptation, the white points of BT.470 M and film are used as they are
+        let to_bt709 = multiply(&invert(&rgb_to_xyz(&bt709)), &rgb_to_xyz(&source));
+
+        let column = |column: usize| {
+            [
+                to_bt709[0][column] as f32,
+                to_bt709[1][column] as f32,
+                to_bt709[2][column] as f32,
+                0.0,
+            ]
+        };
+
+        let encode_srgb = !matches!(
+            surface_format,
+            vk::Format::B8G8R8A8_SRGB
+                | vk::Format::R8G8B8A8_SRGB
+                | vk::Format::A8B8G8R8_SRGB_PACK32
+        );
+
+        ColourUniform {
+            primaries: [column(0), column(1), column(2)],
+            transfer_characteristics: colour_params.transfer_characteristics as u32,
+            encode_srgb: encode_srgb as u32,
+            _pad: [0; 2],
+        }
+    }
+}
+
+type Matrix = [[f64; 3]; 3];
+
+/// The x and y chromaticity of the red, green and blue primaries and the white point, Table E-3.
+fn primaries_chromaticities(colour_primaries: u8) -> Option<[[f64; 2]; 4]> {
+    const D65: [f64; 2] = [0.3127, 0.3290];
+    const C: [f64; 2] = [0.310, 0.316];
+
+    match colour_primaries {
+        1 => Some([[0.640, 0.330], [0.300, 0.600], [0.150, 0.060], D65]),
+        4 => Some([[0.67, 0.33], [0.21, 0.71], [0.14, 0.08], C]),
+        5 => Some([[0.640, 0.330], [0.290, 0.600], [0.150, 0.060], D65]),
+        6 | 7 => Some([[0.630, 0.340], [0.310, 0.595], [0.155, 0.070], D65]),
+        8 => Some([[0.681, 0.319], [0.243, 0.692], [0.145, 0.049], C]),
+        9 => Some([[0.708, 0.292], [0.170, 0.797], [0.131, 0.046], D65]),
+        _ => None,
+    }
+}
+
+/// The matrix taking linear RGB with `chromaticities` to CIE XYZ.
+fn rgb_to_xyz(chromaticities: &[[f64; 2]; 4]) -> Matrix {
+    let xyz = |[x, y]: [f64; 2]| [x / y, 1.0, (1.0 - x - y) / y];
+
+    let [red, green, blue, white] = chromaticities.map(xyz);
+    let primaries = [
+        [red[0], green[0], blue[0]],
+        [red[1], green[1], blue[1]],
+        [red[2], green[2], blue[2]],
+    ];
+
+    // Scaled so that equal RGB gives the white point
+    let inverse = invert(&primaries);
+    let scale: [f64; 3] =
+        std::array::from_fn(|row| (0..3).map(|k| inverse[row][k] * white[k]).sum());
+
+    std::array::from_fn(|row| std::array::from_fn(|column| primaries[row][column] * scale[column]))
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    std::array::from_fn(|row| {
+        std::array::from_fn(|column| (0..3).map(|k| a[row][k] * b[k][column]).sum())
+    })
+}
+
+fn invert(m: &Matrix) -> Matrix {
+    let cofactor = |row: usize, column: usize| {
+        let (r0, r1) = ((row + 1) % 3, (row + 2) % 3);
+        let (c0, c1) = ((column + 1) % 3, (column + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+
+    let determinant = (0..3).map(|k| m[0][k] * cofactor(0, k)).sum::<f64>();
+
+    // The adjugate is the transposed cofactor matrix
+    std::array::from_fn(|row| std::array::from_fn(|column| cofactor(column, row) / determinant))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sps::{ColourDescription, FrameCropping, VideoSignalType};
+    use crate::test_util::{sps, vui};
+
+    /// An SPS coding `height` lines, with a video_signal_type() when `video_format` is given.
+    fn colour_sps(
+        height: u32,
+        video_format: Option<u8>,
+        colour_description: Option<(u8, u8, u8)>,
+    ) -> SequenceParameterSet {
+        let mut sps = sps(0);
+        sps.pic_height_in_map_units_minus1 = height.div_ceil(16) - 1;
+        if !height.is_multiple_of(16) {
+            // Crop units of 4:2:0 frames are 2 lines
+            sps.frame_cropping = Some(FrameCropping {
+                left_offset: 0,
+                right_offset: 0,
+                top_offset: 0,
+                bottom_offset: (16 - height % 16) / 2,
+            });
+        }
+        sps.vui = video_format.map(|video_format| {
+            let mut vui = vui(0, 4);
+            vui.video_signal_type = Some(VideoSignalType {
+                video_format,
+                video_full_range_flag: true,
+                colour_description: colour_description.map(
+                    |(colour_primaries, transfer_characteristics, matrix_coefficients)| {
+                        ColourDescription {
+                            colour_primaries,
+                            transfer_characteristics,
+                            matrix_coefficients,
+                        }
+                    },
+                ),
+            });
+            vui
+        });
+
+        sps
+    }
+
+    fn colour_params(sps: &SequenceParameterSet) -> (u8, u8, u8, bool) {
+        let params = ColourParams::from_sps(sps);
+        (
+            params.colour_primaries,
+            params.transfer_characteristics,
+            params.matrix_coefficients,
+            params.video_full_range_flag,
+        )
+    }
+
+    /// Rows of the matrix taking the colour primaries of the video to BT.709.
+    fn to_bt709(colour_primaries: u8) -> [[f32; 3]; 3] {
+        let params = ColourParams {
+            colour_primaries,
+            transfer_characteristics: 1,
+            matrix_coefficients: 1,
+            video_full_range_flag: false,
+        };
+        let uniform = ColourUniform::new(&params, vk::Format::B8G8R8A8_UNORM);
+
+        std::array::from_fn(|row| std::array::from_fn(|column| uniform.primaries[column][row]))
+    }
+
+    #[test]
+    fn inferred() {
+        // Without a VUI or a video_format of 5, unspecified, the display height decides
+        let cases = [
+            (240, None, (6, 6, 6)),
+            (480, None, (6, 6, 6)),
+            (496, None, (5, 6, 5)),
+            (576, None, (5, 6, 5)),
+            (720, None, (1, 1, 1)),
+            (1080, None, (1, 1, 1)),
+            (240, Some(5), (6, 6, 6)),
+            (576, Some(5), (5, 6, 5)),
+            (1080, Some(5), (1, 1, 1)),
+            // otherwise the video_format
+            (1080, Some(1), (5, 6, 5)),
+            (1080, Some(2), (6, 6, 6)),
+            (240, Some(3), (5, 6, 5)),
+            (240, Some(4), (5, 6, 5)),
+            (240, Some(0), (6, 6, 6)),
+            (1080, Some(0), (1, 1, 1)),
+        ];
+
+        for (height, video_format, (primaries, transfer, matrix)) in cases {
+            let sps = colour_sps(height, video_format, None);
+            assert_eq!(sps.display_rect().extent.height, height);
+            assert_eq!(
+                colour_params(&sps),
+                (primaries, transfer, matrix, video_format.is_some()),
+                "{} lines, video_format {:?}",
+                height,
+                video_format
+            );
+        }
+    }
+
+    #[test]
+    fn colour_description() {
+        let cases = [
+            ((1, 1, 1), (1, 1, 1)),
+            ((9, 16, 9), (9, 16, 9)),
+            ((9, 18, 10), (9, 18, 10)),
+            ((6, 6, 6), (6, 6, 6)),
+            ((4, 4, 4), (4, 4, 4)),
+            ((8, 8, 7), (8, 8, 7)),
+            // Unspecified and reserved values fall back one by one
+            ((2, 2, 2), (6, 6, 6)),
+            ((3, 3, 3), (6, 6, 6)),
+            ((10, 0, 0), (6, 6, 6)),
+            ((22, 19, 8), (6, 6, 6)),
+            ((1, 255, 11), (1, 6, 6)),
+            ((255, 1, 12), (6, 1, 6)),
+        ];
+
+        for (colour_description, (primaries, transfer, matrix)) in cases {
+            assert_eq!(
+                colour_params(&colour_sps(240, Some(5), Some(colour_description))),
+                (primaries, transfer, matrix, true),
+                "{:?}",
+                colour_description
+            );
+        }
+
+        let mut limited_range = colour_sps(240, Some(5), Some((1, 1, 1)));
+        let vui = limited_range.vui.as_mut().unwrap();
+        vui.video_signal_type
+            .as_mut()
+            .unwrap()
+            .video_full_range_flag = false;
+        assert_eq!(colour_params(&limited_range), (1, 1, 1, false));
+    }
+
+    #[test]
+    fn primaries() {
+        let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        let cases = [
+            (1, identity),
+            // Unknown primaries are taken as BT.709
+            (2, identity),
+            (
+                5,
+                [
+                    [1.0440, -0.0440, 0.0],
+                    [0.0, 1.0, 0.0],
+                    [0.0, 0.0118, 0.9882],
+                ],
+            ),
+            (
+                6,
+                [
+                    [0.9395, 0.0502, 0.0103],
+                    [0.0178, 0.9658, 0.0164],
+                    [-0.0016, -0.0044, 1.0060],
+                ],
+            ),
+            (
+                9,
+                [
+                    [1.6605, -0.5876, -0.0728],
+                    [-0.1246, 1.1329, -0.0083],
+                    [-0.0182, -0.1006, 1.1187],
+                ],
+            ),
+        ];
+
+        for (colour_primaries, expected) in cases {
+            let matrix = to_bt709(colour_primaries);
+            for (row, expected_row) in matrix.iter().zip(expected) {
+                for (value, expected_value) in row.iter().zip(expected_row) {
+                    assert!(
+                        (value - expected_value).abs() < 1e-3,
+                        "colour_primaries {}: {:?}",
+                        colour_primaries,
+                        matrix
+                    );
+                }
+            }
+
+            // D65 white stays white
+            for row in matrix {
+                assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-5, "{:?}", matrix);
+            }
+        }
+    }
+
+    #[test]
+    fn uniform() {
+        let params = ColourParams::from_sps(&colour_sps(1080, Some(5), Some((1, 16, 9))));
+
+        let unorm = ColourUniform::new(&params, vk::Format::B8G8R8A8_UNORM);
+        assert_eq!(unorm.transfer_characteristics, 16);
+        assert_eq!(unorm.encode_srgb, 1);
+        assert_eq!(unorm._pad, [0; 2]);
+        // The fourth component of every column is std140 padding
+        assert!(unorm.primaries.iter().all(|column| column[3] == 0.0));
+
+        for format in [
+            vk::Format::B8G8R8A8_SRGB,
+            vk::Format::R8G8B8A8_SRGB,
+            vk::Format::A8B8G8R8_SRGB_PACK32,
+        ] {
+            assert_eq!(
+                ColourUniform::new(&params, format).encode_srgb,
+                0,
+                "{:?}",
+                format
+            );
+        }
+
+        assert_eq!(std::mem::size_of::<ColourUniform>(), 64);
+    }
+}
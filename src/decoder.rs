@@ -0,0 +1,878 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+
+use anyhow::{anyhow, Result};
+use ash::extensions::khr::{VideoDecodeQueue, VideoQueue};
+use ash::{vk, Device};
+
+use crate::bitstream::{BitstreamRegion, BitstreamRing};
+use crate::dpb::{DecodedPictureBuffer, Frame};
+use crate::dpb_pool::{self, DpbPool};
+use crate::output_mode::{presentable_usage, DecodeCapabilities, OutputMode};
+use crate::pps::PictureParameterSet;
+use crate::ref_list;
+use crate::session_memory::VideoSessionMemory;
+use crate::session_parameters::{SessionLimits, SessionParameters};
+use crate::slice::{find_parameter_sets, CodedPicture};
+use crate::source::{AccessUnit, VideoSource};
+use crate::sps::SequenceParameterSet;
+use crate::sync::{self, Timeline, TimelinePoint};
+use crate::{find_video_format, ExampleBase};
+
+/// Number of decode submissions that can be in flight at once.
+pub const FRAMES_IN_FLIGHT: usize = 2;
+
+#[derive(Default)]
+pub struct VideoSpec {
+    pub width: u32,
+    pub height: u32,
+    pub max_sps_count: u32,
+    pub max_pps_count: u32,
+    pub output_mode: Option<OutputMode>,
+}
+
+/// A decoded picture, handed out in output order.
+///
+/// The image stays untouched as long as it is the last picture handed out, and after that until a
+/// decode waiting on graphics work submitted after the picture was last read, see
+/// [`Decoder::next_picture`].
+#[derive(Clone, Copy, Debug)]
+pub struct DecodedPicture {
+    pub image: vk::Image,
+    pub array_layer: u32,
+    /// Layout the decode left the image in.
+    pub layout: vk::ImageLayout,
+    pub format: vk::Format,
+    /// Size of the decoded picture before cropping.
+    pub coded_extent: vk::Extent2D,
+    /// Presentation timestamp in [`VideoSource::timescale`] units.
+    pub pts: i64,
+    /// Decode and graphics queue family when the decode released the image to the graphics
+    /// queue family, both `QUEUE_FAMILY_IGNORED` when the graphics queue can use it as it is.
+    pub src_queue_family_index: u32,
+    pub dst_queue_family_index: u32,
+    /// Reached once the decode wrote the picture, see [`Decoder::sampling_wait_point`] for what
+    /// graphics work reading it waits on.
+    pub decoded: TimelinePoint,
+}
+
+impl DecodedPicture {
+    /// Acquires the image on the graphics queue family, recorded once before the first graphics
+    /// command using the picture.
+    pub fn acquire_barrier(&self) -> Option<vk::ImageMemoryBarrier<'static>> {
+        if self.src_queue_family_index == self.dst_queue_family_index {
+            return None;
+        }
+
+        Some(vk::ImageMemoryBarrier {
+            dst_access_mask: vk::AccessFlags::SHADER_READ,
+            ..ownership_barrier(
+                self.image,
+                self.array_layer,
+                self.layout,
+                self.src_queue_family_index,
+                self.dst_queue_family_index,
+            )
+        })
+    }
+
+    /// Moves the picture into `SHADER_READ_ONLY_OPTIMAL` before a draw sampling it, recorded after
+    /// [`Self::acquire_barrier`].
+    pub fn begin_sampling_barrier(&self) -> vk::ImageMemoryBarrier<'static> {
+        vk::ImageMemoryBarrier {
+            dst_access_mask: vk::AccessFlags::SHADER_READ,
+            new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ..ownership_barrier(
+                self.image,
+                self.array_layer,
+                self.layout,
+                vk::QUEUE_FAMILY_IGNORED,
+                vk::QUEUE_FAMILY_IGNORED,
+            )
+        }
+    }
+
+    /// Moves the picture back into the layout the decoder keeps it in once the draw is done, a
+    /// coinciding picture may be referenced by later decodes.
+    pub fn end_sampling_barrier(&self) -> vk::ImageMemoryBarrier<'static> {
+        vk::ImageMemoryBarrier {
+            src_access_mask: vk::AccessFlags::SHADER_READ,
+            old_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ..ownership_barrier(
+                self.image,
+                self.array_layer,
+                self.layout,
+                vk::QUEUE_FAMILY_IGNORED,
+                vk::QUEUE_FAMILY_IGNORED,
+            )
+        }
+    }
+}
+
+/// Hands a layer of an image over between queue families, keeping its layout.
+fn ownership_barrier(
+    image: vk::Image,
+    array_layer: u32,
+    layout: vk::ImageLayout,
+    src_queue_family_index: u32,
+    dst_queue_family_index: u32,
+) -> vk::ImageMemoryBarrier<'static> {
+    vk::ImageMemoryBarrier {
+        old_layout: layout,
+        new_layout: layout,
+        src_queue_family_index,
+        dst_queue_family_index,
+        image,
+        subresource_range: vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_array_layer: array_layer,
+            level_count: 1,
+            layer_count: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// Resources of one decode submission, reused once the decode timeline reached its value.
+struct FrameContext {
+    command_buffer: vk::CommandBuffer,
+    /// Decode timeline value the last submission using the context signals.
+    decode_value: u64,
+}
+
+fn vk_make_video_std_version(major: u32, minor: u32, patch: u32) -> u32 {
+    (major << 22) | (minor << 12) | patch
+}
+
+fn vk_make_extension_name(text: &str) -> [i8; 256] {
+    let mut array: [i8; 256] = [0; 256];
+    let bytes = CString::new(text).unwrap().into_bytes();
+
+    for (i, &b) in bytes.iter().enumerate() {
+        array[i] = b as i8;
+    }
+
+    array
+}
+
+/// Streams H.264 access units through a video session, one decode submission per picture.
+pub struct Decoder {
+    device: Device,
+    video_queue_loader: VideoQueue,
+    video_decode_queue_loader: VideoDecodeQueue,
+    decode_queue: vk::Queue,
+    decode_queue_family_index: u32,
+    graphics_queue_family_index: u32,
+
+    video_source: Box<dyn VideoSource>,
+    // read ahead to set the session up, decoded first
+    first_access_unit: Option<AccessUnit>,
+    end_of_stream: bool,
+
+    video_session: vk::VideoSessionKHR,
+    // freed when the decoder is dropped, after destroy took the session down
+    _video_session_memory: VideoSessionMemory,
+    session_parameters: SessionParameters,
+
+    dpb_pool: DpbPool,
+    // one output picture per DPB slot when distinct from the DPB
+    output_pool: Option<DpbPool>,
+    decoded_picture_buffer: DecodedPictureBuffer,
+
+    bitstream_ring: BitstreamRing,
+    frames: Vec<FrameContext>,
+    frame_index: usize,
+    // one value per decode submission
+    decode_timeline: Timeline,
+
+    // presentation timestamps and decode completion of the pictures in the DPB by decode index
+    decoded: HashMap<u64, (i64, TimelinePoint)>,
+    // pictures waiting to be handed out with their slot
+    output: VecDeque<(usize, DecodedPicture)>,
+}
+
+impl Decoder {
+    /// Sets a session up for the coded video sequence the first access unit starts, the parameter
+    /// sets are the ones it carries.
+    pub fn new(
+        base: &ExampleBase,
+        video_spec: &VideoSpec,
+        video_source: Box<dyn VideoSource>,
+        first_access_unit: AccessUnit,
+        sequence_parameter_sets: Vec<SequenceParameterSet>,
+        picture_parameter_sets: Vec<PictureParameterSet>,
+    ) -> Result<Self> {
+        unsafe {
+            let first_sps = sequence_parameter_sets
+                .first()
+                .ok_or_else(|| anyhow!("No sequence parameter set before the first picture"))?;
+
+            // The profile, chroma format and bit depths of the first SPS hold for the session
+            let mut video_profile_operation = vk::VideoDecodeH264ProfileInfoKHR::default()
+                .std_profile_idc(first_sps.std_profile_idc()?)
+                .picture_layout(vk::VideoDecodeH264PictureLayoutFlagsKHR::PROGRESSIVE);
+
+            let profile_info = vk::VideoProfileInfoKHR::default()
+                .push_next(&mut video_profile_operation)
+                .video_codec_operation(vk::VideoCodecOperationFlagsKHR::DECODE_H264)
+                .chroma_subsampling(first_sps.chroma_subsampling())
+                .luma_bit_depth(first_sps.luma_bit_depth()?)
+                .chroma_bit_depth(first_sps.chroma_bit_depth()?);
+
+            let mut h264_decode_capibilities = vk::VideoDecodeH264CapabilitiesKHR::default();
+
+            let mut decode_capabilities = vk::VideoDecodeCapabilitiesKHR {
+                // TODO no p_next or push_next motheods yet this is failing when not passed
+                p_next: &mut h264_decode_capibilities as *mut _ as _,
+                ..Default::default()
+            };
+
+            let mut capabilities =
+                vk::VideoCapabilitiesKHR::default().push_next(&mut decode_capabilities);
+
+            let video_queue_loader = VideoQueue::new(&base.instance, &base.device);
+            let video_decode_queue_loader = VideoDecodeQueue::new(&base.instance, &base.device);
+
+            video_queue_loader.get_physical_device_video_capabilities(
+                base.pdevice,
+                &profile_info,
+                &mut capabilities,
+            )?;
+
+            let dpb_slot_count = dpb_pool::slot_count(first_sps, &capabilities)?;
+            let video_capability_flags = capabilities.flags;
+            let min_bitstream_buffer_offset_alignment =
+                capabilities.min_bitstream_buffer_offset_alignment;
+            let min_bitstream_buffer_size_alignment =
+                capabilities.min_bitstream_buffer_size_alignment;
+
+            let video_profiles = [profile_info];
+            let mut profile_list_info =
+                vk::VideoProfileListInfoKHR::default().profiles(&video_profiles);
+
+            let output_mode = OutputMode::select(
+                &DecodeCapabilities::from_vk(&decode_capabilities),
+                video_spec.output_mode,
+            )?;
+
+            let dpb_format_properties = find_video_format(
+                base.pdevice,
+                &video_queue_loader,
+                output_mode.dpb_usage(),
+                &mut profile_list_info,
+            )?;
+            let dst_format_properties = match output_mode.output_usage() {
+                Some(output_usage) => find_video_format(
+                    base.pdevice,
+                    &video_queue_loader,
+                    output_usage,
+                    &mut profile_list_info,
+                )?,
+                None => dpb_format_properties,
+            };
+            let dpb_video_format = dpb_format_properties.format;
+            let dst_video_format = dst_format_properties.format;
+
+            // The formats are looked up by their decode usage alone, output pictures are sampled
+            // for display however the format allows it
+            let (dst_sampled_usage, dst_image_flags) = presentable_usage(
+                dst_format_properties.image_usage_flags,
+                dst_format_properties.image_create_flags,
+            )?;
+            let (dpb_usage, dpb_image_flags) = match output_mode {
+                OutputMode::Coincide => {
+                    (output_mode.dpb_usage() | dst_sampled_usage, dst_image_flags)
+                }
+                OutputMode::Distinct => (output_mode.dpb_usage(), vk::ImageCreateFlags::empty()),
+            };
+
+            // Pictures are decoded in whole macroblocks, cropping only applies when displaying them
+            let session_limits = SessionLimits::new(first_sps)?;
+            let video_extent = session_limits.coded_extent;
+
+            // Coinciding output pictures stay in use as references while they are displayed,
+            // handing them back and forth between the queue families would stall both
+            let dpb_queue_family_indices = match output_mode {
+                OutputMode::Coincide
+                    if base.decode_queue_family_index != base.graphics_queue_family_index =>
+                {
+                    vec![
+                        base.decode_queue_family_index,
+                        base.graphics_queue_family_index,
+                    ]
+                }
+                _ => vec![base.decode_queue_family_index],
+            };
+
+            // Every slot gets an output picture of its own, it has to last until the picture is
+            // output however long that takes
+            let output_pool = output_mode
+                .output_usage()
+                .map(|output_usage| {
+                    DpbPool::new(
+                        &base.device,
+                        &base.device_memory_properties,
+                        &mut profile_list_info,
+                        vk::VideoCapabilityFlagsKHR::empty(),
+                        dst_video_format,
+                        video_extent,
+                        dpb_slot_count,
+                        output_usage | dst_sampled_usage,
+                        dst_image_flags,
+                        &[base.decode_queue_family_index],
+                    )
+                })
+                .transpose()?;
+
+            let dpb_pool = DpbPool::new(
+                &base.device,
+                &base.device_memory_properties,
+                &mut profile_list_info,
+                video_capability_flags,
+                dpb_video_format,
+                video_extent,
+                dpb_slot_count,
+                dpb_usage,
+                dpb_image_flags,
+                &dpb_queue_family_indices,
+            )?;
+
+            let decoded_picture_buffer =
+                DecodedPictureBuffer::new(first_sps, dpb_pool.slot_count())?;
+
+            // VideoSession
+
+            let extension_properties = vk::ExtensionProperties::default()
+                .extension_name(vk_make_extension_name(
+                    "VK_STD_vulkan_video_codec_h264_decode",
+                ))
+                //TODO header version update
+                .spec_version(vk_make_video_std_version(1, 0, 0));
+
+            let video_session_info = vk::VideoSessionCreateInfoKHR::default()
+                .queue_family_index(base.decode_queue_family_index)
+                .video_profile(&video_profiles[0])
+                .picture_format(dst_video_format)
+                .std_header_version(&extension_properties)
+                .max_coded_extent(video_extent)
+                .reference_picture_format(dpb_video_format)
+                .max_dpb_slots(dpb_slot_count)
+                .max_active_reference_pictures(session_limits.max_active_reference_pictures);
+
+            let video_session =
+                video_queue_loader.create_video_session(&video_session_info, None)?;
+
+            let video_session_memory = VideoSessionMemory::bind(
+                &base.device,
+                &video_queue_loader,
+                &base.device_memory_properties,
+                video_session,
+            )?;
+
+            let session_parameters = SessionParameters::new(
+                &video_queue_loader,
+                video_session,
+                session_limits,
+                video_spec.max_sps_count,
+                video_spec.max_pps_count,
+                sequence_parameter_sets,
+                picture_parameter_sets,
+            )?;
+
+            // Frames in flight
+
+            let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
+                .command_buffer_count(FRAMES_IN_FLIGHT as u32)
+                .command_pool(base.decode_pool)
+                .level(vk::CommandBufferLevel::PRIMARY);
+
+            let command_buffers = base
+                .device
+                .allocate_command_buffers(&command_buffer_allocate_info)?;
+
+            // An access unit is not expected to be larger than the uncompressed picture, the
+            // ring holds one per decode in flight
+            let bitstream_ring = BitstreamRing::new(
+                &base.device,
+                &base.device_memory_properties,
+                &mut profile_list_info,
+                min_bitstream_buffer_offset_alignment,
+                min_bitstream_buffer_size_alignment,
+                FRAMES_IN_FLIGHT as u64
+                    * video_extent.width as u64
+                    * video_extent.height as u64
+                    * 3
+                    / 2,
+            )?;
+
+            let frames = command_buffers
+                .into_iter()
+                .map(|command_buffer| FrameContext {
+                    command_buffer,
+                    decode_value: 0,
+                })
+                .collect();
+
+            Ok(Decoder {
+                device: base.device.clone(),
+                video_queue_loader,
+                video_decode_queue_loader,
+                decode_queue: base.decode_queue,
+                decode_queue_family_index: base.decode_queue_family_index,
+                graphics_queue_family_index: base.graphics_queue_family_index,
+                video_source,
+                first_access_unit: Some(first_access_unit),
+                end_of_stream: false,
+                video_session,
+                _video_session_memory: video_session_memory,
+                session_parameters,
+                dpb_pool,
+                output_pool,
+                decoded_picture_buffer,
+                bitstream_ring,
+                frames,
+                frame_index: 0,
+                decode_timeline: Timeline::new(&base.device)?,
+                decoded: HashMap::new(),
+                output: VecDeque::new(),
+            })
+        }
+    }
+
+    /// Format of the pictures handed out.
+    pub fn output_format(&self) -> vk::Format {
+        self.output_pool.as_ref().unwrap_or(&self.dpb_pool).format
+    }
+
+    /// Create flags of the images pictures are handed out in.
+    pub fn output_image_flags(&self) -> vk::ImageCreateFlags {
+        self.output_pool.as_ref().unwrap_or(&self.dpb_pool).flags
+    }
+
+    /// Size of the images pictures are handed out in.
+    pub fn output_extent(&self) -> vk::Extent2D {
+        self.output_pool
+            .as_ref()
+            .unwrap_or(&self.dpb_pool)
+            .coded_extent
+    }
+
+    /// Number of distinct images and array layers pictures are handed out in.
+    pub fn output_picture_count(&self) -> usize {
+        self.output_pool
+            .as_ref()
+            .unwrap_or(&self.dpb_pool)
+            .slot_count()
+    }
+
+    /// See [`VideoSource::timescale`].
+    pub fn timescale(&self) -> u64 {
+        self.video_source.timescale()
+    }
+
+    /// The next picture in output order, decoding another access unit when none is waiting.
+    /// `None` when the access unit decoded outputs nothing yet and once every picture of the
+    /// stream was handed out, the last picture handed out can be drawn again meanwhile.
+    ///
+    /// `graphics_done` is reached once the graphics work reading the pictures handed out so far
+    /// finished, a decode overwriting one of them waits on it on the GPU. Up to
+    /// [`FRAMES_IN_FLIGHT`] decodes are in flight before this blocks.
+    pub fn next_picture(&mut self, graphics_done: TimelinePoint) -> Result<Option<DecodedPicture>> {
+        if self.output.is_empty() && !self.end_of_stream {
+            let access_unit = match self.first_access_unit.take() {
+                Some(access_unit) => Some(access_unit),
+                None => self.video_source.next_access_unit().transpose()?,
+            };
+
+            match access_unit {
+                Some(access_unit) => self.decode(&access_unit, graphics_done)?,
+                None => {
+                    self.end_of_stream = true;
+                    self.decoded_picture_buffer.flush();
+                }
+            }
+
+            self.queue_output()?;
+        }
+
+        // The picture handed out is drawn again until the next one is, decodes submitted
+        // meanwhile leave its slot alone
+        Ok(self.output.pop_front().map(|(slot_index, picture)| {
+            self.decoded_picture_buffer.set_displayed(slot_index);
+            picture
+        }))
+    }
+
+    /// The point a draw sampling `picture` waits for. A coinciding picture leaves the DPB layout
+    /// while it is sampled, so every decode submitted so far, any of which may reference its slot,
+    /// has to be done first.
+    pub fn sampling_wait_point(&self, picture: &DecodedPicture) -> TimelinePoint {
+        match self.output_pool {
+            Some(_) => picture.decoded,
+            None => self.decode_timeline.last_point(),
+        }
+    }
+
+    /// Whether every decode submitted so far finished, without blocking.
+    pub fn is_idle(&self) -> Result<bool> {
+        self.decode_timeline
+            .is_reached(&self.device, self.decode_timeline.last_point().value)
+    }
+
+    fn decode(&mut self, access_unit: &AccessUnit, graphics_done: TimelinePoint) -> Result<()> {
+        // Parameter sets repeated or changed in band apply from this access unit on
+        self.session_parameters.update(
+            &self.device,
+            &self.video_queue_loader,
+            &self.decode_timeline,
+            access_unit,
+        )?;
+
+        let coded_picture = CodedPicture::parse(
+            access_unit,
+            &self.session_parameters.sequence_parameter_sets,
+            &self.session_parameters.picture_parameter_sets,
+        )?;
+        let (sps, _) = find_parameter_sets(
+            coded_picture.header.pic_parameter_set_id,
+            &self.session_parameters.sequence_parameter_sets,
+            &self.session_parameters.picture_parameter_sets,
+        )?;
+
+        let frame_index = self.frame_index;
+        self.frame_index = (frame_index + 1) % self.frames.len();
+
+        self.decode_timeline
+            .wait(&self.device, self.frames[frame_index].decode_value)?;
+
+        let bitstream_region =
+            self.bitstream_ring
+                .write(&self.device, &self.decode_timeline, access_unit)?;
+
+        let current_picture = self
+            .decoded_picture_buffer
+            .begin_picture(sps, &coded_picture.header)?
+            .clone();
+        let reset = self.decoded_picture_buffer.take_reset();
+
+        let frame = &self.frames[frame_index];
+        self.record(
+            frame,
+            &coded_picture,
+            &current_picture,
+            bitstream_region,
+            reset,
+        )?;
+
+        // The slot and output picture written may still be read by earlier graphics work
+        let decoded = self.decode_timeline.next_point();
+        sync::queue_submit(
+            &self.device,
+            self.decode_queue,
+            &[frame.command_buffer],
+            &[graphics_done.wait(vk::PipelineStageFlags::ALL_COMMANDS)],
+            &[decoded.signal()],
+            vk::Fence::null(),
+        )?;
+        self.frames[frame_index].decode_value = decoded.value;
+        self.bitstream_ring.release(bitstream_region, decoded.value);
+        self.decoded
+            .insert(current_picture.decode_index, (access_unit.pts, decoded));
+
+        self.decoded_picture_buffer
+            .end_picture(&coded_picture.header)
+    }
+
+    fn record(
+        &self,
+        frame: &FrameContext,
+        coded_picture: &CodedPicture,
+        current_picture: &Frame,
+        bitstream_region: BitstreamRegion,
+        reset: bool,
+    ) -> Result<()> {
+        let command_buffer = frame.command_buffer;
+
+        // A list referring to a picture the DPB no longer holds fails before recording
+        let referenced_frames = ref_list::referenced_frames(
+            &self.decoded_picture_buffer,
+            &coded_picture.slice_headers,
+        )?;
+
+        unsafe {
+            self.device.reset_command_buffer(
+                command_buffer,
+                vk::CommandBufferResetFlags::RELEASE_RESOURCES,
+            )?;
+
+            let command_buffer_begin_info = vk::CommandBufferBeginInfo::default()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            self.device
+                .begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
+
+            // The slot being set up and the output picture are overwritten, whatever they held is
+            // discarded once everything submitted before, presenting included, is done with it
+            let mut image_barriers = vec![self.discard_barrier(
+                &self.dpb_pool,
+                current_picture.slot_index,
+                vk::ImageLayout::VIDEO_DECODE_DPB_KHR,
+            )];
+            if let Some(output_pool) = &self.output_pool {
+                image_barriers.push(self.discard_barrier(
+                    output_pool,
+                    current_picture.slot_index,
+                    vk::ImageLayout::VIDEO_DECODE_DST_KHR,
+                ));
+            }
+
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &image_barriers,
+            );
+
+            // The current picture's slot is set up in either mode, coinciding output is read
+            // back from it
+            let setup_picture_resource = self.dpb_pool.picture_resource(current_picture.slot_index);
+            let decode_output_picture_resource = match &self.output_pool {
+                Some(output_pool) => output_pool.picture_resource(current_picture.slot_index),
+                None => setup_picture_resource,
+            };
+
+            let setup_std_reference_info = ref_list::std_reference_info(current_picture);
+            let mut setup_dpb_slot_info = vk::VideoDecodeH264DpbSlotInfoKHR::default()
+                .std_reference_info(&setup_std_reference_info);
+            let setup_reference_slot = vk::VideoReferenceSlotInfoKHR {
+                p_next: &mut setup_dpb_slot_info as *mut _ as _,
+                slot_index: current_picture.slot_index as i32,
+                p_picture_resource: &setup_picture_resource,
+                ..Default::default()
+            };
+
+            // Every reference frame stays bound, the decode reads those the lists of its slices
+            // refer to
+            let reference_frames =
+                ref_list::reference_frames(&self.decoded_picture_buffer).collect::<Vec<_>>();
+            let std_reference_infos = reference_frames
+                .iter()
+                .map(|frame| ref_list::std_reference_info(frame))
+                .collect::<Vec<_>>();
+            let mut dpb_slot_infos = std_reference_infos
+                .iter()
+                .map(|std_reference_info| {
+                    vk::VideoDecodeH264DpbSlotInfoKHR::default()
+                        .std_reference_info(std_reference_info)
+                })
+                .collect::<Vec<_>>();
+            let reference_picture_resources = reference_frames
+                .iter()
+                .map(|frame| self.dpb_pool.picture_resource(frame.slot_index))
+                .collect::<Vec<_>>();
+            let bound_reference_slots = reference_frames
+                .iter()
+                .zip(&mut dpb_slot_infos)
+                .zip(&reference_picture_resources)
+                .map(
+                    |((frame, dpb_slot_info), picture_resource)| vk::VideoReferenceSlotInfoKHR {
+                        p_next: dpb_slot_info as *mut _ as _,
+                        slot_index: frame.slot_index as i32,
+                        p_picture_resource: picture_resource,
+                        ..Default::default()
+                    },
+                )
+                .collect::<Vec<_>>();
+            let reference_slots = bound_reference_slots
+                .iter()
+                .filter(|slot| {
+                    referenced_frames
+                        .iter()
+                        .any(|frame| frame.slot_index as i32 == slot.slot_index)
+                })
+                .copied()
+                .collect::<Vec<_>>();
+
+            // Every active slot is bound for the coding scope, the slot being set up joins them
+            // without a picture yet
+            let mut begin_reference_slots = bound_reference_slots.clone();
+            begin_reference_slots.push(vk::VideoReferenceSlotInfoKHR {
+                slot_index: -1,
+                p_picture_resource: &setup_picture_resource,
+                ..Default::default()
+            });
+
+            let begin_info = vk::VideoBeginCodingInfoKHR::default()
+                .video_session(self.video_session)
+                .video_session_parameters(self.session_parameters.video_session_parameters)
+                .reference_slots(&begin_reference_slots);
+
+            self.video_queue_loader
+                .cmd_begin_video_coding(command_buffer, &begin_info);
+
+            // A new session starts out undefined, an IDR picture leaves no slot holding a
+            // reference either
+            if reset {
+                let control_info = vk::VideoCodingControlInfoKHR::default()
+                    .flags(vk::VideoCodingControlFlagsKHR::RESET);
+                self.video_queue_loader
+                    .cmd_control_video_coding(command_buffer, &control_info);
+            }
+
+            let std_picture_info = coded_picture.std_picture_info(current_picture.field_order_cnt);
+
+            let mut h264_picture_info = vk::VideoDecodeH264PictureInfoKHR::default()
+                .std_picture_info(&std_picture_info)
+                .slice_offsets(&coded_picture.slice_offsets);
+
+            let decode_info = vk::VideoDecodeInfoKHR {
+                p_next: &mut h264_picture_info as *mut _ as _,
+                src_buffer: self.bitstream_ring.buffer,
+                src_buffer_offset: bitstream_region.offset,
+                src_buffer_range: bitstream_region.size,
+                dst_picture_resource: decode_output_picture_resource,
+                p_setup_reference_slot: &setup_reference_slot,
+                reference_slot_count: reference_slots.len() as u32,
+                p_reference_slots: reference_slots.as_ptr(),
+                ..Default::default()
+            };
+
+            self.video_decode_queue_loader
+                .cmd_decode_video(command_buffer, &decode_info);
+
+            self.video_queue_loader
+                .cmd_end_video_coding(command_buffer, &vk::VideoEndCodingInfoKHR::default());
+
+            // Distinct output pictures go to the graphics queue family, the next decode into the
+            // same slot takes the image back without its content
+            if let Some(output_pool) = &self.output_pool {
+                if self.decode_queue_family_index != self.graphics_queue_family_index {
+                    let (image, array_layer) = output_pool.slot_image(current_picture.slot_index);
+                    let release_barrier = vk::ImageMemoryBarrier {
+                        src_access_mask: vk::AccessFlags::MEMORY_WRITE,
+                        ..ownership_barrier(
+                            image,
+                            array_layer,
+                            vk::ImageLayout::VIDEO_DECODE_DST_KHR,
+                            self.decode_queue_family_index,
+                            self.graphics_queue_family_index,
+                        )
+                    };
+
+                    self.device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::ALL_COMMANDS,
+                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[],
+                        &[release_barrier],
+                    );
+                }
+            }
+
+            self.device.end_command_buffer(command_buffer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves a slot of `pool` into `layout` without keeping its content.
+    fn discard_barrier(
+        &self,
+        pool: &DpbPool,
+        slot_index: usize,
+        layout: vk::ImageLayout,
+    ) -> vk::ImageMemoryBarrier<'static> {
+        let (image, base_array_layer) = pool.slot_image(slot_index);
+
+        vk::ImageMemoryBarrier {
+            dst_access_mask: vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE,
+            old_layout: vk::ImageLayout::UNDEFINED,
+            new_layout: layout,
+            src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            image,
+            subresource_range: vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_array_layer,
+                level_count: 1,
+                layer_count: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Queues the pictures the DPB output for [`Self::next_picture`].
+    fn queue_output(&mut self) -> Result<()> {
+        let (pool, layout) = match &self.output_pool {
+            Some(output_pool) => (output_pool, vk::ImageLayout::VIDEO_DECODE_DST_KHR),
+            None => (&self.dpb_pool, vk::ImageLayout::VIDEO_DECODE_DPB_KHR),
+        };
+        let (src_queue_family_index, dst_queue_family_index) = if self.output_pool.is_some()
+            && self.decode_queue_family_index != self.graphics_queue_family_index
+        {
+            (
+                self.decode_queue_family_index,
+                self.graphics_queue_family_index,
+            )
+        } else {
+            (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED)
+        };
+
+        // Pictures discarded without being output are never handed out
+        for decode_index in self.decoded_picture_buffer.take_discarded() {
+            self.decoded.remove(&decode_index);
+        }
+
+        for output_picture in self.decoded_picture_buffer.take_output() {
+            let (pts, decoded) = self
+                .decoded
+                .remove(&output_picture.decode_index)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Picture {} was output without being decoded",
+                        output_picture.decode_index
+                    )
+                })?;
+            let (image, array_layer) = pool.slot_image(output_picture.slot_index);
+
+            self.output.push_back((
+                output_picture.slot_index,
+                DecodedPicture {
+                    image,
+                    array_layer,
+                    layout,
+                    format: pool.format,
+                    coded_extent: pool.coded_extent,
+                    pts,
+                    src_queue_family_index,
+                    dst_queue_family_index,
+                    decoded,
+                },
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Frees everything the decoder created, once the device is idle. The session memory goes
+    /// when the decoder is dropped.
+    pub fn destroy(&self) {
+        unsafe {
+            self.bitstream_ring.destroy(&self.device);
+            self.decode_timeline.destroy(&self.device);
+            self.session_parameters.destroy(&self.video_queue_loader);
+            self.video_queue_loader
+                .destroy_video_session(self.video_session, None);
+            self.dpb_pool.destroy(&self.device);
+            if let Some(output_pool) = &self.output_pool {
+                output_pool.destroy(&self.device);
+            }
+        }
+    }
+}
@@ -0,0 +1,1050 @@
+use std::mem;
+
+use anyhow::{anyhow, Result};
+
+use crate::poc::{has_mmco5, pic_order_cnt, PicOrderCntState};
+use crate::slice::{DecRefPicMarking, MemoryManagementControlOperation, SliceHeader};
+use crate::sps::SequenceParameterSet;
+
+/// How a frame is marked for inter prediction, ITU-T H.264 8.2.5
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reference {
+    Unused,
+    ShortTerm,
+    LongTerm { long_term_frame_idx: u32 },
+}
+
+/// A decoded frame held in the decoded picture buffer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Vulkan DPB slot the frame is decoded into.
+    pub slot_index: usize,
+    /// Position of the frame in decode order, lets the caller match output pictures to samples.
+    pub decode_index: u64,
+    pub frame_num: u32,
+    /// FrameNumWrap relative to the picture being decoded, ITU-T H.264 8-27.
+    pub frame_num_wrap: i32,
+    /// TopFieldOrderCnt and BottomFieldOrderCnt.
+    pub field_order_cnt: [i32; 2],
+    pub reference: Reference,
+    pub needed_for_output: bool,
+    /// Inferred for a gap in frame_num, ITU-T H.264 8.2.5.2, the slot holds no decoded samples.
+    pub non_existing: bool,
+}
+
+impl Frame {
+    /// PicOrderCnt() of the frame, ITU-T H.264 8-1.
+    pub fn pic_order_cnt(&self) -> i32 {
+        self.field_order_cnt[0].min(self.field_order_cnt[1])
+    }
+
+    pub fn is_reference(&self) -> bool {
+        self.reference != Reference::Unused
+    }
+
+    pub fn is_short_term(&self) -> bool {
+        self.reference == Reference::ShortTerm
+    }
+
+    /// LongTermFrameIdx, which is also the LongTermPicNum of a frame.
+    pub fn long_term_frame_idx(&self) -> Option<u32> {
+        match self.reference {
+            Reference::LongTerm {
+                long_term_frame_idx,
+            } => Some(long_term_frame_idx),
+            _ => None,
+        }
+    }
+}
+
+/// A frame leaving the DPB for display, handed out in output order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputPicture {
+    pub slot_index: usize,
+    pub decode_index: u64,
+    pub pic_order_cnt: i32,
+}
+
+/// Reference marking and output order of decoded frames, ITU-T H.264 8.2.5 and C.4.
+///
+/// Every picture is given a slot for its whole stay in the DPB, two more slots than the DPB holds
+/// frames are needed so the picture being decoded always finds one besides the picture on screen.
+/// Output pictures are queued until [`Self::take_output`] and keep their slots until then, a slot
+/// stays untouched only until the next [`Self::begin_picture`] once its picture was taken and is
+/// not the one set with [`Self::set_displayed`].
+///
+/// Field pictures are not supported, every picture has to be a coded frame.
+pub struct DecodedPictureBuffer {
+    slot_count: usize,
+    max_dpb_frames: usize,
+    max_num_reorder_frames: usize,
+    max_num_ref_frames: usize,
+    max_frame_num: u32,
+    // the picture being decoded is not among them
+    frames: Vec<Frame>,
+    current: Option<Frame>,
+    // None for "no long-term frame indices"
+    max_long_term_frame_idx: Option<u32>,
+    prev_ref_frame_num: u32,
+    pic_order_cnt_state: PicOrderCntState,
+    decode_index: u64,
+    output: Vec<OutputPicture>,
+    // decode indices of pictures dropped without being output
+    discarded: Vec<u64>,
+    // slot of the picture on screen, redrawn after later pictures were begun
+    displayed: Option<usize>,
+    // the slots of the video session no longer match the frames
+    reset_pending: bool,
+}
+
+impl DecodedPictureBuffer {
+    /// Sizes the DPB for the coded video sequence of `sps` within `slot_count` slots, it holds
+    /// fewer frames than MaxDpbFrames when the slots do not suffice, but never too few for the
+    /// reference frames and max_num_reorder_frames.
+    pub fn new(sps: &SequenceParameterSet, slot_count: usize) -> Result<Self> {
+        let mut dpb = DecodedPictureBuffer {
+            slot_count,
+            max_dpb_frames: 0,
+            max_num_reorder_frames: 0,
+            max_num_ref_frames: 0,
+            max_frame_num: 0,
+            frames: Vec::with_capacity(slot_count),
+            current: None,
+            max_long_term_frame_idx: None,
+            prev_ref_frame_num: 0,
+            pic_order_cnt_state: PicOrderCntState::new(),
+            decode_index: 0,
+            output: Vec::new(),
+            discarded: Vec::new(),
+            displayed: None,
+            reset_pending: true,
+        };
+        dpb.activate(sps)?;
+
+        Ok(dpb)
+    }
+
+    /// Number of Vulkan DPB slots the frames are spread over.
+    pub fn slot_count(&self) -> usize {
+        self.slot_count
+    }
+
+    /// MaxFrameNum of the active SPS, which is also MaxPicNum for frames.
+    pub fn max_frame_num(&self) -> u32 {
+        self.max_frame_num
+    }
+
+    /// The stored frames in no particular order, the picture being decoded is not among them.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// The picture between [`Self::begin_picture`] and [`Self::end_picture`].
+    pub fn current(&self) -> Option<&Frame> {
+        self.current.as_ref()
+    }
+
+    /// Whether the video session has to be reset before decoding the picture begun last, which is
+    /// the case for the first picture and every IDR picture, where seeks land as well. Every slot
+    /// is left without a reference picture then.
+    pub fn take_reset(&mut self) -> bool {
+        mem::take(&mut self.reset_pending)
+    }
+
+    /// Takes the pictures output so far, oldest first.
+    pub fn take_output(&mut self) -> Vec<OutputPicture> {
+        mem::take(&mut self.output)
+    }
+
+    /// Takes the decode indices of the pictures dropped without being output so far, an IDR
+    /// picture with no_output_of_prior_pics_flag discards every frame still waiting.
+    pub fn take_discarded(&mut self) -> Vec<u64> {
+        mem::take(&mut self.discarded)
+    }
+
+    /// Keeps the slot of an output picture out of use until another one is displayed, it may be
+    /// read again after later pictures were begun.
+    pub fn set_displayed(&mut self, slot_index: usize) {
+        self.displayed = Some(slot_index);
+    }
+
+    /// Starts decoding the picture the slice belongs to and picks the slot it is decoded into.
+    ///
+    /// An IDR picture outputs or discards everything stored before, a gap in frame_num is filled
+    /// with "non-existing" frames.
+    pub fn begin_picture(
+        &mut self,
+        sps: &SequenceParameterSet,
+        header: &SliceHeader,
+    ) -> Result<&Frame> {
+        if self.current.is_some() {
+            return Err(anyhow!("The previous picture was not finished"));
+        }
+        if header.field_pic_flag {
+            return Err(anyhow!("Field pictures are not supported"));
+        }
+
+        if header.idr {
+            self.start_coded_video_sequence(sps, header)?;
+        } else {
+            self.fill_frame_num_gap(sps, header)?;
+        }
+
+        let field_order_cnt = self.pic_order_cnt_state.compute(sps, header)?;
+        self.update_frame_num_wrap(header.frame_num);
+
+        let frame = Frame {
+            slot_index: self.free_slot()?,
+            decode_index: self.decode_index,
+            frame_num: header.frame_num,
+            frame_num_wrap: header.frame_num as i32,
+            field_order_cnt,
+            reference: Reference::Unused,
+            needed_for_output: true,
+            non_existing: false,
+        };
+        self.decode_index += 1;
+
+        Ok(self.current.insert(frame))
+    }
+
+    /// Marks the decoded picture, ITU-T H.264 8.2.5, and stores it for reference or output.
+    pub fn end_picture(&mut self, header: &SliceHeader) -> Result<()> {
+        let mut current = self
+            .current
+            .take()
+            .ok_or_else(|| anyhow!("No picture is being decoded"))?;
+
+        if header.nal_ref_idc != 0 {
+            self.mark_current(&mut current, header)?;
+        }
+
+        if has_mmco5(header) {
+            // The picture is output as if it had a picture order count of 0, ITU-T H.264 8.2.1
+            let temp_pic_order_cnt = pic_order_cnt(header, current.field_order_cnt);
+            for field_order_cnt in &mut current.field_order_cnt {
+                *field_order_cnt = field_order_cnt
+                    .checked_sub(temp_pic_order_cnt)
+                    .ok_or_else(|| anyhow!("Picture order count overflows"))?;
+            }
+            current.frame_num = 0;
+            current.frame_num_wrap = 0;
+
+            self.flush();
+        }
+
+        if current.is_reference() {
+            self.prev_ref_frame_num = current.frame_num;
+        }
+
+        self.frames
+            .retain(|frame| frame.is_reference() || frame.needed_for_output);
+
+        // ITU-T H.264 C.4.5.2, a non-reference picture coming first in output order skips storage
+        if !current.is_reference()
+            && self.frames.len() >= self.max_dpb_frames
+            && self
+                .frames
+                .iter()
+                .filter(|frame| frame.needed_for_output)
+                .all(|frame| current.pic_order_cnt() < frame.pic_order_cnt())
+        {
+            self.output.push(OutputPicture {
+                slot_index: current.slot_index,
+                decode_index: current.decode_index,
+                pic_order_cnt: current.pic_order_cnt(),
+            });
+
+            return Ok(());
+        }
+
+        self.store(current)?;
+
+        while self
+            .frames
+            .iter()
+            .filter(|frame| frame.needed_for_output)
+            .count()
+            > self.max_num_reorder_frames
+        {
+            self.bump();
+        }
+
+        Ok(())
+    }
+
+    /// Outputs every frame still waiting, at the end of the stream.
+    pub fn flush(&mut self) {
+        while self.bump() {}
+    }
+
+    fn start_coded_video_sequence(
+        &mut self,
+        sps: &SequenceParameterSet,
+        header: &SliceHeader,
+    ) -> Result<()> {
+        let no_output_of_prior_pics_flag = matches!(
+            header.dec_ref_pic_marking,
+            Some(DecRefPicMarking::Idr {
+                no_output_of_prior_pics_flag: true,
+                ..
+            })
+        );
+
+        if !no_output_of_prior_pics_flag {
+            self.flush();
+        }
+        self.discarded.extend(
+            self.frames
+                .iter()
+                .filter(|frame| frame.needed_for_output)
+                .map(|frame| frame.decode_index),
+        );
+        self.frames.clear();
+        self.prev_ref_frame_num = 0;
+        self.reset_pending = true;
+
+        self.activate(sps)
+    }
+
+    /// Takes over the limits of the SPS a coded video sequence starts with.
+    fn activate(&mut self, sps: &SequenceParameterSet) -> Result<()> {
+        // The picture being decoded and the one on screen take a slot each besides the frames
+        let max_num_ref_frames = sps.max_num_ref_frames as usize;
+        let max_num_reorder_frames = sps.max_num_reorder_frames()? as usize;
+        if sps.min_dpb_frames()? as usize + 2 > self.slot_count {
+            return Err(anyhow!(
+                "SPS {} needs {} reference frames and reorders up to {} frames, the DPB has {} slots",
+                sps.seq_parameter_set_id,
+                max_num_ref_frames,
+                max_num_reorder_frames,
+                self.slot_count
+            ));
+        }
+
+        self.max_dpb_frames = (sps.max_dpb_frames()? as usize).clamp(1, self.slot_count - 2);
+        self.max_num_reorder_frames = max_num_reorder_frames;
+        self.max_num_ref_frames = max_num_ref_frames;
+        self.max_frame_num = sps.max_frame_num();
+
+        Ok(())
+    }
+
+    /// Decoding process for gaps in frame_num, ITU-T H.264 8.2.5.2.
+    fn fill_frame_num_gap(
+        &mut self,
+        sps: &SequenceParameterSet,
+        header: &SliceHeader,
+    ) -> Result<()> {
+        // Lost reference pictures are concealed the same way when gaps are not allowed
+        let mut unused_short_term_frame_num = (self.prev_ref_frame_num + 1) % self.max_frame_num;
+
+        while header.frame_num != self.prev_ref_frame_num
+            && header.frame_num != unused_short_term_frame_num
+        {
+            self.pic_order_cnt_state
+                .skip_frame_num(sps, unused_short_term_frame_num)?;
+            self.update_frame_num_wrap(unused_short_term_frame_num);
+            self.sliding_window();
+
+            let frame = Frame {
+                slot_index: self.free_slot()?,
+                decode_index: self.decode_index,
+                frame_num: unused_short_term_frame_num,
+                frame_num_wrap: unused_short_term_frame_num as i32,
+                field_order_cnt: [0; 2],
+                reference: Reference::ShortTerm,
+                needed_for_output: false,
+                non_existing: true,
+            };
+            self.decode_index += 1;
+
+            self.frames
+                .retain(|frame| frame.is_reference() || frame.needed_for_output);
+            self.store(frame)?;
+
+            self.prev_ref_frame_num = unused_short_term_frame_num;
+            unused_short_term_frame_num = (unused_short_term_frame_num + 1) % self.max_frame_num;
+        }
+
+        Ok(())
+    }
+
+    /// FrameNumWrap of the short-term frames, ITU-T H.264 8.2.4.1.
+    fn update_frame_num_wrap(&mut self, frame_num: u32) {
+        for frame in &mut self.frames {
+            frame.frame_num_wrap = if frame.frame_num > frame_num {
+                frame.frame_num as i32 - self.max_frame_num as i32
+            } else {
+                frame.frame_num as i32
+            };
+        }
+    }
+
+    /// A slot neither a stored frame, the current picture nor an output picture not yet taken is in.
+    fn free_slot(&self) -> Result<usize> {
+        (0..self.slot_count)
+            .find(|&slot_index| {
+                self.frames
+                    .iter()
+                    .chain(&self.current)
+                    .all(|frame| frame.slot_index != slot_index)
+                    && self
+                        .output
+                        .iter()
+                        .all(|picture| picture.slot_index != slot_index)
+                    && self.displayed != Some(slot_index)
+            })
+            .ok_or_else(|| anyhow!("All {} DPB slots are in use", self.slot_count))
+    }
+
+    /// Bumps frames out until there is room and stores the frame, ITU-T H.264 C.4.5.1.
+    fn store(&mut self, frame: Frame) -> Result<()> {
+        while self.frames.len() >= self.max_dpb_frames {
+            if !self.bump() {
+                return Err(anyhow!(
+                    "DPB is full with {} reference frames",
+                    self.frames.len()
+                ));
+            }
+        }
+
+        self.frames.push(frame);
+
+        Ok(())
+    }
+
+    /// Outputs the frame with the smallest picture order count and empties its frame buffer when
+    /// it is no longer used for reference, ITU-T H.264 C.4.5.3. False when nothing waits for output.
+    fn bump(&mut self) -> bool {
+        let index = match self
+            .frames
+            .iter()
+            .enumerate()
+            .filter(|(_, frame)| frame.needed_for_output)
+            .min_by_key(|(_, frame)| (frame.pic_order_cnt(), frame.decode_index))
+        {
+            Some((index, _)) => index,
+            None => return false,
+        };
+
+        let frame = &mut self.frames[index];
+        frame.needed_for_output = false;
+
+        self.output.push(OutputPicture {
+            slot_index: frame.slot_index,
+            decode_index: frame.decode_index,
+            pic_order_cnt: frame.pic_order_cnt(),
+        });
+
+        if !frame.is_reference() {
+            self.frames.remove(index);
+        }
+
+        true
+    }
+
+    /// Sliding window marking, ITU-T H.264 8.2.5.3, before the current picture is stored.
+    fn sliding_window(&mut self) {
+        let reference_count = self
+            .frames
+            .iter()
+            .filter(|frame| frame.is_reference())
+            .count();
+        if reference_count < self.max_num_ref_frames.max(1) {
+            return;
+        }
+
+        if let Some(frame) = self
+            .frames
+            .iter_mut()
+            .filter(|frame| frame.is_short_term())
+            .min_by_key(|frame| frame.frame_num_wrap)
+        {
+            frame.reference = Reference::Unused;
+        }
+    }
+
+    /// Decoded reference picture marking of the current picture, ITU-T H.264 8.2.5.1.
+    fn mark_current(&mut self, current: &mut Frame, header: &SliceHeader) -> Result<()> {
+        current.reference = Reference::ShortTerm;
+
+        match &header.dec_ref_pic_marking {
+            Some(DecRefPicMarking::Idr {
+                long_term_reference_flag: true,
+                ..
+            }) => {
+                self.max_long_term_frame_idx = Some(0);
+                current.reference = Reference::LongTerm {
+                    long_term_frame_idx: 0,
+                };
+            }
+            Some(DecRefPicMarking::Idr { .. }) => self.max_long_term_frame_idx = None,
+            Some(DecRefPicMarking::Adaptive(operations)) => {
+                for operation in operations {
+                    self.apply_mmco(current, *operation)?;
+                }
+            }
+            Some(DecRefPicMarking::SlidingWindow) | None => self.sliding_window(),
+        }
+
+        Ok(())
+    }
+
+    fn check_long_term_frame_idx(&self, long_term_frame_idx: u32) -> Result<()> {
+        match self.max_long_term_frame_idx {
+            Some(max) if long_term_frame_idx <= max => Ok(()),
+            _ => Err(anyhow!(
+                "LongTermFrameIdx {} exceeds MaxLongTermFrameIdx {:?}",
+                long_term_frame_idx,
+                self.max_long_term_frame_idx
+            )),
+        }
+    }
+
+    fn unmark_long_term_frame_idx(&mut self, long_term_frame_idx: u32) {
+        for frame in &mut self.frames {
+            if frame.long_term_frame_idx() == Some(long_term_frame_idx) {
+                frame.reference = Reference::Unused;
+            }
+        }
+    }
+
+    /// ITU-T H.264 8.2.5.4
+    fn apply_mmco(
+        &mut self,
+        current: &mut Frame,
+        operation: MemoryManagementControlOperation,
+    ) -> Result<()> {
+        // CurrPicNum is frame_num for frames, ITU-T H.264 7.4.3, and picNumX has to lie within
+        // MaxPicNum of it
+        let max_frame_num = self.max_frame_num;
+        let pic_num_x = |difference_of_pic_nums_minus1: u32| {
+            if difference_of_pic_nums_minus1 >= max_frame_num {
+                return Err(anyhow!(
+                    "difference_of_pic_nums_minus1 of {} exceeds MaxPicNum of {}",
+                    difference_of_pic_nums_minus1,
+                    max_frame_num
+                ));
+            }
+
+            Ok(current.frame_num as i32 - (difference_of_pic_nums_minus1 as i32 + 1))
+        };
+
+        match operation {
+            MemoryManagementControlOperation::UnmarkShortTerm {
+                difference_of_pic_nums_minus1,
+            } => {
+                let pic_num_x = pic_num_x(difference_of_pic_nums_minus1)?;
+                for frame in &mut self.frames {
+                    if frame.is_short_term() && frame.frame_num_wrap == pic_num_x {
+                        frame.reference = Reference::Unused;
+                    }
+                }
+            }
+            MemoryManagementControlOperation::UnmarkLongTerm { long_term_pic_num } => {
+                self.unmark_long_term_frame_idx(long_term_pic_num);
+            }
+            MemoryManagementControlOperation::MarkLongTerm {
+                difference_of_pic_nums_minus1,
+                long_term_frame_idx,
+            } => {
+                self.check_long_term_frame_idx(long_term_frame_idx)?;
+
+                let pic_num_x = pic_num_x(difference_of_pic_nums_minus1)?;
+                let frame = self
+                    .frames
+                    .iter()
+                    .position(|frame| frame.is_short_term() && frame.frame_num_wrap == pic_num_x)
+                    .ok_or_else(|| {
+                        anyhow!("MMCO 3 refers to missing short-term picture {}", pic_num_x)
+                    })?;
+
+                self.unmark_long_term_frame_idx(long_term_frame_idx);
+                self.frames[frame].reference = Reference::LongTerm {
+                    long_term_frame_idx,
+                };
+            }
+            MemoryManagementControlOperation::SetMaxLongTermFrameIdx {
+                max_long_term_frame_idx_plus1,
+            } => {
+                self.max_long_term_frame_idx = max_long_term_frame_idx_plus1.checked_sub(1);
+
+                for frame in &mut self.frames {
+                    if let Some(long_term_frame_idx) = frame.long_term_frame_idx() {
+                        if !matches!(self.max_long_term_frame_idx, Some(max) if long_term_frame_idx <= max)
+                        {
+                            frame.reference = Reference::Unused;
+                        }
+                    }
+                }
+            }
+            MemoryManagementControlOperation::UnmarkAll => {
+                for frame in &mut self.frames {
+                    frame.reference = Reference::Unused;
+                }
+                self.max_long_term_frame_idx = None;
+            }
+            MemoryManagementControlOperation::MarkCurrentAsLongTerm {
+                long_term_frame_idx,
+            } => {
+                self.check_long_term_frame_idx(long_term_frame_idx)?;
+                self.unmark_long_term_frame_idx(long_term_frame_idx);
+
+                current.reference = Reference::LongTerm {
+                    long_term_frame_idx,
+                };
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{adaptive_header, idr_header, slice_header, sps, vui};
+
+    use MemoryManagementControlOperation::*;
+
+    fn decode(
+        dpb: &mut DecodedPictureBuffer,
+        sps: &SequenceParameterSet,
+        header: &SliceHeader,
+    ) -> Result<()> {
+        dpb.begin_picture(sps, header)?;
+        dpb.end_picture(header)
+    }
+
+    fn short_term(dpb: &DecodedPictureBuffer) -> Vec<u32> {
+        let mut frame_nums = dpb
+            .frames()
+            .iter()
+            .filter(|frame| frame.is_short_term())
+            .map(|frame| frame.frame_num)
+            .collect::<Vec<_>>();
+        frame_nums.sort_unstable();
+        frame_nums
+    }
+
+    /// (frame_num, LongTermFrameIdx)
+    fn long_term(dpb: &DecodedPictureBuffer) -> Vec<(u32, u32)> {
+        let mut frames = dpb
+            .frames()
+            .iter()
+            .filter_map(|frame| Some((frame.frame_num, frame.long_term_frame_idx()?)))
+            .collect::<Vec<_>>();
+        frames.sort_unstable();
+        frames
+    }
+
+    fn output_order(dpb: &mut DecodedPictureBuffer) -> Vec<i32> {
+        dpb.take_output()
+            .iter()
+            .map(|picture| picture.pic_order_cnt)
+            .collect()
+    }
+
+    /// An IDR picture followed by reference frames 1 to `last_frame_num`.
+    fn reference_frames(last_frame_num: u32) -> (SequenceParameterSet, DecodedPictureBuffer) {
+        let sps = sps(2);
+        let mut dpb = DecodedPictureBuffer::new(&sps, 18).unwrap();
+
+        decode(&mut dpb, &sps, &idr_header()).unwrap();
+        for frame_num in 1..=last_frame_num {
+            decode(&mut dpb, &sps, &slice_header(frame_num, 2)).unwrap();
+            // Output pictures keep their slots until taken
+            dpb.take_output();
+        }
+
+        (sps, dpb)
+    }
+
+    #[test]
+    fn sliding_window() {
+        let (sps, mut dpb) = reference_frames(3);
+        assert_eq!(short_term(&dpb), [0, 1, 2, 3]);
+
+        decode(&mut dpb, &sps, &slice_header(4, 2)).unwrap();
+        assert_eq!(short_term(&dpb), [1, 2, 3, 4]);
+
+        // Non-reference pictures leave the marking alone
+        decode(&mut dpb, &sps, &slice_header(5, 0)).unwrap();
+        assert_eq!(short_term(&dpb), [1, 2, 3, 4]);
+
+        // The smallest FrameNumWrap goes first once frame_num wraps
+        for frame_num in (5..16).chain(0..2) {
+            decode(&mut dpb, &sps, &slice_header(frame_num, 2)).unwrap();
+            dpb.take_output();
+        }
+        assert_eq!(short_term(&dpb), [0, 1, 14, 15]);
+    }
+
+    #[test]
+    fn sliding_window_keeps_long_term() {
+        let (sps, mut dpb) = reference_frames(1);
+
+        let header = adaptive_header(
+            2,
+            &[
+                SetMaxLongTermFrameIdx {
+                    max_long_term_frame_idx_plus1: 1,
+                },
+                MarkCurrentAsLongTerm {
+                    long_term_frame_idx: 0,
+                },
+            ],
+        );
+        decode(&mut dpb, &sps, &header).unwrap();
+        for frame_num in 3..6 {
+            decode(&mut dpb, &sps, &slice_header(frame_num, 2)).unwrap();
+        }
+
+        assert_eq!(short_term(&dpb), [3, 4, 5]);
+        assert_eq!(long_term(&dpb), [(2, 0)]);
+    }
+
+    #[test]
+    fn mmco_1() {
+        let (sps, mut dpb) = reference_frames(3);
+
+        // picNumX 4 - 2
+        let header = adaptive_header(
+            4,
+            &[UnmarkShortTerm {
+                difference_of_pic_nums_minus1: 1,
+            }],
+        );
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(short_term(&dpb), [0, 1, 3, 4]);
+
+        // picNumX -1 is frame_num 15 of before the wrap
+        let (sps, mut dpb) = reference_frames(15);
+        for frame_num in 0..2 {
+            decode(&mut dpb, &sps, &slice_header(frame_num, 2)).unwrap();
+            dpb.take_output();
+        }
+        let header = adaptive_header(
+            2,
+            &[UnmarkShortTerm {
+                difference_of_pic_nums_minus1: 2,
+            }],
+        );
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(short_term(&dpb), [0, 1, 2, 14]);
+    }
+
+    #[test]
+    fn mmco_2_and_3() {
+        let (sps, mut dpb) = reference_frames(2);
+
+        let header = adaptive_header(
+            3,
+            &[
+                SetMaxLongTermFrameIdx {
+                    max_long_term_frame_idx_plus1: 2,
+                },
+                MarkLongTerm {
+                    difference_of_pic_nums_minus1: 0,
+                    long_term_frame_idx: 1,
+                },
+            ],
+        );
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(short_term(&dpb), [0, 1, 3]);
+        assert_eq!(long_term(&dpb), [(2, 1)]);
+
+        // Taking over LongTermFrameIdx 1 unmarks frame 2
+        let header = adaptive_header(
+            4,
+            &[MarkLongTerm {
+                difference_of_pic_nums_minus1: 0,
+                long_term_frame_idx: 1,
+            }],
+        );
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(short_term(&dpb), [0, 1, 4]);
+        assert_eq!(long_term(&dpb), [(3, 1)]);
+
+        let header = adaptive_header(
+            5,
+            &[UnmarkLongTerm {
+                long_term_pic_num: 1,
+            }],
+        );
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(short_term(&dpb), [0, 1, 4, 5]);
+        assert_eq!(long_term(&dpb), []);
+    }
+
+    #[test]
+    fn mmco_3_errors() {
+        // No long-term frame indices yet
+        let (sps, mut dpb) = reference_frames(2);
+        let header = adaptive_header(
+            3,
+            &[MarkLongTerm {
+                difference_of_pic_nums_minus1: 0,
+                long_term_frame_idx: 0,
+            }],
+        );
+        assert!(decode(&mut dpb, &sps, &header).is_err());
+
+        // No short-term picture with picNumX 0
+        let (sps, mut dpb) = reference_frames(4);
+        let header = adaptive_header(
+            5,
+            &[
+                SetMaxLongTermFrameIdx {
+                    max_long_term_frame_idx_plus1: 1,
+                },
+                MarkLongTerm {
+                    difference_of_pic_nums_minus1: 4,
+                    long_term_frame_idx: 0,
+                },
+            ],
+        );
+        assert!(decode(&mut dpb, &sps, &header).is_err());
+    }
+
+    #[test]
+    fn difference_of_pic_nums_out_of_range() {
+        for difference_of_pic_nums_minus1 in [16, u32::MAX] {
+            let (sps, mut dpb) = reference_frames(2);
+            let header = adaptive_header(
+                3,
+                &[UnmarkShortTerm {
+                    difference_of_pic_nums_minus1,
+                }],
+            );
+            assert!(decode(&mut dpb, &sps, &header).is_err());
+        }
+    }
+
+    #[test]
+    fn mmco_4() {
+        let (sps, mut dpb) = reference_frames(2);
+
+        let header = adaptive_header(
+            3,
+            &[
+                SetMaxLongTermFrameIdx {
+                    max_long_term_frame_idx_plus1: 3,
+                },
+                MarkLongTerm {
+                    difference_of_pic_nums_minus1: 0,
+                    long_term_frame_idx: 2,
+                },
+                MarkLongTerm {
+                    difference_of_pic_nums_minus1: 1,
+                    long_term_frame_idx: 0,
+                },
+            ],
+        );
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(long_term(&dpb), [(1, 0), (2, 2)]);
+
+        // Indices above the new maximum are unmarked
+        let header = adaptive_header(
+            4,
+            &[SetMaxLongTermFrameIdx {
+                max_long_term_frame_idx_plus1: 1,
+            }],
+        );
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(long_term(&dpb), [(1, 0)]);
+
+        // 0 for "no long-term frame indices"
+        let header = adaptive_header(
+            5,
+            &[SetMaxLongTermFrameIdx {
+                max_long_term_frame_idx_plus1: 0,
+            }],
+        );
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(long_term(&dpb), []);
+        assert_eq!(short_term(&dpb), [0, 3, 4, 5]);
+    }
+
+    #[test]
+    fn mmco_5() {
+        let (sps, mut dpb) = reference_frames(3);
+
+        let header = adaptive_header(4, &[UnmarkAll]);
+        decode(&mut dpb, &sps, &header).unwrap();
+
+        // Everything before is output, the picture itself counts as frame_num and POC 0
+        assert_eq!(output_order(&mut dpb), [0, 2, 4, 6]);
+        assert_eq!(short_term(&dpb), [0]);
+        assert_eq!(dpb.frames()[0].pic_order_cnt(), 0);
+
+        decode(&mut dpb, &sps, &slice_header(1, 2)).unwrap();
+        assert_eq!(short_term(&dpb), [0, 1]);
+
+        dpb.flush();
+        assert_eq!(output_order(&mut dpb), [0, 2]);
+    }
+
+    #[test]
+    fn mmco_6() {
+        let (sps, mut dpb) = reference_frames(1);
+
+        let header = adaptive_header(
+            2,
+            &[
+                SetMaxLongTermFrameIdx {
+                    max_long_term_frame_idx_plus1: 1,
+                },
+                MarkCurrentAsLongTerm {
+                    long_term_frame_idx: 0,
+                },
+            ],
+        );
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(short_term(&dpb), [0, 1]);
+        assert_eq!(long_term(&dpb), [(2, 0)]);
+
+        // Replaces the frame with the same LongTermFrameIdx
+        let header = adaptive_header(
+            3,
+            &[MarkCurrentAsLongTerm {
+                long_term_frame_idx: 0,
+            }],
+        );
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(long_term(&dpb), [(3, 0)]);
+
+        let header = adaptive_header(
+            4,
+            &[MarkCurrentAsLongTerm {
+                long_term_frame_idx: 1,
+            }],
+        );
+        assert!(decode(&mut dpb, &sps, &header).is_err());
+    }
+
+    #[test]
+    fn idr_long_term_reference() {
+        let sps = sps(2);
+        let mut dpb = DecodedPictureBuffer::new(&sps, 18).unwrap();
+
+        let mut header = idr_header();
+        header.dec_ref_pic_marking = Some(DecRefPicMarking::Idr {
+            no_output_of_prior_pics_flag: false,
+            long_term_reference_flag: true,
+        });
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(long_term(&dpb), [(0, 0)]);
+    }
+
+    #[test]
+    fn bumping() {
+        // MaxDpbFrames of 3, 2 reference frames
+        let mut sps = sps(0);
+        sps.max_num_ref_frames = 2;
+        sps.vui = Some(vui(3, 3));
+        let mut dpb = DecodedPictureBuffer::new(&sps, 5).unwrap();
+
+        // I0 P8 B4 b2 b6 b3 in decode order, (frame_num, nal_ref_idc, pic_order_cnt_lsb)
+        let pictures = [(1, 2, 8), (2, 2, 4), (3, 0, 2), (3, 0, 6), (3, 0, 3)];
+        // What is output once each is decoded
+        let outputs: [&[i32]; 5] = [&[], &[], &[0], &[2], &[3]];
+
+        decode(&mut dpb, &sps, &idr_header()).unwrap();
+        assert_eq!(output_order(&mut dpb), []);
+        for ((frame_num, nal_ref_idc, pic_order_cnt_lsb), output) in
+            pictures.into_iter().zip(outputs)
+        {
+            let mut header = slice_header(frame_num, nal_ref_idc);
+            header.pic_order_cnt_lsb = pic_order_cnt_lsb;
+            decode(&mut dpb, &sps, &header).unwrap();
+            assert_eq!(output_order(&mut dpb), output, "POC {}", pic_order_cnt_lsb);
+        }
+
+        dpb.flush();
+        assert_eq!(output_order(&mut dpb), [4, 6, 8]);
+    }
+
+    #[test]
+    fn idr_outputs_prior_pictures() {
+        let (sps, mut dpb) = reference_frames(2);
+
+        decode(&mut dpb, &sps, &idr_header()).unwrap();
+        assert_eq!(output_order(&mut dpb), [0, 2, 4]);
+        assert_eq!(short_term(&dpb), [0]);
+
+        let mut header = idr_header();
+        header.dec_ref_pic_marking = Some(DecRefPicMarking::Idr {
+            no_output_of_prior_pics_flag: true,
+            long_term_reference_flag: false,
+        });
+        decode(&mut dpb, &sps, &slice_header(1, 2)).unwrap();
+        decode(&mut dpb, &sps, &header).unwrap();
+        assert_eq!(output_order(&mut dpb), []);
+        assert_eq!(dpb.frames().len(), 1);
+
+        // The IDR picture and frame 1 are gone without being output
+        assert_eq!(dpb.take_discarded(), [3, 4]);
+        assert_eq!(dpb.take_discarded(), []);
+    }
+
+    #[test]
+    fn frame_num_gap() {
+        let mut sps = sps(2);
+        sps.gaps_in_frame_num_value_allowed_flag = true;
+        let mut dpb = DecodedPictureBuffer::new(&sps, 18).unwrap();
+
+        decode(&mut dpb, &sps, &idr_header()).unwrap();
+        decode(&mut dpb, &sps, &slice_header(1, 2)).unwrap();
+        decode(&mut dpb, &sps, &slice_header(4, 2)).unwrap();
+
+        // frame_num 2 and 3 are "non-existing" and went through the sliding window
+        assert_eq!(short_term(&dpb), [1, 2, 3, 4]);
+        let non_existing = dpb
+            .frames()
+            .iter()
+            .filter(|frame| frame.non_existing)
+            .map(|frame| frame.frame_num)
+            .collect::<Vec<_>>();
+        assert_eq!(non_existing, [2, 3]);
+
+        // and are never output
+        dpb.flush();
+        assert_eq!(output_order(&mut dpb), [0, 2, 8]);
+    }
+
+    #[test]
+    fn slots() {
+        // As few slots as the 4 reference frames allow
+        let sps = SequenceParameterSet {
+            vui: Some(vui(4, 4)),
+            ..sps(2)
+        };
+        let mut dpb = DecodedPictureBuffer::new(&sps, 6).unwrap();
+
+        for frame_num in 0..20 {
+            let header = match frame_num {
+                0 => idr_header(),
+                _ => slice_header(frame_num % 16, 2),
+            };
+            let slot_index = dpb.begin_picture(&sps, &header).unwrap().slot_index;
+
+            // Never one a stored frame, a picture waiting to be taken or the one on screen is in
+            assert_ne!(dpb.displayed, Some(slot_index));
+            assert!(dpb
+                .frames()
+                .iter()
+                .all(|frame| frame.slot_index != slot_index));
+            assert!(dpb
+                .output
+                .iter()
+                .all(|picture| picture.slot_index != slot_index));
+
+            dpb.end_picture(&header).unwrap();
+            if let Some(picture) = dpb.take_output().last() {
+                dpb.set_displayed(picture.slot_index);
+            }
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use anyhow::{anyhow, Result};
+use ash::{vk, Device};
+
+use crate::find_memorytype_index;
+use crate::sps::SequenceParameterSet;
+
+/// Number of DPB slots to decode the coded video sequence of `sps` with: every frame the DPB can
+/// hold plus the picture being decoded and the one on screen. MaxDpbFrames follows from the level
+/// and is often more than the stream uses, with fewer slots the DPB is shrunk as far as
+/// [`SequenceParameterSet::min_dpb_frames`] allows.
+pub fn slot_count(
+    sps: &SequenceParameterSet,
+    capabilities: &vk::VideoCapabilitiesKHR,
+) -> Result<u32> {
+    let max_num_ref_frames = sps.max_num_ref_frames as u32;
+
+    if max_num_ref_frames > capabilities.max_active_reference_pictures
+        || sps.min_dpb_frames()? + 2 > capabilities.max_dpb_slots
+    {
+        return Err(anyhow!(
+            "SPS {} needs {} reference frames and reorders up to {} frames, the implementation supports {} active references in {} DPB slots",
+            sps.seq_parameter_set_id,
+            max_num_ref_frames,
+            sps.max_num_reorder_frames()?,
+            capabilities.max_active_reference_pictures,
+            capabilities.max_dpb_slots
+        ));
+    }
+
+    Ok((sps.max_dpb_frames()? + 2).min(capabilities.max_dpb_slots))
+}
+
+/// The images backing the DPB slots of a video session, or the output pictures when they are
+/// distinct from the DPB.
+///
+/// Without `VideoCapabilityFlagsKHR::SEPARATE_REFERENCE_IMAGES` every slot has to live in the same
+/// image, one array layer per slot, otherwise each slot gets an image of its own.
+pub struct DpbPool {
+    pub images: Vec<vk::Image>,
+    pub memories: Vec<vk::DeviceMemory>,
+    /// One view per slot, of its own image or of its layer of the shared one.
+    pub image_views: Vec<vk::ImageView>,
+    pub format: vk::Format,
+    pub coded_extent: vk::Extent2D,
+    /// Create flags of the images, `MUTABLE_FORMAT` when their planes can be viewed on their own.
+    pub flags: vk::ImageCreateFlags,
+}
+
+impl DpbPool {
+    /// Creates the images of `slot_count` slots, `usage` includes `VIDEO_DECODE_DPB_KHR` and
+    /// whatever else the slots are used for besides holding references, `flags` are among the
+    /// `image_create_flags` the video format supports. The images are shared concurrently when
+    /// more than one queue family uses them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &Device,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        profile_list_info: &mut vk::VideoProfileListInfoKHR,
+        capability_flags: vk::VideoCapabilityFlagsKHR,
+        format: vk::Format,
+        coded_extent: vk::Extent2D,
+        slot_count: u32,
+        usage: vk::ImageUsageFlags,
+        flags: vk::ImageCreateFlags,
+        queue_family_indices: &[u32],
+    ) -> Result<Self> {
+        unsafe {
+            let separate_images =
+                capability_flags.contains(vk::VideoCapabilityFlagsKHR::SEPARATE_REFERENCE_IMAGES);
+            let (image_count, array_layers) = if separate_images {
+                (slot_count, 1)
+            } else {
+                (1, slot_count)
+            };
+
+            let mut pool = DpbPool {
+                images: Vec::with_capacity(image_count as usize),
+                memories: Vec::with_capacity(image_count as usize),
+                image_views: Vec::with_capacity(slot_count as usize),
+                format,
+                coded_extent,
+                flags,
+            };
+
+            let sharing_mode = if queue_family_indices.len() > 1 {
+                vk::SharingMode::CONCURRENT
+            } else {
+                vk::SharingMode::EXCLUSIVE
+            };
+
+            let image_create_info = vk::ImageCreateInfo {
+                p_next: profile_list_info as *mut _ as _,
+                flags,
+                image_type: vk::ImageType::TYPE_2D,
+                format,
+                extent: coded_extent.into(),
+                mip_levels: 1,
+                array_layers,
+                samples: vk::SampleCountFlags::TYPE_1,
+                tiling: vk::ImageTiling::OPTIMAL,
+                usage,
+                sharing_mode,
+                queue_family_index_count: queue_family_indices.len() as u32,
+                p_queue_family_indices: queue_family_indices.as_ptr(),
+                ..Default::default()
+            };
+
+            // Whatever was created before a failure goes with the pool
+            if let Err(err) = pool.create(
+                device,
+                device_memory_properties,
+                &image_create_info,
+                image_count,
+                slot_count,
+                usage,
+            ) {
+                pool.destroy(device);
+                return Err(err);
+            }
+
+            Ok(pool)
+        }
+    }
+
+    /// Creates the images, their memory and the slot views, each one is kept as soon as it exists.
+    unsafe fn create(
+        &mut self,
+        device: &Device,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        image_create_info: &vk::ImageCreateInfo,
+        image_count: u32,
+        slot_count: u32,
+        usage: vk::ImageUsageFlags,
+    ) -> Result<()> {
+        for _ in 0..image_count {
+            let image = device.create_image(image_create_info, None)?;
+            self.images.push(image);
+
+            let memory_req = device.get_image_memory_requirements(image);
+            let memory_index = find_memorytype_index(
+                &memory_req,
+                device_memory_properties,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )
+            .ok_or_else(|| anyhow!("Unable to find suitable memory index for a DPB image"))?;
+
+            let allocate_info = vk::MemoryAllocateInfo {
+                allocation_size: memory_req.size,
+                memory_type_index: memory_index,
+                ..Default::default()
+            };
+            let memory = device.allocate_memory(&allocate_info, None)?;
+            self.memories.push(memory);
+
+            device.bind_image_memory(image, memory, 0)?;
+        }
+
+        // Sampling a multi-planar format takes a view with a YCbCr conversion, these views
+        // are only decoded into and referenced
+        let mut image_view_usage_create_info = vk::ImageViewUsageCreateInfo {
+            usage: usage & !vk::ImageUsageFlags::SAMPLED,
+            ..Default::default()
+        };
+
+        for slot_index in 0..slot_count {
+            let (image, base_array_layer) = self.slot_image(slot_index as usize);
+
+            let image_view_info = vk::ImageViewCreateInfo {
+                p_next: &mut image_view_usage_create_info as *mut _ as _,
+                view_type: vk::ImageViewType::TYPE_2D,
+                format: self.format,
+                image,
+                subresource_range: vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_array_layer,
+                    level_count: 1,
+                    layer_count: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+
+            self.image_views
+                .push(device.create_image_view(&image_view_info, None)?);
+        }
+
+        Ok(())
+    }
+
+    pub fn slot_count(&self) -> usize {
+        self.image_views.len()
+    }
+
+    /// The image and array layer a slot lives in, for barriers and copies.
+    pub fn slot_image(&self, slot_index: usize) -> (vk::Image, u32) {
+        if self.images.len() == 1 {
+            (self.images[0], slot_index as u32)
+        } else {
+            (self.images[slot_index], 0)
+        }
+    }
+
+    /// The picture resource of a slot, to set up or reference it in a decode.
+    pub fn picture_resource(&self, slot_index: usize) -> vk::VideoPictureResourceInfoKHR<'static> {
+        vk::VideoPictureResourceInfoKHR {
+            coded_extent: self.coded_extent,
+            // relative to the view, which already selects the layer
+            base_array_layer: 0,
+            image_view_binding: self.image_views[slot_index],
+            ..Default::default()
+        }
+    }
+
+    pub fn destroy(&self, device: &Device) {
+        unsafe {
+            for &image_view in &self.image_views {
+                device.destroy_image_view(image_view, None);
+            }
+            for &image in &self.images {
+                device.destroy_image(image, None);
+            }
+            for &memory in &self.memories {
+                device.free_memory(memory, None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{sps, vui};
+
+    fn capabilities(max_dpb_slots: u32) -> vk::VideoCapabilitiesKHR<'static> {
+        vk::VideoCapabilitiesKHR {
+            max_dpb_slots,
+            max_active_reference_pictures: 16,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn slots() {
+        // 320x240 at level 3, a MaxDpbFrames of 16
+        let level_sized = sps(0);
+        let restricted = SequenceParameterSet {
+            vui: Some(vui(2, 3)),
+            ..sps(0)
+        };
+        let reordering = SequenceParameterSet {
+            max_num_ref_frames: 2,
+            vui: Some(vui(8, 8)),
+            ..sps(0)
+        };
+
+        // (SPS, max_dpb_slots, slots), the DPB shrinks down to the reference frames and reordering
+        let cases = [
+            (&level_sized, 32, Some(18)),
+            (&level_sized, 18, Some(18)),
+            // Without bitstream_restriction max_num_reorder_frames is MaxDpbFrames as well
+            (&level_sized, 17, None),
+            (&restricted, 32, Some(6)),
+            (&restricted, 6, Some(6)),
+            (&restricted, 5, None),
+            (&reordering, 10, Some(10)),
+            (&reordering, 9, None),
+        ];
+        for (sps, max_dpb_slots, slots) in cases {
+            assert_eq!(
+                slot_count(sps, &capabilities(max_dpb_slots)).ok(),
+                slots,
+                "{:?} in {} slots",
+                sps.vui,
+                max_dpb_slots
+            );
+        }
+
+        let shrunk = SequenceParameterSet {
+            vui: Some(vui(2, 16)),
+            ..sps(0)
+        };
+        assert_eq!(slot_count(&shrunk, &capabilities(17)).unwrap(), 17);
+    }
+
+    #[test]
+    fn active_references() {
+        let sps = SequenceParameterSet {
+            max_num_ref_frames: 5,
+            ..sps(0)
+        };
+        let capabilities = vk::VideoCapabilitiesKHR {
+            max_active_reference_pictures: 4,
+            ..capabilities(32)
+        };
+        assert!(slot_count(&sps, &capabilities).is_err());
+    }
+}
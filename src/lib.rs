@@ -1,8 +1,33 @@
+pub mod annexb;
+pub mod avc;
+pub mod bitstream;
+pub mod colour;
+pub mod decoder;
+pub mod dpb;
+pub mod dpb_pool;
+pub mod mp4;
+pub mod nal;
+pub mod output_mode;
+pub mod poc;
+pub mod pps;
+pub mod presenter;
+pub mod rbsp;
+pub mod ref_list;
+pub mod session_memory;
+pub mod session_parameters;
+pub mod slice;
+pub mod source;
+pub mod sps;
+pub mod sync;
+#[cfg(test)]
+mod test_util;
+
 use ash::{
     extensions::{
         ext::DebugUtils,
         khr::{Surface, Swapchain, VideoQueue},
     },
+    vk::KhrVideoDecodeH264Fn,
     vk::KhrVideoDecodeQueueFn,
     vk::KhrVideoQueueFn,
 };
@@ -11,7 +36,6 @@ use ash::{vk, Entry};
 pub use ash::{Device, Instance};
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
 use std::borrow::Cow;
-use std::cell::RefCell;
 use std::default::Default;
 use std::ffi::CStr;
 use std::ops::Drop;
@@ -143,12 +167,14 @@ pub fn find_memorytype_index(
         .map(|(index, _memory_type)| index as _)
 }
 
+/// The properties of the first format `image_usage` is supported with, the flags images of it can
+/// be created with among them.
 pub fn find_video_format(
     pdevice: vk::PhysicalDevice,
     video_queue_loader: &VideoQueue,
     image_usage: vk::ImageUsageFlags,
     profile_list_info: &mut vk::VideoProfileListInfoKHR,
-) -> Result<vk::Format> {
+) -> Result<vk::VideoFormatPropertiesKHR<'static>> {
     let format_info = vk::PhysicalDeviceVideoFormatInfoKHR::default()
         .push_next(profile_list_info)
         .image_usage(image_usage);
@@ -168,7 +194,7 @@ pub fn find_video_format(
             &mut format_properties,
         )?;
 
-        Ok(format_properties[0].format)
+        Ok(format_properties[0])
     }
     // TODO more conscious decision
 }
@@ -181,7 +207,8 @@ pub struct ExampleBase {
     pub swapchain_loader: Swapchain,
     pub debug_utils_loader: DebugUtils,
     pub window: winit::window::Window,
-    pub event_loop: RefCell<EventLoop<()>>,
+    /// Taken while the render loop runs.
+    pub event_loop: Option<EventLoop<()>>,
     pub debug_call_back: vk::DebugUtilsMessengerEXT,
 
     pub pdevice: vk::PhysicalDevice,
@@ -189,6 +216,7 @@ pub struct ExampleBase {
     pub graphics_queue_family_index: u32,
     pub decode_queue_family_index: u32,
     pub present_queue: vk::Queue,
+    pub decode_queue: vk::Queue,
 
     //pub video_profiles: Vec<vk::VideoProfileInfoKHR>,
     //pub profile_list_info: VideoProfileInfoKHR,
@@ -205,7 +233,8 @@ pub struct ExampleBase {
     pub graphics_pool: vk::CommandPool,
     pub draw_command_buffer: vk::CommandBuffer,
     pub setup_command_buffer: vk::CommandBuffer,
-    pub decode_command_buffer: vk::CommandBuffer,
+
+    pub decode_pool: vk::CommandPool,
 
     pub depth_image: vk::Image,
     pub depth_image_view: vk::ImageView,
@@ -219,30 +248,70 @@ pub struct ExampleBase {
 }
 
 impl ExampleBase {
-    pub fn render_loop<F: Fn()>(&self, f: F) {
-        self.event_loop
-            .borrow_mut()
-            .run_return(|event, _, control_flow| {
-                *control_flow = ControlFlow::Poll;
-                match event {
-                    Event::WindowEvent {
-                        event:
-                            WindowEvent::CloseRequested
-                            | WindowEvent::KeyboardInput {
-                                input:
-                                    KeyboardInput {
-                                        state: ElementState::Pressed,
-                                        virtual_keycode: Some(VirtualKeyCode::Escape),
-                                        ..
-                                    },
-                                ..
-                            },
-                        ..
-                    } => *control_flow = ControlFlow::Exit,
-                    Event::MainEventsCleared => f(),
-                    _ => (),
+    /// Calls `f` once per frame until the window is closed, `f` returns whether it found the
+    /// swapchain out of date or the error that stops the loop.
+    ///
+    /// The swapchain is recreated once the window was resized or found out of date, `f` is told
+    /// when it was since its last call to recreate whatever depends on the swapchain images. The
+    /// loop stops with the error of a recreation that failed as well.
+    pub fn render_loop<F: FnMut(&Self, bool) -> Result<bool>>(&mut self, mut f: F) -> Result<()> {
+        let mut event_loop = self
+            .event_loop
+            .take()
+            .expect("The render loop is already running");
+        let mut out_of_date = false;
+        let mut result = Ok(());
+
+        event_loop.run_return(|event, _, control_flow| {
+            *control_flow = ControlFlow::Poll;
+            match event {
+                Event::WindowEvent {
+                    event:
+                        WindowEvent::CloseRequested
+                        | WindowEvent::KeyboardInput {
+                            input:
+                                KeyboardInput {
+                                    state: ElementState::Pressed,
+                                    virtual_keycode: Some(VirtualKeyCode::Escape),
+                                    ..
+                                },
+                            ..
+                        },
+                    ..
+                } => *control_flow = ControlFlow::Exit,
+                Event::WindowEvent {
+                    event: WindowEvent::Resized(_),
+                    ..
+                } => out_of_date = true,
+                Event::MainEventsCleared => {
+                    // A minimized window has no swapchain images to draw to
+                    let size = self.window.inner_size();
+                    if size.width == 0 || size.height == 0 {
+                        return;
+                    }
+                    let recreated = out_of_date;
+                    if recreated {
+                        if let Err(err) = self.recreate_swapchain() {
+                            result = Err(err);
+                            *control_flow = ControlFlow::Exit;
+                            return;
+                        }
+                    }
+                    match f(self, recreated) {
+                        Ok(found_out_of_date) => out_of_date = found_out_of_date,
+                        Err(err) => {
+                            result = Err(err);
+                            *control_flow = ControlFlow::Exit;
+                        }
+                    }
                 }
-            });
+                _ => (),
+            }
+        });
+
+        self.event_loop = Some(event_loop);
+
+        result
     }
 
     pub fn new(window_width: u32, window_height: u32) -> Result<Self> {
@@ -340,6 +409,14 @@ impl ExampleBase {
 
             let surface_loader = Surface::new(&entry, &instance);
 
+            // H.264 pictures are decoded on a video decode queue and presented
+            let device_extension_names = [
+                Swapchain::name(),
+                KhrVideoQueueFn::name(),
+                KhrVideoDecodeQueueFn::name(),
+                KhrVideoDecodeH264Fn::name(),
+            ];
+
             let mut found_graphics_queue = false;
             let mut found_decode_queue = false;
             let mut pdevice = vk::PhysicalDevice::null();
@@ -352,6 +429,17 @@ impl ExampleBase {
                 found_graphics_queue = false;
                 found_decode_queue = false;
 
+                let extension_properties =
+                    instance.enumerate_device_extension_properties(device)?;
+                let supports_extensions = device_extension_names.iter().all(|&name| {
+                    extension_properties.iter().any(|extension_property| {
+                        CStr::from_ptr(extension_property.extension_name.as_ptr()) == name
+                    })
+                });
+                if !supports_extensions {
+                    continue;
+                }
+
                 let queue_family_properties_count =
                     instance.get_physical_device_queue_family_properties2_len(device);
 
@@ -382,6 +470,7 @@ impl ExampleBase {
                         .queue_flags
                         .contains(vk::QueueFlags::VIDEO_DECODE_KHR)
                     {
+                        // The family has to decode H.264, not just some codec
                         if video_queue_family_property
                             .video_codec_operations
                             .contains(vk::VideoCodecOperationFlagsKHR::DECODE_H264)
@@ -421,12 +510,17 @@ impl ExampleBase {
                 ));
             }
 
-            let device_extension_names_raw =
-                [Swapchain::name().as_ptr(), KhrVideoQueueFn::name().as_ptr()];
+            let device_extension_names_raw = device_extension_names.map(CStr::as_ptr);
             let features = vk::PhysicalDeviceFeatures {
                 shader_clip_distance: 1,
                 ..Default::default()
             };
+            // Decoded pictures are sampled through a YCbCr conversion
+            let mut vulkan_11_features =
+                vk::PhysicalDeviceVulkan11Features::default().sampler_ycbcr_conversion(true);
+            // Decode and graphics submissions are ordered with timeline semaphores
+            let mut vulkan_12_features =
+                vk::PhysicalDeviceVulkan12Features::default().timeline_semaphore(true);
             let priorities = [0.0];
 
             let graphics_queue_info = vk::DeviceQueueCreateInfo::default()
@@ -437,75 +531,33 @@ impl ExampleBase {
                 .queue_family_index(decode_queue_family_index)
                 .queue_priorities(&priorities);
 
-            let queue_infos = [graphics_queue_info, decode_queue_info];
+            // A family can only be listed once, the decode queue may be the graphics one
+            let queue_infos = if decode_queue_family_index == graphics_queue_family_index {
+                vec![graphics_queue_info]
+            } else {
+                vec![graphics_queue_info, decode_queue_info]
+            };
 
             let device_create_info = vk::DeviceCreateInfo::default()
                 .queue_create_infos(&queue_infos)
                 .enabled_extension_names(&device_extension_names_raw)
-                .enabled_features(&features);
+                .enabled_features(&features)
+                .push_next(&mut vulkan_11_features)
+                .push_next(&mut vulkan_12_features);
 
             let device: Device = instance
                 .create_device(pdevice, &device_create_info, None)
                 .unwrap();
 
             let present_queue = device.get_device_queue(graphics_queue_family_index, 0);
+            let decode_queue = device.get_device_queue(decode_queue_family_index, 0);
 
             let surface_format = surface_loader
                 .get_physical_device_surface_formats(pdevice, surface)
                 .unwrap()[0];
 
-            let surface_capabilities = surface_loader
-                .get_physical_device_surface_capabilities(pdevice, surface)
-                .unwrap();
-            let mut desired_image_count = surface_capabilities.min_image_count + 1;
-            if surface_capabilities.max_image_count > 0
-                && desired_image_count > surface_capabilities.max_image_count
-            {
-                desired_image_count = surface_capabilities.max_image_count;
-            }
-            let surface_resolution = match surface_capabilities.current_extent.width {
-                std::u32::MAX => vk::Extent2D {
-                    width: window_width,
-                    height: window_height,
-                },
-                _ => surface_capabilities.current_extent,
-            };
-            let pre_transform = if surface_capabilities
-                .supported_transforms
-                .contains(vk::SurfaceTransformFlagsKHR::IDENTITY)
-            {
-                vk::SurfaceTransformFlagsKHR::IDENTITY
-            } else {
-                surface_capabilities.current_transform
-            };
-            let present_modes = surface_loader
-                .get_physical_device_surface_present_modes(pdevice, surface)
-                .unwrap();
-            let present_mode = present_modes
-                .iter()
-                .cloned()
-                .find(|&mode| mode == vk::PresentModeKHR::MAILBOX)
-                .unwrap_or(vk::PresentModeKHR::FIFO);
             let swapchain_loader = Swapchain::new(&instance, &device);
 
-            let swapchain_create_info = vk::SwapchainCreateInfoKHR::default()
-                .surface(surface)
-                .min_image_count(desired_image_count)
-                .image_color_space(surface_format.color_space)
-                .image_format(surface_format.format)
-                .image_extent(surface_resolution)
-                .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
-                .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
-                .pre_transform(pre_transform)
-                .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-                .present_mode(present_mode)
-                .clipped(true)
-                .image_array_layers(1);
-
-            let swapchain = swapchain_loader
-                .create_swapchain(&swapchain_create_info, None)
-                .unwrap();
-
             // Grapgics and resentation command pool
             let graphics_pool_create_info = vk::CommandPoolCreateInfo::default()
                 .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
@@ -536,73 +588,7 @@ impl ExampleBase {
                 .create_command_pool(&decode_pool_create_info, None)
                 .unwrap();
 
-            let deocde_command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
-                .command_buffer_count(1)
-                .command_pool(decode_pool)
-                .level(vk::CommandBufferLevel::PRIMARY);
-
-            let decode_command_buffers = device
-                .allocate_command_buffers(&deocde_command_buffer_allocate_info)
-                .unwrap();
-            let decode_command_buffer = decode_command_buffers[0];
-
-            // Presentation images
-            let present_images = swapchain_loader.get_swapchain_images(swapchain).unwrap();
-            let present_image_views: Vec<vk::ImageView> = present_images
-                .iter()
-                .map(|&image| {
-                    let create_view_info = vk::ImageViewCreateInfo::default()
-                        .view_type(vk::ImageViewType::TYPE_2D)
-                        .format(surface_format.format)
-                        .components(vk::ComponentMapping {
-                            r: vk::ComponentSwizzle::R,
-                            g: vk::ComponentSwizzle::G,
-                            b: vk::ComponentSwizzle::B,
-                            a: vk::ComponentSwizzle::A,
-                        })
-                        .subresource_range(vk::ImageSubresourceRange {
-                            aspect_mask: vk::ImageAspectFlags::COLOR,
-                            base_mip_level: 0,
-                            level_count: 1,
-                            base_array_layer: 0,
-                            layer_count: 1,
-                        })
-                        .image(image);
-                    device.create_image_view(&create_view_info, None).unwrap()
-                })
-                .collect();
             let device_memory_properties = instance.get_physical_device_memory_properties(pdevice);
-            let depth_image_create_info = vk::ImageCreateInfo::default()
-                .image_type(vk::ImageType::TYPE_2D)
-                .format(vk::Format::D16_UNORM)
-                .extent(surface_resolution.into())
-                .mip_levels(1)
-                .array_layers(1)
-                .samples(vk::SampleCountFlags::TYPE_1)
-                .tiling(vk::ImageTiling::OPTIMAL)
-                .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
-                .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-            let depth_image = device.create_image(&depth_image_create_info, None).unwrap();
-            let depth_image_memory_req = device.get_image_memory_requirements(depth_image);
-            let depth_image_memory_index = find_memorytype_index(
-                &depth_image_memory_req,
-                &device_memory_properties,
-                vk::MemoryPropertyFlags::DEVICE_LOCAL,
-            )
-            .expect("Unable to find suitable memory index for depth image.");
-
-            let depth_image_allocate_info = vk::MemoryAllocateInfo::default()
-                .allocation_size(depth_image_memory_req.size)
-                .memory_type_index(depth_image_memory_index);
-
-            let depth_image_memory = device
-                .allocate_memory(&depth_image_allocate_info, None)
-                .unwrap();
-
-            device
-                .bind_image_memory(depth_image, depth_image_memory, 0)
-                .expect("Unable to bind depth image memory");
 
             let fence_create_info =
                 vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED);
@@ -614,57 +600,6 @@ impl ExampleBase {
                 .create_fence(&fence_create_info, None)
                 .expect("Create fence failed.");
 
-            record_submit_commandbuffer(
-                &device,
-                setup_command_buffer,
-                setup_commands_reuse_fence,
-                present_queue,
-                &[],
-                &[],
-                &[],
-                |device, setup_command_buffer| {
-                    let layout_transition_barriers = vk::ImageMemoryBarrier::default()
-                        .image(depth_image)
-                        .dst_access_mask(
-                            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
-                                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
-                        )
-                        .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-                        .old_layout(vk::ImageLayout::UNDEFINED)
-                        .subresource_range(
-                            vk::ImageSubresourceRange::default()
-                                .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                                .layer_count(1)
-                                .level_count(1),
-                        );
-
-                    device.cmd_pipeline_barrier(
-                        setup_command_buffer,
-                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                        vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
-                        vk::DependencyFlags::empty(),
-                        &[],
-                        &[],
-                        &[layout_transition_barriers],
-                    );
-                },
-            );
-
-            let depth_image_view_info = vk::ImageViewCreateInfo::default()
-                .subresource_range(
-                    vk::ImageSubresourceRange::default()
-                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                        .level_count(1)
-                        .layer_count(1),
-                )
-                .image(depth_image)
-                .format(depth_image_create_info.format)
-                .view_type(vk::ImageViewType::TYPE_2D);
-
-            let depth_image_view = device
-                .create_image_view(&depth_image_view_info, None)
-                .unwrap();
-
             let semaphore_create_info = vk::SemaphoreCreateInfo::default();
 
             let present_complete_semaphore = device
@@ -674,8 +609,8 @@ impl ExampleBase {
                 .create_semaphore(&semaphore_create_info, None)
                 .unwrap();
 
-            Ok(ExampleBase {
-                event_loop: RefCell::new(event_loop),
+            let mut base = ExampleBase {
+                event_loop: Some(event_loop),
                 entry,
                 instance,
                 device,
@@ -687,20 +622,22 @@ impl ExampleBase {
                 surface_loader,
                 surface_format,
                 present_queue,
+                decode_queue,
                 //video_profiles,
                 //dst_video_format,
                 //dpb_video_format,
-                surface_resolution,
+                // Filled in by create_swapchain
+                surface_resolution: vk::Extent2D::default(),
                 swapchain_loader,
-                swapchain,
-                present_images,
-                present_image_views,
+                swapchain: vk::SwapchainKHR::null(),
+                present_images: Vec::new(),
+                present_image_views: Vec::new(),
                 graphics_pool,
                 draw_command_buffer,
                 setup_command_buffer,
-                decode_command_buffer,
-                depth_image,
-                depth_image_view,
+                decode_pool,
+                depth_image: vk::Image::null(),
+                depth_image_view: vk::ImageView::null(),
                 present_complete_semaphore,
                 rendering_complete_semaphore,
                 draw_commands_reuse_fence,
@@ -708,9 +645,221 @@ impl ExampleBase {
                 surface,
                 debug_call_back,
                 debug_utils_loader,
-                depth_image_memory,
+                depth_image_memory: vk::DeviceMemory::null(),
+            };
+
+            base.create_swapchain(vk::SwapchainKHR::null())?;
+
+            Ok(base)
+        }
+    }
+
+    /// Recreates the swapchain and the depth image for the current size of the window, once the
+    /// device is idle. When that fails the old swapchain stays until the base is dropped.
+    pub fn recreate_swapchain(&mut self) -> Result<()> {
+        unsafe {
+            self.device.device_wait_idle()?;
+            self.destroy_swapchain_images();
+
+            let old_swapchain = self.swapchain;
+            self.create_swapchain(old_swapchain)?;
+            self.swapchain_loader.destroy_swapchain(old_swapchain, None);
+        }
+
+        Ok(())
+    }
+
+    /// Creates the swapchain, its image views and a depth image of the same size, `old_swapchain`
+    /// is the one it replaces if any.
+    unsafe fn create_swapchain(&mut self, old_swapchain: vk::SwapchainKHR) -> Result<()> {
+        let surface_capabilities = self
+            .surface_loader
+            .get_physical_device_surface_capabilities(self.pdevice, self.surface)?;
+        let mut desired_image_count = surface_capabilities.min_image_count + 1;
+        if surface_capabilities.max_image_count > 0
+            && desired_image_count > surface_capabilities.max_image_count
+        {
+            desired_image_count = surface_capabilities.max_image_count;
+        }
+        let surface_resolution = match surface_capabilities.current_extent.width {
+            u32::MAX => {
+                let window_size = self.window.inner_size();
+                vk::Extent2D {
+                    width: window_size.width,
+                    height: window_size.height,
+                }
+            }
+            _ => surface_capabilities.current_extent,
+        };
+        let pre_transform = if surface_capabilities
+            .supported_transforms
+            .contains(vk::SurfaceTransformFlagsKHR::IDENTITY)
+        {
+            vk::SurfaceTransformFlagsKHR::IDENTITY
+        } else {
+            surface_capabilities.current_transform
+        };
+        let present_modes = self
+            .surface_loader
+            .get_physical_device_surface_present_modes(self.pdevice, self.surface)?;
+        let present_mode = present_modes
+            .iter()
+            .cloned()
+            .find(|&mode| mode == vk::PresentModeKHR::MAILBOX)
+            .unwrap_or(vk::PresentModeKHR::FIFO);
+
+        let swapchain_create_info = vk::SwapchainCreateInfoKHR::default()
+            .surface(self.surface)
+            .min_image_count(desired_image_count)
+            .image_color_space(self.surface_format.color_space)
+            .image_format(self.surface_format.format)
+            .image_extent(surface_resolution)
+            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+            .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .pre_transform(pre_transform)
+            .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+            .present_mode(present_mode)
+            .clipped(true)
+            .image_array_layers(1)
+            .old_swapchain(old_swapchain);
+
+        let swapchain = self
+            .swapchain_loader
+            .create_swapchain(&swapchain_create_info, None)?;
+
+        // Presentation images
+        let present_images = self.swapchain_loader.get_swapchain_images(swapchain)?;
+        let present_image_views = present_images
+            .iter()
+            .map(|&image| {
+                let create_view_info = vk::ImageViewCreateInfo::default()
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(self.surface_format.format)
+                    .components(vk::ComponentMapping {
+                        r: vk::ComponentSwizzle::R,
+                        g: vk::ComponentSwizzle::G,
+                        b: vk::ComponentSwizzle::B,
+                        a: vk::ComponentSwizzle::A,
+                    })
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: 0,
+                        level_count: 1,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image(image);
+                self.device.create_image_view(&create_view_info, None)
             })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let depth_image_create_info = vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(vk::Format::D16_UNORM)
+            .extent(surface_resolution.into())
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+        let depth_image = self.device.create_image(&depth_image_create_info, None)?;
+        let depth_image_memory_req = self.device.get_image_memory_requirements(depth_image);
+        let depth_image_memory_index = find_memorytype_index(
+            &depth_image_memory_req,
+            &self.device_memory_properties,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )
+        .ok_or_else(|| anyhow!("Unable to find suitable memory index for depth image."))?;
+
+        let depth_image_allocate_info = vk::MemoryAllocateInfo::default()
+            .allocation_size(depth_image_memory_req.size)
+            .memory_type_index(depth_image_memory_index);
+
+        let depth_image_memory = self
+            .device
+            .allocate_memory(&depth_image_allocate_info, None)?;
+
+        self.device
+            .bind_image_memory(depth_image, depth_image_memory, 0)?;
+
+        record_submit_commandbuffer(
+            &self.device,
+            self.setup_command_buffer,
+            self.setup_commands_reuse_fence,
+            self.present_queue,
+            &[],
+            &[],
+            &[],
+            |device, setup_command_buffer| {
+                let layout_transition_barriers = vk::ImageMemoryBarrier::default()
+                    .image(depth_image)
+                    .dst_access_mask(
+                        vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                    )
+                    .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .subresource_range(
+                        vk::ImageSubresourceRange::default()
+                            .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                            .layer_count(1)
+                            .level_count(1),
+                    );
+
+                device.cmd_pipeline_barrier(
+                    setup_command_buffer,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[layout_transition_barriers],
+                );
+            },
+        );
+
+        let depth_image_view_info = vk::ImageViewCreateInfo::default()
+            .subresource_range(
+                vk::ImageSubresourceRange::default()
+                    .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                    .level_count(1)
+                    .layer_count(1),
+            )
+            .image(depth_image)
+            .format(depth_image_create_info.format)
+            .view_type(vk::ImageViewType::TYPE_2D);
+
+        let depth_image_view = self
+            .device
+            .create_image_view(&depth_image_view_info, None)?;
+
+        self.surface_resolution = surface_resolution;
+        self.swapchain = swapchain;
+        self.present_images = present_images;
+        self.present_image_views = present_image_views;
+        self.depth_image = depth_image;
+        self.depth_image_view = depth_image_view;
+        self.depth_image_memory = depth_image_memory;
+
+        Ok(())
+    }
+
+    /// Destroys the present image views and the depth image, the swapchain images are destroyed
+    /// along with the swapchain.
+    ///
+    /// The handles are cleared, destroying them again after a failed recreation does nothing.
+    unsafe fn destroy_swapchain_images(&mut self) {
+        self.device.free_memory(self.depth_image_memory, None);
+        self.device.destroy_image_view(self.depth_image_view, None);
+        self.device.destroy_image(self.depth_image, None);
+        for image_view in self.present_image_views.drain(..) {
+            self.device.destroy_image_view(image_view, None);
         }
+        self.depth_image_memory = vk::DeviceMemory::null();
+        self.depth_image_view = vk::ImageView::null();
+        self.depth_image = vk::Image::null();
     }
 }
 
@@ -726,13 +875,9 @@ impl Drop for ExampleBase {
                 .destroy_fence(self.draw_commands_reuse_fence, None);
             self.device
                 .destroy_fence(self.setup_commands_reuse_fence, None);
-            self.device.free_memory(self.depth_image_memory, None);
-            self.device.destroy_image_view(self.depth_image_view, None);
-            self.device.destroy_image(self.depth_image, None);
-            for &image_view in self.present_image_views.iter() {
-                self.device.destroy_image_view(image_view, None);
-            }
+            self.destroy_swapchain_images();
             self.device.destroy_command_pool(self.graphics_pool, None);
+            self.device.destroy_command_pool(self.decode_pool, None);
             self.swapchain_loader
                 .destroy_swapchain(self.swapchain, None);
             self.device.destroy_device(None);
@@ -1,141 +1,28 @@
 use std::default::Default;
 use std::env;
-use std::ffi::{CStr, CString};
-use std::io::{Cursor, Read};
+use std::ffi::CStr;
+use std::io::Cursor;
 use std::mem::{self, align_of};
 use std::os::raw::c_void;
 
-use ash::extensions::khr::{VideoDecodeQueue, VideoQueue};
 use ash::util::*;
-use ash::vk::native::StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_MAIN;
-use ash::vk::{self, DeviceMemory, VideoSessionCreateInfoKHR};
+use ash::vk;
 
-use anyhow::Result;
-use mp4parse;
+use anyhow::{anyhow, Result};
 
 use ash_video::*;
 
-#[derive(Default)]
-struct VideoSpec {
-    width: u16,
-    height: u16,
-    max_sps_count: u32,
-    max_pps_count: u32,
-}
-
-// impl Default for VideoSpec {
-//     fn default() -> Self {
-//         Self {
-//             codec_type: mp4parse::CodecType::Unknown,
-//             ..Default::default()
-//         }
-//     }
-// }
-
 #[derive(Clone, Debug, Copy)]
 struct Vertex {
     pos: [f32; 4],
     uv: [f32; 2],
 }
 
-#[derive(Clone, Debug, Copy)]
-pub struct Vector3 {
-    pub x: f32,
-    pub y: f32,
-    pub z: f32,
-    pub _pad: f32,
-}
-
-#[derive(Debug)]
-struct AVCVideoConfiguration {
-    version: u8,
-    profile: u8,
-    compatibility: u8,
-    level: u8,
-    // indicates the length in bytes of the length field in an AVC video access unit used indicate the length of each NAL unit.
-    length_size_minus_one: u8,
-    sps: Vec<Vec<u8>>,
-    pps: Vec<Vec<u8>>,
-}
-
-fn parse_avc_config(data: &[u8]) -> AVCVideoConfiguration {
-    let version = data[0];
-    let avc_profile = data[1];
-    let avc_compatibility = data[2];
-    let avc_level = data[3];
-    let nalulength_size_minus_one = data[4] & 0b00000011;
-    let number_of_sps_nalus = data[5] & 0b00011111;
-    let mut i: usize = 6;
-
-    let sps_elems = (0..number_of_sps_nalus)
-        .map(|_| {
-            let sps_size = u16::from_be_bytes([data[i], data[i + 1]]) as usize;
-            i += 2;
-            let sps: Vec<u8> = data[i..i + sps_size].to_vec();
-            i += sps_size;
-            sps
-        })
-        .collect::<Vec<Vec<u8>>>();
-
-    let number_of_pps_nalus = data[i];
-    i += 1;
-
-    let pps_elems = (0..number_of_pps_nalus)
-        .map(|_| {
-            let pps_size = u16::from_be_bytes([data[i], data[i + 1]]) as usize;
-            i += 2;
-            let sps: Vec<u8> = data[i..i + pps_size].to_vec();
-            i += pps_size;
-            sps
-        })
-        .collect::<Vec<Vec<u8>>>();
-
-    assert_eq!(version, 1);
-
-    AVCVideoConfiguration {
-        version,
-        profile: avc_profile,
-        compatibility: avc_compatibility,
-        level: avc_level,
-        length_size_minus_one: nalulength_size_minus_one,
-        sps: sps_elems,
-        pps: pps_elems,
-    }
-}
-
-pub fn vk_make_video_std_version(major: u32, minor: u32, patch: u32) -> u32 {
-    (major << 22) | (minor << 12) | patch
-}
-
-fn vk_make_extension_name(text: &str) -> [i8; 256] {
-    let mut array: [i8; 256] = [0; 256];
-    let bytes = CString::new(text).unwrap().into_bytes();
-
-    for (i, &b) in bytes.iter().enumerate() {
-        array[i] = b as i8;
-    }
-
-    array
-}
-
-fn vk_find_bit_index(num: u32) -> u32 {
-    let mut index = 0;
-    let mut bits = num;
-    while bits != 0 {
-        if bits & 1 == 1 {
-            return index;
-        }
-        index += 1;
-        bits >>= 1;
-    }
-    return index;
-}
-
 fn main() -> Result<()> {
     unsafe {
         let args: Vec<String> = env::args().collect();
 
-        let mut file = std::fs::File::open({
+        let mut video_source = source::open_video_source({
             if DEBUG_ENABLED {
                 //"./samples/Big_Buck_Bunny_360_10s_1MB.mp4"
                 "./samples/a.mp4"
@@ -143,453 +30,67 @@ fn main() -> Result<()> {
                 &args[1]
             }
         })?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
-
-        let mut c = Cursor::new(&buf);
-        let video_context = mp4parse::read_mp4(&mut c)?;
 
-        let mut video_spec = VideoSpec::default();
-
-        assert_eq!(
-            video_context.timescale,
-            Some(mp4parse::MediaTimeScale(1000))
-        );
-
-        for track in video_context.tracks {
-            match track.track_type {
-                mp4parse::TrackType::Video => {
-                    //let ctts = track.ctts.unwrap();
-                    let stco = track.stco.unwrap();
-                    let stsc = track.stsc.unwrap();
-                    let stss = track.stss.unwrap();
-                    let stsz = track.stsz.unwrap();
-                    let stts = track.stts.unwrap();
-
-                    //offset 48
-                    //size 90
-                    let stsd = track.stsd.expect("expected an stsd");
+        let mut video_spec = decoder::VideoSpec {
+            output_mode: output_mode::OutputMode::from_env()?,
+            ..Default::default()
+        };
 
-                    let v = match stsd.descriptions.first().expect("expected a SampleEntry") {
-                        mp4parse::SampleEntry::Video(v) => v,
-                        _ => panic!("expected a VideoSampleEntry"),
-                    };
+        let first_access_unit = video_source
+            .next_access_unit()
+            .ok_or_else(|| anyhow!("No video access units found"))??;
 
-                    video_spec.width = v.width;
-                    video_spec.height = v.height;
+        let mut sequence_parameter_sets = Vec::new();
+        let mut pps_nal_units = Vec::new();
 
-                    if let mp4parse::VideoCodecSpecific::AVCConfig(ref avc) = v.codec_specific {
-                        let _config = parse_avc_config(avc);
-                    }
+        for nal_unit in &first_access_unit.nal_units {
+            let nal_unit = nal::NalUnit::parse(nal_unit)?;
+            match nal_unit.nal_unit_type {
+                nal::NalUnitType::Sps => {
+                    sequence_parameter_sets.push(sps::SequenceParameterSet::parse(&nal_unit)?)
                 }
+                nal::NalUnitType::Pps => pps_nal_units.push(nal_unit),
                 _ => {}
             }
         }
 
-        let base = ExampleBase::new(video_spec.width as u32, video_spec.height as u32)?;
-
-        // let mut video_decode_usage_info = vk::VideoDecodeUsageInfoKHR::default()
-        //     .video_usage_hints(vk::VideoDecodeUsageFlagsKHR::OFFLINE);
-
-        let mut video_profile_operation = vk::VideoDecodeH264ProfileInfoKHR::default()
-            .std_profile_idc(StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_MAIN)
-            .picture_layout(vk::VideoDecodeH264PictureLayoutFlagsKHR::PROGRESSIVE);
-
-        //video_profile_operation.p_next = &mut video_decode_usage_info as *mut _ as _;
-
-        let profile_info = vk::VideoProfileInfoKHR::default()
-            .push_next(&mut video_profile_operation)
-            .video_codec_operation(vk::VideoCodecOperationFlagsKHR::DECODE_H264)
-            .chroma_subsampling(vk::VideoChromaSubsamplingFlagsKHR::TYPE_420)
-            .luma_bit_depth(vk::VideoComponentBitDepthFlagsKHR::TYPE_8)
-            .chroma_bit_depth(vk::VideoComponentBitDepthFlagsKHR::TYPE_8);
-
-        let mut h264_decode_capibilities = vk::VideoDecodeH264CapabilitiesKHR::default();
-
-        let mut decode_capabilities = vk::VideoDecodeCapabilitiesKHR::default();
-
-        // TODO no p_next or push_next motheods yet this is failing when not passed
-        decode_capabilities.p_next = &mut h264_decode_capibilities as *mut _ as _;
-
-        let mut capabilities =
-            vk::VideoCapabilitiesKHR::default().push_next(&mut decode_capabilities);
-
-        let video_queue_loader = VideoQueue::new(&base.instance, &base.device);
-        //let video_queue_loader = VideoQueue::new(&base.entry, &base.instance);
-
-        let video_decode_queue_loader =
-            VideoDecodeQueue::new(&base.instance, &base.device);
-        //let video_decode_queue_loader = VideoDecodeQueue::new(&base.instance, &base.device);
-
-        video_queue_loader
-            .get_physical_device_video_capabilities(base.pdevice, &profile_info, &mut capabilities)
-            .unwrap();
-
-        let video_profiles = vec![profile_info];
-
-        let mut profile_list_info =
-            vk::VideoProfileListInfoKHR::default().profiles(&video_profiles);
-
-        let mut dst_video_format = find_video_format(
-            base.pdevice,
-            &video_queue_loader,
-            vk::ImageUsageFlags::VIDEO_DECODE_DST_KHR,
-            &mut profile_list_info,
-        )?;
-        let mut dpb_video_format = find_video_format(
-            base.pdevice,
-            &video_queue_loader,
-            vk::ImageUsageFlags::VIDEO_DECODE_DPB_KHR,
-            &mut profile_list_info,
-        )?;
-
-        //TODO experimental
-        let prefer_coincide_mode = false;
-
-        if decode_capabilities
-            .flags
-            .contains(vk::VideoDecodeCapabilityFlagsKHR::DPB_AND_OUTPUT_DISTINCT)
-            && prefer_coincide_mode
-        {
-            dst_video_format = find_video_format(
-                base.pdevice,
-                &video_queue_loader,
-                vk::ImageUsageFlags::VIDEO_DECODE_DST_KHR
-                    | vk::ImageUsageFlags::VIDEO_DECODE_DPB_KHR,
-                &mut profile_list_info,
-            )?;
-
-            dpb_video_format = dst_video_format;
-        }
-
-        // Bitstream buffer
-        
-        //let bitstream_buffer_data = [0u32, 1, 2, 2, 3, 0];
-        let bitstream_buffer_data = buf;
-
-        let bitstream_buffer_info = vk::BufferCreateInfo {
-            p_next: &mut profile_list_info as *mut _ as _,
-            //size: buf.len() as u64,
-            size: bitstream_buffer_data.len() as u64,
-            usage: vk::BufferUsageFlags::VIDEO_DECODE_DST_KHR,
-            sharing_mode: vk::SharingMode::EXCLUSIVE,
-            ..Default::default()
-        };
-
-        let bitstream_buffer = base
-            .device
-            .create_buffer(&bitstream_buffer_info, None)
-            .unwrap();
-
-        let bitstream_buffer_memory_req = base.device.get_buffer_memory_requirements(bitstream_buffer);
-        let bitstream_buffer_memory_index = find_memorytype_index(
-            &bitstream_buffer_memory_req,
-            &base.device_memory_properties,
-            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-        )
-        .expect("Unable to find suitable memorytype for the index buffer.");
-        let bitstream_allocate_info = vk::MemoryAllocateInfo {
-            allocation_size: bitstream_buffer_memory_req.size,
-            memory_type_index: bitstream_buffer_memory_index,
-            ..Default::default()
-        };
-        let bitstream_buffer_memory = base
-            .device
-            .allocate_memory(&bitstream_allocate_info, None)
-            .unwrap();
-        let bitstream_ptr: *mut c_void = base
-            .device
-            .map_memory(
-                bitstream_buffer_memory,
-                0,
-                bitstream_buffer_memory_req.size,
-                vk::MemoryMapFlags::empty(),
-            )
-            .unwrap();
-        let mut bitstream_slice = Align::new(
-            bitstream_ptr,
-            align_of::<u32>() as u64,
-            bitstream_buffer_memory_req.size,
-        );
-        bitstream_slice.copy_from_slice(&bitstream_buffer_data);
-        base.device.unmap_memory(bitstream_buffer_memory);
-        base.device
-            .bind_buffer_memory(bitstream_buffer, bitstream_buffer_memory, 0)
-            .unwrap(); 
-
-        // DST
-
-        let video_extent = vk::Extent2D {
-            width: video_spec.width as u32,
-            height: video_spec.height as u32,
-        };
-
-        let dst_image_create_info = vk::ImageCreateInfo {
-            p_next: &mut profile_list_info as *mut _ as _,
-            image_type: vk::ImageType::TYPE_2D,
-            format: dst_video_format,
-            extent: video_extent.into(),
-            mip_levels: 1,
-            array_layers: 1,
-            samples: vk::SampleCountFlags::TYPE_1,
-            tiling: vk::ImageTiling::OPTIMAL,
-            usage: vk::ImageUsageFlags::VIDEO_DECODE_DST_KHR,
-            ..Default::default()
-        };
-
-        let dst_image = base
-            .device
-            .create_image(&dst_image_create_info, None)
-            .unwrap();
-
-        let dst_memory_req = base.device.get_image_memory_requirements(dst_image);
-        let dst_memory_index = find_memorytype_index(
-            &dst_memory_req,
-            &base.device_memory_properties,
-            vk::MemoryPropertyFlags::DEVICE_LOCAL,
-        )
-        .expect("Unable to find suitable memory index for dst image.");
-
-        let dst_allocate_info = vk::MemoryAllocateInfo {
-            allocation_size: dst_memory_req.size,
-            memory_type_index: dst_memory_index,
-            ..Default::default()
-        };
-        let dst_memory = base
-            .device
-            .allocate_memory(&dst_allocate_info, None)
-            .unwrap();
-        base.device
-            .bind_image_memory(dst_image, dst_memory, 0)
-            .expect("Unable to bind dst image memory");
-
-        let mut dst_image_view_usage_create_info = vk::ImageViewUsageCreateInfo {
-            usage: vk::ImageUsageFlags::VIDEO_DECODE_DST_KHR,
-            ..Default::default()
-        };
-
-        let dst_image_view_info = vk::ImageViewCreateInfo {
-            p_next: &mut dst_image_view_usage_create_info as *mut _ as _,
-            view_type: vk::ImageViewType::TYPE_2D,
-            format: dst_video_format,
-            image: dst_image,
-            subresource_range: vk::ImageSubresourceRange {
-                aspect_mask: vk::ImageAspectFlags::COLOR,
-                level_count: 1,
-                layer_count: 1,
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        let dst_image_view = base
-            .device
-            .create_image_view(&dst_image_view_info, None)
-            .unwrap();
-
-        // DPB
-
-        let dpb_image_create_info = vk::ImageCreateInfo {
-            p_next: &mut profile_list_info as *mut _ as _,
-            image_type: vk::ImageType::TYPE_2D,
-            format: dpb_video_format,
-            extent: video_extent.into(),
-            mip_levels: 1,
-            array_layers: 1,
-            samples: vk::SampleCountFlags::TYPE_1,
-            tiling: vk::ImageTiling::OPTIMAL,
-            usage: vk::ImageUsageFlags::VIDEO_DECODE_DPB_KHR,
-            ..Default::default()
-        };
-
-        let dpb_image = base
-            .device
-            .create_image(&dpb_image_create_info, None)
-            .unwrap();
-
-        let dpb_memory_req = base.device.get_image_memory_requirements(dpb_image);
-        let dpb_memory_index = find_memorytype_index(
-            &dpb_memory_req,
-            &base.device_memory_properties,
-            vk::MemoryPropertyFlags::DEVICE_LOCAL,
-        )
-        .expect("Unable to find suitable memory index for dpb image.");
-
-        let dpb_allocate_info = vk::MemoryAllocateInfo {
-            allocation_size: dpb_memory_req.size,
-            memory_type_index: dpb_memory_index,
-            ..Default::default()
-        };
-        let dpb_memory = base
-            .device
-            .allocate_memory(&dpb_allocate_info, None)
-            .unwrap();
-        base.device
-            .bind_image_memory(dpb_image, dpb_memory, 0)
-            .expect("Unable to bind dpb image memory");
-
-        let mut dpb_image_view_usage_create_info = vk::ImageViewUsageCreateInfo {
-            usage: vk::ImageUsageFlags::VIDEO_DECODE_DPB_KHR,
-            ..Default::default()
-        };
-
-        let dpb_image_view_info = vk::ImageViewCreateInfo {
-            p_next: &mut dpb_image_view_usage_create_info as *mut _ as _,
-            view_type: vk::ImageViewType::TYPE_2D,
-            format: dpb_video_format,
-            image: dpb_image,
-            subresource_range: vk::ImageSubresourceRange {
-                aspect_mask: vk::ImageAspectFlags::COLOR,
-                level_count: 1,
-                layer_count: 1,
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        let dpb_image_view = base
-            .device
-            .create_image_view(&dpb_image_view_info, None)
-            .unwrap();
-
-        // VideoSession
-
-        let extension_properties = vk::ExtensionProperties::default()
-            .extension_name(vk_make_extension_name(
-                "VK_STD_vulkan_video_codec_h264_decode",
-            ))
-            //TODO header version update
-            .spec_version(vk_make_video_std_version(1, 0, 0));
-            //.spec_version(vk_make_video_std_version(0, 9, 8));
-
-        let video_session_info = vk::VideoSessionCreateInfoKHR::default()
-            .queue_family_index(base.decode_queue_family_index)
-            .video_profile(&video_profiles[0])
-            .picture_format(dst_video_format)
-            .std_header_version(&extension_properties)
-            .max_coded_extent(video_extent);
-
-        let video_session = video_queue_loader
-            .create_video_session(&video_session_info, None)
-            .unwrap();
-
-        let video_session_memory_requirements_count =
-            video_queue_loader.get_video_session_memory_requirements_len(video_session);
-
-        let mut video_session_memory_requirements = vec![
-                vk::VideoSessionMemoryRequirementsKHR::default();
-                video_session_memory_requirements_count
-            ];
-
-        video_queue_loader.get_video_session_memory_requirements(
-            video_session,
-            &mut video_session_memory_requirements,
+        // A PPS can only be read once the SPS it refers to is known
+        let picture_parameter_sets = pps_nal_units
+            .iter()
+            .map(|nal_unit| pps::PictureParameterSet::parse(nal_unit, &sequence_parameter_sets))
+            .collect::<Result<Vec<_>>>()?;
+
+        let first_sps = sequence_parameter_sets
+            .first()
+            .ok_or_else(|| anyhow!("No sequence parameter set before the first picture"))?;
+
+        video_spec.max_sps_count = sequence_parameter_sets.len() as u32;
+        video_spec.max_pps_count = picture_parameter_sets.len() as u32;
+
+        // Annex B streams carry no container level size, fall back to the cropped SPS size
+        let display_extent = first_sps.display_rect().extent;
+        let (width, height) = video_source
+            .coded_extent()
+            .unwrap_or((display_extent.width, display_extent.height));
+
+        video_spec.width = width;
+        video_spec.height = height;
+
+        let colour_params = colour::ColourParams::from_sps(first_sps);
+        let ycbcr_params = presenter::YcbcrParams::from_sps(first_sps, &colour_params);
+        let display_geometry = presenter::DisplayGeometry::from_sps(first_sps);
+
+        let mut base = ExampleBase::new(video_spec.width, video_spec.height)?;
+
+        let mut decoder = decoder::Decoder::new(
+            &base,
+            &video_spec,
+            video_source,
+            first_access_unit,
+            sequence_parameter_sets,
+            picture_parameter_sets,
         )?;
 
-        let mut video_session_memory =
-            vec![vk::DeviceMemory::default(); video_session_memory_requirements_count];
-        //let mut video_session_bind_memory   = vec![vk::BindVideoSessionMemoryInfoKHR::default(); video_session_memory_requirements_count];
-        let mut video_session_bind_memory = Vec::new();
-
-        // TODO single allocation + offset
-        for i in 0..video_session_memory_requirements_count {
-            let memory_type_index = vk_find_bit_index(
-                video_session_memory_requirements[i]
-                    .memory_requirements
-                    .memory_type_bits,
-            );
-
-            let video_session_memory_allocate_info = vk::MemoryAllocateInfo {
-                allocation_size: video_session_memory_requirements[i]
-                    .memory_requirements
-                    .size,
-                memory_type_index: memory_type_index,
-                ..Default::default()
-            };
-
-            video_session_memory[i] = base
-                .device
-                .allocate_memory(&video_session_memory_allocate_info, None)
-                .unwrap();
-
-            let video_session_bind_memory_info = vk::BindVideoSessionMemoryInfoKHR::default()
-                .memory_bind_index(video_session_memory_requirements[i].memory_bind_index)
-                .memory_size(
-                    video_session_memory_requirements[i]
-                        .memory_requirements
-                        .size,
-                )
-                .memory(video_session_memory[i])
-                .memory_offset(0)
-                .memory_size(
-                    video_session_memory_requirements[i]
-                        .memory_requirements
-                        .size,
-                );
-
-            video_session_bind_memory.push(video_session_bind_memory_info);
-        }
-
-        video_queue_loader
-            .bind_video_session_memory(video_session, &mut video_session_bind_memory)?;
-
-        // Video session parameters
-
-        let video_decode_session_paramteres_add_info =
-            vk::VideoDecodeH264SessionParametersAddInfoKHR::default();
-
-        let mut video_decode_session_paramteres_create_info =
-            vk::VideoDecodeH264SessionParametersCreateInfoKHR::default()
-                .parameters_add_info(&video_decode_session_paramteres_add_info);
-
-        let video_session_parameters_info = vk::VideoSessionParametersCreateInfoKHR::default()
-            .push_next(&mut video_decode_session_paramteres_create_info)
-            .video_session(video_session);
-
-        let video_session_paramaters = video_queue_loader
-            .create_video_session_parameters(&video_session_parameters_info, None)?;
-
-        // Record and submit
-        record_submit_commandbuffer(
-            &base.device,
-            base.decode_command_buffer,
-            base.draw_commands_reuse_fence,
-            base.present_queue,
-            &[vk::PipelineStageFlags::BOTTOM_OF_PIPE],
-            &[base.present_complete_semaphore],
-            &[base.rendering_complete_semaphore],
-            |device, decode_command_buffer| {
-
-                let begin_info =
-                vk::VideoBeginCodingInfoKHR::default().video_session(video_session)
-                .video_session_parameters(video_session_paramaters);
-
-                video_queue_loader.cmd_begin_video_coding(decode_command_buffer, &begin_info);
-
-                let decode_output_picture_resource = vk::VideoPictureResourceInfoKHR {
-                    base_array_layer: 0,
-                    image_view_binding: dst_image_view,
-                    ..Default::default()
-                };
-
-                let decode_info = vk::VideoDecodeInfoKHR {
-                    src_buffer: bitstream_buffer,
-                    src_buffer_offset: 48,
-                    src_buffer_range: 90,
-                    dst_picture_resource: decode_output_picture_resource,
-                    reference_slot_count: 0,
-                    ..Default::default()
-                };
-
-                video_decode_queue_loader.cmd_decode_video(decode_command_buffer, &decode_info);
-
-                video_queue_loader.cmd_end_video_coding(
-                    decode_command_buffer,
-                    &vk::VideoEndCodingInfoKHR::default(),
-                );
-            },
-        );
-
         // Render pass
 
         let renderpass_attachments = [
@@ -642,23 +143,26 @@ fn main() -> Result<()> {
             .create_render_pass(&renderpass_create_info, None)
             .unwrap();
 
-        let framebuffers: Vec<vk::Framebuffer> = base
-            .present_image_views
-            .iter()
-            .map(|&present_image_view| {
-                let framebuffer_attachments = [present_image_view, base.depth_image_view];
-                let frame_buffer_create_info = vk::FramebufferCreateInfo::default()
-                    .render_pass(renderpass)
-                    .attachments(&framebuffer_attachments)
-                    .width(base.surface_resolution.width)
-                    .height(base.surface_resolution.height)
-                    .layers(1);
-
-                base.device
-                    .create_framebuffer(&frame_buffer_create_info, None)
-                    .unwrap()
-            })
-            .collect();
+        // Created again along with the swapchain
+        let create_framebuffers = |base: &ExampleBase| -> Vec<vk::Framebuffer> {
+            base.present_image_views
+                .iter()
+                .map(|&present_image_view| {
+                    let framebuffer_attachments = [present_image_view, base.depth_image_view];
+                    let frame_buffer_create_info = vk::FramebufferCreateInfo::default()
+                        .render_pass(renderpass)
+                        .attachments(&framebuffer_attachments)
+                        .width(base.surface_resolution.width)
+                        .height(base.surface_resolution.height)
+                        .layers(1);
+
+                    base.device
+                        .create_framebuffer(&frame_buffer_create_info, None)
+                        .unwrap()
+                })
+                .collect()
+        };
+        let mut framebuffers = create_framebuffers(&base);
         let index_buffer_data = [0u32, 1, 2, 2, 3, 0];
         let index_buffer_info = vk::BufferCreateInfo {
             size: std::mem::size_of_val(&index_buffer_data) as u64,
@@ -703,22 +207,24 @@ fn main() -> Result<()> {
             .bind_buffer_memory(index_buffer, index_buffer_memory, 0)
             .unwrap();
 
+        // The quad fills the viewport with the cropped part of the pictures
+        let (crop_min, crop_max) = display_geometry.crop_coordinates(decoder.output_extent());
         let vertices = [
             Vertex {
                 pos: [-1.0, -1.0, 0.0, 1.0],
-                uv: [0.0, 0.0],
+                uv: [crop_min[0], crop_min[1]],
             },
             Vertex {
                 pos: [-1.0, 1.0, 0.0, 1.0],
-                uv: [0.0, 1.0],
+                uv: [crop_min[0], crop_max[1]],
             },
             Vertex {
                 pos: [1.0, 1.0, 0.0, 1.0],
-                uv: [1.0, 1.0],
+                uv: [crop_max[0], crop_max[1]],
             },
             Vertex {
                 pos: [1.0, -1.0, 0.0, 1.0],
-                uv: [1.0, 0.0],
+                uv: [crop_max[0], crop_min[1]],
             },
         ];
         let vertex_input_buffer_info = vk::BufferCreateInfo {
@@ -771,12 +277,8 @@ fn main() -> Result<()> {
             .bind_buffer_memory(vertex_input_buffer, vertex_input_buffer_memory, 0)
             .unwrap();
 
-        let uniform_color_buffer_data = Vector3 {
-            x: 1.0,
-            y: 1.0,
-            z: 1.0,
-            _pad: 0.0,
-        };
+        let uniform_color_buffer_data =
+            colour::ColourUniform::new(&colour_params, base.surface_format.format);
         let uniform_color_buffer_info = vk::BufferCreateInfo {
             size: std::mem::size_of_val(&uniform_color_buffer_data) as u64,
             usage: vk::BufferUsageFlags::UNIFORM_BUFFER,
@@ -817,7 +319,7 @@ fn main() -> Result<()> {
             .unwrap();
         let mut uniform_aligned_slice = Align::new(
             uniform_ptr,
-            align_of::<Vector3>() as u64,
+            align_of::<colour::ColourUniform>() as u64,
             uniform_color_buffer_memory_req.size,
         );
         uniform_aligned_slice.copy_from_slice(&[uniform_color_buffer_data]);
@@ -826,280 +328,22 @@ fn main() -> Result<()> {
             .bind_buffer_memory(uniform_color_buffer, uniform_color_buffer_memory, 0)
             .unwrap();
 
-        let image = image::load_from_memory(include_bytes!("../assets/rust.png"))
-            .unwrap()
-            .to_rgba8();
-        let (width, height) = image.dimensions();
-        let image_extent = vk::Extent2D { width, height };
-        let image_data = image.into_raw();
-        let image_buffer_info = vk::BufferCreateInfo {
-            size: (std::mem::size_of::<u8>() * image_data.len()) as u64,
-            usage: vk::BufferUsageFlags::TRANSFER_SRC,
-            sharing_mode: vk::SharingMode::EXCLUSIVE,
-            ..Default::default()
-        };
-        let image_buffer = base.device.create_buffer(&image_buffer_info, None).unwrap();
-        let image_buffer_memory_req = base.device.get_buffer_memory_requirements(image_buffer);
-        let image_buffer_memory_index = find_memorytype_index(
-            &image_buffer_memory_req,
-            &base.device_memory_properties,
-            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-        )
-        .expect("Unable to find suitable memorytype for the image buffer.");
-
-        let image_buffer_allocate_info = vk::MemoryAllocateInfo {
-            allocation_size: image_buffer_memory_req.size,
-            memory_type_index: image_buffer_memory_index,
-            ..Default::default()
-        };
-        let image_buffer_memory = base
-            .device
-            .allocate_memory(&image_buffer_allocate_info, None)
-            .unwrap();
-        let image_ptr = base
-            .device
-            .map_memory(
-                image_buffer_memory,
-                0,
-                image_buffer_memory_req.size,
-                vk::MemoryMapFlags::empty(),
-            )
-            .unwrap();
-        let mut image_slice = Align::new(
-            image_ptr,
-            std::mem::align_of::<u8>() as u64,
-            image_buffer_memory_req.size,
-        );
-        image_slice.copy_from_slice(&image_data);
-        base.device.unmap_memory(image_buffer_memory);
-        base.device
-            .bind_buffer_memory(image_buffer, image_buffer_memory, 0)
-            .unwrap();
-
-        let texture_create_info = vk::ImageCreateInfo {
-            image_type: vk::ImageType::TYPE_2D,
-            format: vk::Format::R8G8B8A8_UNORM,
-            extent: image_extent.into(),
-            mip_levels: 1,
-            array_layers: 1,
-            samples: vk::SampleCountFlags::TYPE_1,
-            tiling: vk::ImageTiling::OPTIMAL,
-            usage: vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
-            sharing_mode: vk::SharingMode::EXCLUSIVE,
-            ..Default::default()
-        };
-        let texture_image = base
-            .device
-            .create_image(&texture_create_info, None)
-            .unwrap();
-        let texture_memory_req = base.device.get_image_memory_requirements(texture_image);
-        let texture_memory_index = find_memorytype_index(
-            &texture_memory_req,
-            &base.device_memory_properties,
-            vk::MemoryPropertyFlags::DEVICE_LOCAL,
-        )
-        .expect("Unable to find suitable memory index for depth image.");
-
-        let texture_allocate_info = vk::MemoryAllocateInfo {
-            allocation_size: texture_memory_req.size,
-            memory_type_index: texture_memory_index,
-            ..Default::default()
-        };
-        let texture_memory = base
-            .device
-            .allocate_memory(&texture_allocate_info, None)
-            .unwrap();
-        base.device
-            .bind_image_memory(texture_image, texture_memory, 0)
-            .expect("Unable to bind depth image memory");
-
-        record_submit_commandbuffer(
-            &base.device,
-            base.setup_command_buffer,
-            base.setup_commands_reuse_fence,
-            base.present_queue,
-            &[],
-            &[],
-            &[],
-            |device, texture_command_buffer| {
-                let texture_barrier = vk::ImageMemoryBarrier {
-                    dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-                    new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    image: texture_image,
-                    subresource_range: vk::ImageSubresourceRange {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        level_count: 1,
-                        layer_count: 1,
-                        ..Default::default()
-                    },
-                    ..Default::default()
-                };
-                device.cmd_pipeline_barrier(
-                    texture_command_buffer,
-                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                    vk::PipelineStageFlags::TRANSFER,
-                    vk::DependencyFlags::empty(),
-                    &[],
-                    &[],
-                    &[texture_barrier],
-                );
-                let buffer_copy_regions = vk::BufferImageCopy::default()
-                    .image_subresource(
-                        vk::ImageSubresourceLayers::default()
-                            .aspect_mask(vk::ImageAspectFlags::COLOR)
-                            .layer_count(1),
-                    )
-                    .image_extent(image_extent.into());
-
-                device.cmd_copy_buffer_to_image(
-                    texture_command_buffer,
-                    image_buffer,
-                    texture_image,
-                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    &[buffer_copy_regions],
-                );
-                let texture_barrier_end = vk::ImageMemoryBarrier {
-                    src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-                    dst_access_mask: vk::AccessFlags::SHADER_READ,
-                    old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-                    image: texture_image,
-                    subresource_range: vk::ImageSubresourceRange {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        level_count: 1,
-                        layer_count: 1,
-                        ..Default::default()
-                    },
-                    ..Default::default()
-                };
-                device.cmd_pipeline_barrier(
-                    texture_command_buffer,
-                    vk::PipelineStageFlags::TRANSFER,
-                    vk::PipelineStageFlags::FRAGMENT_SHADER,
-                    vk::DependencyFlags::empty(),
-                    &[],
-                    &[],
-                    &[texture_barrier_end],
-                );
-            },
-        );
-
-        let sampler_info = vk::SamplerCreateInfo {
-            mag_filter: vk::Filter::LINEAR,
-            min_filter: vk::Filter::LINEAR,
-            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
-            address_mode_u: vk::SamplerAddressMode::MIRRORED_REPEAT,
-            address_mode_v: vk::SamplerAddressMode::MIRRORED_REPEAT,
-            address_mode_w: vk::SamplerAddressMode::MIRRORED_REPEAT,
-            max_anisotropy: 1.0,
-            border_color: vk::BorderColor::FLOAT_OPAQUE_WHITE,
-            compare_op: vk::CompareOp::NEVER,
-            ..Default::default()
-        };
-
-        let sampler = base.device.create_sampler(&sampler_info, None).unwrap();
-
-        let tex_image_view_info = vk::ImageViewCreateInfo {
-            view_type: vk::ImageViewType::TYPE_2D,
-            format: texture_create_info.format,
-            components: vk::ComponentMapping {
-                r: vk::ComponentSwizzle::R,
-                g: vk::ComponentSwizzle::G,
-                b: vk::ComponentSwizzle::B,
-                a: vk::ComponentSwizzle::A,
-            },
-            subresource_range: vk::ImageSubresourceRange {
-                aspect_mask: vk::ImageAspectFlags::COLOR,
-                level_count: 1,
-                layer_count: 1,
-                ..Default::default()
-            },
-            image: texture_image,
-            ..Default::default()
-        };
-        let tex_image_view = base
-            .device
-            .create_image_view(&tex_image_view_info, None)
-            .unwrap();
-        let descriptor_sizes = [
-            vk::DescriptorPoolSize {
-                ty: vk::DescriptorType::UNIFORM_BUFFER,
-                descriptor_count: 1,
-            },
-            vk::DescriptorPoolSize {
-                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                descriptor_count: 1,
-            },
-        ];
-        let descriptor_pool_info = vk::DescriptorPoolCreateInfo::default()
-            .pool_sizes(&descriptor_sizes)
-            .max_sets(1);
-
-        let descriptor_pool = base
-            .device
-            .create_descriptor_pool(&descriptor_pool_info, None)
-            .unwrap();
-        let desc_layout_bindings = [
-            vk::DescriptorSetLayoutBinding {
-                descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
-                descriptor_count: 1,
-                stage_flags: vk::ShaderStageFlags::FRAGMENT,
-                ..Default::default()
-            },
-            vk::DescriptorSetLayoutBinding {
-                binding: 1,
-                descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                descriptor_count: 1,
-                stage_flags: vk::ShaderStageFlags::FRAGMENT,
-                ..Default::default()
-            },
-        ];
-        let descriptor_info =
-            vk::DescriptorSetLayoutCreateInfo::default().bindings(&desc_layout_bindings);
-
-        let desc_set_layouts = [base
-            .device
-            .create_descriptor_set_layout(&descriptor_info, None)
-            .unwrap()];
-
-        let desc_alloc_info = vk::DescriptorSetAllocateInfo::default()
-            .descriptor_pool(descriptor_pool)
-            .set_layouts(&desc_set_layouts);
-        let descriptor_sets = base
-            .device
-            .allocate_descriptor_sets(&desc_alloc_info)
-            .unwrap();
-
         let uniform_color_buffer_descriptor = vk::DescriptorBufferInfo {
             buffer: uniform_color_buffer,
             offset: 0,
             range: mem::size_of_val(&uniform_color_buffer_data) as u64,
         };
 
-        let tex_descriptor = vk::DescriptorImageInfo {
-            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-            image_view: tex_image_view,
-            sampler,
-        };
-
-        let write_desc_sets = [
-            vk::WriteDescriptorSet {
-                dst_set: descriptor_sets[0],
-                descriptor_count: 1,
-                descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
-                p_buffer_info: &uniform_color_buffer_descriptor,
-                ..Default::default()
-            },
-            vk::WriteDescriptorSet {
-                dst_set: descriptor_sets[0],
-                dst_binding: 1,
-                descriptor_count: 1,
-                descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                p_image_info: &tex_descriptor,
-                ..Default::default()
-            },
-        ];
-        base.device.update_descriptor_sets(&write_desc_sets, &[]);
+        let mut presenter = presenter::Presenter::new(
+            &base,
+            &decoder,
+            &ycbcr_params,
+            uniform_color_buffer_descriptor,
+            env::var(presenter::PRESENTER_ENV)
+                .ok()
+                .map(|kind| kind.parse())
+                .transpose()?,
+        )?;
 
         let mut vertex_spv_file = Cursor::new(&include_bytes!("../shader/texture/vert.spv")[..]);
         let mut frag_spv_file = Cursor::new(&include_bytes!("../shader/texture/frag.spv")[..]);
@@ -1122,6 +366,7 @@ fn main() -> Result<()> {
             .create_shader_module(&frag_shader_info, None)
             .expect("Fragment shader module error");
 
+        let desc_set_layouts = [presenter.descriptor_set_layout()];
         let layout_create_info =
             vk::PipelineLayoutCreateInfo::default().set_layouts(&desc_set_layouts);
 
@@ -1250,16 +495,68 @@ fn main() -> Result<()> {
 
         let graphic_pipeline = graphics_pipelines[0];
 
-        base.render_loop(|| {
-            let (present_index, _) = base
-                .swapchain_loader
-                .acquire_next_image(
-                    base.swapchain,
-                    std::u64::MAX,
-                    base.present_complete_semaphore,
-                    vk::Fence::null(),
-                )
-                .unwrap();
+        let timescale = decoder.timescale() as f64;
+
+        // One value per draw, a decode overwriting a picture waits for the draws reading it
+        let mut graphics_timeline = sync::Timeline::new(&base.device)?;
+        let mut current_picture: Option<decoder::DecodedPicture> = None;
+
+        let rendered = base.render_loop(|base, recreated| {
+            if recreated {
+                for &framebuffer in framebuffers.iter() {
+                    base.device.destroy_framebuffer(framebuffer, None);
+                }
+                framebuffers = create_framebuffers(base);
+            }
+
+            let (present_index, suboptimal) = match base.swapchain_loader.acquire_next_image(
+                base.swapchain,
+                std::u64::MAX,
+                base.present_complete_semaphore,
+                vk::Fence::null(),
+            ) {
+                Ok(acquired) => acquired,
+                Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => return Ok(true),
+                Err(result) => {
+                    return Err(anyhow!("Acquiring a swapchain image failed: {:?}", result))
+                }
+            };
+
+            let mut acquire_barriers = Vec::new();
+            if let Some(picture) = decoder.next_picture(graphics_timeline.last_point())? {
+                acquire_barriers.extend(picture.acquire_barrier());
+                base.window.set_title(&format!(
+                    "Ash - Example {:.2}s",
+                    picture.pts as f64 / timescale
+                ));
+                current_picture = Some(picture);
+            }
+            let descriptor_set = current_picture
+                .as_ref()
+                .map(|picture| presenter.descriptor_set(picture))
+                .transpose()?;
+
+            let mut waits = vec![sync::SemaphoreSubmit::binary(
+                base.present_complete_semaphore,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            )];
+            if let Some(picture) = &current_picture {
+                waits.push(
+                    decoder
+                        .sampling_wait_point(picture)
+                        .wait(vk::PipelineStageFlags::ALL_COMMANDS),
+                );
+            }
+            let signals = [
+                sync::SemaphoreSubmit::binary(
+                    base.rendering_complete_semaphore,
+                    vk::PipelineStageFlags::ALL_COMMANDS,
+                ),
+                graphics_timeline.next_point().signal(),
+            ];
+
+            let viewports = [display_geometry.viewport(base.surface_resolution)];
+            let scissors = [base.surface_resolution.into()];
 
             let clear_values = [
                 vk::ClearValue {
@@ -1281,60 +578,77 @@ fn main() -> Result<()> {
                 .render_area(base.surface_resolution.into())
                 .clear_values(&clear_values);
 
-            record_submit_commandbuffer(
+            sync::record_submit_timeline(
                 &base.device,
                 base.draw_command_buffer,
                 base.draw_commands_reuse_fence,
                 base.present_queue,
-                &[vk::PipelineStageFlags::BOTTOM_OF_PIPE],
-                &[base.present_complete_semaphore],
-                &[base.rendering_complete_semaphore],
+                &waits,
+                &signals,
                 |device, draw_command_buffer| {
+                    if !acquire_barriers.is_empty() {
+                        device.cmd_pipeline_barrier(
+                            draw_command_buffer,
+                            vk::PipelineStageFlags::ALL_COMMANDS,
+                            presenter.sampling_stage(),
+                            vk::DependencyFlags::empty(),
+                            &[],
+                            &[],
+                            &acquire_barriers,
+                        );
+                    }
+                    if let Some(picture) = &current_picture {
+                        presenter.cmd_begin_sampling(draw_command_buffer, picture);
+                    }
                     device.cmd_begin_render_pass(
                         draw_command_buffer,
                         &render_pass_begin_info,
                         vk::SubpassContents::INLINE,
                     );
-                    device.cmd_bind_descriptor_sets(
-                        draw_command_buffer,
-                        vk::PipelineBindPoint::GRAPHICS,
-                        pipeline_layout,
-                        0,
-                        &descriptor_sets[..],
-                        &[],
-                    );
-                    device.cmd_bind_pipeline(
-                        draw_command_buffer,
-                        vk::PipelineBindPoint::GRAPHICS,
-                        graphic_pipeline,
-                    );
-                    device.cmd_set_viewport(draw_command_buffer, 0, &viewports);
-                    device.cmd_set_scissor(draw_command_buffer, 0, &scissors);
-                    device.cmd_bind_vertex_buffers(
-                        draw_command_buffer,
-                        0,
-                        &[vertex_input_buffer],
-                        &[0],
-                    );
-                    device.cmd_bind_index_buffer(
-                        draw_command_buffer,
-                        index_buffer,
-                        0,
-                        vk::IndexType::UINT32,
-                    );
-                    device.cmd_draw_indexed(
-                        draw_command_buffer,
-                        index_buffer_data.len() as u32,
-                        1,
-                        0,
-                        0,
-                        1,
-                    );
-                    // Or draw without the index buffer
-                    // device.cmd_draw(draw_command_buffer, 3, 1, 0, 0);
+                    // Nothing is drawn over the clear color before the first picture
+                    if let Some(descriptor_set) = descriptor_set {
+                        device.cmd_bind_descriptor_sets(
+                            draw_command_buffer,
+                            vk::PipelineBindPoint::GRAPHICS,
+                            pipeline_layout,
+                            0,
+                            &[descriptor_set],
+                            &[],
+                        );
+                        device.cmd_bind_pipeline(
+                            draw_command_buffer,
+                            vk::PipelineBindPoint::GRAPHICS,
+                            graphic_pipeline,
+                        );
+                        device.cmd_set_viewport(draw_command_buffer, 0, &viewports);
+                        device.cmd_set_scissor(draw_command_buffer, 0, &scissors);
+                        device.cmd_bind_vertex_buffers(
+                            draw_command_buffer,
+                            0,
+                            &[vertex_input_buffer],
+                            &[0],
+                        );
+                        device.cmd_bind_index_buffer(
+                            draw_command_buffer,
+                            index_buffer,
+                            0,
+                            vk::IndexType::UINT32,
+                        );
+                        device.cmd_draw_indexed(
+                            draw_command_buffer,
+                            index_buffer_data.len() as u32,
+                            1,
+                            0,
+                            0,
+                            1,
+                        );
+                    }
                     device.cmd_end_render_pass(draw_command_buffer);
+                    if let Some(picture) = &current_picture {
+                        presenter.cmd_end_sampling(draw_command_buffer, picture);
+                    }
                 },
-            );
+            )?;
 
             //let mut present_info_err = mem::zeroed();
             let present_info = vk::PresentInfoKHR {
@@ -1345,9 +659,14 @@ fn main() -> Result<()> {
                 p_image_indices: &present_index,
                 ..Default::default()
             };
-            base.swapchain_loader
+            match base
+                .swapchain_loader
                 .queue_present(base.present_queue, &present_info)
-                .unwrap();
+            {
+                Ok(present_suboptimal) => Ok(suboptimal || present_suboptimal),
+                Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(true),
+                Err(result) => Err(anyhow!("Presenting failed: {:?}", result)),
+            }
         });
         base.device.device_wait_idle().unwrap();
 
@@ -1359,35 +678,20 @@ fn main() -> Result<()> {
             .destroy_shader_module(vertex_shader_module, None);
         base.device
             .destroy_shader_module(fragment_shader_module, None);
-        base.device.destroy_buffer(bitstream_buffer, None);
-        base.device.free_memory(dpb_memory, None);
-        base.device.destroy_image(dpb_image, None);
-        base.device.destroy_image_view(dpb_image_view, None);
-        base.device.free_memory(dst_memory, None);
-        base.device.destroy_image(dst_image, None);
-        base.device.destroy_image_view(dst_image_view, None);
-        base.device.free_memory(image_buffer_memory, None);
-        base.device.destroy_buffer(image_buffer, None);
-        base.device.free_memory(texture_memory, None);
-        base.device.destroy_image_view(tex_image_view, None);
-        base.device.destroy_image(texture_image, None);
+        presenter.destroy();
+        decoder.destroy();
+        graphics_timeline.destroy(&base.device);
         base.device.free_memory(index_buffer_memory, None);
         base.device.destroy_buffer(index_buffer, None);
         base.device.free_memory(uniform_color_buffer_memory, None);
         base.device.destroy_buffer(uniform_color_buffer, None);
         base.device.free_memory(vertex_input_buffer_memory, None);
         base.device.destroy_buffer(vertex_input_buffer, None);
-        for &descriptor_set_layout in desc_set_layouts.iter() {
-            base.device
-                .destroy_descriptor_set_layout(descriptor_set_layout, None);
-        }
-        base.device.destroy_descriptor_pool(descriptor_pool, None);
-        base.device.destroy_sampler(sampler, None);
         for framebuffer in framebuffers {
             base.device.destroy_framebuffer(framebuffer, None);
         }
         base.device.destroy_render_pass(renderpass, None);
-    }
 
-    Ok(())
+        rendered
+    }
 }
@@ -0,0 +1,621 @@
+use anyhow::{anyhow, Result};
+
+/// A single sample of an MP4 track, for H.264 tracks this is one access unit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mp4Sample {
+    /// Absolute byte offset of the sample in the file.
+    pub file_offset: u64,
+    pub size: u32,
+    /// Decode timestamp in track timescale units.
+    pub dts: u64,
+    /// Presentation timestamp in track timescale units, `dts` plus the ctts composition offset.
+    pub pts: i64,
+    pub is_keyframe: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SampleToChunkRun {
+    /// 1-based index of the first chunk this run applies to.
+    pub first_chunk: u32,
+    pub samples_per_chunk: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeToSampleRun {
+    pub sample_count: u32,
+    pub sample_delta: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompositionOffsetRun {
+    pub sample_count: u32,
+    /// Version 0 ctts stores unsigned offsets, version 1 signed ones, both fit in an i64.
+    pub sample_offset: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SampleSizes {
+    /// Every sample has the same size, `stsz.sample_size != 0`.
+    Constant(u32),
+    PerSample(Vec<u32>),
+}
+
+/// Owned copy of the sample table boxes (stco/co64, stsc, stsz, stss, stts, ctts) of a single track.
+#[derive(Clone, Debug)]
+pub struct SampleTable {
+    pub timescale: u64,
+    pub chunk_offsets: Vec<u64>,
+    pub sample_to_chunk: Vec<SampleToChunkRun>,
+    pub sample_sizes: SampleSizes,
+    /// 1-based sample numbers of sync samples, `None` when the track has no stss and every sample is a sync sample.
+    pub sync_samples: Option<Vec<u32>>,
+    pub time_to_sample: Vec<TimeToSampleRun>,
+    /// `None` when the track has no ctts and presentation order equals decode order.
+    pub composition_offsets: Option<Vec<CompositionOffsetRun>>,
+}
+
+impl SampleTable {
+    pub fn from_track(track: &mp4parse::Track) -> Result<Self> {
+        let timescale = track
+            .timescale
+            .map(|timescale| timescale.0)
+            .ok_or_else(|| anyhow!("Track {} has no timescale", track.id))?;
+        let stco = track
+            .stco
+            .as_ref()
+            .ok_or_else(|| anyhow!("Track {} has no stco/co64 box", track.id))?;
+        let stsc = track
+            .stsc
+            .as_ref()
+            .ok_or_else(|| anyhow!("Track {} has no stsc box", track.id))?;
+        let stsz = track
+            .stsz
+            .as_ref()
+            .ok_or_else(|| anyhow!("Track {} has no stsz box", track.id))?;
+        let stts = track
+            .stts
+            .as_ref()
+            .ok_or_else(|| anyhow!("Track {} has no stts box", track.id))?;
+
+        let sample_sizes = if stsz.sample_size != 0 {
+            SampleSizes::Constant(stsz.sample_size)
+        } else {
+            SampleSizes::PerSample(stsz.sample_sizes.to_vec())
+        };
+
+        let table = SampleTable {
+            timescale,
+            chunk_offsets: stco.offsets.to_vec(),
+            sample_to_chunk: stsc
+                .samples
+                .iter()
+                .map(|run| SampleToChunkRun {
+                    first_chunk: run.first_chunk,
+                    samples_per_chunk: run.samples_per_chunk,
+                })
+                .collect(),
+            sample_sizes,
+            sync_samples: track.stss.as_ref().map(|stss| stss.samples.to_vec()),
+            time_to_sample: stts
+                .samples
+                .iter()
+                .map(|run| TimeToSampleRun {
+                    sample_count: run.sample_count,
+                    sample_delta: run.sample_delta,
+                })
+                .collect(),
+            composition_offsets: track.ctts.as_ref().map(|ctts| {
+                ctts.samples
+                    .iter()
+                    .map(|run| CompositionOffsetRun {
+                        sample_count: run.sample_count,
+                        sample_offset: match run.time_offset {
+                            mp4parse::TimeOffsetVersion::Version0(offset) => offset as i64,
+                            mp4parse::TimeOffsetVersion::Version1(offset) => offset as i64,
+                        },
+                    })
+                    .collect()
+            }),
+        };
+
+        table.validate()?;
+
+        Ok(table)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.timescale == 0 {
+            return Err(anyhow!("Sample table timescale is zero"));
+        }
+
+        match self.sample_to_chunk.first() {
+            Some(run) if run.first_chunk != 1 => {
+                return Err(anyhow!(
+                    "stsc must start at chunk 1, found {}",
+                    run.first_chunk
+                ))
+            }
+            None if self.sample_count() > 0 => return Err(anyhow!("stsc box is empty")),
+            _ => {}
+        }
+
+        if self
+            .sample_to_chunk
+            .windows(2)
+            .any(|runs| runs[0].first_chunk >= runs[1].first_chunk)
+        {
+            return Err(anyhow!("stsc first_chunk entries are not increasing"));
+        }
+
+        if let SampleSizes::PerSample(sizes) = &self.sample_sizes {
+            let stts_count = self.time_to_sample_count();
+            if sizes.len() as u64 != stts_count {
+                return Err(anyhow!(
+                    "stsz describes {} samples but stts describes {}",
+                    sizes.len(),
+                    stts_count
+                ));
+            }
+        }
+
+        if let Some(composition_offsets) = &self.composition_offsets {
+            let ctts_count: u64 = composition_offsets
+                .iter()
+                .map(|run| run.sample_count as u64)
+                .sum();
+            if ctts_count != self.sample_count() as u64 {
+                return Err(anyhow!(
+                    "ctts describes {} samples but the track has {}",
+                    ctts_count,
+                    self.sample_count()
+                ));
+            }
+        }
+
+        if let Some(sync_samples) = &self.sync_samples {
+            if sync_samples.windows(2).any(|s| s[0] >= s[1]) {
+                return Err(anyhow!("stss sample numbers are not increasing"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn time_to_sample_count(&self) -> u64 {
+        self.time_to_sample
+            .iter()
+            .map(|run| run.sample_count as u64)
+            .sum()
+    }
+
+    pub fn sample_count(&self) -> usize {
+        match &self.sample_sizes {
+            SampleSizes::Constant(_) => self.time_to_sample_count() as usize,
+            SampleSizes::PerSample(sizes) => sizes.len(),
+        }
+    }
+
+    /// Walks the chunk and timing tables yielding every sample in decode order.
+    pub fn samples(&self) -> Samples<'_> {
+        Samples {
+            table: self,
+            sample_index: 0,
+            sample_count: self.sample_count(),
+            chunk_index: 0,
+            samples_left_in_chunk: 0,
+            next_offset: 0,
+            stsc_index: 0,
+            stts_index: 0,
+            samples_left_in_stts_run: self
+                .time_to_sample
+                .first()
+                .map_or(0, |run| run.sample_count),
+            dts: 0,
+            stss_index: 0,
+            ctts_index: 0,
+            samples_left_in_ctts_run: self
+                .composition_offsets
+                .as_ref()
+                .and_then(|runs| runs.first())
+                .map_or(0, |run| run.sample_count),
+            failed: false,
+        }
+    }
+}
+
+pub struct Samples<'a> {
+    table: &'a SampleTable,
+    sample_index: usize,
+    sample_count: usize,
+    // 0-based index of the chunk the next sample belongs to, only valid once samples_left_in_chunk > 0
+    chunk_index: usize,
+    samples_left_in_chunk: u32,
+    next_offset: u64,
+    stsc_index: usize,
+    stts_index: usize,
+    samples_left_in_stts_run: u32,
+    dts: u64,
+    stss_index: usize,
+    ctts_index: usize,
+    samples_left_in_ctts_run: u32,
+    failed: bool,
+}
+
+impl<'a> Samples<'a> {
+    fn enter_next_chunk(&mut self) -> Result<()> {
+        let runs = &self.table.sample_to_chunk;
+
+        // chunk_index is the index of the chunk to enter, it is advanced once the chunk is exhausted
+        loop {
+            let chunk_number = self.chunk_index as u32 + 1;
+
+            while self.stsc_index + 1 < runs.len()
+                && runs[self.stsc_index + 1].first_chunk <= chunk_number
+            {
+                self.stsc_index += 1;
+            }
+
+            let offset = *self
+                .table
+                .chunk_offsets
+                .get(self.chunk_index)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Sample {} lies past the last of {} chunks",
+                        self.sample_index + 1,
+                        self.table.chunk_offsets.len()
+                    )
+                })?;
+
+            let samples_per_chunk = runs[self.stsc_index].samples_per_chunk;
+
+            if samples_per_chunk == 0 {
+                self.chunk_index += 1;
+                continue;
+            }
+
+            self.samples_left_in_chunk = samples_per_chunk;
+            self.next_offset = offset;
+
+            return Ok(());
+        }
+    }
+
+    fn next_delta(&mut self) -> Result<u32> {
+        let runs = &self.table.time_to_sample;
+
+        while self.samples_left_in_stts_run == 0 {
+            self.stts_index += 1;
+            self.samples_left_in_stts_run = runs
+                .get(self.stts_index)
+                .ok_or_else(|| anyhow!("stts ends before sample {}", self.sample_index + 1))?
+                .sample_count;
+        }
+
+        self.samples_left_in_stts_run -= 1;
+
+        Ok(runs[self.stts_index].sample_delta)
+    }
+
+    fn next_composition_offset(&mut self) -> Result<i64> {
+        let runs = match &self.table.composition_offsets {
+            Some(runs) => runs,
+            None => return Ok(0),
+        };
+
+        while self.samples_left_in_ctts_run == 0 {
+            self.ctts_index += 1;
+            self.samples_left_in_ctts_run = runs
+                .get(self.ctts_index)
+                .ok_or_else(|| anyhow!("ctts ends before sample {}", self.sample_index + 1))?
+                .sample_count;
+        }
+
+        self.samples_left_in_ctts_run -= 1;
+
+        Ok(runs[self.ctts_index].sample_offset)
+    }
+
+    fn is_sync_sample(&mut self) -> bool {
+        let sync_samples = match &self.table.sync_samples {
+            Some(sync_samples) => sync_samples,
+            None => return true,
+        };
+
+        let sample_number = self.sample_index as u32 + 1;
+
+        while self.stss_index < sync_samples.len() && sync_samples[self.stss_index] < sample_number
+        {
+            self.stss_index += 1;
+        }
+
+        sync_samples.get(self.stss_index) == Some(&sample_number)
+    }
+
+    fn next_sample(&mut self) -> Result<Mp4Sample> {
+        if self.samples_left_in_chunk == 0 {
+            if self.sample_index > 0 {
+                self.chunk_index += 1;
+            }
+            self.enter_next_chunk()?;
+        }
+
+        let size = match &self.table.sample_sizes {
+            SampleSizes::Constant(size) => *size,
+            SampleSizes::PerSample(sizes) => sizes[self.sample_index],
+        };
+
+        let sample = Mp4Sample {
+            file_offset: self.next_offset,
+            size,
+            dts: self.dts,
+            pts: self.dts as i64 + self.next_composition_offset()?,
+            is_keyframe: self.is_sync_sample(),
+        };
+
+        let delta = self.next_delta()?;
+
+        self.dts += delta as u64;
+        self.next_offset += size as u64;
+        self.samples_left_in_chunk -= 1;
+        self.sample_index += 1;
+
+        Ok(sample)
+    }
+}
+
+impl<'a> Iterator for Samples<'a> {
+    type Item = Result<Mp4Sample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.sample_index >= self.sample_count {
+            return None;
+        }
+
+        let sample = self.next_sample();
+        self.failed = sample.is_err();
+
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.sample_count - self.sample_index;
+        (0, Some(remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A track with a timescale of 1000, `sample_size` of 0 takes the sizes from `sample_sizes`.
+    fn track(
+        chunk_offsets: &[u64],
+        sample_to_chunk: &[(u32, u32)],
+        sample_size: u32,
+        sample_sizes: &[u32],
+        time_to_sample: &[(u32, u32)],
+    ) -> mp4parse::Track {
+        mp4parse::Track {
+            id: 1,
+            timescale: Some(mp4parse::TrackTimeScale(1000, 0)),
+            stco: Some(mp4parse::ChunkOffsetBox {
+                offsets: chunk_offsets.to_vec().into(),
+            }),
+            stsc: Some(mp4parse::SampleToChunkBox {
+                samples: sample_to_chunk
+                    .iter()
+                    .map(
+                        |&(first_chunk, samples_per_chunk)| mp4parse::SampleToChunk {
+                            first_chunk,
+                            samples_per_chunk,
+                            sample_description_index: 1,
+                        },
+                    )
+                    .collect::<Vec<_>>()
+                    .into(),
+            }),
+            stsz: Some(mp4parse::SampleSizeBox {
+                sample_size,
+                sample_sizes: sample_sizes.to_vec().into(),
+            }),
+            stts: Some(mp4parse::TimeToSampleBox {
+                samples: time_to_sample
+                    .iter()
+                    .map(|&(sample_count, sample_delta)| mp4parse::Sample {
+                        sample_count,
+                        sample_delta,
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn samples(track: &mp4parse::Track) -> Vec<Mp4Sample> {
+        SampleTable::from_track(track)
+            .unwrap()
+            .samples()
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn sample_to_chunk_runs() {
+        // Two samples in each of chunks 1 and 2, the last run covers chunks 3 and 4
+        let track = track(
+            &[100, 200, 300, 400],
+            &[(1, 2), (3, 1)],
+            0,
+            &[10, 11, 12, 13, 14, 15],
+            &[(6, 40)],
+        );
+
+        let samples = samples(&track);
+        let offsets: Vec<_> = samples.iter().map(|sample| sample.file_offset).collect();
+        let sizes: Vec<_> = samples.iter().map(|sample| sample.size).collect();
+        assert_eq!(offsets, [100, 110, 200, 212, 300, 400]);
+        assert_eq!(sizes, [10, 11, 12, 13, 14, 15]);
+
+        // Without stss or ctts every sample is a keyframe presented in decode order
+        for (i, sample) in samples.iter().enumerate() {
+            assert_eq!(sample.dts, 40 * i as u64);
+            assert_eq!(sample.pts, sample.dts as i64);
+            assert!(sample.is_keyframe);
+        }
+    }
+
+    #[test]
+    fn empty_chunks() {
+        let track = track(&[10, 20, 30], &[(1, 1), (2, 0), (3, 1)], 5, &[], &[(2, 1)]);
+
+        let offsets: Vec<_> = samples(&track)
+            .iter()
+            .map(|sample| sample.file_offset)
+            .collect();
+        assert_eq!(offsets, [10, 30]);
+    }
+
+    #[test]
+    fn constant_sample_size_and_time_to_sample_runs() {
+        let mut track = track(&[1000], &[(1, 5)], 50, &[], &[(2, 30), (1, 15), (2, 10)]);
+        track.stss = Some(mp4parse::SyncSampleBox {
+            samples: vec![1, 4].into(),
+        });
+
+        let samples = samples(&track);
+        assert_eq!(
+            samples,
+            [
+                (1000, 0, true),
+                (1050, 30, false),
+                (1100, 60, false),
+                (1150, 75, true),
+                (1200, 85, false),
+            ]
+            .map(|(file_offset, dts, is_keyframe)| Mp4Sample {
+                file_offset,
+                size: 50,
+                dts,
+                pts: dts as i64,
+                is_keyframe,
+            })
+        );
+    }
+
+    #[test]
+    fn samples_past_the_last_chunk() {
+        let track = track(&[0], &[(1, 1)], 10, &[], &[(3, 1)]);
+        let table = SampleTable::from_track(&track).unwrap();
+
+        let mut samples = table.samples();
+        assert!(samples.next().unwrap().is_ok());
+        assert!(samples.next().unwrap().is_err());
+        // The walk stops at the first error
+        assert!(samples.next().is_none());
+    }
+
+    fn with_ctts(
+        mut track: mp4parse::Track,
+        runs: &[(u32, mp4parse::TimeOffsetVersion)],
+    ) -> mp4parse::Track {
+        track.ctts = Some(mp4parse::CompositionOffsetBox {
+            samples: runs
+                .iter()
+                .map(|&(sample_count, time_offset)| mp4parse::TimeOffset {
+                    sample_count,
+                    time_offset,
+                })
+                .collect::<Vec<_>>()
+                .into(),
+        });
+        track
+    }
+
+    #[test]
+    fn composition_offsets() {
+        use mp4parse::TimeOffsetVersion::{Version0, Version1};
+
+        // I P B B in decode order, presented as I B B P
+        let ipbb = || track(&[0], &[(1, 4)], 10, &[], &[(4, 10)]);
+
+        let version_0 = with_ctts(
+            ipbb(),
+            &[(1, Version0(10)), (1, Version0(40)), (2, Version0(0))],
+        );
+        // Version 1 offsets are signed, shifting the first sample to a pts of 0
+        let version_1 = with_ctts(
+            ipbb(),
+            &[(1, Version1(0)), (1, Version1(30)), (2, Version1(-10))],
+        );
+
+        let cases = [(version_0, [10, 50, 20, 30]), (version_1, [0, 40, 10, 20])];
+        for (track, pts) in cases {
+            let timestamps: Vec<_> = samples(&track).iter().map(|sample| sample.pts).collect();
+            assert_eq!(timestamps, pts);
+        }
+
+        // Negative presentation timestamps are kept as they are
+        let negative = with_ctts(ipbb(), &[(4, Version1(-20))]);
+        let timestamps: Vec<_> = samples(&negative).iter().map(|sample| sample.pts).collect();
+        assert_eq!(timestamps, [-20, -10, 0, 10]);
+
+        // Large unsigned version 0 offsets do not wrap
+        let large = with_ctts(ipbb(), &[(4, Version0(u32::MAX))]);
+        assert_eq!(samples(&large)[0].pts, u32::MAX as i64);
+    }
+
+    #[test]
+    fn composition_offset_count_mismatch() {
+        let track = with_ctts(
+            track(&[0], &[(1, 4)], 10, &[], &[(4, 10)]),
+            &[(3, mp4parse::TimeOffsetVersion::Version0(0))],
+        );
+        let message = SampleTable::from_track(&track).unwrap_err().to_string();
+        assert!(message.contains("ctts describes 3 samples but the track has 4"));
+    }
+
+    #[test]
+    fn invalid_tables() {
+        let valid = || track(&[0, 10], &[(1, 1)], 0, &[10, 10], &[(2, 1)]);
+
+        let mut no_timescale = valid();
+        no_timescale.timescale = None;
+        let mut zero_timescale = valid();
+        zero_timescale.timescale = Some(mp4parse::TrackTimeScale(0, 0));
+        let mut no_stsz = valid();
+        no_stsz.stsz = None;
+        let mut unsorted_stss = valid();
+        unsorted_stss.stss = Some(mp4parse::SyncSampleBox {
+            samples: vec![2, 1].into(),
+        });
+
+        let cases = [
+            (no_timescale, "no timescale"),
+            (zero_timescale, "timescale is zero"),
+            (no_stsz, "no stsz box"),
+            (
+                track(&[0, 10], &[], 0, &[10, 10], &[(2, 1)]),
+                "stsc box is empty",
+            ),
+            (
+                track(&[0, 10], &[(2, 1)], 0, &[10, 10], &[(2, 1)]),
+                "must start at chunk 1",
+            ),
+            (
+                track(&[0, 10], &[(1, 1), (1, 2)], 0, &[10, 10], &[(2, 1)]),
+                "not increasing",
+            ),
+            (
+                track(&[0, 10], &[(1, 1)], 0, &[10, 10], &[(3, 1)]),
+                "stsz describes 2 samples but stts describes 3",
+            ),
+            (unsorted_stss, "stss sample numbers"),
+        ];
+        for (track, error) in cases {
+            let message = SampleTable::from_track(&track).unwrap_err().to_string();
+            assert!(message.contains(error), "{}: {}", error, message);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{anyhow, Result};
+
+/// nal_unit_type, ITU-T H.264 Table 7-1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NalUnitType {
+    Unspecified(u8),
+    NonIdrSlice,
+    SliceDataPartitionA,
+    SliceDataPartitionB,
+    SliceDataPartitionC,
+    IdrSlice,
+    Sei,
+    Sps,
+    Pps,
+    AccessUnitDelimiter,
+    EndOfSequence,
+    EndOfStream,
+    FillerData,
+    SpsExtension,
+    PrefixNalUnit,
+    SubsetSps,
+    DepthParameterSet,
+    AuxiliarySlice,
+    SliceExtension,
+    SliceExtensionDepthView,
+    Reserved(u8),
+}
+
+impl From<u8> for NalUnitType {
+    fn from(nal_unit_type: u8) -> Self {
+        match nal_unit_type {
+            1 => NalUnitType::NonIdrSlice,
+            2 => NalUnitType::SliceDataPartitionA,
+            3 => NalUnitType::SliceDataPartitionB,
+            4 => NalUnitType::SliceDataPartitionC,
+            5 => NalUnitType::IdrSlice,
+            6 => NalUnitType::Sei,
+            7 => NalUnitType::Sps,
+            8 => NalUnitType::Pps,
+            9 => NalUnitType::AccessUnitDelimiter,
+            10 => NalUnitType::EndOfSequence,
+            11 => NalUnitType::EndOfStream,
+            12 => NalUnitType::FillerData,
+            13 => NalUnitType::SpsExtension,
+            14 => NalUnitType::PrefixNalUnit,
+            15 => NalUnitType::SubsetSps,
+            16 => NalUnitType::DepthParameterSet,
+            19 => NalUnitType::AuxiliarySlice,
+            20 => NalUnitType::SliceExtension,
+            21 => NalUnitType::SliceExtensionDepthView,
+            0 | 24..=31 => NalUnitType::Unspecified(nal_unit_type),
+            _ => NalUnitType::Reserved(nal_unit_type),
+        }
+    }
+}
+
+impl NalUnitType {
+    /// Coded slices of the primary picture, the NAL units a decode submission is built from.
+    pub fn is_slice(&self) -> bool {
+        matches!(self, NalUnitType::NonIdrSlice | NalUnitType::IdrSlice)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NalUnit<'a> {
+    pub nal_ref_idc: u8,
+    pub nal_unit_type: NalUnitType,
+    /// The whole NAL unit including its one byte header, still containing emulation prevention bytes.
+    pub data: &'a [u8],
+}
+
+impl<'a> NalUnit<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let header = *data.first().ok_or_else(|| anyhow!("Empty NAL unit"))?;
+
+        if header & 0b10000000 != 0 {
+            return Err(anyhow!("NAL unit forbidden_zero_bit is set"));
+        }
+
+        Ok(NalUnit {
+            nal_ref_idc: (header >> 5) & 0b00000011,
+            nal_unit_type: NalUnitType::from(header & 0b00011111),
+            data,
+        })
+    }
+
+    /// The NAL unit without its header byte.
+    pub fn payload(&self) -> &'a [u8] {
+        &self.data[1..]
+    }
+}
+
+/// Splits an MP4 sample made of length prefixed NAL units, see [`crate::avc::AVCVideoConfiguration::nal_length_size`].
+pub struct AvccNalUnits<'a> {
+    sample: &'a [u8],
+    length_size: usize,
+    offset: usize,
+    failed: bool,
+}
+
+pub fn avcc_nal_units(sample: &[u8], length_size: usize) -> Result<AvccNalUnits<'_>> {
+    if !matches!(length_size, 1 | 2 | 4) {
+        return Err(anyhow!(
+            "NAL unit length size must be 1, 2 or 4 bytes, got {}",
+            length_size
+        ));
+    }
+
+    Ok(AvccNalUnits {
+        sample,
+        length_size,
+        offset: 0,
+        failed: false,
+    })
+}
+
+impl<'a> AvccNalUnits<'a> {
+    fn next_nal_unit(&mut self) -> Result<NalUnit<'a>> {
+        let remaining = self.sample.len() - self.offset;
+
+        if remaining < self.length_size {
+            return Err(anyhow!(
+                "Truncated NAL unit length at offset {}: need {} bytes, {} left",
+                self.offset,
+                self.length_size,
+                remaining
+            ));
+        }
+
+        let length = self.sample[self.offset..self.offset + self.length_size]
+            .iter()
+            .fold(0usize, |length, &byte| (length << 8) | byte as usize);
+
+        let start = self.offset + self.length_size;
+
+        if length > self.sample.len() - start {
+            return Err(anyhow!(
+                "NAL unit at offset {} of {} bytes overruns the {} byte sample",
+                self.offset,
+                length,
+                self.sample.len()
+            ));
+        }
+
+        self.offset = start + length;
+
+        NalUnit::parse(&self.sample[start..start + length])
+    }
+}
+
+impl<'a> Iterator for AvccNalUnits<'a> {
+    type Item = Result<NalUnit<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset >= self.sample.len() {
+            return None;
+        }
+
+        let nal_unit = self.next_nal_unit();
+        self.failed = nal_unit.is_err();
+
+        Some(nal_unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nal_unit_header() {
+        // (header byte, nal_ref_idc, nal_unit_type)
+        let cases = [
+            (0x67, 3, NalUnitType::Sps),
+            (0x68, 3, NalUnitType::Pps),
+            (0x65, 3, NalUnitType::IdrSlice),
+            (0x41, 2, NalUnitType::NonIdrSlice),
+            (0x21, 1, NalUnitType::NonIdrSlice),
+            (0x01, 0, NalUnitType::NonIdrSlice),
+            (0x06, 0, NalUnitType::Sei),
+            (0x09, 0, NalUnitType::AccessUnitDelimiter),
+            (0x0b, 0, NalUnitType::EndOfStream),
+            (0x14, 0, NalUnitType::SliceExtension),
+            (0x00, 0, NalUnitType::Unspecified(0)),
+            (0x18, 0, NalUnitType::Unspecified(24)),
+            (0x1f, 0, NalUnitType::Unspecified(31)),
+            (0x11, 0, NalUnitType::Reserved(17)),
+            (0x17, 0, NalUnitType::Reserved(23)),
+        ];
+
+        for (header, nal_ref_idc, nal_unit_type) in cases {
+            let data = [header, 0xaa];
+            let nal_unit = NalUnit::parse(&data).unwrap();
+            assert_eq!(nal_unit.nal_ref_idc, nal_ref_idc, "{:#04x}", header);
+            assert_eq!(nal_unit.nal_unit_type, nal_unit_type, "{:#04x}", header);
+            assert_eq!(nal_unit.payload(), [0xaa]);
+        }
+
+        assert!(NalUnit::parse(&[]).is_err());
+        assert!(NalUnit::parse(&[0x80 | 0x67]).is_err());
+    }
+
+    #[test]
+    fn is_slice() {
+        let slices = (0..32)
+            .filter(|&nal_unit_type| NalUnitType::from(nal_unit_type).is_slice())
+            .collect::<Vec<_>>();
+        assert_eq!(slices, [1, 5]);
+    }
+
+    #[test]
+    fn avcc_lengths() {
+        // The same two NAL units behind 1, 2 and 4 byte lengths
+        let cases: [(usize, &[u8]); 3] = [
+            (1, &[2, 0x67, 0x01, 1, 0x68]),
+            (2, &[0, 2, 0x67, 0x01, 0, 1, 0x68]),
+            (4, &[0, 0, 0, 2, 0x67, 0x01, 0, 0, 0, 1, 0x68]),
+        ];
+
+        for (length_size, sample) in cases {
+            let nal_units = avcc_nal_units(sample, length_size)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            let data: Vec<_> = nal_units.iter().map(|nal_unit| nal_unit.data).collect();
+            assert_eq!(data, [&[0x67, 0x01][..], &[0x68]], "{} bytes", length_size);
+        }
+
+        // A length of more than 255 bytes
+        let mut sample = vec![0x01, 0x02, 0x65];
+        sample.resize(2 + 0x102, 0xaa);
+        let nal_unit = avcc_nal_units(&sample, 2).unwrap().next().unwrap().unwrap();
+        assert_eq!(nal_unit.data.len(), 0x102);
+
+        assert!(avcc_nal_units(&[], 3).is_err());
+        assert_eq!(avcc_nal_units(&[], 4).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn avcc_errors() {
+        let cases: [(&[u8], &str); 4] = [
+            // The second length runs past the sample
+            (&[0, 1, 0x67, 0, 3, 0x68, 0x01], "overruns"),
+            (&[0, 1, 0x67, 0], "Truncated NAL unit length"),
+            (&[0, 0], "Empty NAL unit"),
+            (&[0, 1, 0xe7], "forbidden_zero_bit"),
+        ];
+
+        for (sample, error) in cases {
+            let results = avcc_nal_units(sample, 2).unwrap().collect::<Vec<_>>();
+            // Everything up to the error is returned, nothing after it
+            let (last, before) = results.split_last().unwrap();
+            assert!(before.iter().all(|nal_unit| nal_unit.is_ok()));
+            let message = last.as_ref().unwrap_err().to_string();
+            assert!(message.contains(error), "{}: {}", error, message);
+        }
+    }
+}
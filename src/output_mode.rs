@@ -0,0 +1,296 @@
+use std::env;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use ash::vk;
+
+/// Environment variable overriding the output mode picked from the capabilities, either
+/// `coincide` or `distinct`.
+pub const OUTPUT_MODE_ENV: &str = "ASH_VIDEO_OUTPUT_MODE";
+
+/// Where decoded pictures end up, `VkVideoDecodeCapabilityFlagsKHR`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Pictures are decoded into their DPB slot and displayed from there.
+    Coincide,
+    /// Pictures are decoded into their DPB slot and written to a separate output image as well.
+    Distinct,
+}
+
+impl FromStr for OutputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode.to_ascii_lowercase().as_str() {
+            "coincide" => Ok(OutputMode::Coincide),
+            "distinct" => Ok(OutputMode::Distinct),
+            _ => Err(anyhow!(
+                "Unknown output mode {:?}, expected coincide or distinct",
+                mode
+            )),
+        }
+    }
+}
+
+/// The part of `VideoDecodeCapabilitiesKHR` the output mode depends on, kept apart from the
+/// Vulkan structure so it can be filled in by hand.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecodeCapabilities {
+    pub flags: vk::VideoDecodeCapabilityFlagsKHR,
+}
+
+impl DecodeCapabilities {
+    pub fn from_vk(decode_capabilities: &vk::VideoDecodeCapabilitiesKHR) -> Self {
+        DecodeCapabilities {
+            flags: decode_capabilities.flags,
+        }
+    }
+
+    pub fn supports(&self, mode: OutputMode) -> bool {
+        self.flags.contains(match mode {
+            OutputMode::Coincide => vk::VideoDecodeCapabilityFlagsKHR::DPB_AND_OUTPUT_COINCIDE,
+            OutputMode::Distinct => vk::VideoDecodeCapabilityFlagsKHR::DPB_AND_OUTPUT_DISTINCT,
+        })
+    }
+}
+
+impl OutputMode {
+    /// The mode [`OUTPUT_MODE_ENV`] asks for, `None` when it is not set.
+    pub fn from_env() -> Result<Option<Self>> {
+        env::var(OUTPUT_MODE_ENV)
+            .ok()
+            .map(|mode| mode.parse())
+            .transpose()
+    }
+
+    /// Picks the mode to decode with, `preferred` wins when the implementation supports it.
+    ///
+    /// Without a preference distinct output is used when available, the output pictures then
+    /// stay valid however long the DPB keeps their slots.
+    pub fn select(
+        capabilities: &DecodeCapabilities,
+        preferred: Option<OutputMode>,
+    ) -> Result<Self> {
+        if let Some(mode) = preferred {
+            if !capabilities.supports(mode) {
+                return Err(anyhow!(
+                    "Output mode {:?} is not supported, the implementation reports {:?}",
+                    mode,
+                    capabilities.flags
+                ));
+            }
+
+            return Ok(mode);
+        }
+
+        [OutputMode::Distinct, OutputMode::Coincide]
+            .into_iter()
+            .find(|&mode| capabilities.supports(mode))
+            .ok_or_else(|| {
+                anyhow!(
+                    "The implementation reports neither output mode: {:?}",
+                    capabilities.flags
+                )
+            })
+    }
+
+    /// Usage of the DPB images, which are the output images as well when coinciding. Usage for
+    /// presenting the output images comes from [`presentable_usage`].
+    pub fn dpb_usage(self) -> vk::ImageUsageFlags {
+        match self {
+            OutputMode::Coincide => {
+                vk::ImageUsageFlags::VIDEO_DECODE_DPB_KHR
+                    | vk::ImageUsageFlags::VIDEO_DECODE_DST_KHR
+            }
+            OutputMode::Distinct => vk::ImageUsageFlags::VIDEO_DECODE_DPB_KHR,
+        }
+    }
+
+    /// Usage of the separate output images, `None` when there are none.
+    pub fn output_usage(self) -> Option<vk::ImageUsageFlags> {
+        match self {
+            OutputMode::Coincide => None,
+            OutputMode::Distinct => Some(vk::ImageUsageFlags::VIDEO_DECODE_DST_KHR),
+        }
+    }
+}
+
+/// Usage and create flags added to the output images so they can be presented, from the
+/// `image_usage_flags` and `image_create_flags` of their video format.
+///
+/// Images are sampled as they are when the format allows `SAMPLED`, otherwise only views of their
+/// planes are, which takes `MUTABLE_FORMAT` and `EXTENDED_USAGE`. `MUTABLE_FORMAT` is asked for
+/// wherever it is allowed so the compute presenter remains an option.
+pub fn presentable_usage(
+    format_usage: vk::ImageUsageFlags,
+    format_flags: vk::ImageCreateFlags,
+) -> Result<(vk::ImageUsageFlags, vk::ImageCreateFlags)> {
+    let plane_views = vk::ImageCreateFlags::MUTABLE_FORMAT | vk::ImageCreateFlags::EXTENDED_USAGE;
+
+    if format_usage.contains(vk::ImageUsageFlags::SAMPLED) {
+        Ok((
+            vk::ImageUsageFlags::SAMPLED,
+            format_flags & vk::ImageCreateFlags::MUTABLE_FORMAT,
+        ))
+    } else if format_flags.contains(plane_views) {
+        Ok((vk::ImageUsageFlags::SAMPLED, plane_views))
+    } else {
+        Err(anyhow!(
+            "Decoded pictures can not be sampled, the video format allows usage {:?} and flags {:?}",
+            format_usage,
+            format_flags
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vk::VideoDecodeCapabilityFlagsKHR as Flags;
+
+    fn capabilities(flags: Flags) -> DecodeCapabilities {
+        DecodeCapabilities { flags }
+    }
+
+    #[test]
+    fn coincide_only() {
+        let capabilities = capabilities(Flags::DPB_AND_OUTPUT_COINCIDE);
+
+        assert_eq!(
+            OutputMode::select(&capabilities, None).unwrap(),
+            OutputMode::Coincide
+        );
+        assert_eq!(
+            OutputMode::select(&capabilities, Some(OutputMode::Coincide)).unwrap(),
+            OutputMode::Coincide
+        );
+        assert!(OutputMode::select(&capabilities, Some(OutputMode::Distinct)).is_err());
+    }
+
+    #[test]
+    fn distinct_only() {
+        let capabilities = capabilities(Flags::DPB_AND_OUTPUT_DISTINCT);
+
+        assert_eq!(
+            OutputMode::select(&capabilities, None).unwrap(),
+            OutputMode::Distinct
+        );
+        assert_eq!(
+            OutputMode::select(&capabilities, Some(OutputMode::Distinct)).unwrap(),
+            OutputMode::Distinct
+        );
+        assert!(OutputMode::select(&capabilities, Some(OutputMode::Coincide)).is_err());
+    }
+
+    #[test]
+    fn both() {
+        let capabilities =
+            capabilities(Flags::DPB_AND_OUTPUT_COINCIDE | Flags::DPB_AND_OUTPUT_DISTINCT);
+
+        // Distinct output is preferred unless asked otherwise
+        assert_eq!(
+            OutputMode::select(&capabilities, None).unwrap(),
+            OutputMode::Distinct
+        );
+        assert_eq!(
+            OutputMode::select(&capabilities, Some(OutputMode::Coincide)).unwrap(),
+            OutputMode::Coincide
+        );
+        assert_eq!(
+            OutputMode::select(&capabilities, Some(OutputMode::Distinct)).unwrap(),
+            OutputMode::Distinct
+        );
+    }
+
+    #[test]
+    fn neither() {
+        let capabilities = capabilities(Flags::empty());
+
+        assert!(OutputMode::select(&capabilities, None).is_err());
+        assert!(OutputMode::select(&capabilities, Some(OutputMode::Coincide)).is_err());
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "coincide".parse::<OutputMode>().unwrap(),
+            OutputMode::Coincide
+        );
+        assert_eq!(
+            "Distinct".parse::<OutputMode>().unwrap(),
+            OutputMode::Distinct
+        );
+        assert!("separate".parse::<OutputMode>().is_err());
+        assert!("".parse::<OutputMode>().is_err());
+    }
+
+    // The only test touching the environment variable
+    #[test]
+    fn env_override() {
+        let both = capabilities(Flags::DPB_AND_OUTPUT_COINCIDE | Flags::DPB_AND_OUTPUT_DISTINCT);
+        let distinct_only = capabilities(Flags::DPB_AND_OUTPUT_DISTINCT);
+
+        env::remove_var(OUTPUT_MODE_ENV);
+        assert_eq!(OutputMode::from_env().unwrap(), None);
+
+        env::set_var(OUTPUT_MODE_ENV, "COINCIDE");
+        let preferred = OutputMode::from_env().unwrap();
+        assert_eq!(preferred, Some(OutputMode::Coincide));
+        assert_eq!(
+            OutputMode::select(&both, preferred).unwrap(),
+            OutputMode::Coincide
+        );
+
+        // An override the implementation cannot honour is an error, not a fallback
+        assert!(OutputMode::select(&distinct_only, preferred).is_err());
+
+        env::set_var(OUTPUT_MODE_ENV, "bogus");
+        assert!(OutputMode::from_env().is_err());
+
+        env::remove_var(OUTPUT_MODE_ENV);
+    }
+
+    #[test]
+    fn presentable() {
+        use vk::ImageCreateFlags as CreateFlags;
+        use vk::ImageUsageFlags as Usage;
+
+        let decode = Usage::VIDEO_DECODE_DST_KHR | Usage::TRANSFER_SRC;
+        let cases = [
+            // Sampled as they are
+            (
+                decode | Usage::SAMPLED,
+                CreateFlags::empty(),
+                Some((Usage::SAMPLED, CreateFlags::empty())),
+            ),
+            (
+                decode | Usage::SAMPLED,
+                CreateFlags::MUTABLE_FORMAT | CreateFlags::EXTENDED_USAGE | CreateFlags::ALIAS,
+                Some((Usage::SAMPLED, CreateFlags::MUTABLE_FORMAT)),
+            ),
+            // Through views of the planes
+            (
+                decode,
+                CreateFlags::MUTABLE_FORMAT | CreateFlags::EXTENDED_USAGE | CreateFlags::ALIAS,
+                Some((
+                    Usage::SAMPLED,
+                    CreateFlags::MUTABLE_FORMAT | CreateFlags::EXTENDED_USAGE,
+                )),
+            ),
+            (decode, CreateFlags::MUTABLE_FORMAT, None),
+            (decode, CreateFlags::EXTENDED_USAGE, None),
+            (decode, CreateFlags::empty(), None),
+        ];
+
+        for (format_usage, format_flags, expected) in cases {
+            assert_eq!(
+                presentable_usage(format_usage, format_flags).ok(),
+                expected,
+                "{:?} {:?}",
+                format_usage,
+                format_flags
+            );
+        }
+    }
+}
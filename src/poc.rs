@@ -0,0 +1,468 @@
+use anyhow::{anyhow, Result};
+
+use crate::slice::{DecRefPicMarking, MemoryManagementControlOperation, SliceHeader};
+use crate::sps::SequenceParameterSet;
+
+/// Whether the picture carries memory_management_control_operation 5, which resets frame_num
+/// and the picture order count once it is decoded.
+pub fn has_mmco5(header: &SliceHeader) -> bool {
+    match &header.dec_ref_pic_marking {
+        Some(DecRefPicMarking::Adaptive(operations)) => {
+            operations.contains(&MemoryManagementControlOperation::UnmarkAll)
+        }
+        _ => false,
+    }
+}
+
+/// PicOrderCnt() of a frame or field, ITU-T H.264 8-1.
+pub fn pic_order_cnt(header: &SliceHeader, field_order_cnt: [i32; 2]) -> i32 {
+    match (header.field_pic_flag, header.bottom_field_flag) {
+        (false, _) => field_order_cnt[0].min(field_order_cnt[1]),
+        (true, false) => field_order_cnt[0],
+        (true, true) => field_order_cnt[1],
+    }
+}
+
+/// Turns the overflow of a picture order count computation into an error, the values come
+/// straight from the bitstream.
+fn checked(value: Option<i32>) -> Result<i32> {
+    value.ok_or_else(|| anyhow!("Picture order count overflows"))
+}
+
+/// Picture order count decoding state carried from picture to picture, ITU-T H.264 8.2.1.
+///
+/// Pictures have to be fed in decode order, the state is reset by every IDR picture.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PicOrderCntState {
+    // pic_order_cnt_type 0, from the previous reference picture
+    prev_pic_order_cnt_msb: i32,
+    prev_pic_order_cnt_lsb: i32,
+    // pic_order_cnt_type 1 and 2, from the previous picture
+    prev_frame_num_offset: i32,
+    prev_frame_num: u32,
+}
+
+impl PicOrderCntState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// TopFieldOrderCnt and BottomFieldOrderCnt of the picture the slice belongs to, only the one
+    /// of the coded field is meaningful for field pictures and the other is 0.
+    ///
+    /// The values are the ones the picture is decoded with. When it carries MMCO 5 the picture
+    /// is output with [`pic_order_cnt`] subtracted from both, see ITU-T H.264 8.2.1.
+    ///
+    /// Fails when a value overflows, which only a broken or hostile stream leads to.
+    pub fn compute(
+        &mut self,
+        sps: &SequenceParameterSet,
+        header: &SliceHeader,
+    ) -> Result<[i32; 2]> {
+        match sps.pic_order_cnt_type {
+            0 => self.compute_type_0(sps, header),
+            1 => self.compute_type_1(sps, header),
+            _ => self.compute_type_2(sps, header),
+        }
+    }
+
+    /// FrameNumOffset, ITU-T H.264 8-6 and 8-11.
+    fn frame_num_offset(&self, sps: &SequenceParameterSet, header: &SliceHeader) -> Result<i32> {
+        if header.idr {
+            Ok(0)
+        } else if self.prev_frame_num > header.frame_num {
+            checked(
+                self.prev_frame_num_offset
+                    .checked_add(sps.max_frame_num() as i32),
+            )
+        } else {
+            Ok(self.prev_frame_num_offset)
+        }
+    }
+
+    fn update_frame_num(&mut self, header: &SliceHeader, frame_num_offset: i32) {
+        if has_mmco5(header) {
+            self.prev_frame_num_offset = 0;
+            self.prev_frame_num = 0;
+        } else {
+            self.prev_frame_num_offset = frame_num_offset;
+            self.prev_frame_num = header.frame_num;
+        }
+    }
+
+    /// Accounts for a frame_num skipped over when gaps_in_frame_num_value_allowed_flag is set,
+    /// the "non-existing" frame inferred for it counts as a previous picture, ITU-T H.264 8.2.5.2.
+    pub fn skip_frame_num(&mut self, sps: &SequenceParameterSet, frame_num: u32) -> Result<()> {
+        if self.prev_frame_num > frame_num {
+            self.prev_frame_num_offset = checked(
+                self.prev_frame_num_offset
+                    .checked_add(sps.max_frame_num() as i32),
+            )?;
+        }
+        self.prev_frame_num = frame_num;
+
+        Ok(())
+    }
+
+    /// ITU-T H.264 8.2.1.1
+    fn compute_type_0(
+        &mut self,
+        sps: &SequenceParameterSet,
+        header: &SliceHeader,
+    ) -> Result<[i32; 2]> {
+        if header.idr {
+            self.prev_pic_order_cnt_msb = 0;
+            self.prev_pic_order_cnt_lsb = 0;
+        }
+
+        let max_pic_order_cnt_lsb = sps.max_pic_order_cnt_lsb() as i32;
+        let pic_order_cnt_lsb = header.pic_order_cnt_lsb as i32;
+        let prev_lsb = self.prev_pic_order_cnt_lsb;
+
+        let pic_order_cnt_msb = if pic_order_cnt_lsb < prev_lsb
+            && prev_lsb - pic_order_cnt_lsb >= max_pic_order_cnt_lsb / 2
+        {
+            checked(
+                self.prev_pic_order_cnt_msb
+                    .checked_add(max_pic_order_cnt_lsb),
+            )?
+        } else if pic_order_cnt_lsb > prev_lsb
+            && pic_order_cnt_lsb - prev_lsb > max_pic_order_cnt_lsb / 2
+        {
+            checked(
+                self.prev_pic_order_cnt_msb
+                    .checked_sub(max_pic_order_cnt_lsb),
+            )?
+        } else {
+            self.prev_pic_order_cnt_msb
+        };
+
+        let order_cnt = checked(pic_order_cnt_msb.checked_add(pic_order_cnt_lsb))?;
+        let field_order_cnt = match (header.field_pic_flag, header.bottom_field_flag) {
+            (false, _) => [
+                order_cnt,
+                checked(order_cnt.checked_add(header.delta_pic_order_cnt_bottom))?,
+            ],
+            (true, false) => [order_cnt, 0],
+            (true, true) => [0, order_cnt],
+        };
+
+        if header.nal_ref_idc != 0 {
+            if has_mmco5(header) {
+                // The picture is treated as having a picture order count of 0 from now on
+                self.prev_pic_order_cnt_msb = 0;
+                self.prev_pic_order_cnt_lsb = if header.field_pic_flag && header.bottom_field_flag {
+                    0
+                } else {
+                    checked(field_order_cnt[0].checked_sub(pic_order_cnt(header, field_order_cnt)))?
+                };
+            } else {
+                self.prev_pic_order_cnt_msb = pic_order_cnt_msb;
+                self.prev_pic_order_cnt_lsb = pic_order_cnt_lsb;
+            }
+        }
+
+        Ok(field_order_cnt)
+    }
+
+    /// ITU-T H.264 8.2.1.2
+    fn compute_type_1(
+        &mut self,
+        sps: &SequenceParameterSet,
+        header: &SliceHeader,
+    ) -> Result<[i32; 2]> {
+        let frame_num_offset = self.frame_num_offset(sps, header)?;
+        let cycle_length = sps.offset_for_ref_frame.len() as i32;
+
+        let mut abs_frame_num = if cycle_length != 0 {
+            checked(frame_num_offset.checked_add(header.frame_num as i32))?
+        } else {
+            0
+        };
+        if header.nal_ref_idc == 0 && abs_frame_num > 0 {
+            abs_frame_num -= 1;
+        }
+
+        let sum = |offsets: &[i32]| {
+            checked(
+                offsets
+                    .iter()
+                    .try_fold(0i32, |sum, &offset| sum.checked_add(offset)),
+            )
+        };
+
+        let mut expected_pic_order_cnt = if abs_frame_num > 0 {
+            let pic_order_cnt_cycle_cnt = (abs_frame_num - 1) / cycle_length;
+            let frame_num_in_pic_order_cnt_cycle = (abs_frame_num - 1) % cycle_length;
+            let expected_delta_per_pic_order_cnt_cycle = sum(&sps.offset_for_ref_frame)?;
+            let offset_in_cycle =
+                sum(&sps.offset_for_ref_frame[..=frame_num_in_pic_order_cnt_cycle as usize])?;
+
+            checked(
+                pic_order_cnt_cycle_cnt
+                    .checked_mul(expected_delta_per_pic_order_cnt_cycle)
+                    .and_then(|expected| expected.checked_add(offset_in_cycle)),
+            )?
+        } else {
+            0
+        };
+        if header.nal_ref_idc == 0 {
+            expected_pic_order_cnt =
+                checked(expected_pic_order_cnt.checked_add(sps.offset_for_non_ref_pic))?;
+        }
+
+        let top = checked(expected_pic_order_cnt.checked_add(header.delta_pic_order_cnt[0]));
+        let bottom = |top: i32, delta_pic_order_cnt: i32| {
+            checked(
+                top.checked_add(sps.offset_for_top_to_bottom_field)
+                    .and_then(|bottom| bottom.checked_add(delta_pic_order_cnt)),
+            )
+        };
+
+        let field_order_cnt = match (header.field_pic_flag, header.bottom_field_flag) {
+            (false, _) => {
+                let top = top?;
+                [top, bottom(top, header.delta_pic_order_cnt[1])?]
+            }
+            (true, false) => [top?, 0],
+            (true, true) => [
+                0,
+                bottom(expected_pic_order_cnt, header.delta_pic_order_cnt[0])?,
+            ],
+        };
+
+        self.update_frame_num(header, frame_num_offset);
+
+        Ok(field_order_cnt)
+    }
+
+    /// ITU-T H.264 8.2.1.3
+    fn compute_type_2(
+        &mut self,
+        sps: &SequenceParameterSet,
+        header: &SliceHeader,
+    ) -> Result<[i32; 2]> {
+        let frame_num_offset = self.frame_num_offset(sps, header)?;
+
+        let temp_pic_order_cnt = if header.idr {
+            0
+        } else {
+            let ref_pic_order_cnt = checked(
+                frame_num_offset
+                    .checked_add(header.frame_num as i32)
+                    .and_then(|abs_frame_num| abs_frame_num.checked_mul(2)),
+            )?;
+
+            if header.nal_ref_idc == 0 {
+                ref_pic_order_cnt - 1
+            } else {
+                ref_pic_order_cnt
+            }
+        };
+
+        let field_order_cnt = match (header.field_pic_flag, header.bottom_field_flag) {
+            (false, _) => [temp_pic_order_cnt, temp_pic_order_cnt],
+            (true, false) => [temp_pic_order_cnt, 0],
+            (true, true) => [0, temp_pic_order_cnt],
+        };
+
+        self.update_frame_num(header, frame_num_offset);
+
+        Ok(field_order_cnt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{adaptive_header, idr_header, slice_header, sps};
+
+    fn with_lsb(mut header: SliceHeader, pic_order_cnt_lsb: u32, bottom: i32) -> SliceHeader {
+        header.pic_order_cnt_lsb = pic_order_cnt_lsb;
+        header.delta_pic_order_cnt_bottom = bottom;
+        header
+    }
+
+    #[test]
+    fn type_0() {
+        let sps = sps(0);
+        let mut state = PicOrderCntState::new();
+
+        assert_eq!(state.compute(&sps, &idr_header()).unwrap(), [0, 0]);
+
+        // pic_order_cnt_lsb wraps at 16 twice over
+        for i in 1..40u32 {
+            let header = with_lsb(slice_header(i % 16, 2), i * 2 % 16, 1);
+            let order_cnt = i as i32 * 2;
+            assert_eq!(
+                state.compute(&sps, &header).unwrap(),
+                [order_cnt, order_cnt + 1]
+            );
+        }
+
+        // A non-reference picture before the last reference one
+        let header = with_lsb(slice_header(8, 0), 72 % 16, 0);
+        assert_eq!(state.compute(&sps, &header).unwrap(), [72, 72]);
+        // Does not move prevPicOrderCntMsb/Lsb
+        let header = with_lsb(slice_header(8, 2), 80 % 16, 0);
+        assert_eq!(state.compute(&sps, &header).unwrap(), [80, 80]);
+    }
+
+    #[test]
+    fn type_0_backwards_wrap() {
+        let sps = sps(0);
+        let mut state = PicOrderCntState::new();
+
+        state.compute(&sps, &with_lsb(idr_header(), 4, 0)).unwrap();
+        // 14 is more than half of MaxPicOrderCntLsb below 4, taken as -2
+        let header = with_lsb(slice_header(1, 0), 14, 0);
+        assert_eq!(state.compute(&sps, &header).unwrap(), [-2, -2]);
+    }
+
+    #[test]
+    fn type_0_mmco5() {
+        let sps = sps(0);
+        let mut state = PicOrderCntState::new();
+
+        state.compute(&sps, &idr_header()).unwrap();
+        state
+            .compute(&sps, &with_lsb(slice_header(1, 2), 6, 0))
+            .unwrap();
+
+        let header = with_lsb(
+            adaptive_header(2, &[MemoryManagementControlOperation::UnmarkAll]),
+            8,
+            -1,
+        );
+        let field_order_cnt = state.compute(&sps, &header).unwrap();
+        assert_eq!(field_order_cnt, [8, 7]);
+        assert_eq!(pic_order_cnt(&header, field_order_cnt), 7);
+
+        // prevPicOrderCntLsb is tempPicOrderCnt, the top field after subtracting 7
+        let header = with_lsb(slice_header(1, 2), 3, 0);
+        assert_eq!(state.compute(&sps, &header).unwrap(), [3, 3]);
+        assert_eq!(state.prev_pic_order_cnt_lsb, 3);
+    }
+
+    #[test]
+    fn type_1() {
+        let mut sps = sps(1);
+        sps.offset_for_ref_frame = vec![4, 2];
+        sps.offset_for_non_ref_pic = -1;
+        sps.offset_for_top_to_bottom_field = 1;
+        let mut state = PicOrderCntState::new();
+
+        // (frame_num, nal_ref_idc, TopFieldOrderCnt)
+        let pictures = [
+            (1, 2, 4),
+            (2, 0, 3),
+            (2, 2, 6),
+            (3, 2, 10),
+            (4, 2, 12),
+            (5, 0, 11),
+            (5, 2, 16),
+        ];
+
+        assert_eq!(state.compute(&sps, &idr_header()).unwrap(), [0, 1]);
+        for (frame_num, nal_ref_idc, top) in pictures {
+            let header = slice_header(frame_num, nal_ref_idc);
+            assert_eq!(
+                state.compute(&sps, &header).unwrap(),
+                [top, top + 1],
+                "frame_num {}",
+                frame_num
+            );
+        }
+    }
+
+    #[test]
+    fn type_1_frame_num_wrap() {
+        let mut sps = sps(1);
+        sps.offset_for_ref_frame = vec![2];
+        let mut state = PicOrderCntState::new();
+
+        state.compute(&sps, &idr_header()).unwrap();
+        for frame_num in 1..16 {
+            state.compute(&sps, &slice_header(frame_num, 2)).unwrap();
+        }
+        // FrameNumOffset grows by MaxFrameNum, AbsFrameNum 17
+        assert_eq!(state.compute(&sps, &slice_header(1, 2)).unwrap(), [34, 34]);
+    }
+
+    #[test]
+    fn type_1_overflow() {
+        let mut sps = sps(1);
+        sps.offset_for_ref_frame = vec![i32::MAX - 1, 1];
+        let mut state = PicOrderCntState::new();
+
+        state.compute(&sps, &idr_header()).unwrap();
+        assert!(state.compute(&sps, &slice_header(2, 2)).is_ok());
+        // A whole cycle and the first offset of the next
+        assert!(state.compute(&sps, &slice_header(3, 2)).is_err());
+
+        sps.offset_for_ref_frame = vec![i32::MAX, 1];
+        assert!(state.compute(&sps, &slice_header(3, 2)).is_err());
+
+        let mut header = slice_header(3, 2);
+        header.delta_pic_order_cnt = [i32::MIN, 0];
+        sps.offset_for_ref_frame = vec![-1];
+        assert!(state.compute(&sps, &header).is_err());
+
+        header.delta_pic_order_cnt = [0, i32::MAX];
+        sps.offset_for_ref_frame = vec![1];
+        sps.offset_for_top_to_bottom_field = 1;
+        assert!(state.compute(&sps, &header).is_err());
+    }
+
+    #[test]
+    fn type_0_overflow() {
+        let sps = sps(0);
+        let mut state = PicOrderCntState::new();
+
+        state.compute(&sps, &idr_header()).unwrap();
+        let header = with_lsb(slice_header(1, 2), 1, i32::MAX);
+        assert!(state.compute(&sps, &header).is_err());
+    }
+
+    #[test]
+    fn type_2() {
+        let sps = sps(2);
+        let mut state = PicOrderCntState::new();
+
+        assert_eq!(state.compute(&sps, &idr_header()).unwrap(), [0, 0]);
+        for frame_num in 1..16 {
+            let order_cnt = 2 * frame_num as i32;
+            assert_eq!(
+                state.compute(&sps, &slice_header(frame_num, 2)).unwrap(),
+                [order_cnt; 2]
+            );
+        }
+
+        // frame_num wraps
+        assert_eq!(state.compute(&sps, &slice_header(0, 2)).unwrap(), [32, 32]);
+        // Non-reference pictures come right before the next reference one
+        assert_eq!(state.compute(&sps, &slice_header(1, 0)).unwrap(), [33, 33]);
+
+        // MMCO 5 resets FrameNumOffset and frame_num
+        let header = adaptive_header(2, &[MemoryManagementControlOperation::UnmarkAll]);
+        assert_eq!(state.compute(&sps, &header).unwrap(), [36, 36]);
+        assert_eq!(state.compute(&sps, &slice_header(1, 2)).unwrap(), [2, 2]);
+
+        // An IDR picture starts over
+        state.compute(&sps, &slice_header(5, 2)).unwrap();
+        assert_eq!(state.compute(&sps, &idr_header()).unwrap(), [0, 0]);
+        assert_eq!(state.compute(&sps, &slice_header(1, 2)).unwrap(), [2, 2]);
+    }
+
+    #[test]
+    fn skipped_frame_nums() {
+        let sps = sps(2);
+        let mut state = PicOrderCntState::new();
+
+        state.compute(&sps, &idr_header()).unwrap();
+        state.compute(&sps, &slice_header(14, 2)).unwrap();
+        state.skip_frame_num(&sps, 15).unwrap();
+        state.skip_frame_num(&sps, 0).unwrap();
+        assert_eq!(state.compute(&sps, &slice_header(1, 2)).unwrap(), [34, 34]);
+    }
+}
@@ -0,0 +1,446 @@
+use std::mem;
+
+use anyhow::{anyhow, Result};
+use ash::vk::native::{StdVideoH264PictureParameterSet, StdVideoH264ScalingLists};
+
+use crate::nal::{NalUnit, NalUnitType};
+use crate::rbsp::{remove_emulation_prevention, BitReader};
+use crate::sps::{ScalingLists, SequenceParameterSet};
+
+/// pic_parameter_set_rbsp(), ITU-T H.264 7.3.2.2
+///
+/// Slice groups are rejected while parsing, Vulkan video has no way to decode them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PictureParameterSet {
+    pub pic_parameter_set_id: u8,
+    pub seq_parameter_set_id: u8,
+    pub entropy_coding_mode_flag: bool,
+    pub bottom_field_pic_order_in_frame_present_flag: bool,
+    pub num_ref_idx_l0_default_active_minus1: u8,
+    pub num_ref_idx_l1_default_active_minus1: u8,
+    pub weighted_pred_flag: bool,
+    pub weighted_bipred_idc: u8,
+    pub pic_init_qp_minus26: i8,
+    pub pic_init_qs_minus26: i8,
+    pub chroma_qp_index_offset: i8,
+    pub deblocking_filter_control_present_flag: bool,
+    pub constrained_intra_pred_flag: bool,
+    pub redundant_pic_cnt_present_flag: bool,
+    pub transform_8x8_mode_flag: bool,
+    /// Present when pic_scaling_matrix_present_flag is set, fall-back rule B already resolved
+    /// against the referenced SPS.
+    pub scaling_lists: Option<ScalingLists>,
+    /// Equal to chroma_qp_index_offset when the PPS ends before it.
+    pub second_chroma_qp_index_offset: i8,
+}
+
+impl PictureParameterSet {
+    /// Parses a PPS, the SPS it refers to has to be among `sequence_parameter_sets`.
+    pub fn parse(
+        nal_unit: &NalUnit,
+        sequence_parameter_sets: &[SequenceParameterSet],
+    ) -> Result<Self> {
+        if nal_unit.nal_unit_type != NalUnitType::Pps {
+            return Err(anyhow!(
+                "Expected a picture parameter set NAL unit, got {:?}",
+                nal_unit.nal_unit_type
+            ));
+        }
+
+        let rbsp = remove_emulation_prevention(nal_unit.payload());
+        let mut reader = BitReader::new(&rbsp);
+
+        let pic_parameter_set_id = reader.ue_max(255, "pic_parameter_set_id")? as u8;
+        let seq_parameter_set_id = reader.ue_max(31, "seq_parameter_set_id")? as u8;
+
+        let sps = sequence_parameter_sets
+            .iter()
+            .find(|sps| sps.seq_parameter_set_id == seq_parameter_set_id)
+            .ok_or_else(|| {
+                anyhow!(
+                    "PPS {} refers to unknown SPS {}",
+                    pic_parameter_set_id,
+                    seq_parameter_set_id
+                )
+            })?;
+
+        let entropy_coding_mode_flag = reader.flag()?;
+        let bottom_field_pic_order_in_frame_present_flag = reader.flag()?;
+
+        let num_slice_groups_minus1 = reader.ue_max(7, "num_slice_groups_minus1")?;
+        if num_slice_groups_minus1 > 0 {
+            return Err(anyhow!(
+                "PPS {} uses {} slice groups, flexible macroblock ordering is not supported",
+                pic_parameter_set_id,
+                num_slice_groups_minus1 + 1
+            ));
+        }
+
+        let num_ref_idx_l0_default_active_minus1 =
+            reader.ue_max(31, "num_ref_idx_l0_default_active_minus1")? as u8;
+        let num_ref_idx_l1_default_active_minus1 =
+            reader.ue_max(31, "num_ref_idx_l1_default_active_minus1")? as u8;
+        let weighted_pred_flag = reader.flag()?;
+
+        let weighted_bipred_idc = reader.u(2)? as u8;
+        if weighted_bipred_idc == 3 {
+            return Err(anyhow!(
+                "PPS {} has reserved weighted_bipred_idc 3",
+                pic_parameter_set_id
+            ));
+        }
+
+        let qp_bd_offset_y = 6 * sps.bit_depth_luma_minus8 as i32;
+        let pic_init_qp_minus26 =
+            reader.se_range(-(26 + qp_bd_offset_y), 25, "pic_init_qp_minus26")? as i8;
+        let pic_init_qs_minus26 = reader.se_range(-26, 25, "pic_init_qs_minus26")? as i8;
+        let chroma_qp_index_offset = reader.se_range(-12, 12, "chroma_qp_index_offset")? as i8;
+
+        let deblocking_filter_control_present_flag = reader.flag()?;
+        let constrained_intra_pred_flag = reader.flag()?;
+        let redundant_pic_cnt_present_flag = reader.flag()?;
+
+        let mut transform_8x8_mode_flag = false;
+        let mut scaling_lists = None;
+        let mut second_chroma_qp_index_offset = chroma_qp_index_offset;
+
+        if reader.more_rbsp_data() {
+            transform_8x8_mode_flag = reader.flag()?;
+
+            if reader.flag()? {
+                let list_count = 6 + match (sps.chroma_format_idc, transform_8x8_mode_flag) {
+                    (_, false) => 0,
+                    (3, true) => 6,
+                    (_, true) => 2,
+                };
+
+                // Fall-back rule A without an SPS matrix, rule B with one
+                let fallback = sps
+                    .scaling_lists
+                    .clone()
+                    .unwrap_or_else(ScalingLists::defaults);
+
+                scaling_lists = Some(ScalingLists::parse(&mut reader, list_count, &fallback)?);
+            }
+
+            second_chroma_qp_index_offset =
+                reader.se_range(-12, 12, "second_chroma_qp_index_offset")? as i8;
+        }
+
+        Ok(PictureParameterSet {
+            pic_parameter_set_id,
+            seq_parameter_set_id,
+            entropy_coding_mode_flag,
+            bottom_field_pic_order_in_frame_present_flag,
+            num_ref_idx_l0_default_active_minus1,
+            num_ref_idx_l1_default_active_minus1,
+            weighted_pred_flag,
+            weighted_bipred_idc,
+            pic_init_qp_minus26,
+            pic_init_qs_minus26,
+            chroma_qp_index_offset,
+            deblocking_filter_control_present_flag,
+            constrained_intra_pred_flag,
+            redundant_pic_cnt_present_flag,
+            transform_8x8_mode_flag,
+            scaling_lists,
+            second_chroma_qp_index_offset,
+        })
+    }
+
+    /// Converts into the Vulkan std video representation, see [`StdPictureParameterSet`].
+    pub fn to_std(&self) -> StdPictureParameterSet {
+        let scaling_lists = self
+            .scaling_lists
+            .as_ref()
+            .map(|scaling_lists| Box::new(scaling_lists.to_std()));
+
+        let mut pps: StdVideoH264PictureParameterSet = unsafe { mem::zeroed() };
+
+        pps.flags
+            .set_transform_8x8_mode_flag(self.transform_8x8_mode_flag as u32);
+        pps.flags
+            .set_redundant_pic_cnt_present_flag(self.redundant_pic_cnt_present_flag as u32);
+        pps.flags
+            .set_constrained_intra_pred_flag(self.constrained_intra_pred_flag as u32);
+        pps.flags.set_deblocking_filter_control_present_flag(
+            self.deblocking_filter_control_present_flag as u32,
+        );
+        pps.flags
+            .set_weighted_pred_flag(self.weighted_pred_flag as u32);
+        pps.flags.set_bottom_field_pic_order_in_frame_present_flag(
+            self.bottom_field_pic_order_in_frame_present_flag as u32,
+        );
+        pps.flags
+            .set_entropy_coding_mode_flag(self.entropy_coding_mode_flag as u32);
+        pps.flags
+            .set_pic_scaling_matrix_present_flag(self.scaling_lists.is_some() as u32);
+
+        pps.seq_parameter_set_id = self.seq_parameter_set_id;
+        pps.pic_parameter_set_id = self.pic_parameter_set_id;
+        pps.num_ref_idx_l0_default_active_minus1 = self.num_ref_idx_l0_default_active_minus1;
+        pps.num_ref_idx_l1_default_active_minus1 = self.num_ref_idx_l1_default_active_minus1;
+        pps.weighted_bipred_idc = self.weighted_bipred_idc as u32;
+        pps.pic_init_qp_minus26 = self.pic_init_qp_minus26;
+        pps.pic_init_qs_minus26 = self.pic_init_qs_minus26;
+        pps.chroma_qp_index_offset = self.chroma_qp_index_offset;
+        pps.second_chroma_qp_index_offset = self.second_chroma_qp_index_offset;
+
+        if let Some(scaling_lists) = &scaling_lists {
+            pps.pScalingLists = scaling_lists.as_ref();
+        }
+
+        StdPictureParameterSet {
+            pps,
+            _scaling_lists: scaling_lists,
+        }
+    }
+}
+
+/// A `StdVideoH264PictureParameterSet` together with the scaling lists it points to.
+pub struct StdPictureParameterSet {
+    pps: StdVideoH264PictureParameterSet,
+    _scaling_lists: Option<Box<StdVideoH264ScalingLists>>,
+}
+
+impl StdPictureParameterSet {
+    pub fn as_std(&self) -> &StdVideoH264PictureParameterSet {
+        &self.pps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rbsp::BitWriter;
+    use crate::test_util::{pps, sps};
+
+    fn parse(nal_unit: &[u8], sps: &SequenceParameterSet) -> Result<PictureParameterSet> {
+        PictureParameterSet::parse(&NalUnit::parse(nal_unit)?, std::slice::from_ref(sps))
+    }
+
+    /// The PPS of `test_util::pps(0, 0)` up to redundant_pic_cnt_present_flag.
+    fn write_pps(pic_init_qp_minus26: i32, chroma_qp_index_offset: i32) -> BitWriter {
+        let mut writer = BitWriter::default();
+        writer
+            .ue(0)
+            .ue(0)
+            .flag(false)
+            .flag(false)
+            .ue(0)
+            .ue(0)
+            .ue(0)
+            .flag(false)
+            .u(2, 0)
+            .se(pic_init_qp_minus26)
+            .se(0)
+            .se(chroma_qp_index_offset)
+            .flag(true)
+            .flag(false)
+            .flag(false);
+        writer
+    }
+
+    #[test]
+    fn parse_without_extension() {
+        let parsed = parse(&write_pps(0, 0).nal_unit(0x68), &sps(0)).unwrap();
+        assert_eq!(parsed, pps(0, 0));
+
+        // second_chroma_qp_index_offset is inferred from chroma_qp_index_offset when not sent
+        let parsed = parse(&write_pps(0, -3).nal_unit(0x68), &sps(0)).unwrap();
+        assert_eq!(parsed.chroma_qp_index_offset, -3);
+        assert_eq!(parsed.second_chroma_qp_index_offset, -3);
+        assert!(!parsed.transform_8x8_mode_flag);
+        assert_eq!(parsed.scaling_lists, None);
+
+        let parsed = parse(
+            &write_pps(0, -3)
+                .flag(false)
+                .flag(false)
+                .se(4)
+                .nal_unit(0x68),
+            &sps(0),
+        )
+        .unwrap();
+        assert_eq!(parsed.second_chroma_qp_index_offset, 4);
+    }
+
+    #[test]
+    fn scaling_list_fallback() {
+        // transform_8x8_mode_flag, list 0 with 12 repeated, lists 1 to 7 not sent
+        let nal_unit = write_pps(0, 0)
+            .flag(true)
+            .flag(true)
+            .flag(true)
+            .se(4)
+            .se(-12)
+            .u(7, 0)
+            .se(2)
+            .nal_unit(0x68);
+
+        let defaults = ScalingLists::defaults();
+        let sps_lists = ScalingLists {
+            lists_4x4: [[20; 16]; 6],
+            lists_8x8: [[24; 64]; 6],
+            ..ScalingLists::flat()
+        };
+        let with_matrix = SequenceParameterSet {
+            profile_idc: 100,
+            scaling_lists: Some(sps_lists.clone()),
+            ..sps(0)
+        };
+
+        // Rule A falls back to the default lists, rule B to the ones of the SPS
+        let cases = [(sps(0), &defaults), (with_matrix, &sps_lists)];
+        for (sps, fallback) in cases {
+            let parsed = parse(&nal_unit, &sps).unwrap();
+            assert!(parsed.transform_8x8_mode_flag);
+            assert_eq!(parsed.second_chroma_qp_index_offset, 2);
+
+            let lists = parsed.scaling_lists.unwrap();
+            assert_eq!(lists.present_mask, 1);
+            assert_eq!(lists.use_default_mask, 0);
+            assert_eq!(
+                lists.lists_4x4,
+                [
+                    [12; 16],
+                    [12; 16],
+                    [12; 16],
+                    fallback.lists_4x4[3],
+                    fallback.lists_4x4[3],
+                    fallback.lists_4x4[3],
+                ]
+            );
+            assert_eq!(
+                lists.lists_8x8,
+                [
+                    fallback.lists_8x8[0],
+                    fallback.lists_8x8[1],
+                    fallback.lists_8x8[0],
+                    fallback.lists_8x8[1],
+                    fallback.lists_8x8[0],
+                    fallback.lists_8x8[1],
+                ]
+            );
+        }
+
+        // 4:4:4 sends all 12 lists, the Cb 8x8 one here
+        let high_444 = SequenceParameterSet {
+            profile_idc: 244,
+            chroma_format_idc: 3,
+            ..sps(0)
+        };
+        let nal_unit = write_pps(0, 0)
+            .flag(true)
+            .flag(true)
+            .u(8, 0)
+            .flag(true)
+            .se(8)
+            .se(-16)
+            .u(3, 0)
+            .se(0)
+            .nal_unit(0x68);
+        let lists = parse(&nal_unit, &high_444).unwrap().scaling_lists.unwrap();
+        assert_eq!(lists.present_mask, 1 << 8);
+        assert_eq!(lists.lists_8x8[2], [16; 64]);
+        assert_eq!(lists.lists_8x8[4], [16; 64]);
+        assert_eq!(lists.lists_8x8[3], defaults.lists_8x8[1]);
+    }
+
+    #[test]
+    fn pic_init_qp_range() {
+        let high_10 = SequenceParameterSet {
+            profile_idc: 110,
+            bit_depth_luma_minus8: 2,
+            bit_depth_chroma_minus8: 2,
+            ..sps(0)
+        };
+
+        // The lower bound widens by QpBdOffsetY
+        let cases = [
+            (sps(0), -26, true),
+            (sps(0), -27, false),
+            (sps(0), 25, true),
+            (sps(0), 26, false),
+            (high_10.clone(), -38, true),
+            (high_10.clone(), -39, false),
+            (high_10, 26, false),
+        ];
+        for (sps, pic_init_qp_minus26, valid) in cases {
+            let parsed = parse(&write_pps(pic_init_qp_minus26, 0).nal_unit(0x68), &sps);
+            assert_eq!(
+                parsed.is_ok(),
+                valid,
+                "pic_init_qp_minus26 {} with bit_depth_luma_minus8 {}",
+                pic_init_qp_minus26,
+                sps.bit_depth_luma_minus8
+            );
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mut slice_groups = BitWriter::default();
+        slice_groups.ue(0).ue(0).flag(false).flag(false).ue(1);
+
+        let mut weighted_bipred_idc = BitWriter::default();
+        weighted_bipred_idc
+            .ue(0)
+            .ue(0)
+            .flag(false)
+            .flag(false)
+            .ue(0)
+            .ue(0)
+            .ue(0)
+            .flag(true)
+            .u(2, 3);
+
+        let mut unknown_sps = BitWriter::default();
+        unknown_sps.ue(3).ue(1);
+
+        let cases = [
+            (
+                write_pps(0, 0).nal_unit(0x67),
+                "Expected a picture parameter set",
+            ),
+            (slice_groups.nal_unit(0x68), "2 slice groups"),
+            (weighted_bipred_idc.nal_unit(0x68), "weighted_bipred_idc 3"),
+            (unknown_sps.nal_unit(0x68), "PPS 3 refers to unknown SPS 1"),
+            (write_pps(0, 13).nal_unit(0x68), "chroma_qp_index_offset"),
+        ];
+        for (nal_unit, error) in cases {
+            let message = parse(&nal_unit, &sps(0)).unwrap_err().to_string();
+            assert!(message.contains(error), "{}: {}", error, message);
+        }
+    }
+
+    #[test]
+    fn to_std() {
+        let parsed = PictureParameterSet {
+            transform_8x8_mode_flag: true,
+            scaling_lists: Some(ScalingLists::defaults()),
+            weighted_bipred_idc: 2,
+            second_chroma_qp_index_offset: -2,
+            ..pps(3, 1)
+        };
+
+        // The scaling lists are boxed, moving the value keeps the pointer valid
+        let std = [parsed.to_std()];
+        let std = std[0].as_std();
+        assert_eq!(std.pic_parameter_set_id, 3);
+        assert_eq!(std.seq_parameter_set_id, 1);
+        assert_eq!(std.weighted_bipred_idc, 2);
+        assert_eq!(std.second_chroma_qp_index_offset, -2);
+        assert_eq!(std.flags.transform_8x8_mode_flag(), 1);
+        assert_eq!(std.flags.deblocking_filter_control_present_flag(), 1);
+        assert_eq!(std.flags.pic_scaling_matrix_present_flag(), 1);
+
+        let scaling_lists = unsafe { &*std.pScalingLists };
+        assert_eq!(
+            scaling_lists.ScalingList4x4,
+            ScalingLists::defaults().lists_4x4
+        );
+
+        assert!(pps(0, 0).to_std().as_std().pScalingLists.is_null());
+    }
+}
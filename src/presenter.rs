@@ -0,0 +1,1374 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::str::FromStr;
+use std::{mem, slice};
+
+use anyhow::{anyhow, Result};
+use ash::util::read_spv;
+use ash::{vk, Device};
+
+use crate::colour::ColourParams;
+use crate::decoder::{DecodedPicture, Decoder};
+use crate::sps::SequenceParameterSet;
+use crate::{find_memorytype_index, ExampleBase};
+
+/// Environment variable overriding the presenter picked from the format features, either `ycbcr`
+/// or `compute`.
+pub const PRESENTER_ENV: &str = "ASH_VIDEO_PRESENTER";
+
+/// How decoded pictures are turned into RGB for drawing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresenterKind {
+    /// Sampled through a `SamplerYcbcrConversion`, see [`YcbcrPresenter`].
+    Ycbcr,
+    /// Converted by a compute shader reading the planes on their own, see [`ComputePresenter`].
+    Compute,
+}
+
+impl FromStr for PresenterKind {
+    type Err = anyhow::Error;
+
+    fn from_str(kind: &str) -> Result<Self> {
+        match kind.to_ascii_lowercase().as_str() {
+            "ycbcr" => Ok(PresenterKind::Ycbcr),
+            "compute" => Ok(PresenterKind::Compute),
+            _ => Err(anyhow!(
+                "Unknown presenter {:?}, expected ycbcr or compute",
+                kind
+            )),
+        }
+    }
+}
+
+impl PresenterKind {
+    /// Picks the presenter for pictures with `format_features` in images created with
+    /// `image_flags`, `preferred` wins when it can present them.
+    ///
+    /// Without a preference the sampler conversion is used when the format supports it, the
+    /// compute shader needs the images to have a mutable format to view their planes. Images
+    /// created with `EXTENDED_USAGE` are only sampled through their plane views, see
+    /// [`presentable_usage`](crate::output_mode::presentable_usage).
+    pub fn select(
+        format_features: vk::FormatFeatureFlags,
+        image_flags: vk::ImageCreateFlags,
+        preferred: Option<PresenterKind>,
+    ) -> Result<Self> {
+        let supports = |kind: PresenterKind| match kind {
+            PresenterKind::Ycbcr => {
+                !image_flags.contains(vk::ImageCreateFlags::EXTENDED_USAGE)
+                    && format_features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE)
+                    && format_features.intersects(
+                        vk::FormatFeatureFlags::MIDPOINT_CHROMA_SAMPLES
+                            | vk::FormatFeatureFlags::COSITED_CHROMA_SAMPLES,
+                    )
+            }
+            PresenterKind::Compute => image_flags.contains(vk::ImageCreateFlags::MUTABLE_FORMAT),
+        };
+
+        if let Some(kind) = preferred {
+            if !supports(kind) {
+                return Err(anyhow!(
+                    "Presenter {:?} is not supported, the format features are {:?} and the image flags {:?}",
+                    kind,
+                    format_features,
+                    image_flags
+                ));
+            }
+
+            return Ok(kind);
+        }
+
+        [PresenterKind::Ycbcr, PresenterKind::Compute]
+            .into_iter()
+            .find(|&kind| supports(kind))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Decoded pictures can not be presented, the format features are {:?} and the image flags {:?}",
+                    format_features,
+                    image_flags
+                )
+            })
+    }
+}
+
+/// How the decoded samples turn into RGB, from the VUI of the SPS, ITU-T H.264 E.2.1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct YcbcrParams {
+    pub model: vk::SamplerYcbcrModelConversion,
+    pub range: vk::SamplerYcbcrRange,
+    /// Where the chroma samples sit relative to the luma samples.
+    pub x_chroma_offset: vk::ChromaLocation,
+    pub y_chroma_offset: vk::ChromaLocation,
+}
+
+impl YcbcrParams {
+    /// The matrix and range are the ones of `colour_params`, the chroma siting comes from the VUI
+    /// of `sps`.
+    pub fn from_sps(sps: &SequenceParameterSet, colour_params: &ColourParams) -> Self {
+        let vui = sps.vui.as_ref();
+
+        // Table E-5, FCC and SMPTE 240M are close enough to the BT.601 and BT.709 ones
+        let model = match colour_params.matrix_coefficients {
+            4..=6 => vk::SamplerYcbcrModelConversion::YCBCR_601,
+            9 | 10 => vk::SamplerYcbcrModelConversion::YCBCR_2020,
+            _ => vk::SamplerYcbcrModelConversion::YCBCR_709,
+        };
+
+        let range = if colour_params.video_full_range_flag {
+            vk::SamplerYcbcrRange::ITU_FULL
+        } else {
+            vk::SamplerYcbcrRange::ITU_NARROW
+        };
+
+        // Figure E-1, the bottom sited types 4 and 5 are taken as vertically centered
+        let chroma_sample_loc_type = vui
+            .and_then(|vui| vui.chroma_loc_info)
+            .map_or(0, |chroma_loc_info| {
+                chroma_loc_info.chroma_sample_loc_type_top_field
+            });
+        let (x_chroma_offset, y_chroma_offset) = match chroma_sample_loc_type {
+            1 | 5 => (vk::ChromaLocation::MIDPOINT, vk::ChromaLocation::MIDPOINT),
+            2 => (
+                vk::ChromaLocation::COSITED_EVEN,
+                vk::ChromaLocation::COSITED_EVEN,
+            ),
+            3 => (
+                vk::ChromaLocation::MIDPOINT,
+                vk::ChromaLocation::COSITED_EVEN,
+            ),
+            _ => (
+                vk::ChromaLocation::COSITED_EVEN,
+                vk::ChromaLocation::MIDPOINT,
+            ),
+        };
+
+        YcbcrParams {
+            model,
+            range,
+            x_chroma_offset,
+            y_chroma_offset,
+        }
+    }
+}
+
+/// The part of the decoded pictures shown and the shape it is shown in, from the frame cropping
+/// rectangle and the sample aspect ratio of the SPS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplayGeometry {
+    /// The frame cropping rectangle in samples of the coded picture.
+    pub crop: vk::Rect2D,
+    /// Width over height of the cropped picture once its samples are scaled to the sample aspect
+    /// ratio.
+    pub aspect_ratio: f64,
+}
+
+impl DisplayGeometry {
+    /// Samples are taken as square when the VUI leaves the sample aspect ratio out or
+    /// unspecified.
+    pub fn from_sps(sps: &SequenceParameterSet) -> Self {
+        let crop = sps.display_rect();
+        let (sar_width, sar_height) = sps
+            .vui
+            .as_ref()
+            .and_then(|vui| vui.aspect_ratio)
+            .and_then(|aspect_ratio| aspect_ratio.sample_aspect_ratio())
+            .unwrap_or((1, 1));
+
+        DisplayGeometry {
+            crop,
+            // in f64 as a 16-bit Extended_SAR times the width can overflow u32
+            aspect_ratio: crop.extent.width as f64 * sar_width as f64
+                / (crop.extent.height as f64 * sar_height as f64),
+        }
+    }
+
+    /// The top left and bottom right corners of the frame cropping rectangle in texture
+    /// coordinates of images of `extent`, which may be larger than the coded picture.
+    pub fn crop_coordinates(&self, extent: vk::Extent2D) -> ([f32; 2], [f32; 2]) {
+        let x = self.crop.offset.x as f32;
+        let y = self.crop.offset.y as f32;
+        let width = extent.width as f32;
+        let height = extent.height as f32;
+
+        (
+            [x / width, y / height],
+            [
+                (x + self.crop.extent.width as f32) / width,
+                (y + self.crop.extent.height as f32) / height,
+            ],
+        )
+    }
+
+    /// The largest viewport of the display aspect ratio centered in `target`, leaving bars above
+    /// and below or left and right of the picture when the shapes differ.
+    pub fn viewport(&self, target: vk::Extent2D) -> vk::Viewport {
+        let target_width = target.width as f64;
+        let target_height = target.height as f64;
+
+        let (width, height) = if self.aspect_ratio > target_width / target_height {
+            // Letterbox
+            (target_width, (target_width / self.aspect_ratio).round())
+        } else {
+            // Pillarbox
+            ((target_height * self.aspect_ratio).round(), target_height)
+        };
+
+        vk::Viewport {
+            x: ((target_width - width) / 2.0).floor() as f32,
+            y: ((target_height - height) / 2.0).floor() as f32,
+            width: width as f32,
+            height: height as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }
+    }
+}
+
+/// Samples decoded pictures through a `SamplerYcbcrConversion`, the fragment shader sees RGB.
+///
+/// The descriptor set layout holds the uniform buffer at binding 0 and the picture at binding 1,
+/// behind an immutable sampler as the conversion requires. Every image and array layer a picture
+/// is decoded into gets a view and a descriptor set of its own on its first draw, so sets in use
+/// by a draw in flight are never written.
+pub struct YcbcrPresenter {
+    device: Device,
+    pub conversion: vk::SamplerYcbcrConversion,
+    pub sampler: vk::Sampler,
+    pub descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    format: vk::Format,
+    uniform_buffer: vk::DescriptorBufferInfo,
+    // by image and array layer
+    pictures: HashMap<(vk::Image, u32), (vk::ImageView, vk::DescriptorSet)>,
+}
+
+impl YcbcrPresenter {
+    /// Sets up the conversion of `format` pictures, `picture_count` is the number of distinct
+    /// images and layers the decoder outputs.
+    pub fn new(
+        base: &ExampleBase,
+        format: vk::Format,
+        params: &YcbcrParams,
+        uniform_buffer: vk::DescriptorBufferInfo,
+        picture_count: usize,
+    ) -> Result<Self> {
+        unsafe {
+            let format_features = base
+                .instance
+                .get_physical_device_format_properties(base.pdevice, format)
+                .optimal_tiling_features;
+
+            // Chroma siting the format does not support falls back to the other one
+            let chroma_location = |location: vk::ChromaLocation| {
+                let supported = match location {
+                    vk::ChromaLocation::COSITED_EVEN => {
+                        vk::FormatFeatureFlags::COSITED_CHROMA_SAMPLES
+                    }
+                    _ => vk::FormatFeatureFlags::MIDPOINT_CHROMA_SAMPLES,
+                };
+                if format_features.contains(supported) {
+                    location
+                } else if location == vk::ChromaLocation::COSITED_EVEN {
+                    vk::ChromaLocation::MIDPOINT
+                } else {
+                    vk::ChromaLocation::COSITED_EVEN
+                }
+            };
+
+            // Without a separate reconstruction filter the sampler filters like the chroma
+            let filter = if format_features
+                .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_YCBCR_CONVERSION_LINEAR_FILTER)
+            {
+                vk::Filter::LINEAR
+            } else {
+                vk::Filter::NEAREST
+            };
+
+            let conversion_info = vk::SamplerYcbcrConversionCreateInfo {
+                format,
+                ycbcr_model: params.model,
+                ycbcr_range: params.range,
+                components: vk::ComponentMapping::default(),
+                x_chroma_offset: chroma_location(params.x_chroma_offset),
+                y_chroma_offset: chroma_location(params.y_chroma_offset),
+                chroma_filter: filter,
+                force_explicit_reconstruction: 0,
+                ..Default::default()
+            };
+            let conversion = base
+                .device
+                .create_sampler_ycbcr_conversion(&conversion_info, None)?;
+
+            let mut sampler_conversion_info = vk::SamplerYcbcrConversionInfo {
+                conversion,
+                ..Default::default()
+            };
+            let sampler_info = vk::SamplerCreateInfo {
+                p_next: &mut sampler_conversion_info as *mut _ as _,
+                mag_filter: filter,
+                min_filter: filter,
+                mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+                address_mode_u: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                address_mode_v: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                address_mode_w: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                max_anisotropy: 1.0,
+                border_color: vk::BorderColor::FLOAT_OPAQUE_BLACK,
+                compare_op: vk::CompareOp::NEVER,
+                ..Default::default()
+            };
+            let sampler = base.device.create_sampler(&sampler_info, None)?;
+
+            let immutable_samplers = [sampler];
+            let desc_layout_bindings = [
+                vk::DescriptorSetLayoutBinding {
+                    descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+                    descriptor_count: 1,
+                    stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                    ..Default::default()
+                },
+                vk::DescriptorSetLayoutBinding {
+                    binding: 1,
+                    descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    descriptor_count: 1,
+                    stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                    p_immutable_samplers: immutable_samplers.as_ptr(),
+                    ..Default::default()
+                },
+            ];
+            let descriptor_info =
+                vk::DescriptorSetLayoutCreateInfo::default().bindings(&desc_layout_bindings);
+            let descriptor_set_layout = base
+                .device
+                .create_descriptor_set_layout(&descriptor_info, None)?;
+
+            // A converted image may take up a combined image sampler descriptor per plane
+            let descriptor_sizes = [
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::UNIFORM_BUFFER,
+                    descriptor_count: picture_count as u32,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    descriptor_count: picture_count as u32 * 3,
+                },
+            ];
+            let descriptor_pool_info = vk::DescriptorPoolCreateInfo::default()
+                .pool_sizes(&descriptor_sizes)
+                .max_sets(picture_count as u32);
+            let descriptor_pool = base
+                .device
+                .create_descriptor_pool(&descriptor_pool_info, None)?;
+
+            Ok(YcbcrPresenter {
+                device: base.device.clone(),
+                conversion,
+                sampler,
+                descriptor_set_layout,
+                descriptor_pool,
+                format,
+                uniform_buffer,
+                pictures: HashMap::with_capacity(picture_count),
+            })
+        }
+    }
+
+    /// The descriptor set to draw `picture` with, sampled in `SHADER_READ_ONLY_OPTIMAL`.
+    pub fn descriptor_set(&mut self, picture: &DecodedPicture) -> Result<vk::DescriptorSet> {
+        let key = (picture.image, picture.array_layer);
+        if let Some(&(_, descriptor_set)) = self.pictures.get(&key) {
+            return Ok(descriptor_set);
+        }
+
+        unsafe {
+            // The images are used for decoding as well, the view is only ever sampled
+            let mut image_view_usage_create_info = vk::ImageViewUsageCreateInfo {
+                usage: vk::ImageUsageFlags::SAMPLED,
+                ..Default::default()
+            };
+            let mut conversion_info = vk::SamplerYcbcrConversionInfo {
+                p_next: &mut image_view_usage_create_info as *mut _ as _,
+                conversion: self.conversion,
+                ..Default::default()
+            };
+            let image_view_info = vk::ImageViewCreateInfo {
+                p_next: &mut conversion_info as *mut _ as _,
+                view_type: vk::ImageViewType::TYPE_2D,
+                format: self.format,
+                image: picture.image,
+                subresource_range: vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_array_layer: picture.array_layer,
+                    level_count: 1,
+                    layer_count: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let image_view = self.device.create_image_view(&image_view_info, None)?;
+
+            let set_layouts = [self.descriptor_set_layout];
+            let desc_alloc_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(self.descriptor_pool)
+                .set_layouts(&set_layouts);
+            let descriptor_set = self.device.allocate_descriptor_sets(&desc_alloc_info)?[0];
+
+            // The sampler is immutable, only the view is written
+            let image_descriptor = vk::DescriptorImageInfo {
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                image_view,
+                sampler: vk::Sampler::null(),
+            };
+            let write_desc_sets = [
+                vk::WriteDescriptorSet {
+                    dst_set: descriptor_set,
+                    descriptor_count: 1,
+                    descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+                    p_buffer_info: &self.uniform_buffer,
+                    ..Default::default()
+                },
+                vk::WriteDescriptorSet {
+                    dst_set: descriptor_set,
+                    dst_binding: 1,
+                    descriptor_count: 1,
+                    descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    p_image_info: &image_descriptor,
+                    ..Default::default()
+                },
+            ];
+            self.device.update_descriptor_sets(&write_desc_sets, &[]);
+
+            self.pictures.insert(key, (image_view, descriptor_set));
+
+            Ok(descriptor_set)
+        }
+    }
+
+    /// Records the transition of `picture` for sampling, outside the render pass of the draw.
+    pub fn cmd_begin_sampling(&self, command_buffer: vk::CommandBuffer, picture: &DecodedPicture) {
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[picture.begin_sampling_barrier()],
+            );
+        }
+    }
+
+    /// Records the transition of `picture` back after the draw sampled it.
+    pub fn cmd_end_sampling(&self, command_buffer: vk::CommandBuffer, picture: &DecodedPicture) {
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[picture.end_sampling_barrier()],
+            );
+        }
+    }
+
+    pub fn destroy(&self) {
+        unsafe {
+            for &(image_view, _) in self.pictures.values() {
+                self.device.destroy_image_view(image_view, None);
+            }
+            self.device
+                .destroy_descriptor_pool(self.descriptor_pool, None);
+            self.device
+                .destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            self.device.destroy_sampler(self.sampler, None);
+            self.device
+                .destroy_sampler_ycbcr_conversion(self.conversion, None);
+        }
+    }
+}
+
+/// The push constants of `shader/ycbcr/ycbcr.comp`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+struct ConversionConstants {
+    red: [f32; 4],
+    green: [f32; 4],
+    blue: [f32; 4],
+    luma_range: [f32; 2],
+    chroma_range: [f32; 2],
+    chroma_offset: [f32; 2],
+}
+
+impl ConversionConstants {
+    /// The conversion of `bit_depth` samples, `subsampling` tells which chroma dimensions are
+    /// halved.
+    fn new(params: &YcbcrParams, bit_depth: u32, subsampling: (bool, bool)) -> Result<Self> {
+        // Luma coefficients Kr and Kb, ITU-T H.273 8.3
+        let (kr, kb) = match params.model {
+            vk::SamplerYcbcrModelConversion::YCBCR_601 => (0.299, 0.114),
+            vk::SamplerYcbcrModelConversion::YCBCR_709 => (0.2126, 0.0722),
+            vk::SamplerYcbcrModelConversion::YCBCR_2020 => (0.2627, 0.0593),
+            model => return Err(anyhow!("Unsupported YCbCr model {:?}", model)),
+        };
+        let kg = 1.0 - kr - kb;
+
+        // Samples are normalized over their own bits, the narrow range scales with the bit depth
+        let max_value = ((1u32 << bit_depth) - 1) as f32;
+        let (luma_range, chroma_range) = match params.range {
+            vk::SamplerYcbcrRange::ITU_NARROW => {
+                let scale = (1u32 << (bit_depth - 8)) as f32;
+                (
+                    [16.0 * scale / max_value, max_value / (219.0 * scale)],
+                    [128.0 * scale / max_value, max_value / (224.0 * scale)],
+                )
+            }
+            _ => (
+                [0.0, 1.0],
+                [(1u32 << (bit_depth - 1)) as f32 / max_value, 1.0],
+            ),
+        };
+
+        // Luma sample centers map onto chroma sample centers with the siting of the chroma
+        let chroma_offset = |subsampled: bool, location: vk::ChromaLocation| {
+            if subsampled && location == vk::ChromaLocation::COSITED_EVEN {
+                1.0
+            } else {
+                0.5
+            }
+        };
+
+        Ok(ConversionConstants {
+            red: [1.0, 0.0, 2.0 * (1.0 - kr), 0.0],
+            green: [
+                1.0,
+                -2.0 * kb * (1.0 - kb) / kg,
+                -2.0 * kr * (1.0 - kr) / kg,
+                0.0,
+            ],
+            blue: [1.0, 2.0 * (1.0 - kb), 0.0, 0.0],
+            luma_range,
+            chroma_range,
+            chroma_offset: [
+                chroma_offset(subsampling.0, params.x_chroma_offset),
+                chroma_offset(subsampling.1, params.y_chroma_offset),
+            ],
+        })
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, mem::size_of::<Self>()) }
+    }
+}
+
+/// The luma and chroma plane formats of a two-plane format, its bit depth and which chroma
+/// dimensions are subsampled.
+fn plane_formats(format: vk::Format) -> Option<(vk::Format, vk::Format, u32, (bool, bool))> {
+    match format {
+        vk::Format::G8_B8R8_2PLANE_420_UNORM => Some((
+            vk::Format::R8_UNORM,
+            vk::Format::R8G8_UNORM,
+            8,
+            (true, true),
+        )),
+        vk::Format::G8_B8R8_2PLANE_422_UNORM => Some((
+            vk::Format::R8_UNORM,
+            vk::Format::R8G8_UNORM,
+            8,
+            (true, false),
+        )),
+        vk::Format::G10X6_B10X6R10X6_2PLANE_420_UNORM_3PACK16 => Some((
+            vk::Format::R10X6_UNORM_PACK16,
+            vk::Format::R10X6G10X6_UNORM_2PACK16,
+            10,
+            (true, true),
+        )),
+        vk::Format::G10X6_B10X6R10X6_2PLANE_422_UNORM_3PACK16 => Some((
+            vk::Format::R10X6_UNORM_PACK16,
+            vk::Format::R10X6G10X6_UNORM_2PACK16,
+            10,
+            (true, false),
+        )),
+        vk::Format::G12X4_B12X4R12X4_2PLANE_420_UNORM_3PACK16 => Some((
+            vk::Format::R12X4_UNORM_PACK16,
+            vk::Format::R12X4G12X4_UNORM_2PACK16,
+            12,
+            (true, true),
+        )),
+        vk::Format::G12X4_B12X4R12X4_2PLANE_422_UNORM_3PACK16 => Some((
+            vk::Format::R12X4_UNORM_PACK16,
+            vk::Format::R12X4G12X4_UNORM_2PACK16,
+            12,
+            (true, false),
+        )),
+        vk::Format::G16_B16R16_2PLANE_420_UNORM => Some((
+            vk::Format::R16_UNORM,
+            vk::Format::R16G16_UNORM,
+            16,
+            (true, true),
+        )),
+        vk::Format::G16_B16R16_2PLANE_422_UNORM => Some((
+            vk::Format::R16_UNORM,
+            vk::Format::R16G16_UNORM,
+            16,
+            (true, false),
+        )),
+        _ => None,
+    }
+}
+
+/// Converts decoded pictures to RGBA with a compute shader, for formats that can not be sampled
+/// through a `SamplerYcbcrConversion`.
+///
+/// The luma and chroma planes are viewed on their own, which takes images created with
+/// `MUTABLE_FORMAT`, and `shader/ycbcr/ycbcr.comp` writes the RGB picture to an image the draw
+/// samples with a plain sampler. The descriptor set layout of the draw is the one of
+/// [`YcbcrPresenter`], the uniform buffer at binding 0 and the picture at binding 1.
+pub struct ComputePresenter {
+    device: Device,
+    pub descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_set: vk::DescriptorSet,
+    sampler: vk::Sampler,
+    plane_sampler: vk::Sampler,
+    conversion_set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    descriptor_pool: vk::DescriptorPool,
+    // the RGBA picture drawn, written by every conversion
+    image: vk::Image,
+    memory: vk::DeviceMemory,
+    image_view: vk::ImageView,
+    extent: vk::Extent2D,
+    luma_format: vk::Format,
+    chroma_format: vk::Format,
+    constants: ConversionConstants,
+    // plane views and conversion descriptor set by image and array layer
+    pictures: HashMap<(vk::Image, u32), (vk::ImageView, vk::ImageView, vk::DescriptorSet)>,
+}
+
+impl ComputePresenter {
+    /// Sets up the conversion of `format` pictures of `extent`, `picture_count` is the number of
+    /// distinct images and layers the decoder outputs.
+    pub fn new(
+        base: &ExampleBase,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        params: &YcbcrParams,
+        uniform_buffer: vk::DescriptorBufferInfo,
+        picture_count: usize,
+    ) -> Result<Self> {
+        let (luma_format, chroma_format, bit_depth, subsampling) = plane_formats(format)
+            .ok_or_else(|| anyhow!("{:?} is not a two-plane YCbCr format", format))?;
+        let constants = ConversionConstants::new(params, bit_depth, subsampling)?;
+
+        unsafe {
+            let chroma_features = base
+                .instance
+                .get_physical_device_format_properties(base.pdevice, chroma_format)
+                .optimal_tiling_features;
+            let plane_filter =
+                if chroma_features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR) {
+                    vk::Filter::LINEAR
+                } else {
+                    vk::Filter::NEAREST
+                };
+
+            let sampler_info = |filter: vk::Filter| vk::SamplerCreateInfo {
+                mag_filter: filter,
+                min_filter: filter,
+                mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+                address_mode_u: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                address_mode_v: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                address_mode_w: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                max_anisotropy: 1.0,
+                border_color: vk::BorderColor::FLOAT_OPAQUE_BLACK,
+                compare_op: vk::CompareOp::NEVER,
+                ..Default::default()
+            };
+            let sampler = base
+                .device
+                .create_sampler(&sampler_info(vk::Filter::LINEAR), None)?;
+            let plane_sampler = base
+                .device
+                .create_sampler(&sampler_info(plane_filter), None)?;
+
+            let image_create_info = vk::ImageCreateInfo {
+                image_type: vk::ImageType::TYPE_2D,
+                format: vk::Format::R8G8B8A8_UNORM,
+                extent: extent.into(),
+                mip_levels: 1,
+                array_layers: 1,
+                samples: vk::SampleCountFlags::TYPE_1,
+                tiling: vk::ImageTiling::OPTIMAL,
+                usage: vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+                sharing_mode: vk::SharingMode::EXCLUSIVE,
+                ..Default::default()
+            };
+            let image = base.device.create_image(&image_create_info, None)?;
+            let memory_req = base.device.get_image_memory_requirements(image);
+            let memory_index = find_memorytype_index(
+                &memory_req,
+                &base.device_memory_properties,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )
+            .ok_or_else(|| {
+                anyhow!("Unable to find suitable memory index for the converted picture")
+            })?;
+            let allocate_info = vk::MemoryAllocateInfo {
+                allocation_size: memory_req.size,
+                memory_type_index: memory_index,
+                ..Default::default()
+            };
+            let memory = base.device.allocate_memory(&allocate_info, None)?;
+            base.device.bind_image_memory(image, memory, 0)?;
+
+            let image_view_info = vk::ImageViewCreateInfo {
+                view_type: vk::ImageViewType::TYPE_2D,
+                format: image_create_info.format,
+                image,
+                subresource_range: vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    level_count: 1,
+                    layer_count: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let image_view = base.device.create_image_view(&image_view_info, None)?;
+
+            let desc_layout_bindings = [
+                vk::DescriptorSetLayoutBinding {
+                    descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+                    descriptor_count: 1,
+                    stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                    ..Default::default()
+                },
+                vk::DescriptorSetLayoutBinding {
+                    binding: 1,
+                    descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    descriptor_count: 1,
+                    stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                    ..Default::default()
+                },
+            ];
+            let descriptor_info =
+                vk::DescriptorSetLayoutCreateInfo::default().bindings(&desc_layout_bindings);
+            let descriptor_set_layout = base
+                .device
+                .create_descriptor_set_layout(&descriptor_info, None)?;
+
+            let immutable_samplers = [plane_sampler];
+            let conversion_layout_bindings = [
+                vk::DescriptorSetLayoutBinding {
+                    descriptor_type: vk::DescriptorType::SAMPLED_IMAGE,
+                    descriptor_count: 1,
+                    stage_flags: vk::ShaderStageFlags::COMPUTE,
+                    ..Default::default()
+                },
+                vk::DescriptorSetLayoutBinding {
+                    binding: 1,
+                    descriptor_type: vk::DescriptorType::SAMPLED_IMAGE,
+                    descriptor_count: 1,
+                    stage_flags: vk::ShaderStageFlags::COMPUTE,
+                    ..Default::default()
+                },
+                vk::DescriptorSetLayoutBinding {
+                    binding: 2,
+                    descriptor_type: vk::DescriptorType::SAMPLER,
+                    descriptor_count: 1,
+                    stage_flags: vk::ShaderStageFlags::COMPUTE,
+                    p_immutable_samplers: immutable_samplers.as_ptr(),
+                    ..Default::default()
+                },
+                vk::DescriptorSetLayoutBinding {
+                    binding: 3,
+                    descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+                    descriptor_count: 1,
+                    stage_flags: vk::ShaderStageFlags::COMPUTE,
+                    ..Default::default()
+                },
+            ];
+            let conversion_layout_info =
+                vk::DescriptorSetLayoutCreateInfo::default().bindings(&conversion_layout_bindings);
+            let conversion_set_layout = base
+                .device
+                .create_descriptor_set_layout(&conversion_layout_info, None)?;
+
+            let push_constant_ranges = [vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::COMPUTE,
+                offset: 0,
+                size: mem::size_of::<ConversionConstants>() as u32,
+            }];
+            let set_layouts = [conversion_set_layout];
+            let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default()
+                .set_layouts(&set_layouts)
+                .push_constant_ranges(&push_constant_ranges);
+            let pipeline_layout = base
+                .device
+                .create_pipeline_layout(&pipeline_layout_info, None)?;
+
+            let mut comp_spv_file = Cursor::new(&include_bytes!("../shader/ycbcr/comp.spv")[..]);
+            let comp_code = read_spv(&mut comp_spv_file)?;
+            let comp_shader_info = vk::ShaderModuleCreateInfo::default().code(&comp_code);
+            let comp_shader_module = base.device.create_shader_module(&comp_shader_info, None)?;
+
+            let shader_entry_name = c"main";
+            let compute_pipeline_info = vk::ComputePipelineCreateInfo::default()
+                .stage(vk::PipelineShaderStageCreateInfo {
+                    module: comp_shader_module,
+                    p_name: shader_entry_name.as_ptr(),
+                    stage: vk::ShaderStageFlags::COMPUTE,
+                    ..Default::default()
+                })
+                .layout(pipeline_layout);
+            let pipelines = base.device.create_compute_pipelines(
+                vk::PipelineCache::null(),
+                &[compute_pipeline_info],
+                None,
+            );
+            base.device.destroy_shader_module(comp_shader_module, None);
+            let pipeline = pipelines.map_err(|(_, err)| err)?[0];
+
+            // A set for the draw and one per picture for its conversion
+            let descriptor_sizes = [
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::UNIFORM_BUFFER,
+                    descriptor_count: 1,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    descriptor_count: 1,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::SAMPLED_IMAGE,
+                    descriptor_count: picture_count as u32 * 2,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::SAMPLER,
+                    descriptor_count: picture_count as u32,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::STORAGE_IMAGE,
+                    descriptor_count: picture_count as u32,
+                },
+            ];
+            let descriptor_pool_info = vk::DescriptorPoolCreateInfo::default()
+                .pool_sizes(&descriptor_sizes)
+                .max_sets(picture_count as u32 + 1);
+            let descriptor_pool = base
+                .device
+                .create_descriptor_pool(&descriptor_pool_info, None)?;
+
+            let set_layouts = [descriptor_set_layout];
+            let desc_alloc_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(descriptor_pool)
+                .set_layouts(&set_layouts);
+            let descriptor_set = base.device.allocate_descriptor_sets(&desc_alloc_info)?[0];
+
+            let image_descriptor = vk::DescriptorImageInfo {
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                image_view,
+                sampler,
+            };
+            let write_desc_sets = [
+                vk::WriteDescriptorSet {
+                    dst_set: descriptor_set,
+                    descriptor_count: 1,
+                    descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+                    p_buffer_info: &uniform_buffer,
+                    ..Default::default()
+                },
+                vk::WriteDescriptorSet {
+                    dst_set: descriptor_set,
+                    dst_binding: 1,
+                    descriptor_count: 1,
+                    descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    p_image_info: &image_descriptor,
+                    ..Default::default()
+                },
+            ];
+            base.device.update_descriptor_sets(&write_desc_sets, &[]);
+
+            Ok(ComputePresenter {
+                device: base.device.clone(),
+                descriptor_set_layout,
+                descriptor_set,
+                sampler,
+                plane_sampler,
+                conversion_set_layout,
+                pipeline_layout,
+                pipeline,
+                descriptor_pool,
+                image,
+                memory,
+                image_view,
+                extent,
+                luma_format,
+                chroma_format,
+                constants,
+                pictures: HashMap::with_capacity(picture_count),
+            })
+        }
+    }
+
+    /// The descriptor set to draw the conversion of `picture` with, the converted picture is
+    /// sampled in `SHADER_READ_ONLY_OPTIMAL`.
+    pub fn descriptor_set(&mut self, picture: &DecodedPicture) -> Result<vk::DescriptorSet> {
+        let key = (picture.image, picture.array_layer);
+        if self.pictures.contains_key(&key) {
+            return Ok(self.descriptor_set);
+        }
+
+        unsafe {
+            // The images are used for decoding as well, the plane views are only ever sampled
+            let mut image_view_usage_create_info = vk::ImageViewUsageCreateInfo {
+                usage: vk::ImageUsageFlags::SAMPLED,
+                ..Default::default()
+            };
+            let mut plane_view = |format: vk::Format, aspect_mask: vk::ImageAspectFlags| {
+                let image_view_info = vk::ImageViewCreateInfo {
+                    p_next: &mut image_view_usage_create_info as *mut _ as _,
+                    view_type: vk::ImageViewType::TYPE_2D,
+                    format,
+                    image: picture.image,
+                    subresource_range: vk::ImageSubresourceRange {
+                        aspect_mask,
+                        base_array_layer: picture.array_layer,
+                        level_count: 1,
+                        layer_count: 1,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                self.device.create_image_view(&image_view_info, None)
+            };
+            let luma_view = plane_view(self.luma_format, vk::ImageAspectFlags::PLANE_0)?;
+            let chroma_view = plane_view(self.chroma_format, vk::ImageAspectFlags::PLANE_1)?;
+
+            let set_layouts = [self.conversion_set_layout];
+            let desc_alloc_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(self.descriptor_pool)
+                .set_layouts(&set_layouts);
+            let conversion_set = self.device.allocate_descriptor_sets(&desc_alloc_info)?[0];
+
+            // The plane sampler is immutable, only the views are written
+            let plane_descriptor = |image_view: vk::ImageView| vk::DescriptorImageInfo {
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                image_view,
+                sampler: vk::Sampler::null(),
+            };
+            let luma_descriptor = plane_descriptor(luma_view);
+            let chroma_descriptor = plane_descriptor(chroma_view);
+            let output_descriptor = vk::DescriptorImageInfo {
+                image_layout: vk::ImageLayout::GENERAL,
+                image_view: self.image_view,
+                sampler: vk::Sampler::null(),
+            };
+            let write_desc_sets = [
+                vk::WriteDescriptorSet {
+                    dst_set: conversion_set,
+                    descriptor_count: 1,
+                    descriptor_type: vk::DescriptorType::SAMPLED_IMAGE,
+                    p_image_info: &luma_descriptor,
+                    ..Default::default()
+                },
+                vk::WriteDescriptorSet {
+                    dst_set: conversion_set,
+                    dst_binding: 1,
+                    descriptor_count: 1,
+                    descriptor_type: vk::DescriptorType::SAMPLED_IMAGE,
+                    p_image_info: &chroma_descriptor,
+                    ..Default::default()
+                },
+                vk::WriteDescriptorSet {
+                    dst_set: conversion_set,
+                    dst_binding: 3,
+                    descriptor_count: 1,
+                    descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+                    p_image_info: &output_descriptor,
+                    ..Default::default()
+                },
+            ];
+            self.device.update_descriptor_sets(&write_desc_sets, &[]);
+
+            self.pictures
+                .insert(key, (luma_view, chroma_view, conversion_set));
+
+            Ok(self.descriptor_set)
+        }
+    }
+
+    /// Records the conversion of `picture`, outside the render pass of the draw.
+    /// [`Self::descriptor_set`] has to have been called with it first.
+    pub fn cmd_begin_sampling(&self, command_buffer: vk::CommandBuffer, picture: &DecodedPicture) {
+        let Some(&(_, _, conversion_set)) =
+            self.pictures.get(&(picture.image, picture.array_layer))
+        else {
+            return;
+        };
+
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            level_count: 1,
+            layer_count: 1,
+            ..Default::default()
+        };
+
+        unsafe {
+            // The previous conversion was drawn by now, it is overwritten as a whole
+            let output_barrier = vk::ImageMemoryBarrier {
+                dst_access_mask: vk::AccessFlags::SHADER_WRITE,
+                old_layout: vk::ImageLayout::UNDEFINED,
+                new_layout: vk::ImageLayout::GENERAL,
+                src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+                dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+                image: self.image,
+                subresource_range,
+                ..Default::default()
+            };
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[picture.begin_sampling_barrier(), output_barrier],
+            );
+
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline,
+            );
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[conversion_set],
+                &[],
+            );
+            self.device.cmd_push_constants(
+                command_buffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                self.constants.as_bytes(),
+            );
+            // 8 by 8 invocations per workgroup
+            self.device.cmd_dispatch(
+                command_buffer,
+                self.extent.width.div_ceil(8),
+                self.extent.height.div_ceil(8),
+                1,
+            );
+
+            let output_barrier = vk::ImageMemoryBarrier {
+                src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                dst_access_mask: vk::AccessFlags::SHADER_READ,
+                old_layout: vk::ImageLayout::GENERAL,
+                new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+                dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+                image: self.image,
+                subresource_range,
+                ..Default::default()
+            };
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[picture.end_sampling_barrier(), output_barrier],
+            );
+        }
+    }
+
+    pub fn destroy(&self) {
+        unsafe {
+            for &(luma_view, chroma_view, _) in self.pictures.values() {
+                self.device.destroy_image_view(luma_view, None);
+                self.device.destroy_image_view(chroma_view, None);
+            }
+            self.device
+                .destroy_descriptor_pool(self.descriptor_pool, None);
+            self.device.destroy_pipeline(self.pipeline, None);
+            self.device
+                .destroy_pipeline_layout(self.pipeline_layout, None);
+            self.device
+                .destroy_descriptor_set_layout(self.conversion_set_layout, None);
+            self.device
+                .destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            self.device.destroy_image_view(self.image_view, None);
+            self.device.destroy_image(self.image, None);
+            self.device.free_memory(self.memory, None);
+            self.device.destroy_sampler(self.plane_sampler, None);
+            self.device.destroy_sampler(self.sampler, None);
+        }
+    }
+}
+
+/// The presenter decoded pictures are drawn with, see [`PresenterKind`].
+pub enum Presenter {
+    Ycbcr(YcbcrPresenter),
+    Compute(ComputePresenter),
+}
+
+impl Presenter {
+    /// Sets up the presenter for the pictures `decoder` outputs, `preferred` as in
+    /// [`PresenterKind::select`].
+    pub fn new(
+        base: &ExampleBase,
+        decoder: &Decoder,
+        params: &YcbcrParams,
+        uniform_buffer: vk::DescriptorBufferInfo,
+        preferred: Option<PresenterKind>,
+    ) -> Result<Self> {
+        let format = decoder.output_format();
+        let format_features = unsafe {
+            base.instance
+                .get_physical_device_format_properties(base.pdevice, format)
+                .optimal_tiling_features
+        };
+
+        match PresenterKind::select(format_features, decoder.output_image_flags(), preferred)? {
+            PresenterKind::Ycbcr => Ok(Presenter::Ycbcr(YcbcrPresenter::new(
+                base,
+                format,
+                params,
+                uniform_buffer,
+                decoder.output_picture_count(),
+            )?)),
+            PresenterKind::Compute => Ok(Presenter::Compute(ComputePresenter::new(
+                base,
+                format,
+                decoder.output_extent(),
+                params,
+                uniform_buffer,
+                decoder.output_picture_count(),
+            )?)),
+        }
+    }
+
+    pub fn descriptor_set_layout(&self) -> vk::DescriptorSetLayout {
+        match self {
+            Presenter::Ycbcr(presenter) => presenter.descriptor_set_layout,
+            Presenter::Compute(presenter) => presenter.descriptor_set_layout,
+        }
+    }
+
+    /// The stage that first reads decoded pictures, the acquire barriers wait for it.
+    pub fn sampling_stage(&self) -> vk::PipelineStageFlags {
+        match self {
+            Presenter::Ycbcr(_) => vk::PipelineStageFlags::FRAGMENT_SHADER,
+            Presenter::Compute(_) => vk::PipelineStageFlags::COMPUTE_SHADER,
+        }
+    }
+
+    pub fn descriptor_set(&mut self, picture: &DecodedPicture) -> Result<vk::DescriptorSet> {
+        match self {
+            Presenter::Ycbcr(presenter) => presenter.descriptor_set(picture),
+            Presenter::Compute(presenter) => presenter.descriptor_set(picture),
+        }
+    }
+
+    /// Records what comes before the render pass of a draw of `picture`.
+    pub fn cmd_begin_sampling(&self, command_buffer: vk::CommandBuffer, picture: &DecodedPicture) {
+        match self {
+            Presenter::Ycbcr(presenter) => presenter.cmd_begin_sampling(command_buffer, picture),
+            Presenter::Compute(presenter) => presenter.cmd_begin_sampling(command_buffer, picture),
+        }
+    }
+
+    /// Records what comes after the render pass of a draw of `picture`.
+    pub fn cmd_end_sampling(&self, command_buffer: vk::CommandBuffer, picture: &DecodedPicture) {
+        match self {
+            Presenter::Ycbcr(presenter) => presenter.cmd_end_sampling(command_buffer, picture),
+            // The conversion is done with the picture before the draw
+            Presenter::Compute(_) => {}
+        }
+    }
+
+    pub fn destroy(&self) {
+        match self {
+            Presenter::Ycbcr(presenter) => presenter.destroy(),
+            Presenter::Compute(presenter) => presenter.destroy(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sps::{AspectRatio, FrameCropping};
+    use crate::test_util::{sps, vui};
+
+    fn geometry(
+        aspect_ratio: Option<(u8, u16, u16)>,
+        frame_cropping: Option<[u32; 4]>,
+    ) -> DisplayGeometry {
+        let mut sps = sps(0);
+        sps.frame_cropping =
+            frame_cropping.map(|[left_offset, right_offset, top_offset, bottom_offset]| {
+                FrameCropping {
+                    left_offset,
+                    right_offset,
+                    top_offset,
+                    bottom_offset,
+                }
+            });
+        sps.vui = aspect_ratio.map(|(aspect_ratio_idc, sar_width, sar_height)| {
+            let mut vui = vui(0, 4);
+            vui.aspect_ratio = Some(AspectRatio {
+                aspect_ratio_idc,
+                sar_width,
+                sar_height,
+            });
+            vui
+        });
+
+        DisplayGeometry::from_sps(&sps)
+    }
+
+    fn extent(width: u32, height: u32) -> vk::Extent2D {
+        vk::Extent2D { width, height }
+    }
+
+    #[test]
+    fn select() {
+        use vk::FormatFeatureFlags as Features;
+        use vk::ImageCreateFlags as CreateFlags;
+
+        let sampled = Features::SAMPLED_IMAGE | Features::MIDPOINT_CHROMA_SAMPLES;
+        let mutable = CreateFlags::MUTABLE_FORMAT;
+        let plane_views = CreateFlags::MUTABLE_FORMAT | CreateFlags::EXTENDED_USAGE;
+        let (ycbcr, compute) = (Some(PresenterKind::Ycbcr), Some(PresenterKind::Compute));
+
+        let cases = [
+            (sampled, mutable, None, Some(PresenterKind::Ycbcr)),
+            (sampled, mutable, compute, Some(PresenterKind::Compute)),
+            (sampled, CreateFlags::empty(), compute, None),
+            (
+                Features::SAMPLED_IMAGE,
+                mutable,
+                None,
+                Some(PresenterKind::Compute),
+            ),
+            (Features::SAMPLED_IMAGE, mutable, ycbcr, None),
+            // Only the planes of images with extended usage can be sampled
+            (sampled, plane_views, None, Some(PresenterKind::Compute)),
+            (sampled, plane_views, ycbcr, None),
+            (Features::empty(), CreateFlags::empty(), None, None),
+        ];
+
+        for (format_features, image_flags, preferred, expected) in cases {
+            assert_eq!(
+                PresenterKind::select(format_features, image_flags, preferred).ok(),
+                expected,
+                "{:?} {:?} {:?}",
+                format_features,
+                image_flags,
+                preferred
+            );
+        }
+    }
+
+    #[test]
+    fn from_sps() {
+        let square = geometry(None, None);
+        assert_eq!(square.crop.offset, vk::Offset2D::default());
+        assert_eq!(square.crop.extent, extent(320, 240));
+        assert_eq!(square.aspect_ratio, 4.0 / 3.0);
+
+        // 4:3 coded pictures of 16:11 samples are shown 64:33
+        assert_eq!(geometry(Some((4, 0, 0)), None).aspect_ratio, 64.0 / 33.0);
+        assert_eq!(geometry(Some((255, 3, 2)), None).aspect_ratio, 2.0);
+        // Unspecified and reserved sample aspect ratios are square
+        for aspect_ratio in [(0, 0, 0), (17, 0, 0), (255, 0, 1), (255, 1, 0)] {
+            assert_eq!(
+                geometry(Some(aspect_ratio), None).aspect_ratio,
+                4.0 / 3.0,
+                "{:?}",
+                aspect_ratio
+            );
+        }
+
+        // Crop units of 4:2:0 frames are 2 samples
+        let cropped = geometry(None, Some([2, 6, 1, 3]));
+        assert_eq!(cropped.crop.offset, vk::Offset2D { x: 4, y: 2 });
+        assert_eq!(cropped.crop.extent, extent(304, 232));
+        assert_eq!(cropped.aspect_ratio, 304.0 / 232.0);
+
+        let mut wide = sps(0);
+        wide.pic_width_in_mbs_minus1 = 4999;
+        wide.vui = Some(vui(0, 4));
+        wide.vui.as_mut().unwrap().aspect_ratio = Some(AspectRatio {
+            aspect_ratio_idc: 255,
+            sar_width: u16::MAX,
+            sar_height: 1,
+        });
+        assert_eq!(
+            DisplayGeometry::from_sps(&wide).aspect_ratio,
+            80000.0 * 65535.0 / 240.0
+        );
+    }
+
+    #[test]
+    fn crop_coordinates() {
+        let full = geometry(None, None);
+        assert_eq!(
+            full.crop_coordinates(extent(320, 240)),
+            ([0.0, 0.0], [1.0, 1.0])
+        );
+        // Images larger than the coded picture
+        assert_eq!(
+            full.crop_coordinates(extent(640, 480)),
+            ([0.0, 0.0], [0.5, 0.5])
+        );
+
+        let cropped = geometry(None, Some([2, 6, 1, 3]));
+        assert_eq!(
+            cropped.crop_coordinates(extent(320, 240)),
+            ([4.0 / 320.0, 2.0 / 240.0], [308.0 / 320.0, 234.0 / 240.0])
+        );
+    }
+
+    #[test]
+    fn viewport() {
+        let viewport = |geometry: DisplayGeometry, width, height| {
+            let viewport = geometry.viewport(extent(width, height));
+            assert_eq!((viewport.min_depth, viewport.max_depth), (0.0, 1.0));
+            (viewport.x, viewport.y, viewport.width, viewport.height)
+        };
+        let square = geometry(None, None);
+
+        assert_eq!(viewport(square, 320, 240), (0.0, 0.0, 320.0, 240.0));
+        assert_eq!(viewport(square, 640, 480), (0.0, 0.0, 640.0, 480.0));
+        // Pillarbox in a wider target
+        assert_eq!(viewport(square, 1920, 1080), (240.0, 0.0, 1440.0, 1080.0));
+        // Letterbox in a taller target
+        assert_eq!(viewport(square, 800, 800), (0.0, 100.0, 800.0, 600.0));
+        // Odd bars round down on the leading side
+        assert_eq!(viewport(square, 801, 300), (200.0, 0.0, 400.0, 300.0));
+        assert_eq!(viewport(square, 400, 301), (0.0, 0.0, 400.0, 300.0));
+
+        // 64:33 anamorphic pictures fill a wider target and letterbox in a 16:9 one
+        let anamorphic = geometry(Some((4, 0, 0)), None);
+        assert_eq!(viewport(anamorphic, 1280, 660), (0.0, 0.0, 1280.0, 660.0));
+        assert_eq!(viewport(anamorphic, 1280, 720), (0.0, 30.0, 1280.0, 660.0));
+        assert_eq!(
+            viewport(anamorphic, 1280, 1280),
+            (0.0, 310.0, 1280.0, 660.0)
+        );
+        assert_eq!(viewport(anamorphic, 2560, 660), (640.0, 0.0, 1280.0, 660.0));
+
+        // The cropped rectangle sets the shape
+        let cropped = geometry(None, Some([0, 40, 0, 0]));
+        assert_eq!(cropped.crop.extent, extent(240, 240));
+        assert_eq!(viewport(cropped, 640, 480), (80.0, 0.0, 480.0, 480.0));
+    }
+}
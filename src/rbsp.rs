@@ -0,0 +1,437 @@
+use anyhow::{anyhow, Result};
+
+/// Strips the emulation_prevention_three_byte from every 0x000003 sequence of a NAL unit, ITU-T H.264 7.3.1.
+///
+/// The result is the raw byte sequence payload the syntax elements are read from.
+pub fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zero_count = 0;
+
+    for &byte in data {
+        if zero_count >= 2 && byte == 0x03 {
+            zero_count = 0;
+            continue;
+        }
+
+        zero_count = if byte == 0 { zero_count + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+
+    rbsp
+}
+
+/// Reads the syntax elements of an RBSP, MSB first, ITU-T H.264 7.2.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    // in bits
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        BitReader { data, position: 0 }
+    }
+
+    /// Number of bits consumed so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn bits_left(&self) -> usize {
+        self.data.len() * 8 - self.position
+    }
+
+    pub fn is_byte_aligned(&self) -> bool {
+        self.position & 0b111 == 0
+    }
+
+    /// Skips to the next byte boundary, does nothing when already aligned.
+    pub fn byte_align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+
+    pub fn skip(&mut self, n: usize) -> Result<()> {
+        if n > self.bits_left() {
+            return Err(anyhow!(
+                "RBSP truncated skipping {} bits at bit {}: {} left",
+                n,
+                self.position,
+                self.bits_left()
+            ));
+        }
+
+        self.position += n;
+
+        Ok(())
+    }
+
+    /// u(n), at most 32 bits.
+    pub fn u(&mut self, n: usize) -> Result<u32> {
+        if n > 32 {
+            return Err(anyhow!("Cannot read {} bits into a u32", n));
+        }
+        if n > self.bits_left() {
+            return Err(anyhow!(
+                "RBSP truncated reading {} bits at bit {}: {} left",
+                n,
+                self.position,
+                self.bits_left()
+            ));
+        }
+
+        let mut value = 0u64;
+        for _ in 0..n {
+            let bit = (self.data[self.position / 8] >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.position += 1;
+        }
+
+        Ok(value as u32)
+    }
+
+    /// u(1)
+    pub fn flag(&mut self) -> Result<bool> {
+        Ok(self.u(1)? == 1)
+    }
+
+    /// ue(v), unsigned Exp-Golomb code, ITU-T H.264 9.1.
+    pub fn ue(&mut self) -> Result<u32> {
+        let start = self.position;
+        let mut leading_zero_bits = 0;
+
+        while !self.flag()? {
+            leading_zero_bits += 1;
+
+            // 2^32 - 1 and above do not fit, the largest legal value is 2^32 - 2
+            if leading_zero_bits > 31 {
+                return Err(anyhow!(
+                    "Exp-Golomb code at bit {} has more than 31 leading zero bits",
+                    start
+                ));
+            }
+        }
+
+        let suffix = self.u(leading_zero_bits)? as u64;
+        let value = (1u64 << leading_zero_bits) - 1 + suffix;
+
+        u32::try_from(value)
+            .map_err(|_| anyhow!("Exp-Golomb code at bit {} overflows: {}", start, value))
+    }
+
+    /// se(v), signed Exp-Golomb code mapped as in ITU-T H.264 Table 9-3.
+    pub fn se(&mut self) -> Result<i32> {
+        let code_num = self.ue()? as i64;
+
+        let value = if code_num % 2 == 1 {
+            (code_num + 1) / 2
+        } else {
+            -(code_num / 2)
+        };
+
+        Ok(value as i32)
+    }
+
+    /// ue(v) checked against an inclusive upper bound, for the many elements the spec limits to a range.
+    pub fn ue_max(&mut self, max: u32, what: &str) -> Result<u32> {
+        let value = self.ue()?;
+        if value > max {
+            return Err(anyhow!(
+                "{} of {} exceeds the maximum of {}",
+                what,
+                value,
+                max
+            ));
+        }
+
+        Ok(value)
+    }
+
+    /// se(v) checked against an inclusive range.
+    pub fn se_range(&mut self, min: i32, max: i32, what: &str) -> Result<i32> {
+        let value = self.se()?;
+        if value < min || value > max {
+            return Err(anyhow!(
+                "{} of {} is outside of {}..={}",
+                what,
+                value,
+                min,
+                max
+            ));
+        }
+
+        Ok(value)
+    }
+
+    /// Whether syntax elements remain before the rbsp_stop_one_bit, ITU-T H.264 7.2.
+    pub fn more_rbsp_data(&self) -> bool {
+        // Trailing zero bytes are cabac_zero_words or trailing_zero_8bits, not part of the payload
+        let last_byte = match self.data.iter().rposition(|&byte| byte != 0) {
+            Some(last_byte) => last_byte,
+            None => return false,
+        };
+
+        let stop_bit = last_byte * 8 + 7 - self.data[last_byte].trailing_zeros() as usize;
+
+        self.position < stop_bit
+    }
+
+    /// Consumes rbsp_trailing_bits, the stop bit followed by zero bits up to the byte boundary.
+    pub fn rbsp_trailing_bits(&mut self) -> Result<()> {
+        if !self.flag()? {
+            return Err(anyhow!(
+                "Missing rbsp_stop_one_bit at bit {}",
+                self.position - 1
+            ));
+        }
+
+        while !self.is_byte_aligned() {
+            if self.flag()? {
+                return Err(anyhow!(
+                    "Non zero rbsp_alignment_zero_bit at bit {}",
+                    self.position - 1
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes syntax elements MSB first, for building bitstreams in tests.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    bits: Vec<bool>,
+}
+
+#[cfg(test)]
+impl BitWriter {
+    pub fn u(&mut self, n: usize, value: u64) -> &mut Self {
+        self.bits
+            .extend((0..n).rev().map(|bit| (value >> bit) & 1 == 1));
+        self
+    }
+
+    pub fn flag(&mut self, value: bool) -> &mut Self {
+        self.bits.push(value);
+        self
+    }
+
+    pub fn ue(&mut self, value: u32) -> &mut Self {
+        let code = value as u64 + 1;
+        let len = 64 - code.leading_zeros() as usize;
+        self.u(len - 1, 0).u(len, code)
+    }
+
+    pub fn se(&mut self, value: i32) -> &mut Self {
+        let code_num = if value > 0 {
+            2 * value as i64 - 1
+        } else {
+            -2 * value as i64
+        };
+        self.ue(code_num as u32)
+    }
+
+    /// The bits written so far, zero padded to a byte boundary.
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; self.bits.len().div_ceil(8)];
+        for (position, _) in self.bits.iter().enumerate().filter(|(_, &bit)| bit) {
+            bytes[position / 8] |= 0x80 >> (position % 8);
+        }
+        bytes
+    }
+
+    /// Appends rbsp_trailing_bits and returns the RBSP.
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.flag(true);
+        self.bytes()
+    }
+
+    /// Appends rbsp_trailing_bits and returns the NAL unit with the `header` byte, emulation
+    /// prevention bytes inserted.
+    pub fn nal_unit(&mut self, header: u8) -> Vec<u8> {
+        let mut nal_unit = vec![header];
+        let mut zeros = 0;
+
+        for byte in self.finish() {
+            if zeros >= 2 && byte <= 3 {
+                nal_unit.push(3);
+                zeros = 0;
+            }
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+            nal_unit.push(byte);
+        }
+
+        nal_unit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emulation_prevention() {
+        let cases: [(&[u8], &[u8]); 7] = [
+            (&[0x00, 0x00, 0x03, 0x01], &[0x00, 0x00, 0x01]),
+            // At the end, as in a trailing cabac_zero_word
+            (&[0x25, 0x00, 0x00, 0x03], &[0x25, 0x00, 0x00]),
+            // Back to back
+            (
+                &[0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00],
+                &[0x00, 0x00, 0x00, 0x00, 0x00],
+            ),
+            // The zero count restarts after a removed byte
+            (&[0x00, 0x00, 0x03, 0x03], &[0x00, 0x00, 0x03]),
+            (&[0x00, 0x00, 0x00, 0x03], &[0x00, 0x00, 0x00]),
+            // A single zero does not make a sequence
+            (&[0x00, 0x03, 0x00, 0x03], &[0x00, 0x03, 0x00, 0x03]),
+            (&[], &[]),
+        ];
+
+        for (data, rbsp) in cases {
+            assert_eq!(remove_emulation_prevention(data), rbsp, "{:02x?}", data);
+        }
+    }
+
+    #[test]
+    fn fixed_length() {
+        let data = [0x12, 0x34, 0x56, 0x78, 0x9a];
+        let mut reader = BitReader::new(&data);
+
+        assert_eq!(reader.u(0).unwrap(), 0);
+        assert_eq!(reader.u(4).unwrap(), 0x1);
+        assert!(!reader.is_byte_aligned());
+        assert_eq!(reader.u(32).unwrap(), 0x23456789);
+        assert!(reader.flag().unwrap());
+        assert_eq!(reader.position(), 37);
+        assert_eq!(reader.bits_left(), 3);
+        assert!(reader.u(33).is_err());
+    }
+
+    #[test]
+    fn byte_alignment() {
+        let data = [0xff, 0x0f];
+        let mut reader = BitReader::new(&data);
+
+        reader.byte_align();
+        assert_eq!(reader.position(), 0);
+        reader.skip(1).unwrap();
+        reader.byte_align();
+        assert_eq!(reader.position(), 8);
+        assert!(reader.is_byte_aligned());
+        assert_eq!(reader.u(8).unwrap(), 0x0f);
+    }
+
+    #[test]
+    fn ue() {
+        let values = (0..=300).chain([0xffff, 1 << 20, u32::MAX - 1]);
+        let mut writer = BitWriter::default();
+        for value in values.clone() {
+            writer.ue(value);
+        }
+        let data = writer.bytes();
+
+        let mut reader = BitReader::new(&data);
+        for value in values {
+            assert_eq!(reader.ue().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn ue_limits() {
+        // 2^32 - 1, 32 leading zero bits
+        let data = [0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00];
+        assert!(BitReader::new(&data).ue().is_err());
+
+        // 31 leading zero bits and an all ones suffix is the largest code that fits
+        let data = [0x00, 0x00, 0x00, 0x01, 0xff, 0xff, 0xff, 0xfe];
+        assert_eq!(BitReader::new(&data).ue().unwrap(), u32::MAX - 1);
+    }
+
+    #[test]
+    fn se() {
+        // Table 9-3
+        let data = BitWriter::default()
+            .ue(0)
+            .ue(1)
+            .ue(2)
+            .ue(3)
+            .ue(4)
+            .ue(u32::MAX - 2)
+            .ue(u32::MAX - 1)
+            .bytes();
+
+        let mut reader = BitReader::new(&data);
+        let values = (0..7).map(|_| reader.se().unwrap()).collect::<Vec<_>>();
+        assert_eq!(values, [0, 1, -1, 2, -2, i32::MAX, -i32::MAX]);
+    }
+
+    #[test]
+    fn ranges() {
+        let data = BitWriter::default().ue(5).ue(6).se(-3).se(4).bytes();
+        let mut reader = BitReader::new(&data);
+
+        assert_eq!(reader.ue_max(5, "five").unwrap(), 5);
+        assert!(reader.ue_max(5, "six").is_err());
+        assert_eq!(reader.se_range(-3, 3, "minus three").unwrap(), -3);
+        assert!(reader.se_range(-3, 3, "four").is_err());
+    }
+
+    #[test]
+    fn more_rbsp_data() {
+        let data = BitWriter::default().u(3, 0b101).finish();
+        let mut reader = BitReader::new(&data);
+        assert!(reader.more_rbsp_data());
+        reader.u(3).unwrap();
+        assert!(!reader.more_rbsp_data());
+        reader.rbsp_trailing_bits().unwrap();
+        assert_eq!(reader.bits_left(), 0);
+
+        // Trailing cabac_zero_words are not data
+        let data = [0b0100_0000, 0x00, 0x00];
+        let mut reader = BitReader::new(&data);
+        assert!(reader.more_rbsp_data());
+        reader.skip(1).unwrap();
+        assert!(!reader.more_rbsp_data());
+
+        // The stop bit alone in the last byte
+        let data = [0xff, 0x80];
+        let mut reader = BitReader::new(&data);
+        reader.skip(8).unwrap();
+        assert!(!reader.more_rbsp_data());
+
+        assert!(!BitReader::new(&[]).more_rbsp_data());
+        assert!(!BitReader::new(&[0x00, 0x00]).more_rbsp_data());
+    }
+
+    #[test]
+    fn rbsp_trailing_bits() {
+        assert!(BitReader::new(&[0x80]).rbsp_trailing_bits().is_ok());
+        // Missing stop bit
+        assert!(BitReader::new(&[0x40]).rbsp_trailing_bits().is_err());
+        // Set alignment bit
+        assert!(BitReader::new(&[0x81]).rbsp_trailing_bits().is_err());
+    }
+
+    #[test]
+    fn past_the_end() {
+        let data = [0xa5];
+        let mut reader = BitReader::new(&data);
+
+        assert!(reader.u(9).is_err());
+        // A failed read consumes nothing
+        assert_eq!(reader.position(), 0);
+        assert!(reader.skip(9).is_err());
+        assert_eq!(reader.u(8).unwrap(), 0xa5);
+        assert!(reader.flag().is_err());
+        assert!(reader.ue().is_err());
+        assert!(reader.se().is_err());
+        assert!(reader.skip(1).is_err());
+        assert!(reader.rbsp_trailing_bits().is_err());
+
+        // A code whose suffix runs past the end
+        assert!(BitReader::new(&[0x01]).ue().is_err());
+        assert!(BitReader::new(&[]).ue().is_err());
+    }
+}
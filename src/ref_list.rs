@@ -0,0 +1,582 @@
+use std::cmp::Reverse;
+use std::mem;
+
+use anyhow::{anyhow, Result};
+use ash::vk::native::StdVideoDecodeH264ReferenceInfo;
+
+use crate::dpb::{DecodedPictureBuffer, Frame};
+use crate::slice::{RefPicListModification, SliceHeader, SliceType};
+
+/// RefPicList0 and RefPicList1 of a slice, ITU-T H.264 8.2.4
+///
+/// A list can be shorter than num_ref_idx_lX_active_minus1 + 1 when the DPB holds fewer frames,
+/// the missing entries are "no reference picture".
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RefPicLists<'a> {
+    pub list0: Vec<&'a Frame>,
+    pub list1: Vec<&'a Frame>,
+}
+
+impl<'a> RefPicLists<'a> {
+    /// Builds the lists of a slice of the picture between [`DecodedPictureBuffer::begin_picture`]
+    /// and [`DecodedPictureBuffer::end_picture`].
+    pub fn build(dpb: &'a DecodedPictureBuffer, header: &SliceHeader) -> Result<Self> {
+        let current = dpb
+            .current()
+            .ok_or_else(|| anyhow!("No picture is being decoded"))?;
+
+        let (list0, list1) = match header.slice_type {
+            SliceType::P | SliceType::SP => (initial_p_list(dpb), Vec::new()),
+            SliceType::B => initial_b_lists(dpb, current),
+            SliceType::I | SliceType::SI => return Ok(RefPicLists::default()),
+        };
+
+        let mut lists = RefPicLists {
+            list0: modify(
+                dpb,
+                current,
+                list0,
+                &header.ref_pic_list_modification_l0,
+                header.num_ref_idx_l0_active_minus1 as usize + 1,
+            )?,
+            list1: Vec::new(),
+        };
+
+        if header.slice_type == SliceType::B {
+            lists.list1 = modify(
+                dpb,
+                current,
+                list1,
+                &header.ref_pic_list_modification_l1,
+                header.num_ref_idx_l1_active_minus1 as usize + 1,
+            )?;
+        }
+
+        Ok(lists)
+    }
+}
+
+fn short_term_frames(dpb: &DecodedPictureBuffer) -> impl Iterator<Item = &Frame> {
+    dpb.frames().iter().filter(|frame| frame.is_short_term())
+}
+
+/// Long-term frames by ascending LongTermPicNum, the tail of every initial list.
+fn long_term_frames(dpb: &DecodedPictureBuffer) -> Vec<&Frame> {
+    let mut frames = dpb
+        .frames()
+        .iter()
+        .filter(|frame| frame.long_term_frame_idx().is_some())
+        .collect::<Vec<_>>();
+    frames.sort_by_key(|frame| frame.long_term_frame_idx());
+
+    frames
+}
+
+/// ITU-T H.264 8.2.4.2.1, short-term frames by descending PicNum.
+fn initial_p_list(dpb: &DecodedPictureBuffer) -> Vec<&Frame> {
+    let mut list = short_term_frames(dpb).collect::<Vec<_>>();
+    list.sort_by_key(|frame| Reverse(frame.frame_num_wrap));
+    list.extend(long_term_frames(dpb));
+
+    list
+}
+
+/// ITU-T H.264 8.2.4.2.3, short-term frames ordered by their distance in output order, the past
+/// first for list 0 and the future first for list 1.
+fn initial_b_lists<'a>(
+    dpb: &'a DecodedPictureBuffer,
+    current: &Frame,
+) -> (Vec<&'a Frame>, Vec<&'a Frame>) {
+    let pic_order_cnt = current.pic_order_cnt();
+
+    let mut before = short_term_frames(dpb)
+        .filter(|frame| frame.pic_order_cnt() < pic_order_cnt)
+        .collect::<Vec<_>>();
+    before.sort_by_key(|frame| Reverse(frame.pic_order_cnt()));
+
+    let mut after = short_term_frames(dpb)
+        .filter(|frame| frame.pic_order_cnt() > pic_order_cnt)
+        .collect::<Vec<_>>();
+    after.sort_by_key(|frame| frame.pic_order_cnt());
+
+    let long_term = long_term_frames(dpb);
+
+    let list0 = [&before[..], &after[..], &long_term[..]].concat();
+    let mut list1 = [&after[..], &before[..], &long_term[..]].concat();
+
+    if list1.len() > 1 && list0 == list1 {
+        list1.swap(0, 1);
+    }
+
+    (list0, list1)
+}
+
+/// Modification process for reference picture lists, ITU-T H.264 8.2.4.3.
+fn modify<'a>(
+    dpb: &'a DecodedPictureBuffer,
+    current: &Frame,
+    mut list: Vec<&'a Frame>,
+    modifications: &[RefPicListModification],
+    num_ref_idx_active: usize,
+) -> Result<Vec<&'a Frame>> {
+    list.truncate(num_ref_idx_active);
+
+    // CurrPicNum and MaxPicNum are frame_num and MaxFrameNum for frames
+    let curr_pic_num = current.frame_num as i32;
+    let max_pic_num = dpb.max_frame_num() as i32;
+    let mut pic_num_pred = curr_pic_num;
+
+    for (ref_idx, modification) in modifications.iter().enumerate() {
+        let frame = match *modification {
+            RefPicListModification::ShortTermSubtract {
+                abs_diff_pic_num_minus1,
+            }
+            | RefPicListModification::ShortTermAdd {
+                abs_diff_pic_num_minus1,
+            } => {
+                let abs_diff_pic_num = abs_diff_pic_num_minus1 as i32 + 1;
+
+                // picNumLXNoWrap, ITU-T H.264 8-34 and 8-35
+                let mut pic_num_no_wrap = if matches!(
+                    modification,
+                    RefPicListModification::ShortTermSubtract { .. }
+                ) {
+                    pic_num_pred - abs_diff_pic_num
+                } else {
+                    pic_num_pred + abs_diff_pic_num
+                };
+                if pic_num_no_wrap < 0 {
+                    pic_num_no_wrap += max_pic_num;
+                } else if pic_num_no_wrap >= max_pic_num {
+                    pic_num_no_wrap -= max_pic_num;
+                }
+                pic_num_pred = pic_num_no_wrap;
+
+                let pic_num = if pic_num_no_wrap > curr_pic_num {
+                    pic_num_no_wrap - max_pic_num
+                } else {
+                    pic_num_no_wrap
+                };
+
+                short_term_frames(dpb)
+                    .find(|frame| frame.frame_num_wrap == pic_num)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Reference list modification refers to missing short-term picture {}",
+                            pic_num
+                        )
+                    })?
+            }
+            RefPicListModification::LongTerm { long_term_pic_num } => dpb
+                .frames()
+                .iter()
+                .find(|frame| frame.long_term_frame_idx() == Some(long_term_pic_num))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Reference list modification refers to missing long-term picture {}",
+                        long_term_pic_num
+                    )
+                })?,
+        };
+
+        // The frame moves to ref_idx, a later copy of it is dropped
+        let position = ref_idx.min(list.len());
+        list.insert(position, frame);
+        if let Some(duplicate) = list[position + 1..]
+            .iter()
+            .position(|other| other.slot_index == frame.slot_index)
+        {
+            list.remove(position + 1 + duplicate);
+        }
+    }
+
+    list.truncate(num_ref_idx_active);
+
+    Ok(list)
+}
+
+/// The frames that stay bound for a decode submission, whether its slices refer to them or not.
+/// "Non-existing" frames were never decoded and are left out.
+pub fn reference_frames(dpb: &DecodedPictureBuffer) -> impl Iterator<Item = &Frame> {
+    dpb.frames()
+        .iter()
+        .filter(|frame| frame.is_reference() && !frame.non_existing)
+}
+
+/// The frames the reference picture lists of `slice_headers` refer to, each one once in order of
+/// first use. A "non-existing" frame has no picture to read and is left out.
+pub fn referenced_frames<'a>(
+    dpb: &'a DecodedPictureBuffer,
+    slice_headers: &[SliceHeader],
+) -> Result<Vec<&'a Frame>> {
+    let mut frames: Vec<&Frame> = Vec::new();
+
+    for header in slice_headers {
+        let lists = RefPicLists::build(dpb, header)?;
+
+        for frame in lists.list0.into_iter().chain(lists.list1) {
+            let listed = frames
+                .iter()
+                .any(|other| other.slot_index == frame.slot_index);
+            if !frame.non_existing && !listed {
+                frames.push(frame);
+            }
+        }
+    }
+
+    Ok(frames)
+}
+
+/// `StdVideoDecodeH264ReferenceInfo` of a reference frame, chained through
+/// `VideoDecodeH264DpbSlotInfoKHR` into its `VideoReferenceSlotInfoKHR`.
+pub fn std_reference_info(frame: &Frame) -> StdVideoDecodeH264ReferenceInfo {
+    let mut reference_info: StdVideoDecodeH264ReferenceInfo = unsafe { mem::zeroed() };
+
+    // Neither field flag is set for a frame
+    reference_info
+        .flags
+        .set_used_for_long_term_reference(frame.long_term_frame_idx().is_some() as u32);
+    reference_info
+        .flags
+        .set_is_non_existing(frame.non_existing as u32);
+
+    // FrameNum holds LongTermFrameIdx for long-term references
+    reference_info.FrameNum = frame.long_term_frame_idx().unwrap_or(frame.frame_num) as u16;
+    reference_info.PicOrderCnt = frame.field_order_cnt;
+
+    reference_info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slice::MemoryManagementControlOperation::*;
+    use crate::sps::SequenceParameterSet;
+    use crate::test_util::{adaptive_header, idr_header, slice_header, sps};
+
+    use RefPicListModification::*;
+
+    /// A slice of `slice_type` with 4 active entries in each list.
+    fn header(
+        slice_type: SliceType,
+        frame_num: u32,
+        nal_ref_idc: u8,
+        pic_order_cnt_lsb: u32,
+    ) -> SliceHeader {
+        SliceHeader {
+            slice_type,
+            pic_order_cnt_lsb,
+            num_ref_idx_l0_active_minus1: 3,
+            num_ref_idx_l1_active_minus1: 3,
+            ..slice_header(frame_num, nal_ref_idc)
+        }
+    }
+
+    fn decode(dpb: &mut DecodedPictureBuffer, sps: &SequenceParameterSet, header: &SliceHeader) {
+        dpb.begin_picture(sps, header).unwrap();
+        dpb.end_picture(header).unwrap();
+        dpb.take_output();
+    }
+
+    fn frame_nums(list: &[&Frame]) -> Vec<u32> {
+        list.iter().map(|frame| frame.frame_num).collect()
+    }
+
+    fn pic_order_cnts(list: &[&Frame]) -> Vec<i32> {
+        list.iter().map(|frame| frame.pic_order_cnt()).collect()
+    }
+
+    /// An IDR picture followed by `count` reference frames, frame_num wraps at 16.
+    fn reference_frames(count: u32) -> (SequenceParameterSet, DecodedPictureBuffer) {
+        let sps = sps(2);
+        let mut dpb = DecodedPictureBuffer::new(&sps, 18).unwrap();
+
+        decode(&mut dpb, &sps, &idr_header());
+        for frame_num in 1..=count {
+            decode(&mut dpb, &sps, &slice_header(frame_num % 16, 2));
+        }
+
+        (sps, dpb)
+    }
+
+    /// An IDR picture, frames 1 and 2 marked long-term with LongTermFrameIdx 1 and 0, and
+    /// short-term frame 3.
+    fn long_term_frames() -> (SequenceParameterSet, DecodedPictureBuffer) {
+        let sps = sps(2);
+        let mut dpb = DecodedPictureBuffer::new(&sps, 18).unwrap();
+
+        decode(&mut dpb, &sps, &idr_header());
+        let operations = [
+            SetMaxLongTermFrameIdx {
+                max_long_term_frame_idx_plus1: 2,
+            },
+            MarkCurrentAsLongTerm {
+                long_term_frame_idx: 1,
+            },
+        ];
+        decode(&mut dpb, &sps, &adaptive_header(1, &operations));
+        let operations = [MarkCurrentAsLongTerm {
+            long_term_frame_idx: 0,
+        }];
+        decode(&mut dpb, &sps, &adaptive_header(2, &operations));
+        decode(&mut dpb, &sps, &slice_header(3, 2));
+
+        (sps, dpb)
+    }
+
+    #[test]
+    fn initial_p_list() {
+        // (reference frames after the IDR picture, list 0 by frame_num)
+        let cases: [(u32, &[u32]); 4] = [
+            (1, &[1, 0]),
+            (3, &[3, 2, 1, 0]),
+            (15, &[15, 14, 13, 12]),
+            // Descending PicNum, not frame_num, once frame_num wraps
+            (17, &[1, 0, 15, 14]),
+        ];
+
+        for (count, expected) in cases {
+            let (sps, mut dpb) = reference_frames(count);
+            let header = header(SliceType::P, (count + 1) % 16, 2, 0);
+            dpb.begin_picture(&sps, &header).unwrap();
+
+            let lists = RefPicLists::build(&dpb, &header).unwrap();
+            assert_eq!(frame_nums(&lists.list0), expected, "{} frames", count);
+            assert!(lists.list1.is_empty());
+        }
+    }
+
+    #[test]
+    fn initial_p_list_long_term() {
+        let (sps, mut dpb) = long_term_frames();
+        let header = header(SliceType::P, 4, 2, 0);
+        dpb.begin_picture(&sps, &header).unwrap();
+
+        // Short-term by descending PicNum, then long-term by ascending LongTermPicNum
+        let lists = RefPicLists::build(&dpb, &header).unwrap();
+        assert_eq!(frame_nums(&lists.list0), [3, 0, 2, 1]);
+    }
+
+    #[test]
+    fn initial_b_lists() {
+        // (PicOrderCnt of the current picture, list 0, list 1), references at 0, 4 and 8
+        let cases: [(u32, &[i32], &[i32]); 3] = [
+            (2, &[0, 4, 8], &[4, 8, 0]),
+            (6, &[4, 0, 8], &[8, 4, 0]),
+            // Nothing follows in output order, the first two entries of list 1 are swapped
+            (10, &[8, 4, 0], &[4, 8, 0]),
+        ];
+
+        for (pic_order_cnt_lsb, expected_list0, expected_list1) in cases {
+            let sps = sps(0);
+            let mut dpb = DecodedPictureBuffer::new(&sps, 18).unwrap();
+            decode(&mut dpb, &sps, &idr_header());
+            decode(&mut dpb, &sps, &header(SliceType::P, 1, 2, 8));
+            decode(&mut dpb, &sps, &header(SliceType::B, 2, 2, 4));
+
+            let header = header(SliceType::B, 3, 0, pic_order_cnt_lsb);
+            dpb.begin_picture(&sps, &header).unwrap();
+
+            let lists = RefPicLists::build(&dpb, &header).unwrap();
+            assert_eq!(pic_order_cnts(&lists.list0), expected_list0);
+            assert_eq!(pic_order_cnts(&lists.list1), expected_list1);
+        }
+    }
+
+    #[test]
+    fn initial_b_lists_single_reference() {
+        let sps = sps(0);
+        let mut dpb = DecodedPictureBuffer::new(&sps, 18).unwrap();
+        decode(&mut dpb, &sps, &idr_header());
+
+        // A single entry is never swapped
+        let header = header(SliceType::B, 1, 0, 2);
+        dpb.begin_picture(&sps, &header).unwrap();
+
+        let lists = RefPicLists::build(&dpb, &header).unwrap();
+        assert_eq!(pic_order_cnts(&lists.list0), [0]);
+        assert_eq!(pic_order_cnts(&lists.list1), [0]);
+    }
+
+    #[test]
+    fn intra_slices_have_no_lists() {
+        let (sps, mut dpb) = reference_frames(3);
+        let header = header(SliceType::I, 4, 2, 0);
+        dpb.begin_picture(&sps, &header).unwrap();
+
+        assert_eq!(
+            RefPicLists::build(&dpb, &header).unwrap(),
+            RefPicLists::default()
+        );
+    }
+
+    #[test]
+    fn modification() {
+        // References 12 to 15 with PicNum -4 to -1, the current picture has frame_num 0
+        // (modifications, num_ref_idx_l0_active_minus1, list 0 by frame_num)
+        let cases: [(&[RefPicListModification], u8, &[u32]); 6] = [
+            (&[], 3, &[15, 14, 13, 12]),
+            // picNumL0NoWrap wraps below 0 and PicNum back below CurrPicNum
+            (
+                &[ShortTermSubtract {
+                    abs_diff_pic_num_minus1: 2,
+                }],
+                3,
+                &[13, 15, 14, 12],
+            ),
+            // Each one is relative to the previous picNumL0NoWrap
+            (
+                &[
+                    ShortTermSubtract {
+                        abs_diff_pic_num_minus1: 2,
+                    },
+                    ShortTermAdd {
+                        abs_diff_pic_num_minus1: 0,
+                    },
+                ],
+                3,
+                &[13, 14, 15, 12],
+            ),
+            (
+                &[
+                    ShortTermSubtract {
+                        abs_diff_pic_num_minus1: 0,
+                    },
+                    ShortTermSubtract {
+                        abs_diff_pic_num_minus1: 0,
+                    },
+                    ShortTermSubtract {
+                        abs_diff_pic_num_minus1: 0,
+                    },
+                ],
+                3,
+                &[15, 14, 13, 12],
+            ),
+            // The list is cut to num_ref_idx_l0_active_minus1 + 1 entries
+            (
+                &[ShortTermSubtract {
+                    abs_diff_pic_num_minus1: 3,
+                }],
+                1,
+                &[12, 15],
+            ),
+            (&[], 0, &[15]),
+        ];
+
+        for (modifications, num_ref_idx_l0_active_minus1, expected) in cases {
+            let (sps, mut dpb) = reference_frames(15);
+            let header = SliceHeader {
+                num_ref_idx_l0_active_minus1,
+                ref_pic_list_modification_l0: modifications.to_vec(),
+                ..header(SliceType::P, 0, 2, 0)
+            };
+            dpb.begin_picture(&sps, &header).unwrap();
+
+            let lists = RefPicLists::build(&dpb, &header).unwrap();
+            assert_eq!(frame_nums(&lists.list0), expected, "{:?}", modifications);
+        }
+    }
+
+    #[test]
+    fn modification_long_term() {
+        let (sps, mut dpb) = long_term_frames();
+        let header = SliceHeader {
+            ref_pic_list_modification_l0: vec![LongTerm {
+                long_term_pic_num: 1,
+            }],
+            ..header(SliceType::P, 4, 2, 0)
+        };
+        dpb.begin_picture(&sps, &header).unwrap();
+
+        // The later copy of the frame moved to the front is dropped
+        let lists = RefPicLists::build(&dpb, &header).unwrap();
+        assert_eq!(frame_nums(&lists.list0), [1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn modification_list1() {
+        let sps = sps(0);
+        let mut dpb = DecodedPictureBuffer::new(&sps, 18).unwrap();
+        decode(&mut dpb, &sps, &idr_header());
+        decode(&mut dpb, &sps, &header(SliceType::P, 1, 2, 8));
+        decode(&mut dpb, &sps, &header(SliceType::B, 2, 2, 4));
+
+        // CurrPicNum 3 less 1 is frame_num 2 at PicOrderCnt 4
+        let header = SliceHeader {
+            ref_pic_list_modification_l1: vec![ShortTermSubtract {
+                abs_diff_pic_num_minus1: 0,
+            }],
+            ..header(SliceType::B, 3, 0, 6)
+        };
+        dpb.begin_picture(&sps, &header).unwrap();
+
+        let lists = RefPicLists::build(&dpb, &header).unwrap();
+        assert_eq!(pic_order_cnts(&lists.list0), [4, 0, 8]);
+        assert_eq!(pic_order_cnts(&lists.list1), [4, 8, 0]);
+    }
+
+    #[test]
+    fn modification_of_missing_picture() {
+        // Frame 2 is a long-term reference, nothing has LongTermPicNum 2
+        for modification in [
+            ShortTermSubtract {
+                abs_diff_pic_num_minus1: 1,
+            },
+            LongTerm {
+                long_term_pic_num: 2,
+            },
+        ] {
+            let (sps, mut dpb) = long_term_frames();
+            let header = SliceHeader {
+                ref_pic_list_modification_l0: vec![modification],
+                ..header(SliceType::P, 4, 2, 0)
+            };
+            dpb.begin_picture(&sps, &header).unwrap();
+
+            assert!(RefPicLists::build(&dpb, &header).is_err());
+        }
+    }
+
+    #[test]
+    fn referenced_frames_of_every_slice() {
+        let (sps, mut dpb) = reference_frames(3);
+        let first = SliceHeader {
+            num_ref_idx_l0_active_minus1: 0,
+            ..header(SliceType::P, 4, 2, 0)
+        };
+        let second = SliceHeader {
+            ref_pic_list_modification_l0: vec![ShortTermSubtract {
+                abs_diff_pic_num_minus1: 2,
+            }],
+            ..first.clone()
+        };
+        let third = SliceHeader {
+            slice_type: SliceType::I,
+            ..first.clone()
+        };
+        dpb.begin_picture(&sps, &first).unwrap();
+
+        let frames = referenced_frames(&dpb, &[first.clone(), second, third, first]).unwrap();
+        assert_eq!(frame_nums(&frames), [3, 1]);
+    }
+
+    #[test]
+    fn referenced_frames_skip_non_existing() {
+        let sps = SequenceParameterSet {
+            gaps_in_frame_num_value_allowed_flag: true,
+            ..sps(2)
+        };
+        let mut dpb = DecodedPictureBuffer::new(&sps, 18).unwrap();
+        decode(&mut dpb, &sps, &idr_header());
+        decode(&mut dpb, &sps, &slice_header(3, 2));
+
+        let header = header(SliceType::P, 4, 2, 0);
+        dpb.begin_picture(&sps, &header).unwrap();
+
+        // Frames 1 and 2 fill the gap in frame_num
+        let lists = RefPicLists::build(&dpb, &header).unwrap();
+        assert_eq!(frame_nums(&lists.list0), [3, 2, 1, 0]);
+        let frames = referenced_frames(&dpb, &[header]).unwrap();
+        assert_eq!(frame_nums(&frames), [3, 0]);
+    }
+}
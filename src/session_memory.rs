@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+use ash::extensions::khr::VideoQueue;
+use ash::{vk, Device};
+
+use crate::find_memorytype_index;
+
+/// The memory bound to a video session, one allocation per memory type the bindings end up in.
+///
+/// The allocations are freed on drop, after the session was destroyed.
+pub struct VideoSessionMemory {
+    device: Device,
+    pub memories: Vec<vk::DeviceMemory>,
+}
+
+impl VideoSessionMemory {
+    /// Allocates and binds the memory `video_session` requires.
+    ///
+    /// Every binding goes to the first device local memory type it allows, or the first one at
+    /// all without one, and bindings sharing a memory type are packed into one allocation.
+    pub fn bind(
+        device: &Device,
+        video_queue_loader: &VideoQueue,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        video_session: vk::VideoSessionKHR,
+    ) -> Result<Self> {
+        unsafe {
+            let requirements_count =
+                video_queue_loader.get_video_session_memory_requirements_len(video_session);
+            let mut requirements =
+                vec![vk::VideoSessionMemoryRequirementsKHR::default(); requirements_count];
+            video_queue_loader
+                .get_video_session_memory_requirements(video_session, &mut requirements)?;
+
+            // memory type, allocation size and the offset of every binding in it
+            let mut allocations: Vec<(u32, u64)> = Vec::new();
+            let mut placements = Vec::with_capacity(requirements.len());
+
+            for requirement in &requirements {
+                let memory_requirements = &requirement.memory_requirements;
+                let memory_type_index = find_memorytype_index(
+                    memory_requirements,
+                    device_memory_properties,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                )
+                .or_else(|| {
+                    find_memorytype_index(
+                        memory_requirements,
+                        device_memory_properties,
+                        vk::MemoryPropertyFlags::empty(),
+                    )
+                })
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Unable to find suitable memory index for video session binding {}",
+                        requirement.memory_bind_index
+                    )
+                })?;
+
+                let allocation_index = match allocations
+                    .iter()
+                    .position(|&(index, _)| index == memory_type_index)
+                {
+                    Some(allocation_index) => allocation_index,
+                    None => {
+                        allocations.push((memory_type_index, 0));
+                        allocations.len() - 1
+                    }
+                };
+
+                let allocation_size = &mut allocations[allocation_index].1;
+                let alignment = memory_requirements.alignment.max(1);
+                let offset = allocation_size.div_ceil(alignment) * alignment;
+                *allocation_size = offset + memory_requirements.size;
+
+                placements.push((allocation_index, offset));
+            }
+
+            let mut session_memory = VideoSessionMemory {
+                device: device.clone(),
+                memories: Vec::with_capacity(allocations.len()),
+            };
+
+            for &(memory_type_index, allocation_size) in &allocations {
+                let allocate_info = vk::MemoryAllocateInfo {
+                    allocation_size,
+                    memory_type_index,
+                    ..Default::default()
+                };
+                session_memory
+                    .memories
+                    .push(device.allocate_memory(&allocate_info, None)?);
+            }
+
+            let mut bind_infos = requirements
+                .iter()
+                .zip(&placements)
+                .map(|(requirement, &(allocation_index, offset))| {
+                    vk::BindVideoSessionMemoryInfoKHR::default()
+                        .memory_bind_index(requirement.memory_bind_index)
+                        .memory(session_memory.memories[allocation_index])
+                        .memory_offset(offset)
+                        .memory_size(requirement.memory_requirements.size)
+                })
+                .collect::<Vec<_>>();
+
+            video_queue_loader.bind_video_session_memory(video_session, &mut bind_infos)?;
+
+            Ok(session_memory)
+        }
+    }
+}
+
+impl Drop for VideoSessionMemory {
+    fn drop(&mut self) {
+        unsafe {
+            for &memory in &self.memories {
+                self.device.free_memory(memory, None);
+            }
+        }
+    }
+}
@@ -0,0 +1,553 @@
+use anyhow::{anyhow, Result};
+use ash::extensions::khr::VideoQueue;
+use ash::vk::native::StdVideoH264ProfileIdc;
+use ash::{vk, Device};
+
+use crate::nal::{NalUnit, NalUnitType};
+use crate::pps::PictureParameterSet;
+use crate::source::AccessUnit;
+use crate::sps::SequenceParameterSet;
+use crate::sync::Timeline;
+
+/// What a video session was created for, every SPS decoded with it has to fit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionLimits {
+    pub coded_extent: vk::Extent2D,
+    pub max_active_reference_pictures: u32,
+    pub std_profile_idc: StdVideoH264ProfileIdc,
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+}
+
+impl SessionLimits {
+    /// The limits of a session created for `sps`.
+    pub fn new(sps: &SequenceParameterSet) -> Result<Self> {
+        Ok(SessionLimits {
+            coded_extent: sps.coded_extent(),
+            max_active_reference_pictures: sps.max_num_ref_frames as u32,
+            std_profile_idc: sps.std_profile_idc()?,
+            chroma_format_idc: sps.chroma_format_idc,
+            bit_depth_luma_minus8: sps.bit_depth_luma_minus8,
+            bit_depth_chroma_minus8: sps.bit_depth_chroma_minus8,
+        })
+    }
+
+    /// Fails for an SPS the session cannot decode. The DPB images are sized for the coded
+    /// extent, so a stream changing resolution takes a new session.
+    pub fn check(&self, sps: &SequenceParameterSet) -> Result<()> {
+        let limits = SessionLimits::new(sps)?;
+
+        if limits.coded_extent != self.coded_extent {
+            return Err(anyhow!(
+                "SPS {} codes {}x{} pictures, the video session was created for {}x{}",
+                sps.seq_parameter_set_id,
+                limits.coded_extent.width,
+                limits.coded_extent.height,
+                self.coded_extent.width,
+                self.coded_extent.height
+            ));
+        }
+
+        if limits.max_active_reference_pictures > self.max_active_reference_pictures {
+            return Err(anyhow!(
+                "SPS {} uses {} reference frames, the video session was created for {}",
+                sps.seq_parameter_set_id,
+                limits.max_active_reference_pictures,
+                self.max_active_reference_pictures
+            ));
+        }
+
+        if limits.std_profile_idc != self.std_profile_idc
+            || limits.chroma_format_idc != self.chroma_format_idc
+            || limits.bit_depth_luma_minus8 != self.bit_depth_luma_minus8
+            || limits.bit_depth_chroma_minus8 != self.bit_depth_chroma_minus8
+        {
+            return Err(anyhow!(
+                "SPS {} has profile_idc {}, chroma_format_idc {} and bit depths {}/{}, the video session was created for profile_idc {}, chroma_format_idc {} and bit depths {}/{}",
+                sps.seq_parameter_set_id,
+                limits.std_profile_idc,
+                limits.chroma_format_idc,
+                limits.bit_depth_luma_minus8 + 8,
+                limits.bit_depth_chroma_minus8 + 8,
+                self.std_profile_idc,
+                self.chroma_format_idc,
+                self.bit_depth_luma_minus8 + 8,
+                self.bit_depth_chroma_minus8 + 8
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The video session parameters object of a session, kept in step with the parameter sets the
+/// stream carries in band.
+///
+/// New parameter sets are added with `vkUpdateVideoSessionParametersKHR`. A parameter set
+/// replacing one of the same id, or more of them than the object was created for, takes a new
+/// object since an update can only add.
+pub struct SessionParameters {
+    pub video_session_parameters: vk::VideoSessionParametersKHR,
+    video_session: vk::VideoSessionKHR,
+    limits: SessionLimits,
+    max_sps_count: u32,
+    max_pps_count: u32,
+    update_sequence_count: u32,
+    /// Every parameter set in the object, the ones slices are parsed with.
+    pub sequence_parameter_sets: Vec<SequenceParameterSet>,
+    pub picture_parameter_sets: Vec<PictureParameterSet>,
+}
+
+impl SessionParameters {
+    /// Creates the object with room for at least `max_sps_count` and `max_pps_count` parameter
+    /// sets, each SPS has to fit the `limits` of the session.
+    pub fn new(
+        video_queue_loader: &VideoQueue,
+        video_session: vk::VideoSessionKHR,
+        limits: SessionLimits,
+        max_sps_count: u32,
+        max_pps_count: u32,
+        sequence_parameter_sets: Vec<SequenceParameterSet>,
+        picture_parameter_sets: Vec<PictureParameterSet>,
+    ) -> Result<Self> {
+        for sps in &sequence_parameter_sets {
+            limits.check(sps)?;
+        }
+
+        let max_sps_count = max_sps_count.max(sequence_parameter_sets.len() as u32);
+        let max_pps_count = max_pps_count.max(picture_parameter_sets.len() as u32);
+
+        let video_session_parameters = create(
+            video_queue_loader,
+            video_session,
+            max_sps_count,
+            max_pps_count,
+            &sequence_parameter_sets,
+            &picture_parameter_sets,
+        )?;
+
+        Ok(SessionParameters {
+            video_session_parameters,
+            video_session,
+            limits,
+            max_sps_count,
+            max_pps_count,
+            update_sequence_count: 0,
+            sequence_parameter_sets,
+            picture_parameter_sets,
+        })
+    }
+
+    /// Takes in the parameter sets `access_unit` carries, before its slices are parsed. Repeated
+    /// parameter sets identical to the ones known leave the object alone, an SPS the session
+    /// cannot decode is an error.
+    ///
+    /// Replacing the object waits for the decodes submitted so far, they may still use the old
+    /// one.
+    pub fn update(
+        &mut self,
+        device: &Device,
+        video_queue_loader: &VideoQueue,
+        decode_timeline: &Timeline,
+        access_unit: &AccessUnit,
+    ) -> Result<()> {
+        let mut sequence_parameter_sets = Vec::new();
+        let mut pps_nal_units = Vec::new();
+
+        for nal_unit in &access_unit.nal_units {
+            let nal_unit = NalUnit::parse(nal_unit)?;
+            match nal_unit.nal_unit_type {
+                NalUnitType::Sps => {
+                    let sps = SequenceParameterSet::parse(&nal_unit)?;
+                    if !self.sequence_parameter_sets.contains(&sps) {
+                        self.limits.check(&sps)?;
+                    }
+                    merge(&mut sequence_parameter_sets, &[sps], |sps| {
+                        sps.seq_parameter_set_id
+                    });
+                }
+                NalUnitType::Pps => pps_nal_units.push(nal_unit),
+                _ => {}
+            }
+        }
+
+        // A PPS is read with the SPS it refers to, which may have arrived along with it
+        let mut all_sequence_parameter_sets = self.sequence_parameter_sets.clone();
+        merge(
+            &mut all_sequence_parameter_sets,
+            &sequence_parameter_sets,
+            |sps| sps.seq_parameter_set_id,
+        );
+
+        let mut picture_parameter_sets = Vec::new();
+        for nal_unit in &pps_nal_units {
+            let pps = PictureParameterSet::parse(nal_unit, &all_sequence_parameter_sets)?;
+            merge(&mut picture_parameter_sets, &[pps], |pps| {
+                pps.pic_parameter_set_id
+            });
+        }
+
+        let plan = match plan(
+            &self.sequence_parameter_sets,
+            &self.picture_parameter_sets,
+            &sequence_parameter_sets,
+            &picture_parameter_sets,
+        ) {
+            Some(plan) => plan,
+            None => return Ok(()),
+        };
+
+        let sps_count = plan.sequence_parameter_sets.len() as u32;
+        let pps_count = plan.picture_parameter_sets.len() as u32;
+
+        if plan.replaces || sps_count > self.max_sps_count || pps_count > self.max_pps_count {
+            let max_sps_count = self.max_sps_count.max(sps_count);
+            let max_pps_count = self.max_pps_count.max(pps_count);
+
+            let video_session_parameters = create(
+                video_queue_loader,
+                self.video_session,
+                max_sps_count,
+                max_pps_count,
+                &plan.sequence_parameter_sets,
+                &plan.picture_parameter_sets,
+            )?;
+
+            decode_timeline.wait(device, decode_timeline.last_point().value)?;
+            self.destroy(video_queue_loader);
+
+            self.video_session_parameters = video_session_parameters;
+            self.max_sps_count = max_sps_count;
+            self.max_pps_count = max_pps_count;
+            self.update_sequence_count = 0;
+        } else {
+            with_add_info(
+                &plan.added_sequence_parameter_sets,
+                &plan.added_picture_parameter_sets,
+                |add_info| {
+                    // Every update has to count one past the previous one
+                    let update_info = vk::VideoSessionParametersUpdateInfoKHR::default()
+                        .push_next(add_info)
+                        .update_sequence_count(self.update_sequence_count + 1);
+
+                    unsafe {
+                        video_queue_loader.update_video_session_parameters(
+                            self.video_session_parameters,
+                            &update_info,
+                        )?;
+                    }
+
+                    Ok(())
+                },
+            )?;
+
+            self.update_sequence_count += 1;
+        }
+
+        self.sequence_parameter_sets = plan.sequence_parameter_sets;
+        self.picture_parameter_sets = plan.picture_parameter_sets;
+
+        Ok(())
+    }
+
+    pub fn destroy(&self, video_queue_loader: &VideoQueue) {
+        unsafe {
+            video_queue_loader
+                .destroy_video_session_parameters(self.video_session_parameters, None);
+        }
+    }
+}
+
+/// The parameter sets of the object once an access unit's are taken in.
+#[derive(Debug, PartialEq, Eq)]
+struct Plan {
+    sequence_parameter_sets: Vec<SequenceParameterSet>,
+    picture_parameter_sets: Vec<PictureParameterSet>,
+    /// The new ones, all an update has to add.
+    added_sequence_parameter_sets: Vec<SequenceParameterSet>,
+    added_picture_parameter_sets: Vec<PictureParameterSet>,
+    /// Whether one of the same id is replaced or dropped, which an update cannot do.
+    replaces: bool,
+}
+
+/// Takes the parameter sets an access unit carries in, `None` when they are all known already.
+///
+/// A PPS was parsed with the SPS it refers to, one whose SPS is replaced is dropped unless the
+/// access unit carries it again.
+fn plan(
+    known_sequence_parameter_sets: &[SequenceParameterSet],
+    known_picture_parameter_sets: &[PictureParameterSet],
+    sequence_parameter_sets: &[SequenceParameterSet],
+    picture_parameter_sets: &[PictureParameterSet],
+) -> Option<Plan> {
+    let added_sequence_parameter_sets = sequence_parameter_sets
+        .iter()
+        .filter(|sps| !known_sequence_parameter_sets.contains(sps))
+        .cloned()
+        .collect::<Vec<_>>();
+    let replaced_sps_ids = added_sequence_parameter_sets
+        .iter()
+        .map(|sps| sps.seq_parameter_set_id)
+        .filter(|&id| {
+            known_sequence_parameter_sets
+                .iter()
+                .any(|known| known.seq_parameter_set_id == id)
+        })
+        .collect::<Vec<_>>();
+
+    let kept_picture_parameter_sets = known_picture_parameter_sets
+        .iter()
+        .filter(|pps| !replaced_sps_ids.contains(&pps.seq_parameter_set_id))
+        .cloned()
+        .collect::<Vec<_>>();
+    let added_picture_parameter_sets = picture_parameter_sets
+        .iter()
+        .filter(|pps| !kept_picture_parameter_sets.contains(pps))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if added_sequence_parameter_sets.is_empty() && added_picture_parameter_sets.is_empty() {
+        return None;
+    }
+
+    let replaces_pps = added_picture_parameter_sets.iter().any(|pps| {
+        known_picture_parameter_sets
+            .iter()
+            .any(|known| known.pic_parameter_set_id == pps.pic_parameter_set_id)
+    });
+
+    let mut all_sequence_parameter_sets = known_sequence_parameter_sets.to_vec();
+    merge(
+        &mut all_sequence_parameter_sets,
+        &added_sequence_parameter_sets,
+        |sps| sps.seq_parameter_set_id,
+    );
+    let mut all_picture_parameter_sets = kept_picture_parameter_sets;
+    merge(
+        &mut all_picture_parameter_sets,
+        &added_picture_parameter_sets,
+        |pps| pps.pic_parameter_set_id,
+    );
+
+    Some(Plan {
+        sequence_parameter_sets: all_sequence_parameter_sets,
+        picture_parameter_sets: all_picture_parameter_sets,
+        added_sequence_parameter_sets,
+        added_picture_parameter_sets,
+        replaces: !replaced_sps_ids.is_empty() || replaces_pps,
+    })
+}
+
+/// Puts `new` into `known`, replacing the ones with the same id.
+fn merge<T: Clone>(known: &mut Vec<T>, new: &[T], id: impl Fn(&T) -> u8) {
+    for parameter_set in new {
+        match known
+            .iter_mut()
+            .find(|known| id(known) == id(parameter_set))
+        {
+            Some(known) => *known = parameter_set.clone(),
+            None => known.push(parameter_set.clone()),
+        }
+    }
+}
+
+/// Calls `f` with the add info carrying the Vulkan counterparts of the parameter sets.
+fn with_add_info<R>(
+    sequence_parameter_sets: &[SequenceParameterSet],
+    picture_parameter_sets: &[PictureParameterSet],
+    f: impl FnOnce(&mut vk::VideoDecodeH264SessionParametersAddInfoKHR) -> Result<R>,
+) -> Result<R> {
+    let std_sequence_parameter_sets = sequence_parameter_sets
+        .iter()
+        .map(|sps| sps.to_std())
+        .collect::<Result<Vec<_>>>()?;
+    let std_sps: Vec<_> = std_sequence_parameter_sets
+        .iter()
+        .map(|sps| *sps.as_std())
+        .collect();
+
+    let std_picture_parameter_sets: Vec<_> = picture_parameter_sets
+        .iter()
+        .map(|pps| pps.to_std())
+        .collect();
+    let std_pps: Vec<_> = std_picture_parameter_sets
+        .iter()
+        .map(|pps| *pps.as_std())
+        .collect();
+
+    let mut add_info = vk::VideoDecodeH264SessionParametersAddInfoKHR::default()
+        .std_sp_ss(&std_sps)
+        .std_pp_ss(&std_pps);
+
+    f(&mut add_info)
+}
+
+fn create(
+    video_queue_loader: &VideoQueue,
+    video_session: vk::VideoSessionKHR,
+    max_sps_count: u32,
+    max_pps_count: u32,
+    sequence_parameter_sets: &[SequenceParameterSet],
+    picture_parameter_sets: &[PictureParameterSet],
+) -> Result<vk::VideoSessionParametersKHR> {
+    with_add_info(
+        sequence_parameter_sets,
+        picture_parameter_sets,
+        |add_info| {
+            let mut h264_create_info = vk::VideoDecodeH264SessionParametersCreateInfoKHR::default()
+                .max_std_sps_count(max_sps_count)
+                .max_std_pps_count(max_pps_count)
+                .parameters_add_info(add_info);
+
+            let create_info = vk::VideoSessionParametersCreateInfoKHR::default()
+                .push_next(&mut h264_create_info)
+                .video_session(video_session);
+
+            Ok(unsafe { video_queue_loader.create_video_session_parameters(&create_info, None)? })
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{pps, sps};
+
+    fn sps_with_id(seq_parameter_set_id: u8) -> SequenceParameterSet {
+        SequenceParameterSet {
+            seq_parameter_set_id,
+            ..sps(0)
+        }
+    }
+
+    #[test]
+    fn merge_replaces_same_id() {
+        let mut known = vec![pps(0, 0), pps(1, 0)];
+        let replacement = PictureParameterSet {
+            chroma_qp_index_offset: 2,
+            ..pps(1, 0)
+        };
+        merge(&mut known, &[replacement.clone(), pps(2, 0)], |pps| {
+            pps.pic_parameter_set_id
+        });
+
+        assert_eq!(known, vec![pps(0, 0), replacement, pps(2, 0)]);
+    }
+
+    #[test]
+    fn repeated_parameter_sets_change_nothing() {
+        let plan = plan(&[sps(0)], &[pps(0, 0)], &[sps(0)], &[pps(0, 0)]);
+        assert_eq!(plan, None);
+    }
+
+    #[test]
+    fn new_ids_are_added() {
+        let plan = plan(
+            &[sps(0)],
+            &[pps(0, 0)],
+            &[sps_with_id(1)],
+            &[pps(0, 0), pps(1, 1)],
+        )
+        .unwrap();
+
+        assert!(!plan.replaces);
+        assert_eq!(plan.added_sequence_parameter_sets, vec![sps_with_id(1)]);
+        assert_eq!(plan.added_picture_parameter_sets, vec![pps(1, 1)]);
+        assert_eq!(plan.sequence_parameter_sets, vec![sps(0), sps_with_id(1)]);
+        assert_eq!(plan.picture_parameter_sets, vec![pps(0, 0), pps(1, 1)]);
+    }
+
+    #[test]
+    fn replaced_pps_recreates() {
+        let replacement = PictureParameterSet {
+            weighted_pred_flag: true,
+            ..pps(0, 0)
+        };
+        let plan = plan(
+            &[sps(0)],
+            &[pps(0, 0)],
+            &[],
+            std::slice::from_ref(&replacement),
+        )
+        .unwrap();
+
+        assert!(plan.replaces);
+        assert_eq!(plan.picture_parameter_sets, vec![replacement]);
+    }
+
+    #[test]
+    fn replaced_sps_drops_its_pps() {
+        let replacement = sps(2);
+        let plan = plan(
+            &[sps(0), sps_with_id(1)],
+            &[pps(0, 0), pps(1, 1)],
+            std::slice::from_ref(&replacement),
+            &[],
+        )
+        .unwrap();
+
+        assert!(plan.replaces);
+        assert_eq!(
+            plan.sequence_parameter_sets,
+            vec![replacement, sps_with_id(1)]
+        );
+        assert_eq!(plan.picture_parameter_sets, vec![pps(1, 1)]);
+    }
+
+    #[test]
+    fn replaced_sps_keeps_resent_pps() {
+        let plan = plan(&[sps(0)], &[pps(0, 0)], &[sps(2)], &[pps(0, 0)]).unwrap();
+
+        assert!(plan.replaces);
+        assert_eq!(plan.sequence_parameter_sets, vec![sps(2)]);
+        assert_eq!(plan.picture_parameter_sets, vec![pps(0, 0)]);
+    }
+
+    #[test]
+    fn limits() {
+        let limits = SessionLimits::new(&sps(0)).unwrap();
+        assert_eq!(
+            limits.coded_extent,
+            vk::Extent2D {
+                width: 320,
+                height: 240
+            }
+        );
+        assert_eq!(limits.max_active_reference_pictures, 4);
+
+        let fitting = [
+            sps(2),
+            SequenceParameterSet {
+                max_num_ref_frames: 2,
+                ..sps(0)
+            },
+        ];
+        for sps in &fitting {
+            assert!(limits.check(sps).is_ok(), "{:?}", sps);
+        }
+
+        let incompatible = [
+            SequenceParameterSet {
+                pic_width_in_mbs_minus1: 39,
+                ..sps(0)
+            },
+            SequenceParameterSet {
+                max_num_ref_frames: 5,
+                ..sps(0)
+            },
+            SequenceParameterSet {
+                profile_idc: 100,
+                ..sps(0)
+            },
+            SequenceParameterSet {
+                profile_idc: 110,
+                bit_depth_luma_minus8: 2,
+                ..sps(0)
+            },
+        ];
+        for sps in &incompatible {
+            assert!(limits.check(sps).is_err(), "{:?}", sps);
+        }
+    }
+}
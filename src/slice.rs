@@ -0,0 +1,988 @@
+use std::mem;
+
+use anyhow::{anyhow, Result};
+use ash::vk::native::StdVideoDecodeH264PictureInfo;
+
+use crate::nal::{NalUnit, NalUnitType};
+use crate::pps::PictureParameterSet;
+use crate::rbsp::{remove_emulation_prevention, BitReader};
+use crate::source::{AccessUnit, START_CODE};
+use crate::sps::SequenceParameterSet;
+
+/// slice_type modulo 5, ITU-T H.264 Table 7-6
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SliceType {
+    P,
+    B,
+    I,
+    SP,
+    SI,
+}
+
+impl SliceType {
+    fn from_slice_type(slice_type: u32) -> Self {
+        match slice_type % 5 {
+            0 => SliceType::P,
+            1 => SliceType::B,
+            2 => SliceType::I,
+            3 => SliceType::SP,
+            _ => SliceType::SI,
+        }
+    }
+
+    pub fn is_intra(&self) -> bool {
+        matches!(self, SliceType::I | SliceType::SI)
+    }
+}
+
+/// One modification_of_pic_nums_idc entry of ref_pic_list_modification(), ITU-T H.264 7.3.3.1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefPicListModification {
+    ShortTermSubtract { abs_diff_pic_num_minus1: u32 },
+    ShortTermAdd { abs_diff_pic_num_minus1: u32 },
+    LongTerm { long_term_pic_num: u32 },
+}
+
+/// memory_management_control_operation 1 to 6, ITU-T H.264 Table 7-9
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryManagementControlOperation {
+    UnmarkShortTerm {
+        difference_of_pic_nums_minus1: u32,
+    },
+    UnmarkLongTerm {
+        long_term_pic_num: u32,
+    },
+    MarkLongTerm {
+        difference_of_pic_nums_minus1: u32,
+        long_term_frame_idx: u32,
+    },
+    SetMaxLongTermFrameIdx {
+        max_long_term_frame_idx_plus1: u32,
+    },
+    UnmarkAll,
+    MarkCurrentAsLongTerm {
+        long_term_frame_idx: u32,
+    },
+}
+
+/// dec_ref_pic_marking(), ITU-T H.264 7.3.3.3
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecRefPicMarking {
+    Idr {
+        no_output_of_prior_pics_flag: bool,
+        long_term_reference_flag: bool,
+    },
+    SlidingWindow,
+    Adaptive(Vec<MemoryManagementControlOperation>),
+}
+
+/// slice_header(), ITU-T H.264 7.3.3
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SliceHeader {
+    pub nal_ref_idc: u8,
+    /// IdrPicFlag
+    pub idr: bool,
+    pub first_mb_in_slice: u32,
+    pub slice_type: SliceType,
+    pub pic_parameter_set_id: u8,
+    pub colour_plane_id: u8,
+    pub frame_num: u32,
+    pub field_pic_flag: bool,
+    pub bottom_field_flag: bool,
+    pub idr_pic_id: u16,
+    pub pic_order_cnt_lsb: u32,
+    pub delta_pic_order_cnt_bottom: i32,
+    pub delta_pic_order_cnt: [i32; 2],
+    pub redundant_pic_cnt: u8,
+    pub direct_spatial_mv_pred_flag: bool,
+    /// Either the PPS defaults or the overridden values.
+    pub num_ref_idx_l0_active_minus1: u8,
+    pub num_ref_idx_l1_active_minus1: u8,
+    pub ref_pic_list_modification_l0: Vec<RefPicListModification>,
+    pub ref_pic_list_modification_l1: Vec<RefPicListModification>,
+    /// `None` for non-reference pictures.
+    pub dec_ref_pic_marking: Option<DecRefPicMarking>,
+    pub cabac_init_idc: u8,
+    pub slice_qp_delta: i32,
+    pub disable_deblocking_filter_idc: u8,
+    pub slice_alpha_c0_offset_div2: i8,
+    pub slice_beta_offset_div2: i8,
+}
+
+fn parse_ref_pic_list_modification(reader: &mut BitReader) -> Result<Vec<RefPicListModification>> {
+    let mut modifications = Vec::new();
+
+    if !reader.flag()? {
+        return Ok(modifications);
+    }
+
+    loop {
+        let modification = match reader.ue()? {
+            0 => RefPicListModification::ShortTermSubtract {
+                abs_diff_pic_num_minus1: reader.ue()?,
+            },
+            1 => RefPicListModification::ShortTermAdd {
+                abs_diff_pic_num_minus1: reader.ue()?,
+            },
+            2 => RefPicListModification::LongTerm {
+                long_term_pic_num: reader.ue()?,
+            },
+            3 => break,
+            idc => {
+                return Err(anyhow!("Invalid modification_of_pic_nums_idc {}", idc));
+            }
+        };
+
+        // num_ref_idx_lX_active_minus1 + 1 entries at most, plus the terminating 3
+        if modifications.len() == 32 {
+            return Err(anyhow!(
+                "ref_pic_list_modification has more than 32 entries"
+            ));
+        }
+
+        modifications.push(modification);
+    }
+
+    Ok(modifications)
+}
+
+/// Skips pred_weight_table(), ITU-T H.264 7.3.3.2, the decoder reads the weights from the slice data itself.
+fn skip_pred_weight_table(
+    reader: &mut BitReader,
+    chroma_array_type: u8,
+    num_ref_idx_active_minus1: &[u8],
+) -> Result<()> {
+    reader.ue_max(7, "luma_log2_weight_denom")?;
+    if chroma_array_type != 0 {
+        reader.ue_max(7, "chroma_log2_weight_denom")?;
+    }
+
+    for &num_ref_idx_active_minus1 in num_ref_idx_active_minus1 {
+        for _ in 0..=num_ref_idx_active_minus1 {
+            if reader.flag()? {
+                reader.se_range(-128, 127, "luma_weight")?;
+                reader.se_range(-128, 127, "luma_offset")?;
+            }
+
+            if chroma_array_type != 0 && reader.flag()? {
+                for _ in 0..2 {
+                    reader.se_range(-128, 127, "chroma_weight")?;
+                    reader.se_range(-128, 127, "chroma_offset")?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_dec_ref_pic_marking(reader: &mut BitReader, idr: bool) -> Result<DecRefPicMarking> {
+    if idr {
+        return Ok(DecRefPicMarking::Idr {
+            no_output_of_prior_pics_flag: reader.flag()?,
+            long_term_reference_flag: reader.flag()?,
+        });
+    }
+
+    if !reader.flag()? {
+        return Ok(DecRefPicMarking::SlidingWindow);
+    }
+
+    let mut operations = Vec::new();
+
+    loop {
+        let operation = match reader.ue()? {
+            0 => break,
+            1 => MemoryManagementControlOperation::UnmarkShortTerm {
+                difference_of_pic_nums_minus1: reader.ue()?,
+            },
+            2 => MemoryManagementControlOperation::UnmarkLongTerm {
+                long_term_pic_num: reader.ue()?,
+            },
+            3 => MemoryManagementControlOperation::MarkLongTerm {
+                difference_of_pic_nums_minus1: reader.ue()?,
+                long_term_frame_idx: reader.ue()?,
+            },
+            4 => MemoryManagementControlOperation::SetMaxLongTermFrameIdx {
+                max_long_term_frame_idx_plus1: reader.ue()?,
+            },
+            5 => MemoryManagementControlOperation::UnmarkAll,
+            6 => MemoryManagementControlOperation::MarkCurrentAsLongTerm {
+                long_term_frame_idx: reader.ue()?,
+            },
+            operation => {
+                return Err(anyhow!(
+                    "Invalid memory_management_control_operation {}",
+                    operation
+                ));
+            }
+        };
+
+        // Every operation but the last has to refer to a different picture, 66 is plenty
+        if operations.len() == 66 {
+            return Err(anyhow!("dec_ref_pic_marking has too many operations"));
+        }
+
+        operations.push(operation);
+    }
+
+    Ok(DecRefPicMarking::Adaptive(operations))
+}
+
+/// Looks up the PPS of a slice and the SPS that PPS refers to.
+pub fn find_parameter_sets<'a>(
+    pic_parameter_set_id: u8,
+    sequence_parameter_sets: &'a [SequenceParameterSet],
+    picture_parameter_sets: &'a [PictureParameterSet],
+) -> Result<(&'a SequenceParameterSet, &'a PictureParameterSet)> {
+    let pps = picture_parameter_sets
+        .iter()
+        .find(|pps| pps.pic_parameter_set_id == pic_parameter_set_id)
+        .ok_or_else(|| anyhow!("Slice refers to unknown PPS {}", pic_parameter_set_id))?;
+
+    let sps = sequence_parameter_sets
+        .iter()
+        .find(|sps| sps.seq_parameter_set_id == pps.seq_parameter_set_id)
+        .ok_or_else(|| {
+            anyhow!(
+                "PPS {} refers to unknown SPS {}",
+                pps.pic_parameter_set_id,
+                pps.seq_parameter_set_id
+            )
+        })?;
+
+    Ok((sps, pps))
+}
+
+impl SliceHeader {
+    pub fn parse(
+        nal_unit: &NalUnit,
+        sequence_parameter_sets: &[SequenceParameterSet],
+        picture_parameter_sets: &[PictureParameterSet],
+    ) -> Result<Self> {
+        if !nal_unit.nal_unit_type.is_slice() {
+            return Err(anyhow!(
+                "Expected a coded slice NAL unit, got {:?}",
+                nal_unit.nal_unit_type
+            ));
+        }
+
+        let idr = nal_unit.nal_unit_type == NalUnitType::IdrSlice;
+
+        let rbsp = remove_emulation_prevention(nal_unit.payload());
+        let mut reader = BitReader::new(&rbsp);
+
+        let first_mb_in_slice = reader.ue()?;
+        let slice_type = SliceType::from_slice_type(reader.ue_max(9, "slice_type")?);
+
+        if idr && !slice_type.is_intra() {
+            return Err(anyhow!("IDR picture with {:?} slice", slice_type));
+        }
+
+        let pic_parameter_set_id = reader.ue_max(255, "pic_parameter_set_id")? as u8;
+        let (sps, pps) = find_parameter_sets(
+            pic_parameter_set_id,
+            sequence_parameter_sets,
+            picture_parameter_sets,
+        )?;
+
+        let colour_plane_id = if sps.separate_colour_plane_flag {
+            reader.u(2)? as u8
+        } else {
+            0
+        };
+
+        let frame_num = reader.u(sps.log2_max_frame_num_minus4 as usize + 4)?;
+
+        let mut field_pic_flag = false;
+        let mut bottom_field_flag = false;
+        if !sps.frame_mbs_only_flag {
+            field_pic_flag = reader.flag()?;
+            if field_pic_flag {
+                bottom_field_flag = reader.flag()?;
+            }
+        }
+
+        let idr_pic_id = if idr {
+            reader.ue_max(u16::MAX as u32, "idr_pic_id")? as u16
+        } else {
+            0
+        };
+
+        let mut pic_order_cnt_lsb = 0;
+        let mut delta_pic_order_cnt_bottom = 0;
+        let mut delta_pic_order_cnt = [0; 2];
+
+        if sps.pic_order_cnt_type == 0 {
+            pic_order_cnt_lsb = reader.u(sps.log2_max_pic_order_cnt_lsb_minus4 as usize + 4)?;
+            if pps.bottom_field_pic_order_in_frame_present_flag && !field_pic_flag {
+                delta_pic_order_cnt_bottom = reader.se()?;
+            }
+        }
+
+        if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero_flag {
+            delta_pic_order_cnt[0] = reader.se()?;
+            if pps.bottom_field_pic_order_in_frame_present_flag && !field_pic_flag {
+                delta_pic_order_cnt[1] = reader.se()?;
+            }
+        }
+
+        let redundant_pic_cnt = if pps.redundant_pic_cnt_present_flag {
+            reader.ue_max(127, "redundant_pic_cnt")? as u8
+        } else {
+            0
+        };
+
+        let direct_spatial_mv_pred_flag = if slice_type == SliceType::B {
+            reader.flag()?
+        } else {
+            false
+        };
+
+        let mut num_ref_idx_l0_active_minus1 = pps.num_ref_idx_l0_default_active_minus1;
+        let mut num_ref_idx_l1_active_minus1 = pps.num_ref_idx_l1_default_active_minus1;
+
+        if matches!(slice_type, SliceType::P | SliceType::SP | SliceType::B) && reader.flag()? {
+            let max = if field_pic_flag { 31 } else { 15 };
+            num_ref_idx_l0_active_minus1 =
+                reader.ue_max(max, "num_ref_idx_l0_active_minus1")? as u8;
+            if slice_type == SliceType::B {
+                num_ref_idx_l1_active_minus1 =
+                    reader.ue_max(max, "num_ref_idx_l1_active_minus1")? as u8;
+            }
+        }
+
+        let mut ref_pic_list_modification_l0 = Vec::new();
+        let mut ref_pic_list_modification_l1 = Vec::new();
+
+        if !slice_type.is_intra() {
+            ref_pic_list_modification_l0 = parse_ref_pic_list_modification(&mut reader)?;
+        }
+        if slice_type == SliceType::B {
+            ref_pic_list_modification_l1 = parse_ref_pic_list_modification(&mut reader)?;
+        }
+
+        if pps.weighted_pred_flag && matches!(slice_type, SliceType::P | SliceType::SP) {
+            skip_pred_weight_table(
+                &mut reader,
+                sps.chroma_array_type(),
+                &[num_ref_idx_l0_active_minus1],
+            )?;
+        } else if pps.weighted_bipred_idc == 1 && slice_type == SliceType::B {
+            skip_pred_weight_table(
+                &mut reader,
+                sps.chroma_array_type(),
+                &[num_ref_idx_l0_active_minus1, num_ref_idx_l1_active_minus1],
+            )?;
+        }
+
+        let dec_ref_pic_marking = if nal_unit.nal_ref_idc != 0 {
+            Some(parse_dec_ref_pic_marking(&mut reader, idr)?)
+        } else {
+            None
+        };
+
+        let cabac_init_idc = if pps.entropy_coding_mode_flag && !slice_type.is_intra() {
+            reader.ue_max(2, "cabac_init_idc")? as u8
+        } else {
+            0
+        };
+
+        let slice_qp_delta = reader.se()?;
+
+        if matches!(slice_type, SliceType::SP | SliceType::SI) {
+            if slice_type == SliceType::SP {
+                // sp_for_switch_flag
+                reader.flag()?;
+            }
+            // slice_qs_delta
+            reader.se()?;
+        }
+
+        let mut disable_deblocking_filter_idc = 0;
+        let mut slice_alpha_c0_offset_div2 = 0;
+        let mut slice_beta_offset_div2 = 0;
+
+        if pps.deblocking_filter_control_present_flag {
+            disable_deblocking_filter_idc =
+                reader.ue_max(2, "disable_deblocking_filter_idc")? as u8;
+            if disable_deblocking_filter_idc != 1 {
+                slice_alpha_c0_offset_div2 =
+                    reader.se_range(-6, 6, "slice_alpha_c0_offset_div2")? as i8;
+                slice_beta_offset_div2 = reader.se_range(-6, 6, "slice_beta_offset_div2")? as i8;
+            }
+        }
+
+        Ok(SliceHeader {
+            nal_ref_idc: nal_unit.nal_ref_idc,
+            idr,
+            first_mb_in_slice,
+            slice_type,
+            pic_parameter_set_id,
+            colour_plane_id,
+            frame_num,
+            field_pic_flag,
+            bottom_field_flag,
+            idr_pic_id,
+            pic_order_cnt_lsb,
+            delta_pic_order_cnt_bottom,
+            delta_pic_order_cnt,
+            redundant_pic_cnt,
+            direct_spatial_mv_pred_flag,
+            num_ref_idx_l0_active_minus1,
+            num_ref_idx_l1_active_minus1,
+            ref_pic_list_modification_l0,
+            ref_pic_list_modification_l1,
+            dec_ref_pic_marking,
+            cabac_init_idc,
+            slice_qp_delta,
+            disable_deblocking_filter_idc,
+            slice_alpha_c0_offset_div2,
+            slice_beta_offset_div2,
+        })
+    }
+}
+
+/// The primary coded picture of an access unit, everything a decode submission needs from its slices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodedPicture {
+    /// Header of the first slice, the picture level syntax elements are the same in all of them.
+    pub header: SliceHeader,
+    /// Header of every slice in decoding order, each one builds its own reference picture lists.
+    pub slice_headers: Vec<SliceHeader>,
+    pub seq_parameter_set_id: u8,
+    /// Offset of every slice's start code within [`AccessUnit::to_annexb`].
+    pub slice_offsets: Vec<u32>,
+    /// Every slice is an I or SI slice.
+    pub is_intra: bool,
+}
+
+impl CodedPicture {
+    pub fn parse(
+        access_unit: &AccessUnit,
+        sequence_parameter_sets: &[SequenceParameterSet],
+        picture_parameter_sets: &[PictureParameterSet],
+    ) -> Result<Self> {
+        let mut header: Option<SliceHeader> = None;
+        let mut slice_headers = Vec::new();
+        let mut slice_offsets = Vec::new();
+        let mut is_intra = true;
+        let mut offset = 0;
+
+        for data in &access_unit.nal_units {
+            let nal_unit = NalUnit::parse(data)?;
+            let nal_unit_offset = offset;
+            offset += START_CODE.len() + data.len();
+
+            if !nal_unit.nal_unit_type.is_slice() {
+                continue;
+            }
+
+            let slice_header =
+                SliceHeader::parse(&nal_unit, sequence_parameter_sets, picture_parameter_sets)?;
+
+            // Redundant coded pictures are only of use when the primary one is damaged
+            if slice_header.redundant_pic_cnt > 0 {
+                continue;
+            }
+
+            if let Some(header) = &header {
+                if slice_header.pic_parameter_set_id != header.pic_parameter_set_id
+                    || slice_header.frame_num != header.frame_num
+                    || slice_header.idr != header.idr
+                {
+                    return Err(anyhow!(
+                        "Slice at offset {} does not belong to the picture with frame_num {}",
+                        nal_unit_offset,
+                        header.frame_num
+                    ));
+                }
+            }
+
+            is_intra &= slice_header.slice_type.is_intra();
+            slice_offsets.push(u32::try_from(nal_unit_offset)?);
+
+            if header.is_none() {
+                header = Some(slice_header.clone());
+            }
+            slice_headers.push(slice_header);
+        }
+
+        let header = header.ok_or_else(|| anyhow!("Access unit holds no coded slice"))?;
+        let (sps, _) = find_parameter_sets(
+            header.pic_parameter_set_id,
+            sequence_parameter_sets,
+            picture_parameter_sets,
+        )?;
+
+        Ok(CodedPicture {
+            seq_parameter_set_id: sps.seq_parameter_set_id,
+            header,
+            slice_headers,
+            slice_offsets,
+            is_intra,
+        })
+    }
+
+    /// `StdVideoDecodeH264PictureInfo` of the picture, the picture order count is worked out by the caller.
+    pub fn std_picture_info(&self, pic_order_cnt: [i32; 2]) -> StdVideoDecodeH264PictureInfo {
+        let mut picture_info: StdVideoDecodeH264PictureInfo = unsafe { mem::zeroed() };
+
+        picture_info
+            .flags
+            .set_field_pic_flag(self.header.field_pic_flag as u32);
+        picture_info.flags.set_is_intra(self.is_intra as u32);
+        picture_info.flags.set_IdrPicFlag(self.header.idr as u32);
+        picture_info
+            .flags
+            .set_bottom_field_flag(self.header.bottom_field_flag as u32);
+        picture_info
+            .flags
+            .set_is_reference((self.header.nal_ref_idc != 0) as u32);
+
+        picture_info.seq_parameter_set_id = self.seq_parameter_set_id;
+        picture_info.pic_parameter_set_id = self.header.pic_parameter_set_id;
+        // frame_num is at most 16 bits long
+        picture_info.frame_num = self.header.frame_num as u16;
+        picture_info.idr_pic_id = self.header.idr_pic_id;
+        picture_info.PicOrderCnt = pic_order_cnt;
+
+        picture_info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rbsp::BitWriter;
+    use crate::test_util::{idr_header, pps, slice_header, sps};
+
+    /// A slice of `sps(0)` up to pic_order_cnt_lsb, `rest` writes what follows.
+    fn write_slice(
+        first_mb_in_slice: u32,
+        slice_type: u32,
+        frame_num: u64,
+        rest: impl FnOnce(&mut BitWriter),
+    ) -> BitWriter {
+        let mut writer = BitWriter::default();
+        writer
+            .ue(first_mb_in_slice)
+            .ue(slice_type)
+            .ue(0)
+            .u(4, frame_num);
+        // idr_pic_id for I slices, all of them are IDR ones here
+        if slice_type % 5 == 2 {
+            writer.ue(0);
+        }
+        writer.u(4, 0);
+        rest(&mut writer);
+        writer
+    }
+
+    /// A P slice without overrides or modifications, marked with the sliding window when it is a
+    /// reference.
+    fn write_p_slice(first_mb_in_slice: u32, frame_num: u64, reference: bool) -> BitWriter {
+        write_slice(first_mb_in_slice, 0, frame_num, |writer| {
+            writer.flag(false).flag(false);
+            if reference {
+                writer.flag(false);
+            }
+            writer.se(0).ue(0).se(0).se(0);
+        })
+    }
+
+    fn write_idr_slice(first_mb_in_slice: u32) -> BitWriter {
+        write_slice(first_mb_in_slice, 7, 0, |writer| {
+            writer.flag(false).flag(false).se(0).ue(0).se(0).se(0);
+        })
+    }
+
+    fn parse(nal_unit: &[u8], pps: &PictureParameterSet) -> Result<SliceHeader> {
+        parse_with(nal_unit, &sps(0), pps)
+    }
+
+    fn parse_with(
+        nal_unit: &[u8],
+        sps: &SequenceParameterSet,
+        pps: &PictureParameterSet,
+    ) -> Result<SliceHeader> {
+        SliceHeader::parse(
+            &NalUnit::parse(nal_unit)?,
+            std::slice::from_ref(sps),
+            std::slice::from_ref(pps),
+        )
+    }
+
+    #[test]
+    fn parse_headers() {
+        let cases = [
+            (write_idr_slice(0).nal_unit(0x65), idr_header()),
+            (write_p_slice(0, 3, true).nal_unit(0x41), slice_header(3, 2)),
+            (
+                write_p_slice(0, 3, false).nal_unit(0x01),
+                slice_header(3, 0),
+            ),
+        ];
+        for (nal_unit, expected) in cases {
+            assert_eq!(parse(&nal_unit, &pps(0, 0)).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn ref_pic_list_modification() {
+        let nal_unit = write_slice(0, 0, 3, |writer| {
+            // num_ref_idx_l0_active_minus1 of 2
+            writer.flag(true).ue(2);
+            writer.flag(true).ue(0).ue(1).ue(1).ue(0).ue(2).ue(5).ue(3);
+            writer.se(0).ue(1);
+        })
+        .nal_unit(0x01);
+        let header = parse(&nal_unit, &pps(0, 0)).unwrap();
+        assert_eq!(header.num_ref_idx_l0_active_minus1, 2);
+        assert_eq!(
+            header.ref_pic_list_modification_l0,
+            [
+                RefPicListModification::ShortTermSubtract {
+                    abs_diff_pic_num_minus1: 1
+                },
+                RefPicListModification::ShortTermAdd {
+                    abs_diff_pic_num_minus1: 0
+                },
+                RefPicListModification::LongTerm {
+                    long_term_pic_num: 5
+                },
+            ]
+        );
+
+        // B slices modify both lists
+        let nal_unit = write_slice(0, 1, 3, |writer| {
+            writer.flag(true).flag(true).ue(1).ue(0);
+            writer.flag(false);
+            writer.flag(true).ue(1).ue(2).ue(3);
+            writer.se(0).ue(1);
+        })
+        .nal_unit(0x01);
+        let header = parse(&nal_unit, &pps(0, 0)).unwrap();
+        assert!(header.direct_spatial_mv_pred_flag);
+        assert_eq!(header.num_ref_idx_l0_active_minus1, 1);
+        assert_eq!(header.num_ref_idx_l1_active_minus1, 0);
+        assert!(header.ref_pic_list_modification_l0.is_empty());
+        assert_eq!(
+            header.ref_pic_list_modification_l1,
+            [RefPicListModification::ShortTermAdd {
+                abs_diff_pic_num_minus1: 2
+            }]
+        );
+
+        let too_many = write_slice(0, 0, 3, |writer| {
+            writer.flag(false).flag(true);
+            for _ in 0..33 {
+                writer.ue(0).ue(0);
+            }
+            writer.ue(3).se(0).ue(1);
+        })
+        .nal_unit(0x01);
+        let invalid = write_slice(0, 0, 3, |writer| {
+            writer.flag(false).flag(true).ue(4);
+        })
+        .nal_unit(0x01);
+        for (nal_unit, error) in [
+            (too_many, "more than 32 entries"),
+            (invalid, "modification_of_pic_nums_idc 4"),
+        ] {
+            let message = parse(&nal_unit, &pps(0, 0)).unwrap_err().to_string();
+            assert!(message.contains(error), "{}: {}", error, message);
+        }
+    }
+
+    #[test]
+    fn memory_management_control_operations() {
+        let nal_unit = write_slice(0, 0, 3, |writer| {
+            writer.flag(false).flag(false).flag(true);
+            writer
+                .ue(1)
+                .ue(0)
+                .ue(2)
+                .ue(1)
+                .ue(3)
+                .ue(2)
+                .ue(0)
+                .ue(4)
+                .ue(2)
+                .ue(5)
+                .ue(6)
+                .ue(1)
+                .ue(0);
+            writer.se(0).ue(1);
+        })
+        .nal_unit(0x41);
+
+        assert_eq!(
+            parse(&nal_unit, &pps(0, 0)).unwrap().dec_ref_pic_marking,
+            Some(DecRefPicMarking::Adaptive(vec![
+                MemoryManagementControlOperation::UnmarkShortTerm {
+                    difference_of_pic_nums_minus1: 0
+                },
+                MemoryManagementControlOperation::UnmarkLongTerm {
+                    long_term_pic_num: 1
+                },
+                MemoryManagementControlOperation::MarkLongTerm {
+                    difference_of_pic_nums_minus1: 2,
+                    long_term_frame_idx: 0
+                },
+                MemoryManagementControlOperation::SetMaxLongTermFrameIdx {
+                    max_long_term_frame_idx_plus1: 2
+                },
+                MemoryManagementControlOperation::UnmarkAll,
+                MemoryManagementControlOperation::MarkCurrentAsLongTerm {
+                    long_term_frame_idx: 1
+                },
+            ]))
+        );
+
+        let too_many = write_slice(0, 0, 3, |writer| {
+            writer.flag(false).flag(false).flag(true);
+            for _ in 0..67 {
+                writer.ue(1).ue(0);
+            }
+            writer.ue(0).se(0).ue(1);
+        })
+        .nal_unit(0x41);
+        let invalid = write_slice(0, 0, 3, |writer| {
+            writer.flag(false).flag(false).flag(true).ue(7);
+        })
+        .nal_unit(0x41);
+        for (nal_unit, error) in [
+            (too_many, "too many operations"),
+            (invalid, "memory_management_control_operation 7"),
+        ] {
+            let message = parse(&nal_unit, &pps(0, 0)).unwrap_err().to_string();
+            assert!(message.contains(error), "{}: {}", error, message);
+        }
+    }
+
+    #[test]
+    fn pred_weight_table() {
+        let weighted = PictureParameterSet {
+            weighted_pred_flag: true,
+            weighted_bipred_idc: 1,
+            ..pps(0, 0)
+        };
+        let monochrome = SequenceParameterSet {
+            profile_idc: 100,
+            chroma_format_idc: 0,
+            ..sps(0)
+        };
+
+        // P slice with two l0 entries, luma and chroma weights for the first
+        let p_slice = write_slice(0, 0, 3, |writer| {
+            writer.flag(true).ue(1).flag(false);
+            writer.ue(6).ue(6);
+            writer.flag(true).se(-3).se(2);
+            writer.flag(true).se(1).se(-1).se(2).se(-2);
+            writer.flag(false).flag(false);
+            writer.se(-4).ue(1);
+        })
+        .nal_unit(0x01);
+
+        // B slice with one entry in either list
+        let b_slice = write_slice(0, 1, 3, |writer| {
+            writer.flag(false).flag(false).flag(false).flag(false);
+            writer.ue(5).ue(5);
+            writer.flag(false).flag(true).se(1).se(1).se(1).se(1);
+            writer.flag(true).se(7).se(-7).flag(false);
+            writer.se(3).ue(2).se(1).se(-1);
+        })
+        .nal_unit(0x01);
+
+        // Without chroma there are neither chroma_log2_weight_denom nor chroma weights
+        let monochrome_slice = write_slice(0, 0, 3, |writer| {
+            writer.flag(false).flag(false);
+            writer.ue(6);
+            writer.flag(true).se(-3).se(2);
+            writer.se(-4).ue(1);
+        })
+        .nal_unit(0x01);
+
+        // An explicit weighted_bipred_idc without weighted_pred_flag only applies to B slices
+        let bipred_only = PictureParameterSet {
+            weighted_pred_flag: false,
+            ..weighted.clone()
+        };
+        let unweighted_p_slice = write_slice(0, 0, 3, |writer| {
+            writer.flag(false).flag(false).se(-4).ue(1);
+        })
+        .nal_unit(0x01);
+
+        let cases = [
+            (p_slice, sps(0), weighted.clone(), -4, 1),
+            (b_slice, sps(0), weighted.clone(), 3, 2),
+            (monochrome_slice, monochrome, weighted, -4, 1),
+            (unweighted_p_slice, sps(0), bipred_only, -4, 1),
+        ];
+        for (nal_unit, sps, pps, slice_qp_delta, disable_deblocking_filter_idc) in cases {
+            let header = parse_with(&nal_unit, &sps, &pps).unwrap();
+            assert_eq!(header.slice_qp_delta, slice_qp_delta, "{:?}", header);
+            assert_eq!(
+                header.disable_deblocking_filter_idc, disable_deblocking_filter_idc,
+                "{:?}",
+                header
+            );
+        }
+    }
+
+    fn access_unit(nal_units: Vec<Vec<u8>>) -> AccessUnit {
+        AccessUnit {
+            nal_units,
+            dts: 0,
+            pts: 0,
+            is_keyframe: false,
+        }
+    }
+
+    #[test]
+    fn coded_picture() {
+        let first = write_idr_slice(0).nal_unit(0x65);
+        let second = write_idr_slice(150).nal_unit(0x65);
+        let access_unit = access_unit(vec![
+            vec![0x67, 0x4d, 0x40, 0x1e],
+            vec![0x68, 0xee, 0x3c, 0x80, 0x01],
+            vec![0x06, 0x05, 0x80],
+            first.clone(),
+            second,
+        ]);
+
+        let picture = CodedPicture::parse(&access_unit, &[sps(0)], &[pps(0, 0)]).unwrap();
+        assert_eq!(picture.header, idr_header());
+        assert_eq!(picture.slice_headers.len(), 2);
+        assert_eq!(picture.slice_headers[1].first_mb_in_slice, 150);
+        assert!(picture.is_intra);
+        assert_eq!(picture.seq_parameter_set_id, 0);
+
+        // Every NAL unit in front takes a 3 byte start code and itself
+        let first_offset = (3 + 4) + (3 + 5) + (3 + 3);
+        assert_eq!(
+            picture.slice_offsets,
+            [first_offset, first_offset + 3 + first.len() as u32]
+        );
+
+        let annexb = access_unit.to_annexb();
+        for offset in picture.slice_offsets {
+            let offset = offset as usize;
+            assert_eq!(annexb[offset..offset + 3], START_CODE);
+            assert_eq!(annexb[offset + 3], 0x65);
+        }
+    }
+
+    #[test]
+    fn redundant_slices_are_skipped() {
+        let redundant = PictureParameterSet {
+            redundant_pic_cnt_present_flag: true,
+            ..pps(0, 0)
+        };
+        let write = |first_mb_in_slice, frame_num, redundant_pic_cnt| {
+            write_slice(first_mb_in_slice, 0, frame_num, |writer| {
+                writer.ue(redundant_pic_cnt);
+                writer.flag(false).flag(false).flag(false).se(0).ue(1);
+            })
+            .nal_unit(0x41)
+        };
+
+        // The redundant slice does not have to match the primary picture
+        let access_unit = access_unit(vec![write(0, 3, 0), write(0, 4, 1), write(150, 3, 0)]);
+        let picture = CodedPicture::parse(&access_unit, &[sps(0)], &[redundant]).unwrap();
+        assert_eq!(picture.slice_headers.len(), 2);
+        assert_eq!(picture.slice_offsets.len(), 2);
+        assert!(picture
+            .slice_headers
+            .iter()
+            .all(|header| header.redundant_pic_cnt == 0 && header.frame_num == 3));
+        assert!(!picture.is_intra);
+    }
+
+    #[test]
+    fn slices_of_another_picture() {
+        let other_pps = PictureParameterSet {
+            pic_parameter_set_id: 1,
+            ..pps(0, 0)
+        };
+        let mut other_pps_slice = BitWriter::default();
+        other_pps_slice
+            .ue(150)
+            .ue(0)
+            .ue(1)
+            .u(4, 3)
+            .u(4, 0)
+            .flag(false)
+            .flag(false)
+            .flag(false)
+            .se(0)
+            .ue(1);
+
+        let cases = [
+            vec![
+                write_p_slice(0, 3, true).nal_unit(0x41),
+                write_p_slice(150, 4, true).nal_unit(0x41),
+            ],
+            vec![
+                write_p_slice(0, 3, true).nal_unit(0x41),
+                other_pps_slice.nal_unit(0x41),
+            ],
+            vec![
+                write_idr_slice(0).nal_unit(0x65),
+                write_p_slice(150, 0, true).nal_unit(0x41),
+            ],
+        ];
+        for nal_units in cases {
+            let error = CodedPicture::parse(
+                &access_unit(nal_units),
+                &[sps(0)],
+                &[pps(0, 0), other_pps.clone()],
+            )
+            .unwrap_err();
+            assert!(error.to_string().contains("does not belong"), "{}", error);
+        }
+
+        let no_slice = access_unit(vec![vec![0x06, 0x05, 0x80]]);
+        assert!(CodedPicture::parse(&no_slice, &[sps(0)], &[pps(0, 0)]).is_err());
+    }
+
+    #[test]
+    fn std_picture_info() {
+        let picture = |header: SliceHeader| CodedPicture {
+            is_intra: header.slice_type.is_intra(),
+            slice_headers: vec![header.clone()],
+            header,
+            seq_parameter_set_id: 2,
+            slice_offsets: vec![0],
+        };
+
+        let idr = picture(SliceHeader {
+            idr_pic_id: 7,
+            pic_parameter_set_id: 1,
+            ..idr_header()
+        });
+        let info = idr.std_picture_info([0, 0]);
+        assert_eq!(info.flags.IdrPicFlag(), 1);
+        assert_eq!(info.flags.is_intra(), 1);
+        assert_eq!(info.flags.is_reference(), 1);
+        assert_eq!(info.flags.field_pic_flag(), 0);
+        assert_eq!(info.seq_parameter_set_id, 2);
+        assert_eq!(info.pic_parameter_set_id, 1);
+        assert_eq!(info.idr_pic_id, 7);
+
+        let bottom_field = picture(SliceHeader {
+            field_pic_flag: true,
+            bottom_field_flag: true,
+            ..slice_header(9, 0)
+        });
+        let info = bottom_field.std_picture_info([12, 13]);
+        assert_eq!(info.flags.IdrPicFlag(), 0);
+        assert_eq!(info.flags.is_intra(), 0);
+        assert_eq!(info.flags.is_reference(), 0);
+        assert_eq!(info.flags.field_pic_flag(), 1);
+        assert_eq!(info.flags.bottom_field_flag(), 1);
+        assert_eq!(info.frame_num, 9);
+        assert_eq!(info.PicOrderCnt, [12, 13]);
+    }
+}
@@ -0,0 +1,295 @@
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use crate::annexb::AnnexBSource;
+use crate::avc::{parse_avc_config, AVCVideoConfiguration};
+use crate::mp4::{Mp4Sample, SampleTable};
+use crate::nal::avcc_nal_units;
+
+/// Annex B start code written in front of every NAL unit handed to the decoder.
+pub const START_CODE: [u8; 3] = [0, 0, 1];
+
+/// One coded picture with its parameter sets and SEI, independent of the container it came from.
+#[derive(Clone, Debug)]
+pub struct AccessUnit {
+    /// NAL units including their header byte, without start codes or length prefixes.
+    pub nal_units: Vec<Vec<u8>>,
+    /// Decode and presentation timestamps in [`VideoSource::timescale`] units.
+    pub dts: i64,
+    pub pts: i64,
+    pub is_keyframe: bool,
+}
+
+impl AccessUnit {
+    /// The access unit as an Annex B byte stream, the layout Vulkan video expects in the bitstream buffer.
+    pub fn to_annexb(&self) -> Vec<u8> {
+        let size = self
+            .nal_units
+            .iter()
+            .map(|nal_unit| START_CODE.len() + nal_unit.len())
+            .sum();
+
+        let mut out = Vec::with_capacity(size);
+        for nal_unit in &self.nal_units {
+            out.extend_from_slice(&START_CODE);
+            out.extend_from_slice(nal_unit);
+        }
+
+        out
+    }
+}
+
+pub trait VideoSource {
+    /// Next access unit in decode order, `None` at the end of the stream.
+    fn next_access_unit(&mut self) -> Option<Result<AccessUnit>>;
+
+    /// Width and height of the coded pictures if the container stores them.
+    fn coded_extent(&self) -> Option<(u32, u32)>;
+
+    /// Units per second of the access unit timestamps.
+    fn timescale(&self) -> u64;
+}
+
+/// H.264 video track of an MP4 file.
+///
+/// The parameter sets from the avcC box are prepended to the first access unit so that the
+/// decoder sees the same in-band layout as with an Annex B stream.
+pub struct Mp4Source {
+    data: Vec<u8>,
+    samples: Vec<Mp4Sample>,
+    next_sample: usize,
+    config: AVCVideoConfiguration,
+    width: u32,
+    height: u32,
+    timescale: u64,
+}
+
+impl Mp4Source {
+    pub fn new(data: Vec<u8>) -> Result<Self> {
+        let context = mp4parse::read_mp4(&mut Cursor::new(&data))?;
+
+        let track = context
+            .tracks
+            .iter()
+            .find(|track| track.track_type == mp4parse::TrackType::Video)
+            .ok_or_else(|| anyhow!("No video track found"))?;
+
+        let stsd = track
+            .stsd
+            .as_ref()
+            .ok_or_else(|| anyhow!("Video track has no stsd box"))?;
+
+        let v = match stsd.descriptions.first() {
+            Some(mp4parse::SampleEntry::Video(v)) => v,
+            _ => return Err(anyhow!("Expected a VideoSampleEntry")),
+        };
+
+        let config = match v.codec_specific {
+            mp4parse::VideoCodecSpecific::AVCConfig(ref avcc) => parse_avc_config(avcc)?,
+            _ => return Err(anyhow!("Only H.264 video tracks are supported")),
+        };
+
+        let sample_table = SampleTable::from_track(track)?;
+        let samples = sample_table.samples().collect::<Result<Vec<_>>>()?;
+
+        if let Some(sample) = samples
+            .iter()
+            .find(|sample| sample.file_offset + sample.size as u64 > data.len() as u64)
+        {
+            return Err(anyhow!(
+                "Sample at offset {} of {} bytes lies past the end of the file",
+                sample.file_offset,
+                sample.size
+            ));
+        }
+
+        Ok(Mp4Source {
+            width: v.width as u32,
+            height: v.height as u32,
+            timescale: sample_table.timescale,
+            data,
+            samples,
+            next_sample: 0,
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &AVCVideoConfiguration {
+        &self.config
+    }
+
+    pub fn samples(&self) -> &[Mp4Sample] {
+        &self.samples
+    }
+
+    fn read_access_unit(&self, sample: &Mp4Sample) -> Result<AccessUnit> {
+        let start = sample.file_offset as usize;
+        let sample_data = &self.data[start..start + sample.size as usize];
+
+        let mut nal_units = Vec::new();
+
+        if self.next_sample == 0 {
+            nal_units.extend(self.config.sps.iter().cloned());
+            nal_units.extend(self.config.pps.iter().cloned());
+        }
+
+        for nal_unit in avcc_nal_units(sample_data, self.config.nal_length_size())? {
+            nal_units.push(nal_unit?.data.to_vec());
+        }
+
+        Ok(AccessUnit {
+            nal_units,
+            dts: sample.dts as i64,
+            pts: sample.pts,
+            is_keyframe: sample.is_keyframe,
+        })
+    }
+}
+
+impl VideoSource for Mp4Source {
+    fn next_access_unit(&mut self) -> Option<Result<AccessUnit>> {
+        let sample = *self.samples.get(self.next_sample)?;
+        let access_unit = self.read_access_unit(&sample);
+        self.next_sample += 1;
+
+        Some(access_unit)
+    }
+
+    fn coded_extent(&self) -> Option<(u32, u32)> {
+        Some((self.width, self.height))
+    }
+
+    fn timescale(&self) -> u64 {
+        self.timescale
+    }
+}
+
+fn is_annexb_extension(path: &Path) -> Option<bool> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+    match extension.as_str() {
+        "h264" | "264" | "avc" | "h26l" | "jsv" => Some(true),
+        "mp4" | "m4v" | "mov" => Some(false),
+        _ => None,
+    }
+}
+
+fn is_annexb_data(data: &[u8]) -> Result<bool> {
+    if data.get(4..8) == Some(b"ftyp") {
+        Ok(false)
+    } else if data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1]) {
+        Ok(true)
+    } else {
+        Err(anyhow!(
+            "Unrecognized video file, expected MP4 or an Annex B H.264 stream"
+        ))
+    }
+}
+
+/// Opens an MP4 or Annex B file, picked by extension and falling back to sniffing the content.
+pub fn open_video_source(path: impl AsRef<Path>) -> Result<Box<dyn VideoSource>> {
+    let path = path.as_ref();
+    let data = std::fs::read(path)?;
+
+    let is_annexb = match is_annexb_extension(path) {
+        Some(is_annexb) => is_annexb,
+        None => is_annexb_data(&data)?,
+    };
+
+    if is_annexb {
+        Ok(Box::new(AnnexBSource::new(data)))
+    } else {
+        Ok(Box::new(Mp4Source::new(data)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_data() -> Vec<u8> {
+        std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/samples/a.mp4")).unwrap()
+    }
+
+    fn annexb_data() -> Vec<u8> {
+        [
+            &[0, 0, 0, 1, 0x67, 0x4d, 0x40, 0x1e][..],
+            &[0, 0, 1, 0x65, 0x88, 0x84],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn extensions() {
+        let cases = [
+            ("video.h264", Some(true)),
+            ("video.264", Some(true)),
+            ("dir/video.H264", Some(true)),
+            ("video.jsv", Some(true)),
+            ("video.mp4", Some(false)),
+            ("video.MOV", Some(false)),
+            ("video.m4v", Some(false)),
+            ("video.bin", None),
+            ("video", None),
+        ];
+
+        for (path, expected) in cases {
+            assert_eq!(is_annexb_extension(Path::new(path)), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn sniffing() {
+        assert!(!is_annexb_data(&mp4_data()).unwrap());
+        assert!(is_annexb_data(&annexb_data()).unwrap());
+        assert!(is_annexb_data(&annexb_data()[1..]).unwrap());
+
+        for data in [&[][..], &[0, 0, 2, 0x65], &[0x65, 0, 0, 1]] {
+            let message = is_annexb_data(data).unwrap_err().to_string();
+            assert!(message.contains("Unrecognized video file"), "{}", message);
+        }
+    }
+
+    #[test]
+    fn open() {
+        let directory = std::env::temp_dir().join(format!("ash-video-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        // The extension wins over the content, the content is sniffed otherwise
+        let cases = [
+            ("annexb.h264", annexb_data(), Ok(None)),
+            ("annexb.bin", annexb_data(), Ok(None)),
+            ("annexb", annexb_data(), Ok(None)),
+            ("mp4.MP4", mp4_data(), Ok(Some((176, 144)))),
+            ("mp4.bin", mp4_data(), Ok(Some((176, 144)))),
+            ("annexb.mp4", annexb_data(), Err("")),
+            (
+                "garbage.bin",
+                vec![0xff; 16],
+                Err("Unrecognized video file"),
+            ),
+        ];
+
+        for (name, data, expected) in cases {
+            let path = directory.join(name);
+            std::fs::write(&path, data).unwrap();
+
+            match (open_video_source(&path), expected) {
+                (Ok(source), Ok(coded_extent)) => {
+                    assert_eq!(source.coded_extent(), coded_extent, "{}", name)
+                }
+                (Err(err), Err(error)) => {
+                    let message = err.to_string();
+                    assert!(message.contains(error), "{}: {}", name, message);
+                }
+                (Ok(_), Err(_)) => panic!("{}: expected an error", name),
+                (Err(err), Ok(_)) => panic!("{}: {}", name, err),
+            }
+        }
+
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(open_video_source(directory.join("missing.mp4")).is_err());
+    }
+}
@@ -0,0 +1,1799 @@
+use std::mem;
+
+use anyhow::{anyhow, Result};
+use ash::vk;
+use ash::vk::native::{
+    StdVideoH264HrdParameters, StdVideoH264LevelIdc, StdVideoH264ProfileIdc,
+    StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_BASELINE,
+    StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_HIGH,
+    StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_HIGH_444_PREDICTIVE,
+    StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_MAIN, StdVideoH264ScalingLists,
+    StdVideoH264SequenceParameterSet, StdVideoH264SequenceParameterSetVui,
+};
+
+use crate::nal::{NalUnit, NalUnitType};
+use crate::rbsp::{remove_emulation_prevention, BitReader};
+
+/// Default_4x4_Intra, ITU-T H.264 Table 7-3
+const DEFAULT_4X4_INTRA: [u8; 16] = [
+    6, 13, 13, 20, 20, 20, 28, 28, 28, 28, 32, 32, 32, 37, 37, 42,
+];
+/// Default_4x4_Inter, ITU-T H.264 Table 7-3
+const DEFAULT_4X4_INTER: [u8; 16] = [
+    10, 14, 14, 20, 20, 20, 24, 24, 24, 24, 27, 27, 27, 30, 30, 34,
+];
+/// Default_8x8_Intra, ITU-T H.264 Table 7-4
+const DEFAULT_8X8_INTRA: [u8; 64] = [
+    6, 10, 10, 13, 11, 13, 16, 16, 16, 16, 18, 18, 18, 18, 18, 23, 23, 23, 23, 23, 23, 25, 25, 25,
+    25, 25, 25, 25, 27, 27, 27, 27, 27, 27, 27, 27, 29, 29, 29, 29, 29, 29, 29, 31, 31, 31, 31, 31,
+    31, 33, 33, 33, 33, 33, 36, 36, 36, 36, 38, 38, 38, 40, 40, 42,
+];
+/// Default_8x8_Inter, ITU-T H.264 Table 7-4
+const DEFAULT_8X8_INTER: [u8; 64] = [
+    9, 13, 13, 15, 13, 15, 17, 17, 17, 17, 19, 19, 19, 19, 19, 21, 21, 21, 21, 21, 21, 22, 22, 22,
+    22, 22, 22, 22, 24, 24, 24, 24, 24, 24, 24, 24, 25, 25, 25, 25, 25, 25, 25, 27, 27, 27, 27, 27,
+    27, 28, 28, 28, 28, 28, 30, 30, 30, 30, 32, 32, 32, 33, 33, 35,
+];
+
+/// profile_idc values whose SPS carries chroma_format_idc, bit depths and the scaling matrix.
+const HIGH_PROFILES: [u8; 13] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+
+/// Scaling matrices of an SPS or PPS with the fall-back rules of ITU-T H.264 Table 7-2 already applied.
+///
+/// Lists are kept in the zig-zag order they are coded in. Index i of the masks is list i of the
+/// syntax, 0..6 are the 4x4 lists and 6..12 the 8x8 ones.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScalingLists {
+    pub present_mask: u16,
+    pub use_default_mask: u16,
+    pub lists_4x4: [[u8; 16]; 6],
+    pub lists_8x8: [[u8; 64]; 6],
+}
+
+impl ScalingLists {
+    /// Flat_4x4_16 and Flat_8x8_16, what applies when no scaling matrix is sent at all.
+    pub fn flat() -> Self {
+        ScalingLists {
+            present_mask: 0,
+            use_default_mask: 0,
+            lists_4x4: [[16; 16]; 6],
+            lists_8x8: [[16; 64]; 6],
+        }
+    }
+
+    /// The lists fall-back rule A starts from.
+    pub(crate) fn defaults() -> Self {
+        ScalingLists {
+            present_mask: 0,
+            use_default_mask: 0,
+            lists_4x4: [
+                DEFAULT_4X4_INTRA,
+                DEFAULT_4X4_INTRA,
+                DEFAULT_4X4_INTRA,
+                DEFAULT_4X4_INTER,
+                DEFAULT_4X4_INTER,
+                DEFAULT_4X4_INTER,
+            ],
+            lists_8x8: [
+                DEFAULT_8X8_INTRA,
+                DEFAULT_8X8_INTER,
+                DEFAULT_8X8_INTRA,
+                DEFAULT_8X8_INTER,
+                DEFAULT_8X8_INTRA,
+                DEFAULT_8X8_INTER,
+            ],
+        }
+    }
+
+    /// Reads `list_count` scaling_list() entries, lists that are not sent are inferred from
+    /// `fallback` (rule A: the defaults, rule B: the SPS lists) or from the previous list.
+    pub(crate) fn parse(
+        reader: &mut BitReader,
+        list_count: usize,
+        fallback: &ScalingLists,
+    ) -> Result<Self> {
+        let mut lists = ScalingLists::flat();
+
+        for i in 0..12 {
+            let present = i < list_count && reader.flag()?;
+
+            if i < 6 {
+                if present {
+                    let use_default = parse_scaling_list(reader, &mut lists.lists_4x4[i])?;
+                    lists.present_mask |= 1 << i;
+                    if use_default {
+                        lists.lists_4x4[i] = ScalingLists::defaults().lists_4x4[i];
+                        lists.use_default_mask |= 1 << i;
+                    }
+                } else {
+                    lists.lists_4x4[i] = match i {
+                        0 | 3 => fallback.lists_4x4[i],
+                        _ => lists.lists_4x4[i - 1],
+                    };
+                }
+            } else {
+                let j = i - 6;
+                if present {
+                    let use_default = parse_scaling_list(reader, &mut lists.lists_8x8[j])?;
+                    lists.present_mask |= 1 << i;
+                    if use_default {
+                        lists.lists_8x8[j] = ScalingLists::defaults().lists_8x8[j];
+                        lists.use_default_mask |= 1 << i;
+                    }
+                } else {
+                    lists.lists_8x8[j] = match j {
+                        0 | 1 => fallback.lists_8x8[j],
+                        _ => lists.lists_8x8[j - 2],
+                    };
+                }
+            }
+        }
+
+        Ok(lists)
+    }
+
+    pub fn to_std(&self) -> StdVideoH264ScalingLists {
+        StdVideoH264ScalingLists {
+            scaling_list_present_mask: self.present_mask,
+            use_default_scaling_matrix_mask: self.use_default_mask,
+            ScalingList4x4: self.lists_4x4,
+            ScalingList8x8: self.lists_8x8,
+        }
+    }
+}
+
+/// scaling_list(), ITU-T H.264 7.3.2.1.1.1, returns useDefaultScalingMatrixFlag.
+fn parse_scaling_list(reader: &mut BitReader, list: &mut [u8]) -> Result<bool> {
+    let mut last_scale = 8i32;
+    let mut next_scale = 8i32;
+    let mut use_default = false;
+
+    for (j, scale) in list.iter_mut().enumerate() {
+        if next_scale != 0 {
+            let delta_scale = reader.se_range(-128, 127, "delta_scale")?;
+            next_scale = (last_scale + delta_scale + 256) % 256;
+            use_default = j == 0 && next_scale == 0;
+        }
+
+        *scale = if next_scale == 0 {
+            last_scale
+        } else {
+            next_scale
+        } as u8;
+        last_scale = *scale as i32;
+    }
+
+    Ok(use_default)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameCropping {
+    pub left_offset: u32,
+    pub right_offset: u32,
+    pub top_offset: u32,
+    pub bottom_offset: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AspectRatio {
+    /// Table E-1, 255 is Extended_SAR
+    pub aspect_ratio_idc: u8,
+    pub sar_width: u16,
+    pub sar_height: u16,
+}
+
+impl AspectRatio {
+    /// The horizontal and vertical size of a sample as width and height, Table E-1, `None` when
+    /// unspecified or reserved.
+    pub fn sample_aspect_ratio(&self) -> Option<(u32, u32)> {
+        match self.aspect_ratio_idc {
+            1 => Some((1, 1)),
+            2 => Some((12, 11)),
+            3 => Some((10, 11)),
+            4 => Some((16, 11)),
+            5 => Some((40, 33)),
+            6 => Some((24, 11)),
+            7 => Some((20, 11)),
+            8 => Some((32, 11)),
+            9 => Some((80, 33)),
+            10 => Some((18, 11)),
+            11 => Some((15, 11)),
+            12 => Some((64, 33)),
+            13 => Some((160, 99)),
+            14 => Some((4, 3)),
+            15 => Some((3, 2)),
+            16 => Some((2, 1)),
+            // Extended_SAR, either being 0 leaves it unspecified
+            255 if self.sar_width != 0 && self.sar_height != 0 => {
+                Some((self.sar_width as u32, self.sar_height as u32))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColourDescription {
+    pub colour_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VideoSignalType {
+    pub video_format: u8,
+    pub video_full_range_flag: bool,
+    pub colour_description: Option<ColourDescription>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChromaLocInfo {
+    pub chroma_sample_loc_type_top_field: u8,
+    pub chroma_sample_loc_type_bottom_field: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimingInfo {
+    pub num_units_in_tick: u32,
+    pub time_scale: u32,
+    pub fixed_frame_rate_flag: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpbSpecification {
+    pub bit_rate_value_minus1: u32,
+    pub cpb_size_value_minus1: u32,
+    pub cbr_flag: bool,
+}
+
+/// hrd_parameters(), ITU-T H.264 E.1.2
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HrdParameters {
+    pub bit_rate_scale: u8,
+    pub cpb_size_scale: u8,
+    /// One entry per cpb, cpb_cnt_minus1 + 1 in total.
+    pub cpb_specifications: Vec<CpbSpecification>,
+    pub initial_cpb_removal_delay_length_minus1: u8,
+    pub cpb_removal_delay_length_minus1: u8,
+    pub dpb_output_delay_length_minus1: u8,
+    pub time_offset_length: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BitstreamRestriction {
+    pub motion_vectors_over_pic_boundaries_flag: bool,
+    pub max_bytes_per_pic_denom: u8,
+    pub max_bits_per_mb_denom: u8,
+    pub log2_max_mv_length_horizontal: u8,
+    pub log2_max_mv_length_vertical: u8,
+    pub max_num_reorder_frames: u8,
+    pub max_dec_frame_buffering: u8,
+}
+
+/// vui_parameters(), ITU-T H.264 E.1.1, optional parts are `None` when their present flag is 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VuiParameters {
+    pub aspect_ratio: Option<AspectRatio>,
+    pub overscan_appropriate_flag: Option<bool>,
+    pub video_signal_type: Option<VideoSignalType>,
+    pub chroma_loc_info: Option<ChromaLocInfo>,
+    pub timing_info: Option<TimingInfo>,
+    pub nal_hrd_parameters: Option<HrdParameters>,
+    pub vcl_hrd_parameters: Option<HrdParameters>,
+    pub low_delay_hrd_flag: bool,
+    pub pic_struct_present_flag: bool,
+    pub bitstream_restriction: Option<BitstreamRestriction>,
+}
+
+/// seq_parameter_set_data(), ITU-T H.264 7.3.2.1.1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SequenceParameterSet {
+    pub profile_idc: u8,
+    /// constraint_set0_flag to constraint_set5_flag from the most significant bit down, the two
+    /// reserved_zero_2bits are dropped.
+    pub constraint_set_flags: u8,
+    pub level_idc: u8,
+    pub seq_parameter_set_id: u8,
+    pub chroma_format_idc: u8,
+    pub separate_colour_plane_flag: bool,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+    pub qpprime_y_zero_transform_bypass_flag: bool,
+    /// Present when seq_scaling_matrix_present_flag is set.
+    pub scaling_lists: Option<ScalingLists>,
+    pub log2_max_frame_num_minus4: u8,
+    pub pic_order_cnt_type: u8,
+    pub log2_max_pic_order_cnt_lsb_minus4: u8,
+    pub delta_pic_order_always_zero_flag: bool,
+    pub offset_for_non_ref_pic: i32,
+    pub offset_for_top_to_bottom_field: i32,
+    pub offset_for_ref_frame: Vec<i32>,
+    pub max_num_ref_frames: u8,
+    pub gaps_in_frame_num_value_allowed_flag: bool,
+    pub pic_width_in_mbs_minus1: u32,
+    pub pic_height_in_map_units_minus1: u32,
+    pub frame_mbs_only_flag: bool,
+    pub mb_adaptive_frame_field_flag: bool,
+    pub direct_8x8_inference_flag: bool,
+    pub frame_cropping: Option<FrameCropping>,
+    pub vui: Option<VuiParameters>,
+}
+
+fn parse_hrd_parameters(reader: &mut BitReader) -> Result<HrdParameters> {
+    let cpb_cnt_minus1 = reader.ue_max(31, "cpb_cnt_minus1")?;
+    let bit_rate_scale = reader.u(4)? as u8;
+    let cpb_size_scale = reader.u(4)? as u8;
+
+    let cpb_specifications = (0..=cpb_cnt_minus1)
+        .map(|_| {
+            Ok(CpbSpecification {
+                bit_rate_value_minus1: reader.ue_max(u32::MAX - 1, "bit_rate_value_minus1")?,
+                cpb_size_value_minus1: reader.ue_max(u32::MAX - 1, "cpb_size_value_minus1")?,
+                cbr_flag: reader.flag()?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(HrdParameters {
+        bit_rate_scale,
+        cpb_size_scale,
+        cpb_specifications,
+        initial_cpb_removal_delay_length_minus1: reader.u(5)? as u8,
+        cpb_removal_delay_length_minus1: reader.u(5)? as u8,
+        dpb_output_delay_length_minus1: reader.u(5)? as u8,
+        time_offset_length: reader.u(5)? as u8,
+    })
+}
+
+fn parse_vui_parameters(reader: &mut BitReader) -> Result<VuiParameters> {
+    let aspect_ratio = if reader.flag()? {
+        let aspect_ratio_idc = reader.u(8)? as u8;
+        let (sar_width, sar_height) = if aspect_ratio_idc == 255 {
+            (reader.u(16)? as u16, reader.u(16)? as u16)
+        } else {
+            (0, 0)
+        };
+
+        Some(AspectRatio {
+            aspect_ratio_idc,
+            sar_width,
+            sar_height,
+        })
+    } else {
+        None
+    };
+
+    let overscan_appropriate_flag = if reader.flag()? {
+        Some(reader.flag()?)
+    } else {
+        None
+    };
+
+    let video_signal_type = if reader.flag()? {
+        let video_format = reader.u(3)? as u8;
+        let video_full_range_flag = reader.flag()?;
+        let colour_description = if reader.flag()? {
+            Some(ColourDescription {
+                colour_primaries: reader.u(8)? as u8,
+                transfer_characteristics: reader.u(8)? as u8,
+                matrix_coefficients: reader.u(8)? as u8,
+            })
+        } else {
+            None
+        };
+
+        Some(VideoSignalType {
+            video_format,
+            video_full_range_flag,
+            colour_description,
+        })
+    } else {
+        None
+    };
+
+    let chroma_loc_info = if reader.flag()? {
+        Some(ChromaLocInfo {
+            chroma_sample_loc_type_top_field: reader
+                .ue_max(5, "chroma_sample_loc_type_top_field")?
+                as u8,
+            chroma_sample_loc_type_bottom_field: reader
+                .ue_max(5, "chroma_sample_loc_type_bottom_field")?
+                as u8,
+        })
+    } else {
+        None
+    };
+
+    let timing_info = if reader.flag()? {
+        let timing_info = TimingInfo {
+            num_units_in_tick: reader.u(32)?,
+            time_scale: reader.u(32)?,
+            fixed_frame_rate_flag: reader.flag()?,
+        };
+        if timing_info.num_units_in_tick == 0 || timing_info.time_scale == 0 {
+            return Err(anyhow!(
+                "VUI timing info with num_units_in_tick {} and time_scale {}",
+                timing_info.num_units_in_tick,
+                timing_info.time_scale
+            ));
+        }
+
+        Some(timing_info)
+    } else {
+        None
+    };
+
+    let nal_hrd_parameters = if reader.flag()? {
+        Some(parse_hrd_parameters(reader)?)
+    } else {
+        None
+    };
+    let vcl_hrd_parameters = if reader.flag()? {
+        Some(parse_hrd_parameters(reader)?)
+    } else {
+        None
+    };
+
+    let low_delay_hrd_flag = if nal_hrd_parameters.is_some() || vcl_hrd_parameters.is_some() {
+        reader.flag()?
+    } else {
+        false
+    };
+    let pic_struct_present_flag = reader.flag()?;
+
+    let bitstream_restriction = if reader.flag()? {
+        let motion_vectors_over_pic_boundaries_flag = reader.flag()?;
+        let max_bytes_per_pic_denom = reader.ue_max(16, "max_bytes_per_pic_denom")? as u8;
+        let max_bits_per_mb_denom = reader.ue_max(16, "max_bits_per_mb_denom")? as u8;
+        let log2_max_mv_length_horizontal =
+            reader.ue_max(15, "log2_max_mv_length_horizontal")? as u8;
+        let log2_max_mv_length_vertical = reader.ue_max(15, "log2_max_mv_length_vertical")? as u8;
+        let max_num_reorder_frames = reader.ue_max(16, "max_num_reorder_frames")? as u8;
+        let max_dec_frame_buffering = reader.ue_max(16, "max_dec_frame_buffering")? as u8;
+
+        if max_num_reorder_frames > max_dec_frame_buffering {
+            return Err(anyhow!(
+                "max_num_reorder_frames of {} exceeds max_dec_frame_buffering of {}",
+                max_num_reorder_frames,
+                max_dec_frame_buffering
+            ));
+        }
+
+        Some(BitstreamRestriction {
+            motion_vectors_over_pic_boundaries_flag,
+            max_bytes_per_pic_denom,
+            max_bits_per_mb_denom,
+            log2_max_mv_length_horizontal,
+            log2_max_mv_length_vertical,
+            max_num_reorder_frames,
+            max_dec_frame_buffering,
+        })
+    } else {
+        None
+    };
+
+    Ok(VuiParameters {
+        aspect_ratio,
+        overscan_appropriate_flag,
+        video_signal_type,
+        chroma_loc_info,
+        timing_info,
+        nal_hrd_parameters,
+        vcl_hrd_parameters,
+        low_delay_hrd_flag,
+        pic_struct_present_flag,
+        bitstream_restriction,
+    })
+}
+
+/// level_idc to StdVideoH264LevelIdc, level 1b (level_idc 9, or 11 with constraint_set3_flag in
+/// the Baseline and Main profiles) has no value of its own and is rounded up to 1.1.
+fn std_level_idc(level_idc: u8) -> Result<StdVideoH264LevelIdc> {
+    let levels = [
+        9, 10, 11, 12, 13, 20, 21, 22, 30, 31, 32, 40, 41, 42, 50, 51, 52, 60, 61, 62,
+    ];
+
+    match levels.iter().position(|&level| level == level_idc) {
+        Some(0) => Ok(1),
+        Some(index) => Ok(index as StdVideoH264LevelIdc - 1),
+        None => Err(anyhow!("Unknown H.264 level_idc {}", level_idc)),
+    }
+}
+
+impl SequenceParameterSet {
+    pub fn parse(nal_unit: &NalUnit) -> Result<Self> {
+        if nal_unit.nal_unit_type != NalUnitType::Sps {
+            return Err(anyhow!(
+                "Expected a sequence parameter set NAL unit, got {:?}",
+                nal_unit.nal_unit_type
+            ));
+        }
+
+        let rbsp = remove_emulation_prevention(nal_unit.payload());
+        let mut reader = BitReader::new(&rbsp);
+
+        let profile_idc = reader.u(8)? as u8;
+        let constraint_set_flags = reader.u(8)? as u8 & 0b11111100;
+        let level_idc = reader.u(8)? as u8;
+        let seq_parameter_set_id = reader.ue_max(31, "seq_parameter_set_id")? as u8;
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane_flag = false;
+        let mut bit_depth_luma_minus8 = 0;
+        let mut bit_depth_chroma_minus8 = 0;
+        let mut qpprime_y_zero_transform_bypass_flag = false;
+        let mut scaling_lists = None;
+
+        if HIGH_PROFILES.contains(&profile_idc) {
+            chroma_format_idc = reader.ue_max(3, "chroma_format_idc")? as u8;
+            if chroma_format_idc == 3 {
+                separate_colour_plane_flag = reader.flag()?;
+            }
+            bit_depth_luma_minus8 = reader.ue_max(6, "bit_depth_luma_minus8")? as u8;
+            bit_depth_chroma_minus8 = reader.ue_max(6, "bit_depth_chroma_minus8")? as u8;
+            qpprime_y_zero_transform_bypass_flag = reader.flag()?;
+
+            if reader.flag()? {
+                let list_count = if chroma_format_idc == 3 { 12 } else { 8 };
+                scaling_lists = Some(ScalingLists::parse(
+                    &mut reader,
+                    list_count,
+                    &ScalingLists::defaults(),
+                )?);
+            }
+        }
+
+        let log2_max_frame_num_minus4 = reader.ue_max(12, "log2_max_frame_num_minus4")? as u8;
+        let pic_order_cnt_type = reader.ue_max(2, "pic_order_cnt_type")? as u8;
+
+        let mut log2_max_pic_order_cnt_lsb_minus4 = 0;
+        let mut delta_pic_order_always_zero_flag = false;
+        let mut offset_for_non_ref_pic = 0;
+        let mut offset_for_top_to_bottom_field = 0;
+        let mut offset_for_ref_frame = Vec::new();
+
+        match pic_order_cnt_type {
+            0 => {
+                log2_max_pic_order_cnt_lsb_minus4 =
+                    reader.ue_max(12, "log2_max_pic_order_cnt_lsb_minus4")? as u8;
+            }
+            1 => {
+                delta_pic_order_always_zero_flag = reader.flag()?;
+                offset_for_non_ref_pic = reader.se()?;
+                offset_for_top_to_bottom_field = reader.se()?;
+                let num_ref_frames_in_pic_order_cnt_cycle =
+                    reader.ue_max(255, "num_ref_frames_in_pic_order_cnt_cycle")?;
+                offset_for_ref_frame = (0..num_ref_frames_in_pic_order_cnt_cycle)
+                    .map(|_| reader.se())
+                    .collect::<Result<Vec<_>>>()?;
+            }
+            _ => {}
+        }
+
+        let max_num_ref_frames = reader.ue_max(16, "max_num_ref_frames")? as u8;
+        let gaps_in_frame_num_value_allowed_flag = reader.flag()?;
+        let pic_width_in_mbs_minus1 = reader.ue()?;
+        let pic_height_in_map_units_minus1 = reader.ue()?;
+
+        let frame_mbs_only_flag = reader.flag()?;
+        let mb_adaptive_frame_field_flag = if frame_mbs_only_flag {
+            false
+        } else {
+            reader.flag()?
+        };
+        let direct_8x8_inference_flag = reader.flag()?;
+
+        let frame_cropping = if reader.flag()? {
+            Some(FrameCropping {
+                left_offset: reader.ue()?,
+                right_offset: reader.ue()?,
+                top_offset: reader.ue()?,
+                bottom_offset: reader.ue()?,
+            })
+        } else {
+            None
+        };
+
+        let vui = if reader.flag()? {
+            Some(parse_vui_parameters(&mut reader)?)
+        } else {
+            None
+        };
+
+        let sps = SequenceParameterSet {
+            profile_idc,
+            constraint_set_flags,
+            level_idc,
+            seq_parameter_set_id,
+            chroma_format_idc,
+            separate_colour_plane_flag,
+            bit_depth_luma_minus8,
+            bit_depth_chroma_minus8,
+            qpprime_y_zero_transform_bypass_flag,
+            scaling_lists,
+            log2_max_frame_num_minus4,
+            pic_order_cnt_type,
+            log2_max_pic_order_cnt_lsb_minus4,
+            delta_pic_order_always_zero_flag,
+            offset_for_non_ref_pic,
+            offset_for_top_to_bottom_field,
+            offset_for_ref_frame,
+            max_num_ref_frames,
+            gaps_in_frame_num_value_allowed_flag,
+            pic_width_in_mbs_minus1,
+            pic_height_in_map_units_minus1,
+            frame_mbs_only_flag,
+            mb_adaptive_frame_field_flag,
+            direct_8x8_inference_flag,
+            frame_cropping,
+            vui,
+        };
+
+        sps.validate_extent()?;
+
+        Ok(sps)
+    }
+
+    fn validate_extent(&self) -> Result<()> {
+        let (width, height) = self
+            .checked_coded_extent()
+            .ok_or_else(|| anyhow!("SPS {} picture size overflows", self.seq_parameter_set_id))?;
+
+        if let Some(cropping) = &self.frame_cropping {
+            let (crop_unit_x, crop_unit_y) = self.crop_units();
+            let horizontal =
+                (cropping.left_offset as u64 + cropping.right_offset as u64) * crop_unit_x as u64;
+            let vertical =
+                (cropping.top_offset as u64 + cropping.bottom_offset as u64) * crop_unit_y as u64;
+
+            if horizontal >= width as u64 || vertical >= height as u64 {
+                return Err(anyhow!(
+                    "SPS {} crops {}x{} off a {}x{} picture",
+                    self.seq_parameter_set_id,
+                    horizontal,
+                    vertical,
+                    width,
+                    height
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// ChromaArrayType, 0 for monochrome and separately coded colour planes.
+    pub fn chroma_array_type(&self) -> u8 {
+        if self.separate_colour_plane_flag {
+            0
+        } else {
+            self.chroma_format_idc
+        }
+    }
+
+    /// The `StdVideoH264ProfileIdc` of the video profile to decode with. High 10 and High 4:2:2
+    /// streams are decoded as High 4:4:4 Predictive, ITU-T H.264 A.2.11 includes them.
+    pub fn std_profile_idc(&self) -> Result<StdVideoH264ProfileIdc> {
+        match self.profile_idc {
+            66 => Ok(StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_BASELINE),
+            77 => Ok(StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_MAIN),
+            100 => Ok(StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_HIGH),
+            110 | 122 | 244 => {
+                Ok(StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_HIGH_444_PREDICTIVE)
+            }
+            profile_idc => Err(anyhow!(
+                "SPS {} has profile_idc {}, which has no Vulkan video profile",
+                self.seq_parameter_set_id,
+                profile_idc
+            )),
+        }
+    }
+
+    pub fn chroma_subsampling(&self) -> vk::VideoChromaSubsamplingFlagsKHR {
+        match self.chroma_format_idc {
+            0 => vk::VideoChromaSubsamplingFlagsKHR::MONOCHROME,
+            1 => vk::VideoChromaSubsamplingFlagsKHR::TYPE_420,
+            2 => vk::VideoChromaSubsamplingFlagsKHR::TYPE_422,
+            _ => vk::VideoChromaSubsamplingFlagsKHR::TYPE_444,
+        }
+    }
+
+    /// BitDepthY of the video profile.
+    pub fn luma_bit_depth(&self) -> Result<vk::VideoComponentBitDepthFlagsKHR> {
+        self.component_bit_depth(self.bit_depth_luma_minus8)
+    }
+
+    /// BitDepthC of the video profile, none for monochrome pictures.
+    pub fn chroma_bit_depth(&self) -> Result<vk::VideoComponentBitDepthFlagsKHR> {
+        if self.chroma_format_idc == 0 {
+            return Ok(vk::VideoComponentBitDepthFlagsKHR::INVALID);
+        }
+
+        self.component_bit_depth(self.bit_depth_chroma_minus8)
+    }
+
+    fn component_bit_depth(
+        &self,
+        bit_depth_minus8: u8,
+    ) -> Result<vk::VideoComponentBitDepthFlagsKHR> {
+        match bit_depth_minus8 {
+            0 => Ok(vk::VideoComponentBitDepthFlagsKHR::TYPE_8),
+            2 => Ok(vk::VideoComponentBitDepthFlagsKHR::TYPE_10),
+            4 => Ok(vk::VideoComponentBitDepthFlagsKHR::TYPE_12),
+            _ => Err(anyhow!(
+                "SPS {} has a bit depth of {}, Vulkan video supports 8, 10 and 12",
+                self.seq_parameter_set_id,
+                bit_depth_minus8 as u32 + 8
+            )),
+        }
+    }
+
+    pub fn max_frame_num(&self) -> u32 {
+        1 << (self.log2_max_frame_num_minus4 + 4)
+    }
+
+    pub fn max_pic_order_cnt_lsb(&self) -> u32 {
+        1 << (self.log2_max_pic_order_cnt_lsb_minus4 + 4)
+    }
+
+    /// FrameHeightInMbs
+    pub fn frame_height_in_mbs(&self) -> u32 {
+        (2 - self.frame_mbs_only_flag as u32) * (self.pic_height_in_map_units_minus1 + 1)
+    }
+
+    /// MaxDpbFrames, ITU-T H.264 A.3.1 item h), max_dec_frame_buffering takes precedence when the
+    /// VUI carries it.
+    pub fn max_dpb_frames(&self) -> Result<u32> {
+        if let Some(restriction) = self
+            .vui
+            .as_ref()
+            .and_then(|vui| vui.bitstream_restriction.as_ref())
+        {
+            return Ok((restriction.max_dec_frame_buffering as u32)
+                .max(self.max_num_ref_frames as u32)
+                .max(1));
+        }
+
+        // MaxDpbMbs, ITU-T H.264 Table A-1
+        let level_1b = self.level_idc == 11
+            && self.constraint_set_flags >> 4 & 1 == 1
+            && matches!(self.profile_idc, 66 | 77 | 88);
+        let max_dpb_mbs = match self.level_idc {
+            _ if level_1b => 396,
+            9 | 10 => 396,
+            11 => 900,
+            12 | 13 | 20 => 2376,
+            21 => 4752,
+            22 | 30 => 8100,
+            31 => 18000,
+            32 => 20480,
+            40 | 41 => 32768,
+            42 => 34816,
+            50 => 110400,
+            51 | 52 => 184320,
+            _ => 696320,
+        };
+
+        let frame_size_in_mbs = self
+            .pic_width_in_mbs_minus1
+            .checked_add(1)
+            .and_then(|width_in_mbs| width_in_mbs.checked_mul(self.frame_height_in_mbs()))
+            .ok_or_else(|| {
+                anyhow!(
+                    "SPS {} frame size in macroblocks overflows",
+                    self.seq_parameter_set_id
+                )
+            })?;
+
+        Ok((max_dpb_mbs / frame_size_in_mbs.max(1))
+            .clamp(self.max_num_ref_frames.max(1) as u32, 16))
+    }
+
+    /// The most frames that can precede a frame in decode order and follow it in output order,
+    /// ITU-T H.264 E.2.1.
+    pub fn max_num_reorder_frames(&self) -> Result<u32> {
+        if let Some(restriction) = self
+            .vui
+            .as_ref()
+            .and_then(|vui| vui.bitstream_restriction.as_ref())
+        {
+            return Ok(restriction.max_num_reorder_frames as u32);
+        }
+
+        // Intra only profiles never reorder
+        if self.constraint_set_flags >> 4 & 1 == 1
+            && matches!(self.profile_idc, 44 | 86 | 100 | 110 | 122 | 244)
+        {
+            return Ok(0);
+        }
+
+        self.max_dpb_frames()
+    }
+
+    /// The fewest frames a DPB can hold and still keep every reference frame and the
+    /// max_num_reorder_frames frames waiting for output, what it may shrink to when the slots for
+    /// MaxDpbFrames are not there.
+    pub fn min_dpb_frames(&self) -> Result<u32> {
+        Ok((self.max_num_ref_frames as u32)
+            .max(self.max_num_reorder_frames()?)
+            .max(1))
+    }
+
+    fn checked_coded_extent(&self) -> Option<(u32, u32)> {
+        let width = self
+            .pic_width_in_mbs_minus1
+            .checked_add(1)?
+            .checked_mul(16)?;
+        let height = (self.pic_height_in_map_units_minus1 as u64 + 1)
+            * (2 - self.frame_mbs_only_flag as u64)
+            * 16;
+
+        Some((width, u32::try_from(height).ok()?))
+    }
+
+    /// Size of the decoded picture in whole macroblocks, what images and the session have to hold.
+    pub fn coded_extent(&self) -> vk::Extent2D {
+        vk::Extent2D {
+            width: (self.pic_width_in_mbs_minus1 + 1) * 16,
+            height: self.frame_height_in_mbs() * 16,
+        }
+    }
+
+    /// CropUnitX and CropUnitY, ITU-T H.264 7-19 to 7-22.
+    fn crop_units(&self) -> (u32, u32) {
+        let (sub_width_c, sub_height_c) = match self.chroma_array_type() {
+            0 => (1, 1),
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+
+        (
+            sub_width_c,
+            sub_height_c * (2 - self.frame_mbs_only_flag as u32),
+        )
+    }
+
+    /// The part of the coded picture meant for display once the frame cropping offsets are applied.
+    pub fn display_rect(&self) -> vk::Rect2D {
+        let coded_extent = self.coded_extent();
+
+        let cropping = match &self.frame_cropping {
+            Some(cropping) => cropping,
+            None => {
+                return vk::Rect2D {
+                    offset: vk::Offset2D::default(),
+                    extent: coded_extent,
+                }
+            }
+        };
+
+        let (crop_unit_x, crop_unit_y) = self.crop_units();
+
+        vk::Rect2D {
+            offset: vk::Offset2D {
+                x: (cropping.left_offset * crop_unit_x) as i32,
+                y: (cropping.top_offset * crop_unit_y) as i32,
+            },
+            extent: vk::Extent2D {
+                width: coded_extent.width
+                    - (cropping.left_offset + cropping.right_offset) * crop_unit_x,
+                height: coded_extent.height
+                    - (cropping.top_offset + cropping.bottom_offset) * crop_unit_y,
+            },
+        }
+    }
+
+    /// Converts into the Vulkan std video representation, see [`StdSequenceParameterSet`].
+    pub fn to_std(&self) -> Result<StdSequenceParameterSet> {
+        let scaling_lists = self
+            .scaling_lists
+            .as_ref()
+            .map(|scaling_lists| Box::new(scaling_lists.to_std()));
+
+        let vui = self.vui.as_ref();
+
+        // The std VUI has room for a single set of HRD parameters, the NAL ones take precedence
+        let hrd = vui
+            .and_then(|vui| {
+                vui.nal_hrd_parameters
+                    .as_ref()
+                    .or(vui.vcl_hrd_parameters.as_ref())
+            })
+            .map(|hrd| Box::new(std_hrd_parameters(hrd)));
+
+        let vui = vui.map(|vui| {
+            let mut std_vui = std_vui_parameters(vui);
+            if let Some(hrd) = &hrd {
+                std_vui.pHrdParameters = hrd.as_ref();
+            }
+            Box::new(std_vui)
+        });
+
+        let offset_for_ref_frame = self.offset_for_ref_frame.clone();
+
+        let mut sps: StdVideoH264SequenceParameterSet = unsafe { mem::zeroed() };
+
+        sps.flags
+            .set_constraint_set0_flag((self.constraint_set_flags >> 7 & 1) as u32);
+        sps.flags
+            .set_constraint_set1_flag((self.constraint_set_flags >> 6 & 1) as u32);
+        sps.flags
+            .set_constraint_set2_flag((self.constraint_set_flags >> 5 & 1) as u32);
+        sps.flags
+            .set_constraint_set3_flag((self.constraint_set_flags >> 4 & 1) as u32);
+        sps.flags
+            .set_constraint_set4_flag((self.constraint_set_flags >> 3 & 1) as u32);
+        sps.flags
+            .set_constraint_set5_flag((self.constraint_set_flags >> 2 & 1) as u32);
+        sps.flags
+            .set_direct_8x8_inference_flag(self.direct_8x8_inference_flag as u32);
+        sps.flags
+            .set_mb_adaptive_frame_field_flag(self.mb_adaptive_frame_field_flag as u32);
+        sps.flags
+            .set_frame_mbs_only_flag(self.frame_mbs_only_flag as u32);
+        sps.flags
+            .set_delta_pic_order_always_zero_flag(self.delta_pic_order_always_zero_flag as u32);
+        sps.flags
+            .set_separate_colour_plane_flag(self.separate_colour_plane_flag as u32);
+        sps.flags.set_gaps_in_frame_num_value_allowed_flag(
+            self.gaps_in_frame_num_value_allowed_flag as u32,
+        );
+        sps.flags.set_qpprime_y_zero_transform_bypass_flag(
+            self.qpprime_y_zero_transform_bypass_flag as u32,
+        );
+        sps.flags
+            .set_frame_cropping_flag(self.frame_cropping.is_some() as u32);
+        sps.flags
+            .set_seq_scaling_matrix_present_flag(self.scaling_lists.is_some() as u32);
+        sps.flags
+            .set_vui_parameters_present_flag(self.vui.is_some() as u32);
+
+        sps.profile_idc = self.profile_idc as u32;
+        sps.level_idc = std_level_idc(self.level_idc)?;
+        sps.chroma_format_idc = self.chroma_format_idc as u32;
+        sps.seq_parameter_set_id = self.seq_parameter_set_id;
+        sps.bit_depth_luma_minus8 = self.bit_depth_luma_minus8;
+        sps.bit_depth_chroma_minus8 = self.bit_depth_chroma_minus8;
+        sps.log2_max_frame_num_minus4 = self.log2_max_frame_num_minus4;
+        sps.pic_order_cnt_type = self.pic_order_cnt_type as u32;
+        sps.offset_for_non_ref_pic = self.offset_for_non_ref_pic;
+        sps.offset_for_top_to_bottom_field = self.offset_for_top_to_bottom_field;
+        sps.log2_max_pic_order_cnt_lsb_minus4 = self.log2_max_pic_order_cnt_lsb_minus4;
+        sps.num_ref_frames_in_pic_order_cnt_cycle = self.offset_for_ref_frame.len() as u8;
+        sps.max_num_ref_frames = self.max_num_ref_frames;
+        sps.pic_width_in_mbs_minus1 = self.pic_width_in_mbs_minus1;
+        sps.pic_height_in_map_units_minus1 = self.pic_height_in_map_units_minus1;
+
+        if let Some(cropping) = &self.frame_cropping {
+            sps.frame_crop_left_offset = cropping.left_offset;
+            sps.frame_crop_right_offset = cropping.right_offset;
+            sps.frame_crop_top_offset = cropping.top_offset;
+            sps.frame_crop_bottom_offset = cropping.bottom_offset;
+        }
+
+        if !offset_for_ref_frame.is_empty() {
+            sps.pOffsetForRefFrame = offset_for_ref_frame.as_ptr();
+        }
+        if let Some(scaling_lists) = &scaling_lists {
+            sps.pScalingLists = scaling_lists.as_ref();
+        }
+        if let Some(vui) = &vui {
+            sps.pSequenceParameterSetVui = vui.as_ref();
+        }
+
+        Ok(StdSequenceParameterSet {
+            sps,
+            _offset_for_ref_frame: offset_for_ref_frame,
+            _scaling_lists: scaling_lists,
+            _vui: vui,
+            _hrd: hrd,
+        })
+    }
+}
+
+fn std_hrd_parameters(hrd: &HrdParameters) -> StdVideoH264HrdParameters {
+    let mut std_hrd: StdVideoH264HrdParameters = unsafe { mem::zeroed() };
+
+    std_hrd.cpb_cnt_minus1 = (hrd.cpb_specifications.len() - 1) as u8;
+    std_hrd.bit_rate_scale = hrd.bit_rate_scale;
+    std_hrd.cpb_size_scale = hrd.cpb_size_scale;
+
+    for (i, cpb) in hrd.cpb_specifications.iter().enumerate() {
+        std_hrd.bit_rate_value_minus1[i] = cpb.bit_rate_value_minus1;
+        std_hrd.cpb_size_value_minus1[i] = cpb.cpb_size_value_minus1;
+        std_hrd.cbr_flag[i] = cpb.cbr_flag as u8;
+    }
+
+    std_hrd.initial_cpb_removal_delay_length_minus1 =
+        hrd.initial_cpb_removal_delay_length_minus1 as u32;
+    std_hrd.cpb_removal_delay_length_minus1 = hrd.cpb_removal_delay_length_minus1 as u32;
+    std_hrd.dpb_output_delay_length_minus1 = hrd.dpb_output_delay_length_minus1 as u32;
+    std_hrd.time_offset_length = hrd.time_offset_length as u32;
+
+    std_hrd
+}
+
+fn std_vui_parameters(vui: &VuiParameters) -> StdVideoH264SequenceParameterSetVui {
+    let mut std_vui: StdVideoH264SequenceParameterSetVui = unsafe { mem::zeroed() };
+
+    // Inferred values of E.2.1 for whatever is not sent
+    std_vui.video_format = 5;
+    std_vui.colour_primaries = 2;
+    std_vui.transfer_characteristics = 2;
+    std_vui.matrix_coefficients = 2;
+
+    if let Some(aspect_ratio) = &vui.aspect_ratio {
+        std_vui.flags.set_aspect_ratio_info_present_flag(1);
+        std_vui.aspect_ratio_idc = match aspect_ratio.aspect_ratio_idc {
+            // Reserved values are to be treated as unspecified
+            idc @ (0..=16 | 255) => idc as u32,
+            _ => 0,
+        };
+        std_vui.sar_width = aspect_ratio.sar_width;
+        std_vui.sar_height = aspect_ratio.sar_height;
+    }
+
+    if let Some(overscan_appropriate_flag) = vui.overscan_appropriate_flag {
+        std_vui.flags.set_overscan_info_present_flag(1);
+        std_vui
+            .flags
+            .set_overscan_appropriate_flag(overscan_appropriate_flag as u32);
+    }
+
+    if let Some(video_signal_type) = &vui.video_signal_type {
+        std_vui.flags.set_video_signal_type_present_flag(1);
+        std_vui
+            .flags
+            .set_video_full_range_flag(video_signal_type.video_full_range_flag as u32);
+        std_vui.video_format = video_signal_type.video_format;
+
+        if let Some(colour_description) = &video_signal_type.colour_description {
+            std_vui.flags.set_color_description_present_flag(1);
+            std_vui.colour_primaries = colour_description.colour_primaries;
+            std_vui.transfer_characteristics = colour_description.transfer_characteristics;
+            std_vui.matrix_coefficients = colour_description.matrix_coefficients;
+        }
+    }
+
+    if let Some(chroma_loc_info) = &vui.chroma_loc_info {
+        std_vui.flags.set_chroma_loc_info_present_flag(1);
+        std_vui.chroma_sample_loc_type_top_field = chroma_loc_info.chroma_sample_loc_type_top_field;
+        std_vui.chroma_sample_loc_type_bottom_field =
+            chroma_loc_info.chroma_sample_loc_type_bottom_field;
+    }
+
+    if let Some(timing_info) = &vui.timing_info {
+        std_vui.flags.set_timing_info_present_flag(1);
+        std_vui
+            .flags
+            .set_fixed_frame_rate_flag(timing_info.fixed_frame_rate_flag as u32);
+        std_vui.num_units_in_tick = timing_info.num_units_in_tick;
+        std_vui.time_scale = timing_info.time_scale;
+    }
+
+    std_vui
+        .flags
+        .set_nal_hrd_parameters_present_flag(vui.nal_hrd_parameters.is_some() as u32);
+    std_vui
+        .flags
+        .set_vcl_hrd_parameters_present_flag(vui.vcl_hrd_parameters.is_some() as u32);
+
+    if let Some(bitstream_restriction) = &vui.bitstream_restriction {
+        std_vui.flags.set_bitstream_restriction_flag(1);
+        std_vui.max_num_reorder_frames = bitstream_restriction.max_num_reorder_frames;
+        std_vui.max_dec_frame_buffering = bitstream_restriction.max_dec_frame_buffering;
+    }
+
+    std_vui
+}
+
+/// A `StdVideoH264SequenceParameterSet` together with the arrays and structs its pointers refer to.
+///
+/// Everything pointed at lives on the heap, so the value can be moved freely and the std struct
+/// stays valid for as long as this is alive.
+pub struct StdSequenceParameterSet {
+    sps: StdVideoH264SequenceParameterSet,
+    _offset_for_ref_frame: Vec<i32>,
+    _scaling_lists: Option<Box<StdVideoH264ScalingLists>>,
+    _vui: Option<Box<StdVideoH264SequenceParameterSetVui>>,
+    _hrd: Option<Box<StdVideoH264HrdParameters>>,
+}
+
+impl StdSequenceParameterSet {
+    pub fn as_std(&self) -> &StdVideoH264SequenceParameterSet {
+        &self.sps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rbsp::BitWriter;
+    use crate::test_util::sps;
+
+    fn parse(nal_unit: &[u8]) -> Result<SequenceParameterSet> {
+        SequenceParameterSet::parse(&NalUnit::parse(nal_unit)?)
+    }
+
+    /// The SPS of `test_util::sps` up to frame_cropping_flag, `high` writes the fields of the
+    /// High profiles.
+    fn write_sps(
+        profile_idc: u8,
+        high: impl FnOnce(&mut BitWriter),
+        pic_order_cnt_type: u8,
+    ) -> BitWriter {
+        let mut writer = BitWriter::default();
+        writer.u(8, profile_idc as u64).u(8, 0).u(8, 30).ue(0);
+        high(&mut writer);
+        writer.ue(0).ue(pic_order_cnt_type as u32);
+        match pic_order_cnt_type {
+            0 => {
+                writer.ue(0);
+            }
+            1 => {
+                writer.flag(false).se(-2).se(1).ue(2).se(4).se(6);
+            }
+            _ => {}
+        }
+        writer.ue(4).flag(false).ue(19).ue(14).flag(true).flag(true);
+        writer
+    }
+
+    #[test]
+    fn parse_main_profile() {
+        for pic_order_cnt_type in 0..3 {
+            let nal_unit = write_sps(77, |_| {}, pic_order_cnt_type)
+                .flag(false)
+                .flag(false)
+                .nal_unit(0x67);
+            let parsed = parse(&nal_unit).unwrap();
+
+            let expected = match pic_order_cnt_type {
+                1 => SequenceParameterSet {
+                    offset_for_non_ref_pic: -2,
+                    offset_for_top_to_bottom_field: 1,
+                    offset_for_ref_frame: vec![4, 6],
+                    ..sps(1)
+                },
+                _ => sps(pic_order_cnt_type),
+            };
+            assert_eq!(parsed, expected);
+        }
+    }
+
+    #[test]
+    fn parse_real_sps() {
+        // 640x360 High profile SPS of samples/Big_Buck_Bunny_360_10s_1MB.mp4, with emulation
+        // prevention bytes
+        let nal_unit = [
+            0x67, 0x64, 0x00, 0x1f, 0xac, 0x72, 0x84, 0x40, 0xa0, 0x2f, 0xf9, 0x70, 0x11, 0x00,
+            0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x03, 0x00, 0x3c, 0x0f, 0x18, 0x31, 0x84, 0x60,
+        ];
+        let sps = parse(&nal_unit).unwrap();
+
+        assert_eq!(sps.profile_idc, 100);
+        assert_eq!(sps.level_idc, 31);
+        assert_eq!(sps.chroma_format_idc, 1);
+        assert_eq!(sps.scaling_lists, None);
+        assert_eq!(sps.max_frame_num(), 64);
+        assert_eq!(sps.max_pic_order_cnt_lsb(), 256);
+        assert_eq!(sps.max_num_ref_frames, 16);
+        assert_eq!(
+            sps.coded_extent(),
+            vk::Extent2D {
+                width: 640,
+                height: 368
+            }
+        );
+        assert_eq!(
+            sps.display_rect(),
+            vk::Rect2D {
+                offset: vk::Offset2D::default(),
+                extent: vk::Extent2D {
+                    width: 640,
+                    height: 360
+                }
+            }
+        );
+
+        let vui = sps.vui.as_ref().unwrap();
+        assert_eq!(
+            vui.aspect_ratio.unwrap().sample_aspect_ratio(),
+            Some((1, 1))
+        );
+        assert_eq!(
+            vui.timing_info,
+            Some(TimingInfo {
+                num_units_in_tick: 1,
+                time_scale: 60,
+                fixed_frame_rate_flag: false
+            })
+        );
+        assert_eq!(
+            vui.bitstream_restriction,
+            Some(BitstreamRestriction {
+                motion_vectors_over_pic_boundaries_flag: true,
+                max_bytes_per_pic_denom: 0,
+                max_bits_per_mb_denom: 0,
+                log2_max_mv_length_horizontal: 11,
+                log2_max_mv_length_vertical: 11,
+                max_num_reorder_frames: 2,
+                max_dec_frame_buffering: 16,
+            })
+        );
+        assert_eq!(sps.max_dpb_frames().unwrap(), 16);
+        assert_eq!(sps.max_num_reorder_frames().unwrap(), 2);
+
+        let std = sps.to_std().unwrap();
+        assert_eq!(std.as_std().level_idc, 8);
+        assert_eq!(std.as_std().frame_crop_bottom_offset, 4);
+    }
+
+    #[test]
+    fn parse_scaling_lists() {
+        let nal_unit = write_sps(
+            100,
+            |writer| {
+                writer.ue(1).ue(0).ue(0).flag(false).flag(true);
+                // 0: 12 repeated once nextScale is 0
+                writer.flag(true).se(4).se(-12);
+                // 1: not sent, a copy of 0
+                writer.flag(false);
+                // 2: useDefaultScalingMatrixFlag
+                writer.flag(true).se(-8);
+                // 3 to 5: not sent, Default_4x4_Inter by fall-back rule A and copies of it
+                writer.flag(false).flag(false).flag(false);
+                // 6: not sent, Default_8x8_Intra
+                writer.flag(false);
+                // 7: 16 repeated
+                writer.flag(true).se(8).se(-16);
+            },
+            0,
+        )
+        .flag(false)
+        .flag(false)
+        .nal_unit(0x67);
+
+        let scaling_lists = parse(&nal_unit).unwrap().scaling_lists.unwrap();
+        assert_eq!(
+            scaling_lists,
+            ScalingLists {
+                present_mask: 0b10000101,
+                use_default_mask: 0b100,
+                lists_4x4: [
+                    [12; 16],
+                    [12; 16],
+                    DEFAULT_4X4_INTRA,
+                    DEFAULT_4X4_INTER,
+                    DEFAULT_4X4_INTER,
+                    DEFAULT_4X4_INTER,
+                ],
+                // 8 to 11 are only sent for 4:4:4, the Cb and Cr lists copy the ones two before
+                lists_8x8: [
+                    DEFAULT_8X8_INTRA,
+                    [16; 64],
+                    DEFAULT_8X8_INTRA,
+                    [16; 64],
+                    DEFAULT_8X8_INTRA,
+                    [16; 64],
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_vui() {
+        let nal_unit = write_sps(77, |_| {}, 0)
+            .flag(false)
+            .flag(true)
+            // Extended_SAR 4:3
+            .flag(true)
+            .u(8, 255)
+            .u(16, 4)
+            .u(16, 3)
+            .flag(true)
+            .flag(true)
+            // video_signal_type with a colour description
+            .flag(true)
+            .u(3, 5)
+            .flag(false)
+            .flag(true)
+            .u(8, 1)
+            .u(8, 1)
+            .u(8, 1)
+            .flag(true)
+            .ue(1)
+            .ue(1)
+            .flag(true)
+            .u(32, 1001)
+            .u(32, 60000)
+            .flag(true)
+            // NAL HRD with two cpbs, no VCL HRD
+            .flag(true)
+            .ue(1)
+            .u(4, 4)
+            .u(4, 6)
+            .ue(999)
+            .ue(1999)
+            .flag(false)
+            .ue(499)
+            .ue(999)
+            .flag(true)
+            .u(5, 23)
+            .u(5, 23)
+            .u(5, 23)
+            .u(5, 24)
+            .flag(false)
+            .flag(false)
+            .flag(true)
+            // bitstream_restriction
+            .flag(true)
+            .flag(true)
+            .ue(2)
+            .ue(1)
+            .ue(15)
+            .ue(15)
+            .ue(1)
+            .ue(4)
+            .nal_unit(0x67);
+        let sps = parse(&nal_unit).unwrap();
+
+        let hrd = HrdParameters {
+            bit_rate_scale: 4,
+            cpb_size_scale: 6,
+            cpb_specifications: vec![
+                CpbSpecification {
+                    bit_rate_value_minus1: 999,
+                    cpb_size_value_minus1: 1999,
+                    cbr_flag: false,
+                },
+                CpbSpecification {
+                    bit_rate_value_minus1: 499,
+                    cpb_size_value_minus1: 999,
+                    cbr_flag: true,
+                },
+            ],
+            initial_cpb_removal_delay_length_minus1: 23,
+            cpb_removal_delay_length_minus1: 23,
+            dpb_output_delay_length_minus1: 23,
+            time_offset_length: 24,
+        };
+        assert_eq!(
+            sps.vui,
+            Some(VuiParameters {
+                aspect_ratio: Some(AspectRatio {
+                    aspect_ratio_idc: 255,
+                    sar_width: 4,
+                    sar_height: 3,
+                }),
+                overscan_appropriate_flag: Some(true),
+                video_signal_type: Some(VideoSignalType {
+                    video_format: 5,
+                    video_full_range_flag: false,
+                    colour_description: Some(ColourDescription {
+                        colour_primaries: 1,
+                        transfer_characteristics: 1,
+                        matrix_coefficients: 1,
+                    }),
+                }),
+                chroma_loc_info: Some(ChromaLocInfo {
+                    chroma_sample_loc_type_top_field: 1,
+                    chroma_sample_loc_type_bottom_field: 1,
+                }),
+                timing_info: Some(TimingInfo {
+                    num_units_in_tick: 1001,
+                    time_scale: 60000,
+                    fixed_frame_rate_flag: true,
+                }),
+                nal_hrd_parameters: Some(hrd),
+                vcl_hrd_parameters: None,
+                low_delay_hrd_flag: false,
+                pic_struct_present_flag: true,
+                bitstream_restriction: Some(BitstreamRestriction {
+                    motion_vectors_over_pic_boundaries_flag: true,
+                    max_bytes_per_pic_denom: 2,
+                    max_bits_per_mb_denom: 1,
+                    log2_max_mv_length_horizontal: 15,
+                    log2_max_mv_length_vertical: 15,
+                    max_num_reorder_frames: 1,
+                    max_dec_frame_buffering: 4,
+                }),
+            })
+        );
+
+        // max_dec_frame_buffering and max_num_reorder_frames take precedence over the level
+        assert_eq!(sps.max_dpb_frames().unwrap(), 4);
+        assert_eq!(sps.max_num_reorder_frames().unwrap(), 1);
+    }
+
+    #[test]
+    fn frame_cropping() {
+        let cropping = |left_offset, right_offset, top_offset, bottom_offset| {
+            Some(FrameCropping {
+                left_offset,
+                right_offset,
+                top_offset,
+                bottom_offset,
+            })
+        };
+        let high = |chroma_format_idc| SequenceParameterSet {
+            profile_idc: 100,
+            chroma_format_idc,
+            ..sps(0)
+        };
+        let field = SequenceParameterSet {
+            frame_mbs_only_flag: false,
+            pic_height_in_map_units_minus1: 7,
+            ..sps(0)
+        };
+
+        // CropUnitX and CropUnitY follow the chroma format and double for field coding
+        let cases = [
+            (sps(0), cropping(2, 0, 0, 4), (4, 0, 316, 232)),
+            (high(0), cropping(2, 0, 0, 4), (2, 0, 318, 236)),
+            (high(2), cropping(2, 0, 0, 4), (4, 0, 316, 236)),
+            (high(3), cropping(2, 0, 0, 4), (2, 0, 318, 236)),
+            (field, cropping(0, 0, 1, 1), (0, 4, 320, 248)),
+            (sps(0), cropping(159, 0, 0, 0), (318, 0, 2, 240)),
+            (sps(0), None, (0, 0, 320, 240)),
+        ];
+        for (sps, frame_cropping, (x, y, width, height)) in cases {
+            let sps = SequenceParameterSet {
+                frame_cropping,
+                ..sps
+            };
+            assert!(sps.validate_extent().is_ok(), "{:?}", frame_cropping);
+            assert_eq!(
+                sps.display_rect(),
+                vk::Rect2D {
+                    offset: vk::Offset2D { x, y },
+                    extent: vk::Extent2D { width, height },
+                },
+                "{:?}",
+                frame_cropping
+            );
+        }
+
+        // Nothing may be left of the picture
+        for frame_cropping in [cropping(80, 80, 0, 0), cropping(0, 0, 120, 0)] {
+            let sps = SequenceParameterSet {
+                frame_cropping,
+                ..sps(0)
+            };
+            assert!(sps.validate_extent().is_err(), "{:?}", frame_cropping);
+        }
+
+        let nal_unit = write_sps(77, |_| {}, 0)
+            .flag(true)
+            .ue(0)
+            .ue(0)
+            .ue(0)
+            .ue(4)
+            .flag(false)
+            .nal_unit(0x67);
+        assert_eq!(
+            parse(&nal_unit).unwrap().frame_cropping,
+            cropping(0, 0, 0, 4)
+        );
+    }
+
+    #[test]
+    fn picture_size_overflow() {
+        let too_large = [
+            SequenceParameterSet {
+                pic_width_in_mbs_minus1: u32::MAX,
+                ..sps(0)
+            },
+            SequenceParameterSet {
+                pic_width_in_mbs_minus1: 0x1000_0000,
+                ..sps(0)
+            },
+            SequenceParameterSet {
+                pic_height_in_map_units_minus1: u32::MAX / 32,
+                frame_mbs_only_flag: false,
+                ..sps(0)
+            },
+        ];
+        for sps in &too_large {
+            assert!(sps.validate_extent().is_err(), "{:?}", sps);
+        }
+
+        let frame_size_overflow = SequenceParameterSet {
+            pic_width_in_mbs_minus1: 0xffff,
+            pic_height_in_map_units_minus1: 0xffff,
+            ..sps(0)
+        };
+        assert!(frame_size_overflow.max_dpb_frames().is_err());
+    }
+
+    #[test]
+    fn to_std() {
+        let full = SequenceParameterSet {
+            profile_idc: 100,
+            constraint_set_flags: 0b01000000,
+            scaling_lists: Some(ScalingLists::defaults()),
+            offset_for_ref_frame: vec![4, -6],
+            frame_cropping: Some(FrameCropping {
+                left_offset: 0,
+                right_offset: 0,
+                top_offset: 0,
+                bottom_offset: 4,
+            }),
+            vui: Some(VuiParameters {
+                aspect_ratio: Some(AspectRatio {
+                    // Reserved
+                    aspect_ratio_idc: 17,
+                    sar_width: 0,
+                    sar_height: 0,
+                }),
+                overscan_appropriate_flag: None,
+                video_signal_type: None,
+                chroma_loc_info: None,
+                timing_info: None,
+                nal_hrd_parameters: None,
+                vcl_hrd_parameters: Some(HrdParameters {
+                    bit_rate_scale: 4,
+                    cpb_size_scale: 6,
+                    cpb_specifications: vec![CpbSpecification {
+                        bit_rate_value_minus1: 999,
+                        cpb_size_value_minus1: 1999,
+                        cbr_flag: true,
+                    }],
+                    initial_cpb_removal_delay_length_minus1: 23,
+                    cpb_removal_delay_length_minus1: 23,
+                    dpb_output_delay_length_minus1: 23,
+                    time_offset_length: 24,
+                }),
+                low_delay_hrd_flag: false,
+                pic_struct_present_flag: false,
+                bitstream_restriction: None,
+            }),
+            ..sps(1)
+        };
+
+        // The pointers refer to the heap, moving the value keeps them valid
+        let std = [full.to_std().unwrap()];
+        let std = std[0].as_std();
+
+        assert_eq!(std.profile_idc, 100);
+        assert_eq!(std.level_idc, 7);
+        assert_eq!(std.flags.constraint_set1_flag(), 1);
+        assert_eq!(std.flags.frame_cropping_flag(), 1);
+        assert_eq!(std.frame_crop_bottom_offset, 4);
+        assert_eq!(std.flags.seq_scaling_matrix_present_flag(), 1);
+        assert_eq!(std.flags.vui_parameters_present_flag(), 1);
+
+        assert_eq!(std.num_ref_frames_in_pic_order_cnt_cycle, 2);
+        let offset_for_ref_frame = unsafe { std::slice::from_raw_parts(std.pOffsetForRefFrame, 2) };
+        assert_eq!(offset_for_ref_frame, [4, -6]);
+
+        let scaling_lists = unsafe { &*std.pScalingLists };
+        assert_eq!(scaling_lists.ScalingList4x4[0], DEFAULT_4X4_INTRA);
+        assert_eq!(scaling_lists.ScalingList8x8[1], DEFAULT_8X8_INTER);
+
+        // A reserved aspect_ratio_idc is unspecified, the VCL HRD parameters stand in for the NAL
+        // ones
+        let vui = unsafe { &*std.pSequenceParameterSetVui };
+        assert_eq!(vui.flags.aspect_ratio_info_present_flag(), 1);
+        assert_eq!(vui.aspect_ratio_idc, 0);
+        assert_eq!(vui.video_format, 5);
+        assert_eq!(vui.colour_primaries, 2);
+        assert_eq!(vui.flags.vcl_hrd_parameters_present_flag(), 1);
+        let hrd = unsafe { &*vui.pHrdParameters };
+        assert_eq!(hrd.cpb_cnt_minus1, 0);
+        assert_eq!(hrd.bit_rate_value_minus1[0], 999);
+        assert_eq!(hrd.cbr_flag[0], 1);
+        assert_eq!(hrd.time_offset_length, 24);
+
+        // Without any of them the pointers are null
+        let std = sps(0).to_std().unwrap();
+        assert!(std.as_std().pOffsetForRefFrame.is_null());
+        assert!(std.as_std().pScalingLists.is_null());
+        assert!(std.as_std().pSequenceParameterSetVui.is_null());
+    }
+
+    #[test]
+    fn level() {
+        let cases = [(9, 1), (10, 0), (11, 1), (30, 7), (62, 18)];
+        for (level_idc, std_level_idc) in cases {
+            let sps = SequenceParameterSet {
+                level_idc,
+                ..sps(0)
+            };
+            assert_eq!(
+                sps.to_std().unwrap().as_std().level_idc,
+                std_level_idc,
+                "level_idc {}",
+                level_idc
+            );
+        }
+
+        let unknown = SequenceParameterSet {
+            level_idc: 14,
+            ..sps(0)
+        };
+        assert!(unknown.to_std().is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let header = |profile_idc: u64| {
+            let mut writer = BitWriter::default();
+            writer.u(8, profile_idc).u(8, 0).u(8, 30);
+            writer
+        };
+
+        let cases = [
+            (
+                header(77).ue(0).flag(false).flag(false).nal_unit(0x68),
+                "Expected a sequence parameter set",
+            ),
+            // The truncated SPS of the avc tests
+            (vec![0x67, 0x4d, 0x40, 0x1e, 0xe8], "RBSP truncated"),
+            (header(77).ue(32).nal_unit(0x67), "seq_parameter_set_id"),
+            (header(100).ue(0).ue(4).nal_unit(0x67), "chroma_format_idc"),
+            (
+                header(100).ue(0).ue(1).ue(7).nal_unit(0x67),
+                "bit_depth_luma_minus8",
+            ),
+            (
+                header(77).ue(0).ue(13).nal_unit(0x67),
+                "log2_max_frame_num_minus4",
+            ),
+            (
+                header(77).ue(0).ue(0).ue(3).nal_unit(0x67),
+                "pic_order_cnt_type",
+            ),
+            (
+                header(77).ue(0).ue(0).ue(0).ue(0).ue(17).nal_unit(0x67),
+                "max_num_ref_frames",
+            ),
+            (
+                header(77)
+                    .ue(0)
+                    .ue(0)
+                    .ue(0)
+                    .ue(0)
+                    .ue(4)
+                    .flag(false)
+                    .ue(0x1000_0000)
+                    .ue(14)
+                    .flag(true)
+                    .flag(true)
+                    .flag(false)
+                    .flag(false)
+                    .nal_unit(0x67),
+                "picture size overflows",
+            ),
+            (
+                write_sps(77, |_| {}, 0)
+                    .flag(true)
+                    .ue(160)
+                    .ue(0)
+                    .ue(0)
+                    .ue(0)
+                    .flag(false)
+                    .nal_unit(0x67),
+                "crops",
+            ),
+            (
+                write_sps(77, |_| {}, 0)
+                    .flag(false)
+                    .flag(true)
+                    .u(4, 0)
+                    .flag(true)
+                    .u(32, 1001)
+                    .u(32, 0)
+                    .nal_unit(0x67),
+                "time_scale 0",
+            ),
+            (
+                write_sps(77, |_| {}, 0)
+                    .flag(false)
+                    .flag(true)
+                    .u(7, 0)
+                    .flag(false)
+                    .flag(true)
+                    .flag(true)
+                    .ue(0)
+                    .ue(0)
+                    .ue(15)
+                    .ue(15)
+                    .ue(3)
+                    .ue(2)
+                    .nal_unit(0x67),
+                "exceeds max_dec_frame_buffering",
+            ),
+        ];
+
+        for (nal_unit, error) in cases {
+            let message = parse(&nal_unit).unwrap_err().to_string();
+            assert!(message.contains(error), "{}: {}", error, message);
+        }
+    }
+
+    #[test]
+    fn video_profile() {
+        let main = sps(0);
+        assert_eq!(
+            main.std_profile_idc().unwrap(),
+            StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_MAIN
+        );
+        assert_eq!(
+            main.chroma_subsampling(),
+            vk::VideoChromaSubsamplingFlagsKHR::TYPE_420
+        );
+        assert_eq!(
+            main.luma_bit_depth().unwrap(),
+            vk::VideoComponentBitDepthFlagsKHR::TYPE_8
+        );
+        assert_eq!(
+            main.chroma_bit_depth().unwrap(),
+            vk::VideoComponentBitDepthFlagsKHR::TYPE_8
+        );
+
+        // High 10 is decoded with the High 4:4:4 Predictive profile
+        let high_10 = SequenceParameterSet {
+            profile_idc: 110,
+            bit_depth_luma_minus8: 2,
+            bit_depth_chroma_minus8: 2,
+            ..sps(0)
+        };
+        assert_eq!(
+            high_10.std_profile_idc().unwrap(),
+            StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_HIGH_444_PREDICTIVE
+        );
+        assert_eq!(
+            high_10.luma_bit_depth().unwrap(),
+            vk::VideoComponentBitDepthFlagsKHR::TYPE_10
+        );
+        assert_eq!(
+            high_10.chroma_bit_depth().unwrap(),
+            vk::VideoComponentBitDepthFlagsKHR::TYPE_10
+        );
+
+        let high_422 = SequenceParameterSet {
+            profile_idc: 122,
+            chroma_format_idc: 2,
+            ..sps(0)
+        };
+        assert_eq!(
+            high_422.chroma_subsampling(),
+            vk::VideoChromaSubsamplingFlagsKHR::TYPE_422
+        );
+
+        let monochrome = SequenceParameterSet {
+            profile_idc: 100,
+            chroma_format_idc: 0,
+            bit_depth_chroma_minus8: 4,
+            ..sps(0)
+        };
+        assert_eq!(
+            monochrome.chroma_subsampling(),
+            vk::VideoChromaSubsamplingFlagsKHR::MONOCHROME
+        );
+        assert_eq!(
+            monochrome.chroma_bit_depth().unwrap(),
+            vk::VideoComponentBitDepthFlagsKHR::INVALID
+        );
+    }
+
+    #[test]
+    fn unsupported_video_profile() {
+        // Extended profile and Multiview High
+        for profile_idc in [88, 118] {
+            let sps = SequenceParameterSet {
+                profile_idc,
+                ..sps(0)
+            };
+            assert!(sps.std_profile_idc().is_err());
+        }
+
+        let high_9 = SequenceParameterSet {
+            profile_idc: 110,
+            bit_depth_luma_minus8: 1,
+            ..sps(0)
+        };
+        assert!(high_9.luma_bit_depth().is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use anyhow::Result;
+use ash::{vk, Device};
+
+/// A value of a timeline semaphore, reached once the submission signalling it finished.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimelinePoint {
+    pub semaphore: vk::Semaphore,
+    pub value: u64,
+}
+
+impl TimelinePoint {
+    /// Waits for the point on the GPU before `stage_mask` of a submission.
+    pub fn wait(self, stage_mask: vk::PipelineStageFlags) -> SemaphoreSubmit {
+        SemaphoreSubmit {
+            semaphore: self.semaphore,
+            value: self.value,
+            stage_mask,
+        }
+    }
+
+    /// Signals the point when a submission finishes.
+    pub fn signal(self) -> SemaphoreSubmit {
+        SemaphoreSubmit {
+            semaphore: self.semaphore,
+            value: self.value,
+            stage_mask: vk::PipelineStageFlags::ALL_COMMANDS,
+        }
+    }
+}
+
+/// A semaphore waited on or signalled by a submission, the value is ignored for binary
+/// semaphores and the stage mask for signals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SemaphoreSubmit {
+    pub semaphore: vk::Semaphore,
+    pub value: u64,
+    pub stage_mask: vk::PipelineStageFlags,
+}
+
+impl SemaphoreSubmit {
+    /// A binary semaphore, swapchain images can only be synchronised with these.
+    pub fn binary(semaphore: vk::Semaphore, stage_mask: vk::PipelineStageFlags) -> Self {
+        SemaphoreSubmit {
+            semaphore,
+            value: 0,
+            stage_mask,
+        }
+    }
+}
+
+/// A timeline semaphore counting the finished submissions of one queue, Vulkan 1.2.
+///
+/// Every submission signals the next value, so the CPU and other queues can wait for any one of
+/// them and look at how far the queue got without blocking.
+pub struct Timeline {
+    pub semaphore: vk::Semaphore,
+    last_value: u64,
+}
+
+impl Timeline {
+    pub fn new(device: &Device) -> Result<Self> {
+        let mut semaphore_type_create_info = vk::SemaphoreTypeCreateInfo::default()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(0);
+        let semaphore_create_info =
+            vk::SemaphoreCreateInfo::default().push_next(&mut semaphore_type_create_info);
+
+        let semaphore = unsafe { device.create_semaphore(&semaphore_create_info, None)? };
+
+        Ok(Timeline {
+            semaphore,
+            last_value: 0,
+        })
+    }
+
+    /// The point the next submission signals, every call hands out a new one.
+    pub fn next_point(&mut self) -> TimelinePoint {
+        self.last_value += 1;
+        self.point(self.last_value)
+    }
+
+    /// The point signalled last, value 0 is reached from the start.
+    pub fn last_point(&self) -> TimelinePoint {
+        self.point(self.last_value)
+    }
+
+    pub fn point(&self, value: u64) -> TimelinePoint {
+        TimelinePoint {
+            semaphore: self.semaphore,
+            value,
+        }
+    }
+
+    /// The highest value the GPU reached so far.
+    pub fn completed_value(&self, device: &Device) -> Result<u64> {
+        Ok(unsafe { device.get_semaphore_counter_value(self.semaphore)? })
+    }
+
+    /// Whether the submission signalling `value` finished, without blocking.
+    pub fn is_reached(&self, device: &Device, value: u64) -> Result<bool> {
+        Ok(self.completed_value(device)? >= value)
+    }
+
+    /// Blocks until the submission signalling `value` finished.
+    pub fn wait(&self, device: &Device, value: u64) -> Result<()> {
+        let semaphores = [self.semaphore];
+        let values = [value];
+        let wait_info = vk::SemaphoreWaitInfo::default()
+            .semaphores(&semaphores)
+            .values(&values);
+
+        unsafe { device.wait_semaphores(&wait_info, u64::MAX)? };
+
+        Ok(())
+    }
+
+    pub fn destroy(&self, device: &Device) {
+        unsafe {
+            device.destroy_semaphore(self.semaphore, None);
+        }
+    }
+}
+
+/// Submits command buffers waiting on and signalling binary and timeline semaphores alike.
+pub fn queue_submit(
+    device: &Device,
+    queue: vk::Queue,
+    command_buffers: &[vk::CommandBuffer],
+    waits: &[SemaphoreSubmit],
+    signals: &[SemaphoreSubmit],
+    fence: vk::Fence,
+) -> Result<()> {
+    let wait_semaphores = waits.iter().map(|wait| wait.semaphore).collect::<Vec<_>>();
+    let wait_values = waits.iter().map(|wait| wait.value).collect::<Vec<_>>();
+    let wait_mask = waits.iter().map(|wait| wait.stage_mask).collect::<Vec<_>>();
+    let signal_semaphores = signals
+        .iter()
+        .map(|signal| signal.semaphore)
+        .collect::<Vec<_>>();
+    let signal_values = signals
+        .iter()
+        .map(|signal| signal.value)
+        .collect::<Vec<_>>();
+
+    let mut timeline_submit_info = vk::TimelineSemaphoreSubmitInfo::default()
+        .wait_semaphore_values(&wait_values)
+        .signal_semaphore_values(&signal_values);
+
+    let submit_info = vk::SubmitInfo::default()
+        .push_next(&mut timeline_submit_info)
+        .wait_semaphores(&wait_semaphores)
+        .wait_dst_stage_mask(&wait_mask)
+        .command_buffers(command_buffers)
+        .signal_semaphores(&signal_semaphores);
+
+    unsafe { device.queue_submit(queue, &[submit_info], fence)? };
+
+    Ok(())
+}
+
+/// [`crate::record_submit_commandbuffer`] with timeline semaphores among the ones waited on and
+/// signalled.
+#[allow(clippy::too_many_arguments)]
+pub fn record_submit_timeline<F: FnOnce(&Device, vk::CommandBuffer)>(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    command_buffer_reuse_fence: vk::Fence,
+    submit_queue: vk::Queue,
+    waits: &[SemaphoreSubmit],
+    signals: &[SemaphoreSubmit],
+    f: F,
+) -> Result<()> {
+    unsafe {
+        device.wait_for_fences(&[command_buffer_reuse_fence], true, u64::MAX)?;
+        device.reset_fences(&[command_buffer_reuse_fence])?;
+
+        device.reset_command_buffer(
+            command_buffer,
+            vk::CommandBufferResetFlags::RELEASE_RESOURCES,
+        )?;
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        device.begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
+        f(device, command_buffer);
+        device.end_command_buffer(command_buffer)?;
+    }
+
+    queue_submit(
+        device,
+        submit_queue,
+        &[command_buffer],
+        waits,
+        signals,
+        command_buffer_reuse_fence,
+    )
+}
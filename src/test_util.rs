@@ -0,0 +1,141 @@
+//! Parameter sets and slice headers the unit tests of the decoding state are built from.
+
+use crate::pps::PictureParameterSet;
+use crate::slice::{DecRefPicMarking, MemoryManagementControlOperation, SliceHeader, SliceType};
+use crate::sps::{BitstreamRestriction, SequenceParameterSet, VuiParameters};
+
+/// A progressive 320x240 Main profile SPS with a MaxFrameNum and MaxPicOrderCntLsb of 16 and 4
+/// reference frames.
+pub fn sps(pic_order_cnt_type: u8) -> SequenceParameterSet {
+    SequenceParameterSet {
+        profile_idc: 77,
+        constraint_set_flags: 0,
+        level_idc: 30,
+        seq_parameter_set_id: 0,
+        chroma_format_idc: 1,
+        separate_colour_plane_flag: false,
+        bit_depth_luma_minus8: 0,
+        bit_depth_chroma_minus8: 0,
+        qpprime_y_zero_transform_bypass_flag: false,
+        scaling_lists: None,
+        log2_max_frame_num_minus4: 0,
+        pic_order_cnt_type,
+        log2_max_pic_order_cnt_lsb_minus4: 0,
+        delta_pic_order_always_zero_flag: false,
+        offset_for_non_ref_pic: 0,
+        offset_for_top_to_bottom_field: 0,
+        offset_for_ref_frame: Vec::new(),
+        max_num_ref_frames: 4,
+        gaps_in_frame_num_value_allowed_flag: false,
+        pic_width_in_mbs_minus1: 19,
+        pic_height_in_map_units_minus1: 14,
+        frame_mbs_only_flag: true,
+        mb_adaptive_frame_field_flag: false,
+        direct_8x8_inference_flag: true,
+        frame_cropping: None,
+        vui: None,
+    }
+}
+
+/// VUI with nothing but a bitstream_restriction().
+pub fn vui(max_num_reorder_frames: u8, max_dec_frame_buffering: u8) -> VuiParameters {
+    VuiParameters {
+        aspect_ratio: None,
+        overscan_appropriate_flag: None,
+        video_signal_type: None,
+        chroma_loc_info: None,
+        timing_info: None,
+        nal_hrd_parameters: None,
+        vcl_hrd_parameters: None,
+        low_delay_hrd_flag: false,
+        pic_struct_present_flag: false,
+        bitstream_restriction: Some(BitstreamRestriction {
+            motion_vectors_over_pic_boundaries_flag: true,
+            max_bytes_per_pic_denom: 2,
+            max_bits_per_mb_denom: 1,
+            log2_max_mv_length_horizontal: 15,
+            log2_max_mv_length_vertical: 15,
+            max_num_reorder_frames,
+            max_dec_frame_buffering,
+        }),
+    }
+}
+
+/// A CAVLC PPS without weighted prediction or scaling lists referring to SPS
+/// `seq_parameter_set_id`.
+pub fn pps(pic_parameter_set_id: u8, seq_parameter_set_id: u8) -> PictureParameterSet {
+    PictureParameterSet {
+        pic_parameter_set_id,
+        seq_parameter_set_id,
+        entropy_coding_mode_flag: false,
+        bottom_field_pic_order_in_frame_present_flag: false,
+        num_ref_idx_l0_default_active_minus1: 0,
+        num_ref_idx_l1_default_active_minus1: 0,
+        weighted_pred_flag: false,
+        weighted_bipred_idc: 0,
+        pic_init_qp_minus26: 0,
+        pic_init_qs_minus26: 0,
+        chroma_qp_index_offset: 0,
+        deblocking_filter_control_present_flag: true,
+        constrained_intra_pred_flag: false,
+        redundant_pic_cnt_present_flag: false,
+        transform_8x8_mode_flag: false,
+        scaling_lists: None,
+        second_chroma_qp_index_offset: 0,
+    }
+}
+
+/// The first P slice of a non-IDR frame, marked with the sliding window when it is a reference.
+pub fn slice_header(frame_num: u32, nal_ref_idc: u8) -> SliceHeader {
+    SliceHeader {
+        nal_ref_idc,
+        idr: false,
+        first_mb_in_slice: 0,
+        slice_type: SliceType::P,
+        pic_parameter_set_id: 0,
+        colour_plane_id: 0,
+        frame_num,
+        field_pic_flag: false,
+        bottom_field_flag: false,
+        idr_pic_id: 0,
+        pic_order_cnt_lsb: 0,
+        delta_pic_order_cnt_bottom: 0,
+        delta_pic_order_cnt: [0; 2],
+        redundant_pic_cnt: 0,
+        direct_spatial_mv_pred_flag: false,
+        num_ref_idx_l0_active_minus1: 0,
+        num_ref_idx_l1_active_minus1: 0,
+        ref_pic_list_modification_l0: Vec::new(),
+        ref_pic_list_modification_l1: Vec::new(),
+        dec_ref_pic_marking: (nal_ref_idc != 0).then_some(DecRefPicMarking::SlidingWindow),
+        cabac_init_idc: 0,
+        slice_qp_delta: 0,
+        disable_deblocking_filter_idc: 0,
+        slice_alpha_c0_offset_div2: 0,
+        slice_beta_offset_div2: 0,
+    }
+}
+
+/// The first I slice of an IDR picture.
+pub fn idr_header() -> SliceHeader {
+    SliceHeader {
+        idr: true,
+        slice_type: SliceType::I,
+        dec_ref_pic_marking: Some(DecRefPicMarking::Idr {
+            no_output_of_prior_pics_flag: false,
+            long_term_reference_flag: false,
+        }),
+        ..slice_header(0, 3)
+    }
+}
+
+/// A reference frame marked with `operations`.
+pub fn adaptive_header(
+    frame_num: u32,
+    operations: &[MemoryManagementControlOperation],
+) -> SliceHeader {
+    SliceHeader {
+        dec_ref_pic_marking: Some(DecRefPicMarking::Adaptive(operations.to_vec())),
+        ..slice_header(frame_num, 2)
+    }
+}